@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS emails (
+    id TEXT PRIMARY KEY NOT NULL,
+    html TEXT NOT NULL,
+    user TEXT NOT NULL,
+    registered INTEGER NOT NULL,
+    from_addr TEXT NOT NULL,
+    to_addr TEXT NOT NULL,
+    subject TEXT NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Databases created before migrations existed may lack the primary key on id.
+CREATE UNIQUE INDEX IF NOT EXISTS emails_id ON emails (id);
+CREATE INDEX IF NOT EXISTS emails_user_registered ON emails (user, registered DESC);
+CREATE INDEX IF NOT EXISTS emails_user_from_addr ON emails (user, from_addr);
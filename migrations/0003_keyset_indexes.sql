@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS emails_user_registered;
+DROP INDEX IF EXISTS emails_user_from_addr;
+CREATE INDEX emails_user_registered ON emails (user, registered DESC, id DESC);
+CREATE INDEX emails_user_from_addr ON emails (user, from_addr, registered DESC, id DESC);
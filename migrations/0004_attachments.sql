@@ -0,0 +1,10 @@
+CREATE TABLE attachments (
+    email_id TEXT NOT NULL REFERENCES emails (id) ON DELETE CASCADE,
+    idx INTEGER NOT NULL,
+    filename TEXT,
+    mime TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    path TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    PRIMARY KEY (email_id, idx)
+);
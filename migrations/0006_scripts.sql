@@ -0,0 +1,9 @@
+CREATE TABLE scripts (
+    owner TEXT NOT NULL,
+    name TEXT NOT NULL,
+    json TEXT NOT NULL,
+    description TEXT NOT NULL DEFAULT '',
+    created INTEGER NOT NULL,
+    updated INTEGER NOT NULL,
+    PRIMARY KEY (owner, name)
+);
@@ -0,0 +1,13 @@
+CREATE TABLE script_runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    owner TEXT NOT NULL,
+    script_name TEXT,
+    trigger_type TEXT NOT NULL,
+    started INTEGER NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    input_count INTEGER NOT NULL,
+    output_count INTEGER NOT NULL,
+    error TEXT,
+    output_path TEXT
+);
+CREATE INDEX script_runs_owner_started ON script_runs (owner, started DESC);
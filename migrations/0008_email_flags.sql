@@ -0,0 +1,8 @@
+CREATE TABLE email_flags (
+    email_id TEXT PRIMARY KEY NOT NULL REFERENCES emails (id) ON DELETE CASCADE,
+    seen BOOLEAN NOT NULL DEFAULT FALSE,
+    spam BOOLEAN NOT NULL DEFAULT FALSE,
+    archived BOOLEAN NOT NULL DEFAULT FALSE,
+    starred BOOLEAN NOT NULL DEFAULT FALSE,
+    snoozed_until INTEGER
+);
@@ -0,0 +1 @@
+ALTER TABLE script_runs ADD COLUMN stages TEXT NOT NULL DEFAULT '[]';
@@ -0,0 +1,21 @@
+CREATE TABLE usage (
+    user TEXT NOT NULL,
+    -- Unix ms of the start of the hour the usage was recorded in.
+    hour INTEGER NOT NULL,
+    metric TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    bytes INTEGER NOT NULL,
+    PRIMARY KEY (user, hour, metric)
+);
+CREATE INDEX usage_hour ON usage (hour);
+
+INSERT INTO usage (user, hour, metric, count, bytes)
+SELECT emails.user, emails.registered - emails.registered % 3600000, 'email_ingested',
+       COUNT(DISTINCT emails.id), COALESCE(SUM(attachments.size), 0)
+FROM emails LEFT JOIN attachments ON attachments.email_id = emails.id
+GROUP BY 1, 2;
+
+INSERT INTO usage (user, hour, metric, count, bytes)
+SELECT owner, started - started % 3600000, 'script_run', COUNT(*), 0
+FROM script_runs
+GROUP BY 1, 2;
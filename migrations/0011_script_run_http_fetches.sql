@@ -0,0 +1 @@
+ALTER TABLE script_runs ADD COLUMN http_fetches INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,6 @@
+CREATE TABLE url_redirects (
+    url TEXT PRIMARY KEY NOT NULL,
+    target TEXT NOT NULL,
+    resolved INTEGER NOT NULL
+);
+CREATE INDEX url_redirects_resolved ON url_redirects (resolved DESC);
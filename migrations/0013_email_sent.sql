@@ -0,0 +1,5 @@
+-- Unix ms and the sender's UTC offset in minutes, from the Date header. NULL for emails
+-- ingested before this migration or without a parseable Date.
+ALTER TABLE emails ADD COLUMN sent INTEGER;
+ALTER TABLE emails ADD COLUMN sent_offset INTEGER;
+CREATE INDEX emails_user_sent ON emails (user, COALESCE(sent, registered) DESC, id DESC);
@@ -0,0 +1,3 @@
+-- Display names of from_addr and to_addr, as given in the From, To or Cc header.
+ALTER TABLE emails ADD COLUMN from_name TEXT;
+ALTER TABLE emails ADD COLUMN to_name TEXT;
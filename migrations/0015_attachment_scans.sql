@@ -0,0 +1,4 @@
+-- clamd's verdict at ingestion: 'clean', 'infected' or 'error'. NULL when scanning was off.
+ALTER TABLE attachments ADD COLUMN scan TEXT;
+-- The signature clamd matched, for infected attachments.
+ALTER TABLE attachments ADD COLUMN scan_signature TEXT;
@@ -0,0 +1,12 @@
+-- The latest one-click unsubscribe attempt per user and sender address.
+CREATE TABLE unsubscribes (
+    user TEXT NOT NULL,
+    sender TEXT NOT NULL,
+    email_id TEXT NOT NULL,
+    url TEXT NOT NULL,
+    attempted INTEGER NOT NULL,
+    -- HTTP status of the response, NULL when none arrived.
+    status INTEGER,
+    error TEXT,
+    PRIMARY KEY (user, sender)
+);
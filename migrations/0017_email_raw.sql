@@ -0,0 +1,2 @@
+-- Path of the stored RFC822 message. NULL for emails ingested before this migration.
+ALTER TABLE emails ADD COLUMN raw TEXT;
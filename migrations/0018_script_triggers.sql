@@ -0,0 +1,14 @@
+-- Saved scripts that run on each newly stored email of their owner that matches the regexes.
+CREATE TABLE script_triggers (
+    owner TEXT NOT NULL,
+    script_name TEXT NOT NULL,
+    from_regex TEXT,
+    subject_regex TEXT,
+    -- Receives each non-empty output as JSON.
+    webhook TEXT,
+    created INTEGER NOT NULL,
+    PRIMARY KEY (owner, script_name),
+    FOREIGN KEY (owner, script_name) REFERENCES scripts (owner, name) ON DELETE CASCADE
+);
+-- The email a trigger run was fired by.
+ALTER TABLE script_runs ADD COLUMN email_id TEXT;
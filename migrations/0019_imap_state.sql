@@ -0,0 +1,9 @@
+-- Where incremental ingestion left off, per IMAP account and mailbox.
+CREATE TABLE imap_state (
+    account TEXT NOT NULL,
+    mailbox TEXT NOT NULL,
+    uid_validity INTEGER NOT NULL,
+    -- Every message up to this UID has been stored or given up on.
+    last_uid INTEGER NOT NULL,
+    PRIMARY KEY (account, mailbox)
+);
@@ -0,0 +1,12 @@
+-- Images in `multipart/related` parts, which the stored HTML refers to as `inline/<idx>` in place
+-- of their `cid:` URLs.
+CREATE TABLE inline_images (
+    email_id TEXT NOT NULL REFERENCES emails (id) ON DELETE CASCADE,
+    idx INTEGER NOT NULL,
+    -- Without the angle brackets.
+    content_id TEXT NOT NULL,
+    mime TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    path TEXT NOT NULL,
+    PRIMARY KEY (email_id, idx)
+);
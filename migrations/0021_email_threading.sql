@@ -0,0 +1,5 @@
+-- The Message-ID header and the first message ID in In-Reply-To, without angle brackets. NULL
+-- for emails ingested before this migration or without the header.
+ALTER TABLE emails ADD COLUMN message_id TEXT;
+ALTER TABLE emails ADD COLUMN in_reply_to TEXT;
+CREATE INDEX emails_user_message_id ON emails (user, message_id);
@@ -0,0 +1,4 @@
+-- 'pass', 'fail' or 'none' for the DKIM signatures and SPF, as checked at ingestion. NULL when
+-- checking was off or for emails ingested before this migration.
+ALTER TABLE emails ADD COLUMN dkim TEXT;
+ALTER TABLE emails ADD COLUMN spf TEXT;
@@ -0,0 +1,9 @@
+-- Every occurrence, in order, of the headers named in `ingest.capture_headers`; the `headers`
+-- column only keeps the first of each. Names are lowercased.
+CREATE TABLE captured_headers (
+    email_id TEXT NOT NULL REFERENCES emails (id) ON DELETE CASCADE,
+    idx INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (email_id, idx)
+);
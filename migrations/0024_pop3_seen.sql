@@ -0,0 +1,8 @@
+-- POP3 messages left on the server that have been handled or given up on, by UIDL. Rows for
+-- messages no longer on the server are removed.
+CREATE TABLE pop3_seen (
+    account TEXT NOT NULL,
+    uidl TEXT NOT NULL,
+    seen INTEGER NOT NULL,
+    PRIMARY KEY (account, uidl)
+);
@@ -0,0 +1,3 @@
+-- Path of the HTML with scripts and remote resources stripped, stored next to `html`. NULL for
+-- emails stored before, until replayed.
+ALTER TABLE emails ADD COLUMN sanitized_html TEXT;
@@ -0,0 +1,68 @@
+use crate::{config::Config, status::Status};
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use std::time::Duration;
+use tracing::{error, warn};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize)]
+struct LagAlertPayload {
+    event: &'static str,
+    email_id: String,
+    user: String,
+    lag_ms: i64,
+    threshold_ms: i64,
+}
+
+/// Logs, and posts to the configured webhook, when `lag_ms` exceeds `lag_alert.threshold_secs`
+/// and no alert fired within the cooldown. The webhook is sent in the background.
+pub fn ingest_lag(config: &Config, status: &Status, email_id: &str, user: &str, lag_ms: i64) {
+    let Some(lag_alert) = &config.lag_alert else {
+        return;
+    };
+
+    let threshold_ms = lag_alert.threshold_secs as i64 * 1000;
+    if lag_ms <= threshold_ms
+        || !status.take_lag_alert(Duration::from_secs(lag_alert.cooldown_secs))
+    {
+        return;
+    }
+
+    warn!(
+        email_id,
+        user, lag_ms, threshold_ms, "Ingestion lag above threshold"
+    );
+
+    let Some(webhook) = lag_alert.webhook.clone() else {
+        return;
+    };
+    let payload = LagAlertPayload {
+        event: "ingest_lag",
+        email_id: email_id.to_owned(),
+        user: user.to_owned(),
+        lag_ms,
+        threshold_ms,
+    };
+    tokio::spawn(async move {
+        let body = match serde_json::to_vec(&payload) {
+            Ok(x) => x,
+            Err(e) => {
+                error!(error = ?e, "Lag alert serialize error");
+                return;
+            }
+        };
+
+        let result = reqwest::Client::new()
+            .post(&webhook)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .timeout(WEBHOOK_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = result {
+            error!(error = ?e, "Lag alert webhook error");
+        }
+    });
+}
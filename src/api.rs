@@ -1,60 +1,211 @@
+pub mod admin;
 pub mod execute_script;
+pub mod scripts;
+pub mod status;
+pub mod usage;
 
-use crate::{config::Macro, rocket_types::*, sql::*, ManagedConfig, ManagedPool};
-use rocket::{http::ContentType, serde::json::Json, State};
+use crate::{
+    config::{HtmlVersion, InfectedAttachments, Macro},
+    rocket_types::*,
+    sanitize,
+    sql::{self, *},
+    storage,
+    unsubscribe::{self, Unsubscribed},
+    ManagedConfig, ManagedPool,
+};
+use chrono::{DateTime, FixedOffset};
+use lol_html::{element, rewrite_str, RewriteStrSettings};
+use rocket::{http::ContentType, response::Responder, serde::json::Json, Request, State};
 use serde::Serialize;
-use tokio::fs;
+use std::time::Instant;
+use tracing::error;
 
 #[derive(Debug, Serialize)]
 pub struct ApiEmail {
     from_addr: String,
     to_addr: String,
+    from_name: Option<String>,
+    to_name: Option<String>,
     subject: String,
     id: String,
     registered: i64,
+    /// Unix ms from the `Date` header.
+    sent: Option<i64>,
+    /// The `Message-ID` header without angle brackets.
+    message_id: Option<String>,
+    /// The `message_id` of the email this one replies to.
+    in_reply_to: Option<String>,
+    /// `pass`, `fail` or `none` as checked at ingestion, if it was.
+    dkim: Option<String>,
+    spf: Option<String>,
+    /// `registered` as ISO-8601 in UTC, with `iso_dates=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    registered_at: Option<String>,
+    /// `sent` as ISO-8601 in the sender's timezone, with `iso_dates=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sent_at: Option<Option<String>>,
+    /// The headers in `ingest.capture_headers`, for a single email.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    captured_headers: Option<Vec<CapturedHeader>>,
 }
-impl From<Email> for ApiEmail {
-    fn from(email: Email) -> Self {
+impl ApiEmail {
+    fn new(email: Email, iso_dates: bool) -> Self {
+        let sent_at = email
+            .sent
+            .and_then(|sent| iso_timestamp(sent, email.sent_offset.unwrap_or(0)));
         ApiEmail {
+            registered_at: iso_dates
+                .then(|| iso_timestamp(email.registered, 0))
+                .flatten(),
+            sent_at: iso_dates.then_some(sent_at),
+            captured_headers: None,
             from_addr: email.from_addr,
             to_addr: email.to_addr,
+            from_name: email.from_name,
+            to_name: email.to_name,
             subject: email.subject,
             id: email.id,
             registered: email.registered,
+            sent: email.sent,
+            message_id: email.message_id,
+            in_reply_to: email.in_reply_to,
+            dkim: email.dkim,
+            spf: email.spf,
         }
     }
 }
 
-#[rocket::get("/emails/list")]
+fn iso_timestamp(unix_ms: i64, offset_minutes: i64) -> Option<String> {
+    let offset = FixedOffset::east_opt(i32::try_from(offset_minutes * 60).ok()?)?;
+    Some(
+        DateTime::from_timestamp_millis(unix_ms)?
+            .with_timezone(&offset)
+            .to_rfc3339(),
+    )
+}
+
+/// Lists emails newest first by `order`, `registered` (the default) or `sent`. `cursor` is
+/// `<timestamp>-<id>` of the last email already seen, as returned for the same order.
+#[rocket::get("/emails/list?<from>&<header>&<header_value>&<order>&<cursor>&<limit>&<iso_dates>")]
 pub async fn list_emails(
-    user: AuthorizedUser<'_>,
+    from: Option<&str>,
+    header: Option<&str>,
+    header_value: Option<&str>,
+    order: Option<&str>,
+    cursor: Option<&str>,
+    limit: Option<i64>,
+    iso_dates: Option<bool>,
+    user: AuthorizedUser,
     pool: &State<ManagedPool>,
     _ratelimit: Ratelimit,
 ) -> Result<FlexibleFormat<ApiEmail>, Error> {
-    let user_emails: Vec<Email> = match sqlx::query_as!(
-        Email,
-        r#"SELECT * FROM emails WHERE user = $1 ORDER BY registered DESC"#,
-        user.username
-    )
-    .fetch_all(&**pool)
-    .await
+    let timer = Instant::now();
+
+    let order = match order.map(str::parse::<EmailOrder>) {
+        Some(Ok(x)) => x,
+        Some(Err(())) => return Err(Error::invalid_input(ErrorCode::InvalidParameter, "order")),
+        None => EmailOrder::default(),
+    };
+    let cursor = match cursor.map(str::parse::<Cursor>) {
+        Some(Ok(x)) => x,
+        Some(Err(())) => return Err(Error::invalid_input(ErrorCode::InvalidCursor, "cursor")),
+        None => Cursor::start(),
+    };
+    let limit = limit.unwrap_or(-1);
+
+    if let Some(name) = header {
+        if !valid_header_name(name) {
+            return Err(Error::invalid_input(ErrorCode::InvalidHeaderName, name));
+        }
+    }
+
+    let filter = EmailFilter {
+        from_addr: from,
+        header: header.map(|name| (name, header_value)),
+    };
+
+    let user_emails = match emails_page(pool, &user.username, &filter, order, &cursor, limit).await
     {
         Ok(x) => x,
         Err(e) => {
-            eprintln!("/emails/list SELECT error: {:#?}", e);
+            error!(error = ?e, "/emails/list SELECT error");
             return Err(Error::InternalError);
         }
     };
 
-    Ok(FlexibleFormat::from_vec(
-        user_emails.into_iter().map(ApiEmail::from).collect(),
-    ))
+    let total = match count_emails(pool, &user.username, &filter).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!(error = ?e, "/emails/list COUNT error");
+            return Err(Error::InternalError);
+        }
+    };
+
+    let next_cursor =
+        Cursor::next_page(&user_emails, order, limit).map(|cursor| cursor.to_string());
+    let iso_dates = iso_dates.unwrap_or(false);
+    let mut formatted = FlexibleFormat::from_vec(
+        user_emails
+            .into_iter()
+            .map(|email| ApiEmail::new(email, iso_dates))
+            .collect(),
+    );
+    formatted.meta(PageMeta {
+        total: Some(total),
+        next_cursor,
+        elapsed_ms: Some(timer.elapsed().as_millis()),
+    });
+
+    Ok(formatted)
+}
+
+/// Appends the `auth` query parameter the HTML was loaded with to its inline image URLs, as
+/// images load without the `Authorization` header.
+fn authorize_inline_images(html: Vec<u8>, auth: &str) -> Vec<u8> {
+    let html = match String::from_utf8(html) {
+        Ok(x) => x,
+        Err(e) => return e.into_bytes(),
+    };
+    let query = format!(
+        "?auth={}",
+        url::form_urlencoded::byte_serialize(auth.as_bytes()).collect::<String>()
+    );
+    let query = &query;
+
+    let rewritten = rewrite_str(
+        &html,
+        RewriteStrSettings {
+            element_content_handlers: ["src", "background"]
+                .into_iter()
+                .map(|attribute| {
+                    element!(format!("[{}^=\"inline/\"]", attribute), move |el| {
+                        if let Some(value) = el.get_attribute(attribute) {
+                            el.set_attribute(attribute, &format!("{}{}", value, query))?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect(),
+            ..RewriteStrSettings::default()
+        },
+    );
+    match rewritten {
+        Ok(x) => x.into_bytes(),
+        Err(e) => {
+            error!(error = ?e, "Inline image URL rewrite error");
+            html.into_bytes()
+        }
+    }
 }
 
-#[rocket::get("/emails/<id>/html")]
+/// Inline images are served by [`get_inline_image`], relative to this route. `version` defaults
+/// to `ingest.view_html`.
+#[rocket::get("/emails/<id>/html?<auth>&<version>")]
 pub async fn view_email(
     id: &str,
-    user: AuthorizedUser<'_>,
+    auth: Option<&str>,
+    version: Option<HtmlVersion>,
+    user: AuthorizedUser,
     pool: &State<ManagedPool>,
     config: &State<ManagedConfig>,
     _ratelimit: Ratelimit,
@@ -71,27 +222,48 @@ pub async fn view_email(
         Ok(Some(email)) => email,
         Ok(None) => return Err(Error::Unauthorized),
         Err(e) => {
-            eprintln!("/emails/<id>/html SELECT error: {:#?}", e);
+            error!(error = ?e, email_id = %id, "/emails/<id>/html SELECT error");
             return Err(Error::InternalError);
         }
     };
 
-    match fs::read(format!("{}/{}", config.storage.file_root, email.html)).await {
-        Ok(bytes) => Ok((ContentType::HTML, bytes)),
+    let config = config.load();
+    let version = version.unwrap_or(config.ingest.view_html);
+    let (path, sanitize_now) = match (version, &email.sanitized_html) {
+        (HtmlVersion::Sanitized, Some(sanitized_html)) => (sanitized_html, false),
+        (HtmlVersion::Sanitized, None) => (&email.html, true),
+        (HtmlVersion::Original, _) => (&email.html, false),
+    };
+    let bytes = match storage::read(&config.storage, &user.username, path).await {
+        Ok(x) => x,
         Err(e) => {
-            eprintln!("/emails/<id>/html fs::read error: {:#?}", e);
+            error!(error = ?e, email_id = %id, "/emails/<id>/html storage::read error");
             return Err(Error::InternalError);
         }
-    }
+    };
+    let bytes = if sanitize_now {
+        sanitize::sanitize(&String::from_utf8_lossy(&bytes)).into_bytes()
+    } else {
+        bytes
+    };
+
+    Ok((
+        ContentType::HTML,
+        match auth {
+            Some(auth) => authorize_inline_images(bytes, auth),
+            None => bytes,
+        },
+    ))
 }
 
-#[rocket::get("/emails/<id>")]
+#[rocket::get("/emails/<id>?<iso_dates>")]
 pub async fn get_email(
     id: &str,
-    user: AuthorizedUser<'_>,
+    iso_dates: Option<bool>,
+    user: AuthorizedUser,
     pool: &State<ManagedPool>,
     _ratelimit: Ratelimit,
-) -> Result<Json<ApiEmail>, Error> {
+) -> Result<ApiJson<ApiEmail>, Error> {
     let email = match sqlx::query_as!(
         Email,
         r#"SELECT * FROM emails WHERE user = $1 AND id = $2"#,
@@ -103,34 +275,318 @@ pub async fn get_email(
     {
         Ok(x) => x,
         Err(e) => {
-            eprintln!("/emails/<id> SELECT error: {:#?}", e);
+            error!(error = ?e, email_id = %id, "/emails/<id> SELECT error");
+            return Err(Error::InternalError);
+        }
+    };
+
+    let captured_headers = match sql::captured_headers(pool, id).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!(error = ?e, email_id = %id, "/emails/<id> captured headers SELECT error");
+            return Err(Error::InternalError);
+        }
+    };
+
+    let mut api_email = ApiEmail::new(email, iso_dates.unwrap_or(false));
+    api_email.captured_headers = Some(captured_headers);
+    Ok(ApiJson(api_email))
+}
+
+async fn check_email_owner(pool: &ManagedPool, id: &str, username: &str) -> Result<(), Error> {
+    match sqlx::query!(
+        r#"SELECT 1 as existence FROM emails WHERE id = $1 AND user = $2"#,
+        id,
+        username
+    )
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(Error::NotFound(ErrorCode::EmailNotFound)),
+        Err(e) => {
+            error!(error = ?e, email_id = %id, "/emails/<id>/flags SELECT error");
+            Err(Error::InternalError)
+        }
+    }
+}
+
+#[rocket::get("/emails/<id>/flags")]
+pub async fn get_email_flags(
+    id: &str,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    _ratelimit: Ratelimit,
+) -> Result<ApiJson<EmailFlags>, Error> {
+    check_email_owner(pool, id, &user.username).await?;
+
+    match sql::get_email_flags(pool, id).await {
+        Ok(flags) => Ok(ApiJson(flags)),
+        Err(e) => {
+            error!(error = ?e, email_id = %id, "/emails/<id>/flags SELECT flags error");
+            Err(Error::InternalError)
+        }
+    }
+}
+
+#[rocket::put("/emails/<id>/flags", format = "json", data = "<flags>")]
+pub async fn put_email_flags(
+    id: &str,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    flags: Json<EmailFlags>,
+    _ratelimit: Ratelimit,
+) -> Result<ApiJson<EmailFlags>, Error> {
+    check_email_owner(pool, id, &user.username).await?;
+
+    match sql::set_email_flags(pool, id, &flags).await {
+        Ok(()) => Ok(ApiJson(flags.into_inner())),
+        Err(e) => {
+            error!(error = ?e, email_id = %id, "/emails/<id>/flags upsert error");
+            Err(Error::InternalError)
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiAttachment {
+    idx: i64,
+    filename: Option<String>,
+    mime: String,
+    size: i64,
+    hash: String,
+    scan: Option<String>,
+    scan_signature: Option<String>,
+}
+impl From<Attachment> for ApiAttachment {
+    fn from(attachment: Attachment) -> Self {
+        ApiAttachment {
+            idx: attachment.idx,
+            filename: attachment.filename,
+            mime: attachment.mime,
+            size: attachment.size,
+            hash: attachment.hash,
+            scan: attachment.scan,
+            scan_signature: attachment.scan_signature,
+        }
+    }
+}
+
+#[rocket::get("/emails/<id>/attachments")]
+pub async fn list_attachments(
+    id: &str,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    _ratelimit: Ratelimit,
+) -> Result<FlexibleFormat<ApiAttachment>, Error> {
+    check_email_owner(pool, id, &user.username).await?;
+
+    match sql::email_attachments(pool, id).await {
+        Ok(attachments) => Ok(FlexibleFormat::from_vec(
+            attachments.into_iter().map(ApiAttachment::from).collect(),
+        )),
+        Err(e) => {
+            error!(error = ?e, email_id = %id, "/emails/<id>/attachments SELECT error");
+            Err(Error::InternalError)
+        }
+    }
+}
+
+/// An attachment's contents, always as a download so that HTML attachments do not run on this
+/// origin.
+pub struct AttachmentBody {
+    content_type: ContentType,
+    scan: Option<String>,
+    body: Vec<u8>,
+}
+impl<'r, 'o: 'r> Responder<'r, 'o> for AttachmentBody {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'o> {
+        let mut response = (self.content_type, self.body).respond_to(request)?;
+        response.set_raw_header("Content-Disposition", "attachment");
+        response.set_raw_header("X-Content-Type-Options", "nosniff");
+        if let Some(scan) = self.scan {
+            response.set_raw_header("X-Scan-Result", scan);
+        }
+        Ok(response)
+    }
+}
+
+/// Infected attachments are refused unless `clamd.infected` is `flag`.
+#[rocket::get("/emails/<id>/attachments/<idx>")]
+pub async fn get_attachment(
+    id: &str,
+    idx: i64,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    config: &State<ManagedConfig>,
+    _ratelimit: Ratelimit,
+) -> Result<AttachmentBody, Error> {
+    check_email_owner(pool, id, &user.username).await?;
+
+    let attachment = match sql::email_attachment(pool, id, idx).await {
+        Ok(Some(x)) => x,
+        Ok(None) => return Err(Error::NotFound(ErrorCode::AttachmentNotFound)),
+        Err(e) => {
+            error!(error = ?e, email_id = %id, idx, "/emails/<id>/attachments/<idx> SELECT error");
+            return Err(Error::InternalError);
+        }
+    };
+
+    let config = config.load();
+    let infected_action = config
+        .clamd
+        .as_ref()
+        .map_or(InfectedAttachments::Block, |clamd| clamd.infected);
+    if attachment.infected() && infected_action == InfectedAttachments::Block {
+        return Err(Error::Forbidden(ErrorCode::AttachmentInfected));
+    }
+
+    let body = match storage::read(&config.storage, &user.username, &attachment.path).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!(
+                error = ?e,
+                email_id = %id,
+                idx,
+                "/emails/<id>/attachments/<idx> storage::read error"
+            );
+            return Err(Error::InternalError);
+        }
+    };
+
+    Ok(AttachmentBody {
+        content_type: ContentType::parse_flexible(&attachment.mime).unwrap_or(ContentType::Binary),
+        scan: attachment.scan,
+        body,
+    })
+}
+
+/// An image from a `multipart/related` part, as the stored HTML refers to it in place of its
+/// `cid:` URL.
+#[rocket::get("/emails/<id>/inline/<idx>")]
+pub async fn get_inline_image(
+    id: &str,
+    idx: i64,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    config: &State<ManagedConfig>,
+    _ratelimit: Ratelimit,
+) -> Result<AttachmentBody, Error> {
+    check_email_owner(pool, id, &user.username).await?;
+
+    let inline_image = match sql::email_inline_image(pool, id, idx).await {
+        Ok(Some(x)) => x,
+        Ok(None) => return Err(Error::NotFound(ErrorCode::InlineImageNotFound)),
+        Err(e) => {
+            error!(error = ?e, email_id = %id, idx, "/emails/<id>/inline/<idx> SELECT error");
+            return Err(Error::InternalError);
+        }
+    };
+
+    let body = match storage::read(&config.load().storage, &user.username, &inline_image.path).await
+    {
+        Ok(x) => x,
+        Err(e) => {
+            error!(
+                error = ?e,
+                email_id = %id,
+                idx,
+                "/emails/<id>/inline/<idx> storage::read error"
+            );
+            return Err(Error::InternalError);
+        }
+    };
+
+    Ok(AttachmentBody {
+        content_type: ContentType::parse_flexible(&inline_image.mime)
+            .unwrap_or(ContentType::Binary),
+        scan: None,
+        body,
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiUnsubscribe {
+    /// False when the sender had already accepted an earlier request, which was not repeated.
+    attempted: bool,
+    succeeded: bool,
+    #[serde(flatten)]
+    unsubscribe: Unsubscribe,
+}
+
+/// Performs the email's RFC 8058 one-click unsubscribe and records the result for its sender.
+#[rocket::post("/emails/<id>/unsubscribe")]
+pub async fn unsubscribe_email(
+    id: &str,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    config: &State<ManagedConfig>,
+    _ratelimit: Ratelimit,
+) -> Result<ApiJson<ApiUnsubscribe>, Error> {
+    let email = match sqlx::query_as!(
+        Email,
+        r#"SELECT * FROM emails WHERE id = $1 AND user = $2"#,
+        id,
+        user.username
+    )
+    .fetch_optional(&**pool)
+    .await
+    {
+        Ok(Some(email)) => email,
+        Ok(None) => return Err(Error::NotFound(ErrorCode::EmailNotFound)),
+        Err(e) => {
+            error!(error = ?e, email_id = %id, "/emails/<id>/unsubscribe SELECT error");
             return Err(Error::InternalError);
         }
     };
 
-    Ok(Json(email.into()))
+    let (attempted, unsubscribe) =
+        match unsubscribe::unsubscribe(&config.load(), pool, &email).await {
+            Ok(Unsubscribed::NotOffered) => {
+                return Err(Error::invalid_input(ErrorCode::UnsubscribeNotOffered, id))
+            }
+            Ok(Unsubscribed::Already(done)) => (false, done),
+            Ok(Unsubscribed::Attempted(attempt)) => (true, attempt),
+            Err(e) => {
+                error!(error = ?e, email_id = %id, "/emails/<id>/unsubscribe error");
+                return Err(Error::InternalError);
+            }
+        };
+
+    Ok(ApiJson(ApiUnsubscribe {
+        attempted,
+        succeeded: unsubscribe.succeeded(),
+        unsubscribe,
+    }))
 }
 
 #[rocket::get("/macros/list")]
-pub async fn list_macros<'a>(
-    _user: AuthorizedUser<'_>,
-    config: &'a State<ManagedConfig>,
+pub async fn list_macros(
+    _user: AuthorizedUser,
+    config: &State<ManagedConfig>,
     _ratelimit: Ratelimit,
-) -> FlexibleFormat<&'a str> {
-    FlexibleFormat::from_vec(config.macros.iter().map(|mac| &*mac.name).collect())
+) -> FlexibleFormat<String> {
+    FlexibleFormat::from_vec(
+        config
+            .load()
+            .macros
+            .iter()
+            .map(|mac| mac.name.clone())
+            .collect(),
+    )
 }
 
 #[rocket::get("/macros/<name>")]
-pub async fn get_macro<'a>(
+pub async fn get_macro(
     name: String,
-    _user: AuthorizedUser<'_>,
-    config: &'a State<ManagedConfig>,
+    _user: AuthorizedUser,
+    config: &State<ManagedConfig>,
     _ratelimit: Ratelimit,
-) -> Result<Json<&'a Macro>, Error> {
-    if let Some(mac) = config.macros.iter().find(|mac| mac.name == name) {
-        Ok(Json(mac))
+) -> Result<ApiJson<Macro>, Error> {
+    if let Some(mac) = config.load().macros.iter().find(|mac| mac.name == name) {
+        Ok(ApiJson(mac.clone()))
     } else {
-        Err(Error::NotFound)
+        Err(Error::NotFound(ErrorCode::MacroNotFound))
     }
 }
 
@@ -140,6 +596,6 @@ pub struct Verified {
 }
 
 #[rocket::get("/auth/verify")]
-pub async fn verify_auth(_user: AuthorizedUser<'_>, _ratelimit: Ratelimit) -> Json<Verified> {
-    Json(Verified { verified: true })
+pub async fn verify_auth(_user: AuthorizedUser, _ratelimit: Ratelimit) -> ApiJson<Verified> {
+    ApiJson(Verified { verified: true })
 }
@@ -0,0 +1,203 @@
+use crate::{
+    api::execute_script::{self, PipelineDiagnostics},
+    rocket_types::{AdminUser, ApiJson, Error, ErrorCode, Ratelimit},
+    sql::{self, UsageMetric},
+    util::{self, CacheStats},
+    ManagedConfig, ManagedPool, ManagedRatelimits, ManagedStatus, ManagedUrlCache,
+};
+use rocket::{FromFormField, State};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tokio::runtime::Handle;
+use tracing::{error, info};
+use url::Url;
+
+#[derive(Debug, Clone, Copy, FromFormField, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsWindow {
+    Hour,
+    Day,
+    Week,
+    Month,
+    All,
+}
+impl StatsWindow {
+    fn since(self) -> i64 {
+        let hours = match self {
+            StatsWindow::Hour => 1,
+            StatsWindow::Day => 24,
+            StatsWindow::Week => 7 * 24,
+            StatsWindow::Month => 30 * 24,
+            StatsWindow::All => return 0,
+        };
+        util::unix_ms() - hours * 60 * 60 * 1000
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct UserStats {
+    emails: i64,
+    email_bytes: i64,
+    script_runs: i64,
+    ratelimit_hits: i64,
+    requests: i64,
+    bytes_served: i64,
+    http_fetches: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminStats {
+    window: StatsWindow,
+    /// Keyed by username; configured users without any usage are included with zeros.
+    users: BTreeMap<String, UserStats>,
+}
+
+/// `window` is `hour`, `day` (the default), `week`, `month` or `all`, counted in whole hours.
+#[rocket::get("/admin/stats?<window>")]
+pub async fn get_stats(
+    window: Option<StatsWindow>,
+    _admin: AdminUser,
+    pool: &State<ManagedPool>,
+    config: &State<ManagedConfig>,
+    _ratelimit: Ratelimit,
+) -> Result<ApiJson<AdminStats>, Error> {
+    let window = window.unwrap_or(StatsWindow::Day);
+    let totals = match sql::usage_totals(pool, window.since()).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!(error = ?e, "/admin/stats SELECT error");
+            return Err(Error::InternalError);
+        }
+    };
+
+    let mut users: BTreeMap<String, UserStats> = config
+        .load()
+        .users
+        .as_slice()
+        .iter()
+        .map(|user| (user.username.clone(), UserStats::default()))
+        .collect();
+    for total in totals {
+        let stats = users.entry(total.user).or_default();
+        let metric = total.metric.as_str();
+        if metric == UsageMetric::EmailIngested.as_str() {
+            stats.emails = total.count;
+            stats.email_bytes = total.bytes;
+        } else if metric == UsageMetric::ScriptRun.as_str() {
+            stats.script_runs = total.count;
+        } else if metric == UsageMetric::RatelimitHit.as_str() {
+            stats.ratelimit_hits = total.count;
+        } else if metric == UsageMetric::Request.as_str() {
+            stats.requests = total.count;
+            stats.bytes_served = total.bytes;
+        } else if metric == UsageMetric::HttpFetch.as_str() {
+            stats.http_fetches = total.count;
+        }
+    }
+
+    Ok(ApiJson(AdminStats { window, users }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct RuntimeDiagnostics {
+    workers: usize,
+    alive_tasks: usize,
+    global_queue_depth: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Diagnostics {
+    runtime: RuntimeDiagnostics,
+    running_scripts: usize,
+    pipeline: PipelineDiagnostics,
+    url_cache: CacheStats,
+    ratelimit_entries: usize,
+}
+
+#[rocket::get("/admin/diagnostics")]
+pub async fn get_diagnostics(
+    _admin: AdminUser,
+    status: &State<ManagedStatus>,
+    url_cache: &State<ManagedUrlCache>,
+    ratelimits: &State<ManagedRatelimits>,
+    _ratelimit: Ratelimit,
+) -> ApiJson<Diagnostics> {
+    let metrics = Handle::current().metrics();
+
+    ApiJson(Diagnostics {
+        runtime: RuntimeDiagnostics {
+            workers: metrics.num_workers(),
+            alive_tasks: metrics.num_alive_tasks(),
+            global_queue_depth: metrics.global_queue_depth(),
+        },
+        running_scripts: status.running_scripts(),
+        pipeline: execute_script::pipeline_diagnostics(),
+        url_cache: url_cache.stats(),
+        ratelimit_entries: ratelimits.len(),
+    })
+}
+
+/// Keyed by cache name, which is what `DELETE /admin/caches/<name>` takes.
+#[rocket::get("/admin/caches")]
+pub async fn get_caches(
+    _admin: AdminUser,
+    url_cache: &State<ManagedUrlCache>,
+    _ratelimit: Ratelimit,
+) -> ApiJson<BTreeMap<&'static str, CacheStats>> {
+    ApiJson(BTreeMap::from([("url", url_cache.stats())]))
+}
+
+#[derive(Debug, Serialize)]
+pub struct Invalidated {
+    entries: usize,
+    /// Rows removed from the database, for caches that persist entries.
+    persisted: u64,
+}
+
+/// Drops the entry for `key` from the named cache, or every entry when no key is given. For the
+/// `url` cache, redirects persisted in the database are removed too so they are not restored.
+#[rocket::delete("/admin/caches/<name>?<key>")]
+pub async fn invalidate_cache(
+    name: &str,
+    key: Option<&str>,
+    admin: AdminUser,
+    pool: &State<ManagedPool>,
+    url_cache: &State<ManagedUrlCache>,
+    _ratelimit: Ratelimit,
+) -> Result<ApiJson<Invalidated>, Error> {
+    if name != "url" {
+        return Err(Error::NotFound(ErrorCode::CacheNotFound));
+    }
+
+    let url = match key {
+        Some(key) => match Url::parse(key) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                return Err(Error::invalid_input(ErrorCode::InvalidCacheKey, key).with_detail(e))
+            }
+        },
+        None => None,
+    };
+
+    let entries = match &url {
+        Some(url) => url_cache.remove(url) as usize,
+        None => url_cache.clear(),
+    };
+    let persisted = match sql::delete_url_redirects(pool, url.as_ref().map(Url::as_str)).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!(error = ?e, "/admin/caches/<name> DELETE error");
+            return Err(Error::InternalError);
+        }
+    };
+
+    info!(
+        cache = name,
+        key,
+        entries,
+        persisted,
+        admin = %admin.username,
+        "Cache invalidated"
+    );
+    Ok(ApiJson(Invalidated { entries, persisted }))
+}
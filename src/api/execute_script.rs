@@ -1,35 +1,77 @@
 use crate::{
-    rocket_types::{AuthorizedUser, Error, FlexibleFormat, Ratelimit},
-    sql::Email,
-    ManagedConfig, ManagedPool, ManagedUrlCache,
+    api::scripts,
+    config::{Config, Http, InfectedAttachments, Plugin},
+    eval::EvalScript,
+    plugins::PluginOutput,
+    rocket_types::{
+        ArchiveFile, AuthorizedUser, CalendarEvent, Error, ErrorCode, ExpectedFormat,
+        FlexibleFormat, JsonStream, PageMeta, Ratelimit, ScriptClass,
+    },
+    sql::{
+        self, emails_page, Cursor, Email, EmailFilter, EmailOrder, NewScriptRun, RunTrigger,
+        Unsubscribe,
+    },
+    storage,
+    triggers::Triggers,
+    unsubscribe::{self, Unsubscribed},
+    util::{self, WorkerPool},
+    ManagedConfig, ManagedFetchBudget, ManagedPlugins, ManagedPool, ManagedStatus, ManagedUrlCache,
 };
-use futures::Future;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use futures::{Future, Stream};
 use itertools::Itertools;
 use regex::Regex;
 use reqwest::{
-    header::{HeaderMap, HeaderValue},
-    Client as HttpClient,
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Client as HttpClient, Proxy,
 };
-use rocket::{serde::json::Json, State};
+use rocket::{serde::json::Json, Either, Shutdown, State};
+use schemars::JsonSchema;
 use scraper::{ElementRef, Html, Selector};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
 use std::ops::Deref;
 use std::pin::Pin;
-use std::sync::Arc;
-use tokio::{fs, sync::mpsc};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex, PoisonError,
+};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
 use url::Url;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Script {
     actions: Vec<Action>,
+    #[serde(default)]
+    after: Option<String>,
+    #[serde(default)]
+    limit: Option<i64>,
+    /// Header row for CSV-like output, whose flattened rows have no field names of their own.
+    #[serde(default)]
+    columns: Option<Vec<String>>,
 }
 
-#[derive(Debug, Deserialize, Clone, Serialize)]
+#[derive(Debug, Deserialize, Clone, Serialize, JsonSchema)]
 #[serde(tag = "name", content = "arguments")]
 pub enum Action {
     EmailToHtml,
     EmailFilterRegex(EmailAttribute, String),
     EmailGetAttr(EmailAttribute),
+    /// Performs the email's RFC 8058 one-click unsubscribe, at most once per sender, and yields
+    /// the URL posted to when the sender has accepted it.
+    EmailUnsubscribe,
+    /// Yields each attachment whose MIME type matches the pattern, in which `*` stands for any
+    /// run of characters as in `text/*`: `text/html` ones as Html, other `text/*` ones as Text,
+    /// and the rest as the Text of a base64 `data:` URL with their MIME type. Infected
+    /// attachments are skipped unless `clamd.infected` is `flag`.
+    EmailAttachments(String),
+    /// Yields each value of the header as Text: every occurrence for headers in
+    /// `ingest.capture_headers`, otherwise only the first. Case is ignored.
+    EmailGetHeader(String),
 
     HtmlInnerText,
     HtmlOuterHtml,
@@ -42,6 +84,8 @@ pub enum Action {
     TextFilterRegex(String),
     TextToHtml,
     TextToUrl,
+    /// Parses with a chrono format string; date-only formats yield midnight.
+    TextToDate(String),
 
     UrlToText,
     UrlFollowRedirect,
@@ -57,18 +101,39 @@ pub enum Action {
     PairRightLeft,
 
     Macro(String),
+    /// Runs the named entry of the config's `plugins` on each element, handing it the second
+    /// argument as is.
+    Plugin(String, Value),
+    /// Runs a Rhai snippet on each Text or Html element, with the element's string bound to
+    /// `input`; every string it returns becomes a Text element. Needs `scripts.eval`.
+    Eval(String),
 
     Or(Vec<Action>, Vec<Action>),
     Pair(Vec<Action>, Vec<Action>),
     Filter(Vec<Action>),
 }
 
-#[derive(Debug, Deserialize, Clone, Copy, Serialize)]
+#[derive(Debug, Deserialize, Clone, Copy, Serialize, JsonSchema)]
 pub enum EmailAttribute {
     Id,
     FromAddress,
+    /// The sender's display name, empty if the `From` header gives none.
+    FromName,
     ToAddress,
     Subject,
+    /// Without angle brackets, empty if the email has no `Message-ID`.
+    MessageId,
+    /// The `MessageId` of the email this one replies to, empty if it is not a reply.
+    InReplyTo,
+    /// `pass`, `fail` or `none`, the last also when `ingest.authentication` was off. Only a
+    /// signature or envelope sender aligned with the `From` domain passes, so `EmailFilterRegex`
+    /// with `^pass$` drops mail whose `From` is spoofed.
+    Dkim,
+    Spf,
+    /// The `Date` header in the sender's timezone. A date element from `EmailGetAttr`, nothing if
+    /// the email has no parseable `Date`; text in the date element format to regexes, empty
+    /// without one.
+    SentAt,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -78,27 +143,70 @@ pub enum SerdeElement {
     Text(Arc<str>),
     Email(String),
     Url(String),
+    /// ISO-8601 local date and time, `YYYY-MM-DDTHH:MM:SS`.
+    Date(String),
     Pair(Vec<SerdeElement>, Vec<SerdeElement>),
 }
 
+pub(crate) const DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+fn parse_date(text: &str, format: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(text, format)
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(text, format)
+                .ok()
+                .map(|date| date.and_time(NaiveTime::MIN))
+        })
+}
+
+/// HTML source together with its parse tree, built on first use and shared by every clone, so that
+/// a chain of Html actions parses each string at most once.
+#[derive(Clone)]
+struct HtmlDoc {
+    source: Arc<str>,
+    parsed: Arc<Mutex<Option<Html>>>,
+}
+impl HtmlDoc {
+    fn new(source: impl Into<Arc<str>>) -> Self {
+        HtmlDoc {
+            source: source.into(),
+            parsed: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn with_parsed<R>(&self, f: impl FnOnce(&Html) -> R) -> R {
+        let mut parsed = self.parsed.lock().unwrap_or_else(PoisonError::into_inner);
+        f(parsed.get_or_insert_with(|| Html::parse_fragment(&self.source)))
+    }
+}
+impl fmt::Debug for HtmlDoc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("HtmlDoc").field(&self.source).finish()
+    }
+}
+
+/// Every payload is shared, so handing an element to several branches or pairs never copies it.
 #[derive(Debug, Clone)]
 enum Element {
-    Html(Arc<str>),
+    Html(HtmlDoc),
     Text(Arc<str>),
     Email(Arc<Email>),
-    Url(Url),
-    Pair(Vec<Element>, Vec<Element>),
+    Url(Arc<Url>),
+    Date(NaiveDateTime),
+    Pair(Arc<[Element]>, Arc<[Element]>),
 }
 impl From<Element> for SerdeElement {
     fn from(value: Element) -> Self {
         match value {
-            Element::Html(el) => SerdeElement::Html(el),
+            Element::Html(html) => SerdeElement::Html(html.source),
             Element::Text(str) => SerdeElement::Text(str),
             Element::Email(eml) => SerdeElement::Email(eml.id.to_owned()),
             Element::Url(url) => SerdeElement::Url(url.to_string()),
+            Element::Date(date) => SerdeElement::Date(date.format(DATE_FORMAT).to_string()),
             Element::Pair(elements1, elements2) => SerdeElement::Pair(
-                elements1.into_iter().map(SerdeElement::from).collect(),
-                elements2.into_iter().map(SerdeElement::from).collect(),
+                elements1.iter().cloned().map(SerdeElement::from).collect(),
+                elements2.iter().cloned().map(SerdeElement::from).collect(),
             ),
         }
     }
@@ -117,35 +225,253 @@ impl FragmentRoot for Html {
     }
 }
 
+pub fn http_client(http: &Http) -> reqwest::Result<HttpClient> {
+    let mut header_map = HeaderMap::new();
+    header_map.append("User-Agent", HeaderValue::from_static("Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36"));
+    header_map.append("Dnt", HeaderValue::from_static("1"));
+    header_map.append("Sec-Fetch-Site", HeaderValue::from_static("none"));
+    header_map.append("Sec-Fetch-Dest", HeaderValue::from_static("document"));
+    header_map.append("Sec-Fetch-Mode", HeaderValue::from_static("navigate"));
+    header_map.append("Sec-Fetch-User", HeaderValue::from_static("?1"));
+    header_map.append("Accept", HeaderValue::from_static("text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8,application/signed-exchange;v=b3;q=0.7"));
+    header_map.append(
+        "Accept-Encoding",
+        HeaderValue::from_static("gzip, deflate, br"),
+    );
+    header_map.append("Accept-Language", HeaderValue::from_static("en"));
+
+    // Config::validate has already rejected names and values that fail to parse here.
+    let overrides = http
+        .user_agent
+        .iter()
+        .map(|user_agent| ("User-Agent", user_agent.as_str()))
+        .chain(
+            http.headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+    for (name, value) in overrides {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            header_map.insert(name, value);
+        }
+    }
+
+    let mut builder = HttpClient::builder()
+        .default_headers(header_map)
+        .cookie_store(true);
+    if let Some(proxy) = &http.proxy {
+        builder = builder.proxy(Proxy::all(proxy)?);
+    }
+
+    builder.build()
+}
+
+/// How long one top-level stage took. Nested pipelines inside `Pair`, `Or` and `Filter` count
+/// towards the stage that runs them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageTiming {
+    pub action: Action,
+    pub duration_ms: i64,
+    pub input_count: i64,
+    pub output_count: i64,
+}
+
+/// State shared by every stage and nested pipeline of one script execution.
+struct RunContext {
+    config: Arc<Config>,
+    pool: ManagedPool,
+    url_cache: ManagedUrlCache,
+    plugins: ManagedPlugins,
+    fetch_budget: ManagedFetchBudget,
+    /// Who the run's outbound fetches count against.
+    user: String,
+    http_fetches: AtomicUsize,
+}
+impl RunContext {
+    fn http_fetches(&self) -> i64 {
+        self.http_fetches.load(Ordering::Relaxed) as i64
+    }
+
+    /// Counts one outbound fetch, failing once the user has used up
+    /// `scripts.http_fetches_per_hour`.
+    async fn take_fetch(&self) -> Result<(), Error> {
+        self.http_fetches.fetch_add(1, Ordering::Relaxed);
+        let Some(limit) = self.config.scripts.http_fetches_per_hour else {
+            return Ok(());
+        };
+
+        match self.fetch_budget.take(&self.pool, &self.user, limit).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(Error::Ratelimited(ErrorCode::FetchBudgetExceeded)),
+            Err(e) => {
+                error!(error = ?e, "/emails/execute-script fetch budget error");
+                Err(Error::InternalError)
+            }
+        }
+    }
+}
+
+/// `None` when the URL could not be fetched, now or within `url_cache.failure_ttl_secs`.
+async fn follow_redirect(run: &RunContext, url: &Url) -> Result<Option<Url>, Error> {
+    if let Some(cached) = run.url_cache.get(url) {
+        return Ok(cached.deref().deref().clone());
+    }
+    if let Some(x) = stored_redirect(run, url).await {
+        run.url_cache.insert(url.clone(), Some(x.clone()));
+        return Ok(Some(x));
+    }
+
+    let client = match http_client(&run.config.http) {
+        Ok(x) => x,
+        Err(e) => {
+            error!(error = ?e, "/email/execute-script initialize HTTP client error");
+            return Err(Error::InternalError);
+        }
+    };
+
+    run.take_fetch().await?;
+    let response = match client.get(url.clone()).send().await {
+        Ok(x) => x,
+        Err(e) => {
+            warn!(error = ?e, "/email/execute-script HTTP error");
+            if let Some(failure_ttl_secs) = run.config.url_cache.failure_ttl_secs {
+                run.url_cache
+                    .insert_for(url.clone(), None, Duration::from_secs(failure_ttl_secs));
+            }
+            return Ok(None);
+        }
+    };
+
+    store_redirect(run, url, response.url()).await;
+    run.url_cache
+        .insert(url.clone(), Some(response.url().clone()));
+    Ok(Some(response.url().clone()))
+}
+
+async fn stored_redirect(run: &RunContext, url: &Url) -> Option<Url> {
+    if !run.config.url_cache.persist {
+        return None;
+    }
+
+    let since = run.config.url_cache.expired_before(util::unix_ms());
+    match sql::get_url_redirect(&run.pool, url.as_str(), since).await {
+        Ok(redirect) => redirect.and_then(|redirect| Url::parse(&redirect.target).ok()),
+        Err(e) => {
+            error!(error = ?e, "/emails/execute-script url_redirects SELECT error");
+            None
+        }
+    }
+}
+
+async fn store_redirect(run: &RunContext, url: &Url, target: &Url) {
+    if !run.config.url_cache.persist {
+        return;
+    }
+
+    if let Err(e) =
+        sql::upsert_url_redirect(&run.pool, url.as_str(), target.as_str(), util::unix_ms()).await
+    {
+        error!(error = ?e, "/emails/execute-script url_redirects INSERT error");
+    }
+}
+
+/// Fills `url_cache` with the newest persisted redirects that have not expired, oldest first so
+/// that the newest are the last to be evicted.
+pub async fn restore_url_cache(url_cache: &ManagedUrlCache, pool: &ManagedPool, config: &Config) {
+    if !config.url_cache.persist {
+        return;
+    }
+
+    let now = util::unix_ms();
+    let redirects = match sql::list_url_redirects(
+        pool,
+        config.url_cache.expired_before(now),
+        config.url_cache.capacity as i64,
+    )
+    .await
+    {
+        Ok(x) => x,
+        Err(e) => {
+            error!(error = ?e, "URL cache restore error");
+            return;
+        }
+    };
+
+    let mut restored = 0;
+    for redirect in redirects.into_iter().rev() {
+        let (Ok(url), Ok(target)) = (Url::parse(&redirect.url), Url::parse(&redirect.target))
+        else {
+            continue;
+        };
+        let age = Duration::from_millis(now.saturating_sub(redirect.resolved).max(0) as u64);
+        url_cache.insert_aged(url, Some(target), age);
+        restored += 1;
+    }
+    info!(restored, "URL cache restored");
+}
+
 enum ActionMessage {
     Done,
     Error(Error),
     Element(Element),
 }
 
+fn plugin_element(output: PluginOutput) -> Result<Element, Error> {
+    Ok(match output {
+        PluginOutput::Html(html) => Element::Html(HtmlDoc::new(html)),
+        PluginOutput::Text(text) => Element::Text(text.into()),
+        PluginOutput::Url(url) => match Url::parse(&url) {
+            Ok(x) => Element::Url(x.into()),
+            Err(e) => return Err(Error::invalid_input(ErrorCode::InvalidUrl, url).with_detail(e)),
+        },
+        PluginOutput::Date(date) => match NaiveDateTime::parse_from_str(&date, DATE_FORMAT) {
+            Ok(x) => Element::Date(x),
+            Err(e) => return Err(Error::invalid_input(ErrorCode::InvalidDate, date).with_detail(e)),
+        },
+    })
+}
+
+/// Runs a step that waits on I/O, a plugin, an `Eval` snippet or nested pipelines over one
+/// element.
 fn exec_action(
-    action: Arc<Action>,
-    element_index: usize,
+    step: Arc<Step>,
     element: Element,
     channel: mpsc::Sender<ActionMessage>,
-    config: ManagedConfig,
-    url_cache: ManagedUrlCache,
+    run: Arc<RunContext>,
 ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
     Box::pin(async move {
         let mut msgs_to_send = vec![];
-        let mut error = None;
 
-        match (&*action, element) {
-            (Action::EmailToHtml, Element::Email(email)) => {
-                let html_string = match fs::read_to_string(format!(
-                    "{}/{}",
-                    config.storage.file_root, email.html
-                ))
-                .await
-                {
+        match (&*step, element) {
+            (Step::Run(Action::EmailToHtml), Element::Email(email)) => {
+                let html_string =
+                    match storage::read_to_string(&run.config.storage, &email.user, &email.html)
+                        .await
+                    {
+                        Ok(x) => x,
+                        Err(e) => {
+                            error!(error = ?e, "/emails/execute-script file read error");
+                            let _ = channel
+                                .send(ActionMessage::Error(Error::InternalError))
+                                .await;
+                            return;
+                        }
+                    };
+
+                let _ = channel
+                    .send(ActionMessage::Element(Element::Html(HtmlDoc::new(
+                        html_string,
+                    ))))
+                    .await;
+            }
+            (Step::Run(Action::EmailAttachments(pattern)), Element::Email(email)) => {
+                let attachments = match sql::email_attachments(&run.pool, &email.id).await {
                     Ok(x) => x,
                     Err(e) => {
-                        eprintln!("/emails/execute-script file read error: {:#?}", e);
+                        error!(error = ?e, "/emails/execute-script attachments SELECT error");
                         let _ = channel
                             .send(ActionMessage::Error(Error::InternalError))
                             .await;
@@ -153,198 +479,378 @@ fn exec_action(
                     }
                 };
 
-                let _ = channel
-                    .send(ActionMessage::Element(Element::Html(html_string.into())))
-                    .await;
-            }
-            (Action::HtmlSelectCss(selector_str), Element::Html(html_string)) => {
-                match Selector::parse(&selector_str) {
-                    Ok(selector) => {
-                        let html_element = Html::parse_fragment(&html_string);
-
-                        msgs_to_send.extend(
-                            html_element
-                                .select(&selector)
-                                .map(|el| ActionMessage::Element(Element::Html(el.html().into()))),
-                        );
+                let infected_action = run
+                    .config
+                    .clamd
+                    .as_ref()
+                    .map_or(InfectedAttachments::Block, |clamd| clamd.infected);
+                for attachment in attachments {
+                    if !util::wildcard_match(pattern, &attachment.mime)
+                        || (attachment.infected() && infected_action == InfectedAttachments::Block)
+                    {
+                        continue;
                     }
-                    Err(_) => {
-                        error = Some(ActionMessage::Error(Error::InvalidInput(
-                            selector_str.to_owned(),
-                        )));
+
+                    let body =
+                        match storage::read(&run.config.storage, &email.user, &attachment.path)
+                            .await
+                        {
+                            Ok(x) => x,
+                            Err(e) => {
+                                error!(error = ?e, "/emails/execute-script attachment read error");
+                                let _ = channel
+                                    .send(ActionMessage::Error(Error::InternalError))
+                                    .await;
+                                return;
+                            }
+                        };
+                    let mime = attachment.mime.to_ascii_lowercase();
+                    let element = if mime == "text/html" {
+                        Element::Html(HtmlDoc::new(String::from_utf8_lossy(&body).into_owned()))
+                    } else if mime.starts_with("text/") {
+                        Element::Text(String::from_utf8_lossy(&body).into())
+                    } else {
+                        let data_url = format!("data:{};base64,{}", mime, STANDARD.encode(&body));
+                        Element::Text(data_url.into())
+                    };
+                    msgs_to_send.push(ActionMessage::Element(element));
+                }
+            }
+            (Step::Run(Action::EmailGetHeader(name)), Element::Email(email)) => {
+                let values = match sql::captured_header_values(&run.pool, &email.id, name).await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!(error = ?e, "/emails/execute-script captured headers SELECT error");
+                        let _ = channel
+                            .send(ActionMessage::Error(Error::InternalError))
+                            .await;
+                        return;
                     }
                 };
-            }
-            (Action::HtmlFilterCss(selector_str), Element::Html(html_string)) => {
-                match Selector::parse(&selector_str) {
-                    Ok(selector) => {
-                        let html_element = Html::parse_fragment(&html_string);
 
-                        if html_element.select(&selector).count() != 0 {
-                            msgs_to_send.push(ActionMessage::Element(Element::Html(html_string)));
-                        }
-                    }
-                    Err(_) => {
-                        error = Some(ActionMessage::Error(Error::InvalidInput(
-                            selector_str.to_owned(),
-                        )));
-                    }
+                let values = if values.is_empty() {
+                    email.first_header(name).into_iter().collect()
+                } else {
+                    values
                 };
-            }
-            (Action::HtmlInnerText, Element::Html(html_string)) => {
-                let html_element = Html::parse_fragment(&html_string);
                 msgs_to_send.extend(
-                    html_element.fragment_root().map(|el| {
-                        ActionMessage::Element(Element::Text(el.text().join(" ").into()))
-                    }),
-                );
-            }
-            (Action::HtmlOuterHtml, Element::Html(html_string)) => {
-                let _ = channel
-                    .send(ActionMessage::Element(Element::Text(html_string)))
-                    .await;
-            }
-            (Action::HtmlInnerHtml, Element::Html(html_string)) => {
-                let html_element = Html::parse_fragment(&html_string);
-                msgs_to_send.extend(
-                    html_element
-                        .fragment_root()
-                        .map(|el| ActionMessage::Element(Element::Text(el.inner_html().into()))),
+                    values
+                        .into_iter()
+                        .map(|value| ActionMessage::Element(Element::Text(value.into()))),
                 );
             }
-            (Action::TextMatchRegex(regex_string, replacement), Element::Text(string)) => {
-                let regex = match Regex::new(regex_string) {
-                    Ok(x) => x,
-                    Err(_e) => {
+            (Step::Run(Action::EmailUnsubscribe), Element::Email(email)) => {
+                // Only a request that will be made counts against the budget.
+                let requests = match unsubscribe::succeeded_before(&run.pool, &email).await {
+                    Ok(previous) => {
+                        previous.is_none() && unsubscribe::one_click_url(&email).is_some()
+                    }
+                    Err(e) => {
+                        error!(error = ?e, "/emails/execute-script unsubscribe error");
                         let _ = channel
-                            .send(ActionMessage::Error(Error::InvalidInput(
-                                regex_string.to_owned(),
-                            )))
+                            .send(ActionMessage::Error(Error::InternalError))
                             .await;
                         return;
                     }
                 };
-
-                for cap in regex.captures_iter(&string) {
-                    let mut destination = String::new();
-                    cap.expand(replacement, &mut destination);
-                    let _ = channel
-                        .send(ActionMessage::Element(Element::Text(destination.into())))
-                        .await;
+                if requests {
+                    if let Err(e) = run.take_fetch().await {
+                        let _ = channel.send(ActionMessage::Error(e)).await;
+                        return;
+                    }
                 }
-            }
-            (Action::TextFilterRegex(regex_string), Element::Text(string)) => {
-                let regex = match Regex::new(regex_string) {
-                    Ok(x) => x,
-                    Err(_e) => {
+
+                let done = match unsubscribe::unsubscribe(&run.config, &run.pool, &email).await {
+                    Ok(Unsubscribed::NotOffered) => None,
+                    Ok(Unsubscribed::Already(done) | Unsubscribed::Attempted(done)) => Some(done),
+                    Err(e) => {
+                        error!(error = ?e, "/emails/execute-script unsubscribe error");
                         let _ = channel
-                            .send(ActionMessage::Error(Error::InvalidInput(
-                                regex_string.to_owned(),
-                            )))
+                            .send(ActionMessage::Error(Error::InternalError))
                             .await;
                         return;
                     }
                 };
 
-                if regex.is_match(&string) {
-                    let _ = channel
-                        .send(ActionMessage::Element(Element::Text(string)))
-                        .await;
+                if let Some(url) = done
+                    .filter(Unsubscribe::succeeded)
+                    .and_then(|done| Url::parse(&done.url).ok())
+                {
+                    msgs_to_send.push(ActionMessage::Element(Element::Url(url.into())));
                 }
             }
-            (Action::TextToHtml, Element::Text(string)) => {
-                let _ = channel
-                    .send(ActionMessage::Element(Element::Html(string)))
-                    .await;
-            }
-            (Action::HtmlGetAttr(attr_name), Element::Html(html_string)) => {
-                let html = Html::parse_fragment(&html_string);
-                if let Some(attr_value) = html.fragment_root().and_then(|root| root.attr(attr_name))
-                {
-                    msgs_to_send.push(ActionMessage::Element(Element::Text(
-                        attr_value.to_owned().into(),
-                    )));
+            (Step::Run(Action::UrlFollowRedirect), Element::Url(url)) => {
+                match follow_redirect(&run, &url).await {
+                    Ok(Some(redirected_url)) => {
+                        let _ = channel
+                            .send(ActionMessage::Element(Element::Url(redirected_url.into())))
+                            .await;
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        let _ = channel.send(ActionMessage::Error(e)).await;
+                        return;
+                    }
                 }
             }
-            (Action::TextToUrl, Element::Text(url_string)) => {
-                let url = match Url::parse(&url_string) {
-                    Ok(x) => x,
-                    Err(_e) => {
+            (Step::Plugin(plugin, arguments), el) => {
+                let input = SerdeElement::from(el);
+                let call = {
+                    let (plugins, plugin, arguments) = (
+                        Arc::clone(&run.plugins),
+                        Arc::clone(plugin),
+                        Arc::clone(arguments),
+                    );
+                    move || plugins.call(&plugin, &input, &arguments)
+                };
+                let outputs = match tokio::task::spawn_blocking(call).await {
+                    Ok(Ok(x)) => x,
+                    Ok(Err(e)) => {
+                        let _ = channel
+                            .send(ActionMessage::Error(
+                                Error::invalid_input(ErrorCode::PluginFailed, &plugin.name)
+                                    .with_detail(format!("{:#}", e)),
+                            ))
+                            .await;
+                        return;
+                    }
+                    Err(e) => {
+                        error!(error = ?e, "/emails/execute-script plugin task error");
                         let _ = channel
-                            .send(ActionMessage::Error(Error::InvalidInput(
-                                url_string.deref().into(),
-                            )))
+                            .send(ActionMessage::Error(Error::InternalError))
                             .await;
                         return;
                     }
                 };
 
-                let _ = channel
-                    .send(ActionMessage::Element(Element::Url(url)))
-                    .await;
+                for output in outputs {
+                    match plugin_element(output) {
+                        Ok(el) => msgs_to_send.push(ActionMessage::Element(el)),
+                        Err(e) => {
+                            let _ = channel.send(ActionMessage::Error(e)).await;
+                            return;
+                        }
+                    }
+                }
             }
-            (Action::UrlToText, Element::Url(url)) => {
-                let _ = channel
-                    .send(ActionMessage::Element(Element::Text(
-                        url.to_string().into(),
-                    )))
-                    .await;
+            (
+                Step::Eval(script),
+                Element::Text(input) | Element::Html(HtmlDoc { source: input, .. }),
+            ) => {
+                let call = {
+                    let script = Arc::clone(script);
+                    move || script.run(&input)
+                };
+                match tokio::task::spawn_blocking(call).await {
+                    Ok(Ok(outputs)) => msgs_to_send.extend(
+                        outputs
+                            .into_iter()
+                            .map(|output| ActionMessage::Element(Element::Text(output.into()))),
+                    ),
+                    Ok(Err(e)) => {
+                        let _ = channel
+                            .send(ActionMessage::Error(
+                                Error::invalid_input(ErrorCode::EvalFailed, script.code())
+                                    .with_detail(e),
+                            ))
+                            .await;
+                        return;
+                    }
+                    Err(e) => {
+                        error!(error = ?e, "/emails/execute-script eval task error");
+                        let _ = channel
+                            .send(ActionMessage::Error(Error::InternalError))
+                            .await;
+                        return;
+                    }
+                }
             }
-            (Action::UrlFollowRedirect, Element::Url(url)) => {
-                let redirected_url = if let Some(x) = url_cache.get(&url) {
-                    x.deref().deref().clone()
-                } else {
-                    let mut header_map = HeaderMap::new();
-                    header_map.append("User-Agent", HeaderValue::from_static("Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36"));
-                    header_map.append("Dnt", HeaderValue::from_static("1"));
-                    header_map.append("Sec-Fetch-Site", HeaderValue::from_static("none"));
-                    header_map.append("Sec-Fetch-Dest", HeaderValue::from_static("document"));
-                    header_map.append("Sec-Fetch-Mode", HeaderValue::from_static("navigate"));
-                    header_map.append("Sec-Fetch-User", HeaderValue::from_static("?1"));
-                    header_map.append("Accept", HeaderValue::from_static("text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8,application/signed-exchange;v=b3;q=0.7"));
-                    header_map.append(
-                        "Accept-Encoding",
-                        HeaderValue::from_static("gzip, deflate, br"),
-                    );
-                    header_map.append("Accept-Language", HeaderValue::from_static("en"));
-
-                    let client = match HttpClient::builder()
-                        .default_headers(header_map)
-                        .cookie_store(true)
-                        .build()
-                    {
+            (Step::Or(pipeline1, pipeline2), el) => {
+                let mut result =
+                    match exec_pipeline(pipeline1, Arc::clone(&run), vec![el.clone()]).await {
                         Ok(x) => x,
                         Err(e) => {
-                            eprintln!(
-                                "/email/execute-script initialize HTTP client error: {:#?}",
-                                e
-                            );
-                            let _ = channel
-                                .send(ActionMessage::Error(Error::InternalError))
-                                .await;
+                            let _ = channel.send(ActionMessage::Error(e)).await;
                             return;
                         }
                     };
 
-                    let response = match client.get(url.clone()).send().await {
+                if result.is_empty() {
+                    result = match exec_pipeline(pipeline2, Arc::clone(&run), vec![el]).await {
                         Ok(x) => x,
                         Err(e) => {
-                            eprintln!("/email/execute-script HTTP error: {:#?}", e);
-                            let _ = channel.send(ActionMessage::Done).await;
+                            let _ = channel.send(ActionMessage::Error(e)).await;
                             return;
                         }
                     };
+                }
 
-                    url_cache.insert(url, response.url().clone());
+                msgs_to_send.extend(result.into_iter().map(ActionMessage::Element));
+            }
+            (Step::Pair(pipeline1, pipeline2), el) => {
+                let elements1 =
+                    match exec_pipeline(pipeline1, Arc::clone(&run), vec![el.clone()]).await {
+                        Ok(x) => x,
+                        Err(e) => {
+                            let _ = channel.send(ActionMessage::Error(e)).await;
+                            return;
+                        }
+                    };
 
-                    response.url().clone()
+                let elements2 = match exec_pipeline(pipeline2, Arc::clone(&run), vec![el]).await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        let _ = channel.send(ActionMessage::Error(e)).await;
+                        return;
+                    }
                 };
 
                 let _ = channel
-                    .send(ActionMessage::Element(Element::Url(redirected_url)))
+                    .send(ActionMessage::Element(Element::Pair(
+                        elements1.into(),
+                        elements2.into(),
+                    )))
                     .await;
             }
-            (Action::UrlGetQuery(query_name), Element::Url(url)) => {
+            (Step::Filter(pipeline), el) => {
+                let elements =
+                    match exec_pipeline(pipeline, Arc::clone(&run), vec![el.clone()]).await {
+                        Ok(x) => x,
+                        Err(e) => {
+                            let _ = channel.send(ActionMessage::Error(e)).await;
+                            return;
+                        }
+                    };
+
+                if !elements.is_empty() {
+                    let _ = channel.send(ActionMessage::Element(el)).await;
+                }
+            }
+            _ => {}
+        }
+
+        for msg in msgs_to_send {
+            let _ = channel.send(msg).await;
+        }
+
+        let _ = channel.send(ActionMessage::Done).await;
+    })
+}
+
+/// The regex or selector an action takes, parsed once per batch instead of once per element.
+enum Pattern {
+    None,
+    Regex(Regex),
+    Selector(Selector),
+}
+impl Pattern {
+    fn parse(action: &Action) -> Result<Self, Error> {
+        match action {
+            Action::HtmlSelectCss(selector_str) | Action::HtmlFilterCss(selector_str) => {
+                Selector::parse(selector_str)
+                    .map(Pattern::Selector)
+                    .map_err(|e| {
+                        Error::invalid_input(ErrorCode::InvalidSelector, selector_str)
+                            .with_detail(e)
+                    })
+            }
+            Action::TextMatchRegex(regex_string, _)
+            | Action::TextFilterRegex(regex_string)
+            | Action::EmailFilterRegex(_, regex_string) => {
+                Regex::new(regex_string).map(Pattern::Regex).map_err(|e| {
+                    Error::invalid_input(ErrorCode::InvalidRegex, regex_string).with_detail(e)
+                })
+            }
+            _ => Ok(Pattern::None),
+        }
+    }
+}
+
+/// Runs an action that needs no I/O over `batch`, whose elements are paired with their index
+/// in the stage's input.
+fn exec_batch(action: &Action, batch: Vec<(usize, Element)>) -> Result<Vec<Element>, Error> {
+    let pattern = Pattern::parse(action)?;
+    let mut output = vec![];
+
+    for (element_index, element) in batch {
+        match (action, &pattern, element) {
+            (Action::HtmlSelectCss(_), Pattern::Selector(selector), Element::Html(html)) => {
+                output.extend(html.with_parsed(|parsed| {
+                    parsed
+                        .select(selector)
+                        .map(|el| Element::Html(HtmlDoc::new(el.html())))
+                        .collect::<Vec<_>>()
+                }));
+            }
+            (Action::HtmlFilterCss(_), Pattern::Selector(selector), Element::Html(html)) => {
+                if html.with_parsed(|parsed| parsed.select(selector).next().is_some()) {
+                    output.push(Element::Html(html));
+                }
+            }
+            (Action::HtmlInnerText, _, Element::Html(html)) => {
+                output.extend(html.with_parsed(|parsed| {
+                    parsed
+                        .fragment_root()
+                        .map(|el| Element::Text(el.text().join(" ").into()))
+                }));
+            }
+            (Action::HtmlOuterHtml, _, Element::Html(html)) => {
+                output.push(Element::Text(html.source));
+            }
+            (Action::HtmlInnerHtml, _, Element::Html(html)) => {
+                output.extend(html.with_parsed(|parsed| {
+                    parsed
+                        .fragment_root()
+                        .map(|el| Element::Text(el.inner_html().into()))
+                }));
+            }
+            (
+                Action::TextMatchRegex(_, replacement),
+                Pattern::Regex(regex),
+                Element::Text(string),
+            ) => {
+                for cap in regex.captures_iter(&string) {
+                    let mut destination = String::new();
+                    cap.expand(replacement, &mut destination);
+                    output.push(Element::Text(destination.into()));
+                }
+            }
+            (Action::TextFilterRegex(_), Pattern::Regex(regex), Element::Text(string)) => {
+                if regex.is_match(&string) {
+                    output.push(Element::Text(string));
+                }
+            }
+            (Action::TextToHtml, _, Element::Text(string)) => {
+                output.push(Element::Html(HtmlDoc::new(string)));
+            }
+            (Action::HtmlGetAttr(attr_name), _, Element::Html(html)) => {
+                let attr_value = html.with_parsed(|parsed| {
+                    parsed
+                        .fragment_root()
+                        .and_then(|root| root.attr(attr_name))
+                        .map(str::to_owned)
+                });
+                if let Some(attr_value) = attr_value {
+                    output.push(Element::Text(attr_value.into()));
+                }
+            }
+            (Action::TextToUrl, _, Element::Text(url_string)) => {
+                let url = Url::parse(&url_string).map_err(|e| {
+                    Error::invalid_input(ErrorCode::InvalidUrl, url_string.deref()).with_detail(e)
+                })?;
+                output.push(Element::Url(url.into()));
+            }
+            (Action::TextToDate(format), _, Element::Text(text)) => {
+                let Some(date) = parse_date(text.trim(), format) else {
+                    return Err(Error::invalid_input(ErrorCode::InvalidDate, text.deref())
+                        .with_detail(format!("does not match {:?}", format)));
+                };
+                output.push(Element::Date(date));
+            }
+            (Action::UrlToText, _, Element::Url(url)) => {
+                output.push(Element::Text(url.to_string().into()));
+            }
+            (Action::UrlGetQuery(query_name), _, Element::Url(url)) => {
                 if let Some(query_value) = url.query_pairs().find_map(|(key, value)| {
                     if &key == query_name {
                         Some(value)
@@ -352,42 +858,22 @@ fn exec_action(
                         None
                     }
                 }) {
-                    let _ = channel
-                        .send(ActionMessage::Element(Element::Text(
-                            query_value.to_string().into(),
-                        )))
-                        .await;
+                    output.push(Element::Text(query_value.to_string().into()));
                 }
             }
-            (Action::EmailFilterRegex(email_attr, regex_string), Element::Email(email)) => {
-                let regex = match Regex::new(regex_string) {
-                    Ok(x) => x,
-                    Err(_) => {
-                        let _ = channel
-                            .send(ActionMessage::Error(Error::InvalidInput(
-                                regex_string.to_owned(),
-                            )))
-                            .await;
-                        return;
-                    }
-                };
-
-                let attr_value = email.get_attribute(*email_attr);
-
-                if regex.is_match(attr_value) {
-                    let _ = channel
-                        .send(ActionMessage::Element(Element::Email(email)))
-                        .await;
+            (
+                Action::EmailFilterRegex(email_attr, _),
+                Pattern::Regex(regex),
+                Element::Email(email),
+            ) => {
+                if regex.is_match(&email.get_attribute(*email_attr)) {
+                    output.push(Element::Email(email));
                 }
             }
-            (Action::UrlGetSegment(segment_index), Element::Url(url)) => {
-                let mut segments = match url.path_segments() {
-                    Some(x) => x,
-                    None => {
-                        eprintln!("/emails/execute-script URL path segments None");
-                        let _ = channel.send(ActionMessage::Done).await;
-                        return;
-                    }
+            (Action::UrlGetSegment(segment_index), _, Element::Url(url)) => {
+                let Some(mut segments) = url.path_segments() else {
+                    debug!("/emails/execute-script URL path segments None");
+                    continue;
                 };
 
                 let segment_opt = if *segment_index < 0 {
@@ -397,221 +883,389 @@ fn exec_action(
                 };
 
                 if let Some(segment) = segment_opt {
-                    let _ = channel
-                        .send(ActionMessage::Element(Element::Text(segment.into())))
-                        .await;
+                    output.push(Element::Text(segment.into()));
                 }
             }
-            (Action::ArraySelectNth(target_index), el) => {
+            (Action::ArraySelectNth(target_index), _, el) => {
                 if *target_index == element_index {
-                    let _ = channel.send(ActionMessage::Element(el)).await;
+                    output.push(el);
                 }
             }
-            (Action::Or(actions1, actions2), el) => {
-                let mut result = match exec_pipeline(
-                    actions1,
-                    Arc::clone(&config),
-                    url_cache.clone(),
-                    vec![el.clone()],
-                )
-                .await
-                {
-                    Ok(x) => x,
-                    Err(e) => {
-                        let _ = channel.send(ActionMessage::Error(e)).await;
-                        return;
-                    }
-                };
-
-                if result.is_empty() {
-                    result = match exec_pipeline(
-                        actions2,
-                        Arc::clone(&config),
-                        url_cache.clone(),
-                        vec![el],
-                    )
-                    .await
-                    {
-                        Ok(x) => x,
-                        Err(e) => {
-                            let _ = channel.send(ActionMessage::Error(e)).await;
-                            return;
-                        }
-                    };
-                }
-
-                msgs_to_send.extend(result.into_iter().map(ActionMessage::Element));
-            }
-            (Action::EmailGetAttr(email_attr), Element::Email(email)) => {
-                let attr = email.get_attribute(*email_attr);
-
-                let _ = channel
-                    .send(ActionMessage::Element(Element::Text(
-                        attr.to_owned().into(),
-                    )))
-                    .await;
-            }
-            (Action::Pair(action1, action2), el) => {
-                let elements1 = match exec_pipeline(
-                    &*action1,
-                    Arc::clone(&config),
-                    url_cache.clone(),
-                    vec![el.clone()],
-                )
-                .await
-                {
-                    Ok(x) => x,
-                    Err(e) => {
-                        let _ = channel.send(ActionMessage::Error(e)).await;
-                        return;
-                    }
-                };
-
-                let elements2 = match exec_pipeline(
-                    &*action2,
-                    Arc::clone(&config),
-                    url_cache.clone(),
-                    vec![el],
-                )
-                .await
-                {
-                    Ok(x) => x,
-                    Err(e) => {
-                        let _ = channel.send(ActionMessage::Error(e)).await;
-                        return;
-                    }
-                };
-
-                let _ = channel
-                    .send(ActionMessage::Element(Element::Pair(elements1, elements2)))
-                    .await;
+            (Action::EmailGetAttr(EmailAttribute::SentAt), _, Element::Email(email)) => {
+                output.extend(email.sent_local().map(Element::Date));
             }
-            (Action::Filter(actions), el) => {
-                let elements = match exec_pipeline(
-                    &*actions,
-                    Arc::clone(&config),
-                    url_cache,
-                    vec![el.clone()],
-                )
-                .await
-                {
-                    Ok(x) => x,
-                    Err(e) => {
-                        let _ = channel.send(ActionMessage::Error(e)).await;
-                        return;
-                    }
-                };
-
-                if !elements.is_empty() {
-                    let _ = channel.send(ActionMessage::Element(el)).await;
-                }
+            (Action::EmailGetAttr(email_attr), _, Element::Email(email)) => {
+                output.push(Element::Text(email.get_attribute(*email_attr).into()));
             }
-            (Action::PairGetLeft, Element::Pair(elements1, _elements2)) => {
-                msgs_to_send.extend(elements1.into_iter().map(ActionMessage::Element));
+            (Action::PairGetLeft, _, Element::Pair(elements1, _elements2)) => {
+                output.extend(elements1.iter().cloned());
             }
-            (Action::PairGetRight, Element::Pair(_elements1, elements2)) => {
-                msgs_to_send.extend(elements2.into_iter().map(ActionMessage::Element));
+            (Action::PairGetRight, _, Element::Pair(_elements1, elements2)) => {
+                output.extend(elements2.iter().cloned());
             }
-            (Action::PairZipTogether, Element::Pair(elements1, elements2)) => {
-                msgs_to_send.extend(
+            (Action::PairZipTogether, _, Element::Pair(elements1, elements2)) => {
+                output.extend(
                     elements1
-                        .into_iter()
-                        .zip(elements2.into_iter())
-                        .map(|(a, b)| Element::Pair(vec![a], vec![b]))
-                        .map(ActionMessage::Element),
+                        .iter()
+                        .zip(elements2.iter())
+                        .map(|(a, b)| Element::Pair(Arc::new([a.clone()]), Arc::new([b.clone()]))),
                 );
             }
-            (Action::PairDistributeLeft, Element::Pair(elements1, elements2)) => {
-                msgs_to_send.extend(elements2.into_iter().map(|el2| {
-                    ActionMessage::Element(Element::Pair(elements1.clone(), vec![el2]))
-                }));
+            (Action::PairDistributeLeft, _, Element::Pair(elements1, elements2)) => {
+                output.extend(
+                    elements2
+                        .iter()
+                        .map(|el2| Element::Pair(Arc::clone(&elements1), Arc::new([el2.clone()]))),
+                );
             }
-            (Action::PairRightLeft, Element::Pair(elements1, elements2)) => {
-                let _ = channel
-                    .send(ActionMessage::Element(Element::Pair(elements2, elements1)))
-                    .await;
+            (Action::PairRightLeft, _, Element::Pair(elements1, elements2)) => {
+                output.push(Element::Pair(elements2, elements1));
             }
             _ => {}
         }
+    }
 
-        if let Some(error_msg) = error {
-            let _ = channel.send(error_msg).await;
-            return;
+    Ok(output)
+}
+
+/// An action ready to run. Macros are expanded, in nested pipelines too, once per execution
+/// instead of once per element that reaches them.
+#[derive(Debug)]
+enum Step {
+    Run(Action),
+    Plugin(Arc<Plugin>, Arc<Value>),
+    Eval(Arc<EvalScript>),
+    Or(Pipeline, Pipeline),
+    Pair(Pipeline, Pipeline),
+    Filter(Pipeline),
+}
+impl Step {
+    /// The action, when it neither waits on I/O nor runs a plugin, a snippet or nested
+    /// pipelines, and so can go through [`exec_batch`].
+    fn batchable(&self) -> Option<&Action> {
+        match self {
+            Step::Run(
+                Action::EmailToHtml
+                | Action::EmailUnsubscribe
+                | Action::EmailAttachments(_)
+                | Action::EmailGetHeader(_)
+                | Action::UrlFollowRedirect,
+            ) => None,
+            Step::Run(action) => Some(action),
+            Step::Plugin(..) | Step::Eval(_) => None,
+            Step::Or(..) | Step::Pair(..) | Step::Filter(..) => None,
         }
+    }
 
-        for msg in msgs_to_send {
-            let _ = channel.send(msg).await;
+    /// The action with its macros expanded, for stage timings.
+    fn action(&self) -> Action {
+        match self {
+            Step::Run(action) => action.clone(),
+            Step::Plugin(plugin, arguments) => {
+                Action::Plugin(plugin.name.clone(), (**arguments).clone())
+            }
+            Step::Eval(script) => Action::Eval(script.code().to_owned()),
+            Step::Or(pipeline1, pipeline2) => {
+                Action::Or(pipeline_actions(pipeline1), pipeline_actions(pipeline2))
+            }
+            Step::Pair(pipeline1, pipeline2) => {
+                Action::Pair(pipeline_actions(pipeline1), pipeline_actions(pipeline2))
+            }
+            Step::Filter(pipeline) => Action::Filter(pipeline_actions(pipeline)),
         }
+    }
+}
 
-        let _ = channel.send(ActionMessage::Done).await;
-    })
+/// Steps are shared by every task of a stage, so spawning one per element only bumps a count.
+type Pipeline = Arc<[Arc<Step>]>;
+
+fn pipeline_actions(pipeline: &[Arc<Step>]) -> Vec<Action> {
+    pipeline.iter().map(|step| step.action()).collect()
 }
 
-async fn exec_pipeline(
-    actions: &[Action],
-    config: ManagedConfig,
-    url_cache: ManagedUrlCache,
-    mut elements: Vec<Element>,
-) -> Result<Vec<Element>, Error> {
-    let mut expanded_actions = vec![];
-    for action in actions {
-        match action {
-            Action::Macro(macro_name) => {
-                match config.macros.iter().find(|mac| &mac.name == macro_name) {
-                    Some(mac) => expanded_actions.extend(mac.actions.iter().cloned().map(Arc::new)),
-                    None => return Err(Error::InvalidInput(macro_name.to_owned())),
+/// `expanding` holds the macros being expanded, to reject one that contains itself.
+fn compile_step<'a>(
+    action: &'a Action,
+    config: &'a Config,
+    expanding: &mut Vec<&'a str>,
+) -> Result<Step, Error> {
+    Ok(match action {
+        Action::Or(actions1, actions2) => Step::Or(
+            compile_pipeline(actions1, config, expanding)?,
+            compile_pipeline(actions2, config, expanding)?,
+        ),
+        Action::Pair(actions1, actions2) => Step::Pair(
+            compile_pipeline(actions1, config, expanding)?,
+            compile_pipeline(actions2, config, expanding)?,
+        ),
+        Action::Filter(actions) => Step::Filter(compile_pipeline(actions, config, expanding)?),
+        Action::Plugin(plugin_name, arguments) => {
+            let Some(plugin) = config
+                .plugins
+                .iter()
+                .find(|plugin| &plugin.name == plugin_name)
+            else {
+                return Err(Error::invalid_input(ErrorCode::UnknownPlugin, plugin_name));
+            };
+            Step::Plugin(Arc::new(plugin.clone()), Arc::new(arguments.clone()))
+        }
+        Action::Eval(code) => {
+            let Some(limits) = &config.scripts.eval else {
+                return Err(Error::invalid_input(ErrorCode::EvalDisabled, code));
+            };
+            match EvalScript::compile(code, limits) {
+                Ok(x) => Step::Eval(Arc::new(x)),
+                Err(e) => {
+                    return Err(Error::invalid_input(ErrorCode::InvalidEval, code).with_detail(e))
                 }
             }
-            _ => expanded_actions.push(Arc::new(action.clone())),
         }
+        _ => Step::Run(action.clone()),
+    })
+}
+
+/// Only macros named directly in `actions` are expanded; one named inside a macro's own action
+/// list stays a no-op, as it always has.
+fn compile_pipeline<'a>(
+    actions: &'a [Action],
+    config: &'a Config,
+    expanding: &mut Vec<&'a str>,
+) -> Result<Pipeline, Error> {
+    let mut steps = vec![];
+    for action in actions {
+        let Action::Macro(macro_name) = action else {
+            steps.push(Arc::new(compile_step(action, config, expanding)?));
+            continue;
+        };
+
+        let Some(mac) = config.macros.iter().find(|mac| &mac.name == macro_name) else {
+            return Err(Error::invalid_input(ErrorCode::UnknownMacro, macro_name));
+        };
+        if expanding.contains(&macro_name.as_str()) {
+            return Err(Error::invalid_input(ErrorCode::RecursiveMacro, macro_name));
+        }
+        expanding.push(macro_name);
+        for action in &mac.actions {
+            steps.push(Arc::new(compile_step(action, config, expanding)?));
+        }
+        expanding.pop();
+    }
+
+    Ok(steps.into())
+}
+
+fn compile(actions: &[Action], config: &Config) -> Result<Pipeline, Error> {
+    compile_pipeline(actions, config, &mut vec![])
+}
+
+const STAGE_CHANNEL_CAPACITY: usize = 16;
+/// Elements per task for actions that need no I/O. Large enough that spawning is noise, small
+/// enough that a stage of costly selectors still spreads over `scripts.parallelism` workers.
+const STAGE_BATCH_SIZE: usize = 128;
+
+/// Action tasks spawned by every running pipeline, nested ones included, that have not finished.
+static ACTION_TASKS: AtomicUsize = AtomicUsize::new(0);
+/// Every stage channel that still has a sender, for reporting backlogs.
+static STAGE_CHANNELS: Mutex<Vec<mpsc::WeakSender<ActionMessage>>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Serialize)]
+pub struct PipelineDiagnostics {
+    pub action_tasks: usize,
+    pub stage_channels: usize,
+    pub stage_channel_capacity: usize,
+    /// Messages waiting in each open stage channel, largest first.
+    pub stage_backlogs: Vec<usize>,
+}
+
+pub fn pipeline_diagnostics() -> PipelineDiagnostics {
+    let mut channels = STAGE_CHANNELS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let mut stage_backlogs = vec![];
+    channels.retain(|channel| match channel.upgrade() {
+        Some(sender) => {
+            stage_backlogs.push(sender.max_capacity() - sender.capacity());
+            true
+        }
+        None => false,
+    });
+    stage_backlogs.sort_unstable_by(|a, b| b.cmp(a));
+
+    PipelineDiagnostics {
+        action_tasks: ACTION_TASKS.load(Ordering::Relaxed),
+        stage_channels: channels.len(),
+        stage_channel_capacity: STAGE_CHANNEL_CAPACITY,
+        stage_backlogs,
     }
+}
 
-    if expanded_actions.is_empty() {
-        return Ok(elements);
+/// Runs `step` over every element concurrently, in batches when it needs no I/O and otherwise a
+/// task per element; the returned channel closes once every element has been processed.
+fn spawn_stage(
+    step: Arc<Step>,
+    elements: Vec<Element>,
+    run: &Arc<RunContext>,
+) -> mpsc::Receiver<ActionMessage> {
+    let (tx, rx) = mpsc::channel(STAGE_CHANNEL_CAPACITY);
+    {
+        let mut channels = STAGE_CHANNELS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        channels.retain(|channel| channel.upgrade().is_some());
+        channels.push(tx.downgrade());
     }
 
-    for action in expanded_actions {
+    // A pool per stage, since actions like `Or` run whole pipelines of their own.
+    let workers = WorkerPool::new(run.config.scripts.parallelism);
+    let run = Arc::clone(run);
+    tokio::spawn(async move {
+        if let Some(action) = step.batchable() {
+            let action = Arc::new(action.clone());
+            let mut elements = elements.into_iter().enumerate().peekable();
+            while elements.peek().is_some() && !tx.is_closed() {
+                let batch: Vec<_> = elements.by_ref().take(STAGE_BATCH_SIZE).collect();
+                let action = Arc::clone(&action);
+                let tx = tx.clone();
+                ACTION_TASKS.fetch_add(1, Ordering::Relaxed);
+                workers
+                    .spawn(async move {
+                        match exec_batch(&action, batch) {
+                            Ok(output) => {
+                                for el in output {
+                                    let _ = tx.send(ActionMessage::Element(el)).await;
+                                }
+                                let _ = tx.send(ActionMessage::Done).await;
+                            }
+                            Err(e) => {
+                                let _ = tx.send(ActionMessage::Error(e)).await;
+                            }
+                        }
+                        ACTION_TASKS.fetch_sub(1, Ordering::Relaxed);
+                    })
+                    .await;
+            }
+            return;
+        }
+
+        for element in elements {
+            if tx.is_closed() {
+                break;
+            }
+
+            let task = exec_action(Arc::clone(&step), element, tx.clone(), Arc::clone(&run));
+            ACTION_TASKS.fetch_add(1, Ordering::Relaxed);
+            workers
+                .spawn(async move {
+                    task.await;
+                    ACTION_TASKS.fetch_sub(1, Ordering::Relaxed);
+                })
+                .await;
+        }
+    });
+
+    rx
+}
+
+/// Runs `actions` one stage after another, pushing each stage's timing to `stages`, including
+/// the stage that failed.
+async fn exec_stages(
+    pipeline: &[Arc<Step>],
+    run: Arc<RunContext>,
+    mut elements: Vec<Element>,
+    stages: &mut Vec<StageTiming>,
+) -> Result<Vec<Element>, Error> {
+    for step in pipeline {
         if elements.is_empty() {
             return Ok(elements);
         }
 
-        let (tx, mut rx) = mpsc::channel(16);
-        let mut need_finish = elements.len();
-        for (element_index, element) in elements.into_iter().enumerate() {
-            tokio::spawn(exec_action(
-                Arc::clone(&action),
-                element_index,
-                element,
-                tx.clone(),
-                Arc::clone(&config),
-                url_cache.clone(),
-            ));
-        }
+        let timer = Instant::now();
+        let mut timing = StageTiming {
+            action: step.action(),
+            duration_ms: 0,
+            input_count: elements.len() as i64,
+            output_count: 0,
+        };
 
+        let mut rx = spawn_stage(Arc::clone(step), elements, &run);
         let mut new_elements = vec![];
-        loop {
-            match rx.recv().await {
-                Some(ActionMessage::Error(err)) => {
-                    return Err(err);
+        let mut error = None;
+        while let Some(message) = rx.recv().await {
+            match message {
+                ActionMessage::Error(err) => {
+                    error = Some(err);
+                    break;
                 }
-                Some(ActionMessage::Element(el)) => {
+                ActionMessage::Element(el) => {
                     new_elements.push(el);
                 }
-                Some(ActionMessage::Done) => {
-                    need_finish -= 1;
-                    if need_finish == 0 {
-                        elements = new_elements;
-                        break;
-                    }
-                }
-                None => {}
+                ActionMessage::Done => {}
             }
         }
+
+        timing.duration_ms = timer.elapsed().as_millis() as i64;
+        timing.output_count = new_elements.len() as i64;
+        stages.push(timing);
+
+        if let Some(err) = error {
+            return Err(err);
+        }
+        elements = new_elements;
     }
 
     Ok(elements)
 }
 
+async fn exec_timed_pipeline(
+    actions: &[Action],
+    run: Arc<RunContext>,
+    elements: Vec<Element>,
+    stages: &mut Vec<StageTiming>,
+) -> Result<Vec<Element>, Error> {
+    let pipeline = compile(actions, &run.config)?;
+    exec_stages(&pipeline, run, elements, stages).await
+}
+
+async fn exec_pipeline(
+    pipeline: &[Arc<Step>],
+    run: Arc<RunContext>,
+    elements: Vec<Element>,
+) -> Result<Vec<Element>, Error> {
+    exec_stages(pipeline, run, elements, &mut vec![]).await
+}
+
+/// Like [`exec_timed_pipeline`], but yields the final stage's elements as soon as they are
+/// produced instead of collecting them. The final stage's timing is returned with only its input
+/// count filled in, for the consumer to complete once the channel closes.
+async fn stream_pipeline(
+    actions: &[Action],
+    run: Arc<RunContext>,
+    elements: Vec<Element>,
+    stages: &mut Vec<StageTiming>,
+) -> Result<(mpsc::Receiver<ActionMessage>, Option<StageTiming>), Error> {
+    let pipeline = compile(actions, &run.config)?;
+    let Some((last_step, pipeline)) = pipeline.split_last() else {
+        let (tx, rx) = mpsc::channel(elements.len().max(1));
+        for el in elements {
+            let _ = tx.send(ActionMessage::Element(el)).await;
+        }
+        return Ok((rx, None));
+    };
+
+    let elements = exec_stages(pipeline, Arc::clone(&run), elements, stages).await?;
+
+    let last_stage = StageTiming {
+        action: last_step.action(),
+        duration_ms: 0,
+        input_count: elements.len() as i64,
+        output_count: 0,
+    };
+    Ok((
+        spawn_stage(Arc::clone(last_step), elements, &run),
+        Some(last_stage),
+    ))
+}
+
 fn flatten_serde_pair(el: SerdeElement, v: &mut Vec<SerdeElement>) {
     match el {
         SerdeElement::Pair(left, right) => {
@@ -626,66 +1280,400 @@ fn flatten_serde_pair(el: SerdeElement, v: &mut Vec<SerdeElement>) {
     }
 }
 
-#[rocket::post("/emails/execute-script", format = "json", data = "<script>")]
+fn collect_leaves<'a>(el: &'a Element, leaves: &mut Vec<&'a Element>) {
+    match el {
+        Element::Pair(elements1, elements2) => {
+            for el in elements1.iter().chain(elements2.iter()) {
+                collect_leaves(el, leaves);
+            }
+        }
+        other => leaves.push(other),
+    }
+}
+
+/// One file per element of every output row, named `<row>-<column>.<ext>`; emails are archived
+/// as their stored HTML.
+async fn archive_files(output: &[Element], config: &Config) -> Result<Vec<ArchiveFile>, Error> {
+    let mut files = vec![];
+    for (row, el) in output.iter().enumerate() {
+        let mut leaves = vec![];
+        collect_leaves(el, &mut leaves);
+
+        for (col, leaf) in leaves.into_iter().enumerate() {
+            let (extension, contents) = match leaf {
+                Element::Html(html) => ("html", html.source.as_bytes().to_vec()),
+                Element::Text(text) => ("txt", text.as_bytes().to_vec()),
+                Element::Url(url) => (
+                    "url",
+                    format!("[InternetShortcut]\r\nURL={}\r\n", url).into_bytes(),
+                ),
+                Element::Date(date) => ("txt", date.format(DATE_FORMAT).to_string().into_bytes()),
+                Element::Email(email) => {
+                    match storage::read(&config.storage, &email.user, &email.html).await {
+                        Ok(x) => ("html", x),
+                        Err(e) => {
+                            error!(error = ?e, "/emails/execute-script archive read error");
+                            return Err(Error::InternalError);
+                        }
+                    }
+                }
+                Element::Pair(_, _) => continue,
+            };
+
+            files.push(ArchiveFile {
+                name: format!("{}-{}.{}", row, col, extension),
+                contents,
+            });
+        }
+    }
+
+    Ok(files)
+}
+
+/// Abandons `pipeline` once shutdown starts, so the run is recorded as aborted rather than cut
+/// off by the grace period running out.
+async fn until_shutdown<T>(
+    pipeline: impl Future<Output = Result<T, Error>>,
+    shutdown: Shutdown,
+) -> Result<T, Error> {
+    tokio::select! {
+        result = pipeline => result,
+        _ = shutdown => {
+            warn!("/emails/execute-script aborted by shutdown");
+            Err(Error::Unavailable)
+        }
+    }
+}
+
+/// One event per output row holding a date, summarized by the row's first text.
+fn calendar_events(output: &[SerdeElement]) -> Vec<CalendarEvent> {
+    output
+        .iter()
+        .filter_map(|el| {
+            let mut row = vec![];
+            flatten_serde_pair(el.clone(), &mut row);
+
+            let start = row.iter().find_map(|cell| match cell {
+                SerdeElement::Date(date) => NaiveDateTime::parse_from_str(date, DATE_FORMAT).ok(),
+                _ => None,
+            })?;
+            let summary = row.iter().find_map(|cell| match cell {
+                SerdeElement::Text(text) => Some(text.to_string()),
+                _ => None,
+            });
+
+            Some(CalendarEvent { start, summary })
+        })
+        .collect()
+}
+
+/// Runs a trigger's script against the email that fired it and records the run. An empty output
+/// means the script filtered the email out.
+pub async fn run_trigger(
+    config: Arc<Config>,
+    triggers: &Triggers,
+    script_name: &str,
+    actions: &[Action],
+    email: Email,
+    shutdown: Shutdown,
+) -> Result<Vec<SerdeElement>, Error> {
+    let owner = email.user.clone();
+    let email_id = email.id.clone();
+    let started = util::unix_ms();
+    let timer = Instant::now();
+    let mut stages = vec![];
+    let _running = triggers.status.script_started();
+    let run = Arc::new(RunContext {
+        config: Arc::clone(&config),
+        pool: triggers.pool.clone(),
+        url_cache: triggers.url_cache.clone(),
+        plugins: Arc::clone(&triggers.plugins),
+        fetch_budget: Arc::clone(&triggers.fetch_budget),
+        user: owner.clone(),
+        http_fetches: AtomicUsize::new(0),
+    });
+
+    let output = until_shutdown(
+        exec_timed_pipeline(
+            actions,
+            Arc::clone(&run),
+            vec![Element::Email(Arc::new(email))],
+            &mut stages,
+        ),
+        shutdown,
+    )
+    .await
+    .map(|elements| {
+        elements
+            .into_iter()
+            .map(SerdeElement::from)
+            .collect::<Vec<_>>()
+    });
+
+    scripts::record_run(
+        &triggers.pool,
+        &config,
+        NewScriptRun {
+            owner: &owner,
+            script_name: Some(script_name),
+            trigger: RunTrigger::Email,
+            started,
+            duration_ms: timer.elapsed().as_millis() as i64,
+            input_count: 1,
+            output_count: output.as_ref().map_or(0, |output| output.len() as i64),
+            error: output.as_ref().err().map(|e| format!("{:?}", e)),
+            stages: &stages,
+            http_fetches: run.http_fetches(),
+            email_id: Some(&email_id),
+        },
+        output.as_deref().ok(),
+    )
+    .await;
+
+    output
+}
+
+/// `stream=true` with JSON output sends the final stage's elements as they are produced, for
+/// results too large to buffer.
+#[rocket::post("/emails/execute-script?<stream>", format = "json", data = "<script>")]
 pub async fn execute_script(
-    user: AuthorizedUser<'_>,
+    stream: Option<bool>,
+    user: AuthorizedUser,
+    format: ExpectedFormat,
     pool: &State<ManagedPool>,
     config: &State<ManagedConfig>,
     url_cache: &State<ManagedUrlCache>,
+    plugins: &State<ManagedPlugins>,
+    fetch_budget: &State<ManagedFetchBudget>,
+    status: &State<ManagedStatus>,
     script: Json<Script>,
-    _ratelimit: Ratelimit,
+    shutdown: Shutdown,
+    _ratelimit: Ratelimit<ScriptClass>,
 ) -> Result<
-    FlexibleFormat<
-        Vec<SerdeElement>,
-        Vec<SerdeElement>,
-        impl FnOnce(Vec<SerdeElement>) -> Vec<Vec<SerdeElement>>,
+    Either<
+        FlexibleFormat<
+            Vec<SerdeElement>,
+            Vec<SerdeElement>,
+            impl FnOnce(Vec<SerdeElement>) -> Vec<Vec<SerdeElement>>,
+        >,
+        JsonStream<impl Stream<Item = Result<SerdeElement, Error>> + Send>,
     >,
     Error,
 > {
-    let emails = match sqlx::query_as!(
-        Email,
-        r#"SELECT * FROM emails WHERE user = $1"#,
-        user.username
+    let config = config.load_full();
+
+    let cursor = match script.after.as_deref().map(str::parse::<Cursor>) {
+        Some(Ok(x)) => x,
+        Some(Err(())) => return Err(Error::invalid_input(ErrorCode::InvalidCursor, "after")),
+        None => Cursor::start(),
+    };
+
+    let emails = match emails_page(
+        pool,
+        &user.username,
+        &EmailFilter::default(),
+        EmailOrder::Registered,
+        &cursor,
+        script.limit.unwrap_or(-1),
     )
-    .fetch_all(&**pool)
     .await
     {
         Ok(x) => x,
         Err(e) => {
-            eprintln!("/emails/execute-script SQL error: {:#?}", e);
+            error!(error = ?e, "/emails/execute-script SQL error");
             return Err(Error::InternalError);
         }
     };
 
+    let next_cursor =
+        Cursor::next_page(&emails, EmailOrder::Registered, script.limit.unwrap_or(-1));
     let elements: Vec<_> = emails
         .into_iter()
         .map(Arc::new)
         .map(Element::Email)
         .collect();
-    let pipelined = exec_pipeline(
-        &script.actions,
-        Arc::clone(&*config),
-        (*url_cache).clone(),
-        elements,
-    )
-    .await?;
+    let input_count = elements.len() as i64;
+    let started = util::unix_ms();
+    let timer = Instant::now();
+    let mut stages = vec![];
+    let running = status.script_started();
+    let run = Arc::new(RunContext {
+        config: Arc::clone(&config),
+        pool: (*pool).clone(),
+        url_cache: (*url_cache).clone(),
+        plugins: Arc::clone(plugins),
+        fetch_budget: Arc::clone(fetch_budget),
+        user: user.username.clone(),
+        http_fetches: AtomicUsize::new(0),
+    });
+
+    if stream == Some(true) && matches!(format, ExpectedFormat::Json) {
+        let (mut rx, last_stage) = match until_shutdown(
+            stream_pipeline(&script.actions, Arc::clone(&run), elements, &mut stages),
+            shutdown.clone(),
+        )
+        .await
+        {
+            Ok(x) => x,
+            Err(e) => {
+                scripts::record_run(
+                    pool,
+                    &config,
+                    NewScriptRun {
+                        owner: &user.username,
+                        script_name: None,
+                        trigger: RunTrigger::Manual,
+                        started,
+                        duration_ms: timer.elapsed().as_millis() as i64,
+                        input_count,
+                        output_count: 0,
+                        error: Some(format!("{:?}", e)),
+                        stages: &stages,
+                        http_fetches: run.http_fetches(),
+                        email_id: None,
+                    },
+                    None,
+                )
+                .await;
+                return Err(e);
+            }
+        };
+
+        let (tx, output) = mpsc::channel(16);
+        let pool = (*pool).clone();
+        let owner = user.username.clone();
+        let last_stage_timer = Instant::now();
+        tokio::spawn(async move {
+            let _running = running;
+            let mut stored = vec![];
+            let mut output_count = 0;
+            let mut error = None;
+            loop {
+                let message = tokio::select! {
+                    message = rx.recv() => message,
+                    _ = shutdown.clone() => Some(ActionMessage::Error(Error::Unavailable)),
+                };
+                let Some(message) = message else {
+                    break;
+                };
 
-    let mut formatted = FlexibleFormat::from_complex(
-        pipelined
+                match message {
+                    ActionMessage::Element(el) => {
+                        let el = SerdeElement::from(el);
+                        output_count += 1;
+                        if config.scripts.store_output {
+                            stored.push(el.clone());
+                        }
+                        // Keep draining if the client has gone away so the run is still recorded.
+                        let _ = tx.send(Ok(el)).await;
+                    }
+                    ActionMessage::Error(e) => {
+                        error = Some(format!("{:?}", e));
+                        let _ = tx.send(Err(e)).await;
+                        break;
+                    }
+                    ActionMessage::Done => {}
+                }
+            }
+            drop(tx);
+
+            if let Some(mut last_stage) = last_stage {
+                last_stage.duration_ms = last_stage_timer.elapsed().as_millis() as i64;
+                last_stage.output_count = output_count;
+                stages.push(last_stage);
+            }
+
+            scripts::record_run(
+                &pool,
+                &config,
+                NewScriptRun {
+                    owner: &owner,
+                    script_name: None,
+                    trigger: RunTrigger::Manual,
+                    started,
+                    duration_ms: timer.elapsed().as_millis() as i64,
+                    input_count,
+                    output_count,
+                    error,
+                    stages: &stages,
+                    http_fetches: run.http_fetches(),
+                    email_id: None,
+                },
+                Some(stored.as_slice()),
+            )
+            .await;
+        });
+
+        return Ok(Either::Right(JsonStream(futures::stream::unfold(
+            output,
+            |mut output| async move { output.recv().await.map(|item| (item, output)) },
+        ))));
+    }
+
+    let output = until_shutdown(
+        exec_timed_pipeline(&script.actions, Arc::clone(&run), elements, &mut stages),
+        shutdown,
+    )
+    .await;
+    let files = match (&output, format) {
+        (Ok(elements), ExpectedFormat::Zip) => Some(archive_files(elements, &config).await),
+        _ => None,
+    };
+    let pipelined = output.map(|elements| {
+        elements
             .into_iter()
             .map(SerdeElement::from)
-            .collect::<Vec<_>>(),
-        |data| {
-            data.into_iter()
-                .map(|el| {
-                    let mut v = vec![];
-                    flatten_serde_pair(el, &mut v);
-                    return v;
-                })
-                .collect()
+            .collect::<Vec<_>>()
+    });
+
+    scripts::record_run(
+        pool,
+        &config,
+        NewScriptRun {
+            owner: &user.username,
+            script_name: None,
+            trigger: RunTrigger::Manual,
+            started,
+            duration_ms: timer.elapsed().as_millis() as i64,
+            input_count,
+            output_count: pipelined.as_ref().map_or(0, |output| output.len() as i64),
+            error: pipelined.as_ref().err().map(|e| format!("{:?}", e)),
+            stages: &stages,
+            http_fetches: run.http_fetches(),
+            email_id: None,
         },
-    );
+        pipelined.as_deref().ok(),
+    )
+    .await;
+
+    let pipelined = pipelined?;
+    let files = files.transpose()?;
+    let events = matches!(format, ExpectedFormat::Ics).then(|| calendar_events(&pipelined));
+
+    let mut formatted = FlexibleFormat::from_complex(pipelined, |data| {
+        data.into_iter()
+            .map(|el| {
+                let mut v = vec![];
+                flatten_serde_pair(el, &mut v);
+                return v;
+            })
+            .collect()
+    });
     formatted.include_header(false);
+    if let Some(columns) = script.columns.clone() {
+        formatted.header(columns);
+    }
+    if let Some(events) = events {
+        formatted.events(events);
+    }
+    if let Some(files) = files {
+        formatted.files(files);
+    }
+    formatted.meta(PageMeta {
+        total: None,
+        next_cursor: next_cursor.map(|cursor| cursor.to_string()),
+        elapsed_ms: Some(timer.elapsed().as_millis()),
+    });
 
-    Ok(formatted)
+    Ok(Either::Left(formatted))
 }
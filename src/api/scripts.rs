@@ -0,0 +1,702 @@
+use crate::{
+    api::execute_script::{Action, SerdeElement, StageTiming},
+    config::{Config, Macro},
+    rocket_types::{ApiJson, AuthorizedUser, Error, ErrorCode, FlexibleFormat, Ratelimit},
+    sql::{self, NewScriptRun, RunTrigger, SavedScript, ScriptRun, ScriptTrigger, UsageMetric},
+    storage, util, ManagedConfig, ManagedPool,
+};
+use regex::Regex;
+use rocket::{serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::str::FromStr;
+use tracing::{error, warn};
+use url::Url;
+
+#[derive(Debug, Serialize)]
+pub struct ApiScriptSummary {
+    name: String,
+    description: String,
+    created: i64,
+    updated: i64,
+}
+impl From<SavedScript> for ApiScriptSummary {
+    fn from(script: SavedScript) -> Self {
+        ApiScriptSummary {
+            name: script.name,
+            description: script.description,
+            created: script.created,
+            updated: script.updated,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiScript {
+    name: String,
+    description: String,
+    created: i64,
+    updated: i64,
+    actions: Vec<Action>,
+}
+impl TryFrom<SavedScript> for ApiScript {
+    type Error = serde_json::Error;
+
+    fn try_from(script: SavedScript) -> Result<Self, Self::Error> {
+        Ok(ApiScript {
+            actions: script.actions()?,
+            name: script.name,
+            description: script.description,
+            created: script.created,
+            updated: script.updated,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScriptInput {
+    #[serde(default)]
+    description: String,
+    actions: Vec<Action>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiScriptRun {
+    id: i64,
+    script_name: Option<String>,
+    trigger: String,
+    started: i64,
+    duration_ms: i64,
+    input_count: i64,
+    output_count: i64,
+    error: Option<String>,
+    has_output: bool,
+    stages: Vec<StageTiming>,
+    http_fetches: i64,
+    /// The email that fired a trigger run.
+    email_id: Option<String>,
+}
+impl TryFrom<ScriptRun> for ApiScriptRun {
+    type Error = serde_json::Error;
+
+    fn try_from(run: ScriptRun) -> Result<Self, Self::Error> {
+        Ok(ApiScriptRun {
+            stages: run.stages()?,
+            id: run.id,
+            script_name: run.script_name,
+            trigger: run.trigger_type,
+            started: run.started,
+            duration_ms: run.duration_ms,
+            input_count: run.input_count,
+            output_count: run.output_count,
+            error: run.error,
+            has_output: run.output_path.is_some(),
+            http_fetches: run.http_fetches,
+            email_id: run.email_id,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Deleted {
+    deleted: bool,
+}
+
+/// Persists a finished run, its output if configured or fired by a trigger, and prunes history beyond the retention limits.
+pub async fn record_run(
+    pool: &ManagedPool,
+    config: &Config,
+    run: NewScriptRun<'_>,
+    output: Option<&[SerdeElement]>,
+) {
+    if let Some(slow_run_ms) = config.scripts.slow_run_ms {
+        if run.duration_ms >= slow_run_ms {
+            let slowest = run.stages.iter().max_by_key(|stage| stage.duration_ms);
+            warn!(
+                owner = run.owner,
+                script_name = run.script_name,
+                duration_ms = run.duration_ms,
+                slowest_stage = ?slowest.map(|stage| &stage.action),
+                slowest_stage_ms = slowest.map(|stage| stage.duration_ms),
+                "Slow script run"
+            );
+        }
+    }
+
+    if let Err(e) = sql::record_usage(pool, run.owner, UsageMetric::ScriptRun, 1, 0).await {
+        error!(error = ?e, "Script run usage accounting error");
+    }
+    if run.http_fetches > 0 {
+        if let Err(e) =
+            sql::record_usage(pool, run.owner, UsageMetric::HttpFetch, run.http_fetches, 0).await
+        {
+            error!(error = ?e, "Script run usage accounting error");
+        }
+    }
+
+    let id = match sql::insert_script_run(pool, &run).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!(error = ?e, "Script run INSERT error");
+            return;
+        }
+    };
+
+    // Nobody awaits a trigger run's output, so it is kept whatever `store_output` says.
+    let store_output = config.scripts.store_output || matches!(run.trigger, RunTrigger::Email);
+    if let (true, Some(output)) = (store_output, output) {
+        let output_path = format!("{}/runs/{}.json", run.owner, id);
+        match serde_json::to_vec(output) {
+            Ok(bytes) => {
+                match storage::write(&config.storage, run.owner, &output_path, &bytes).await {
+                    Ok(()) => {
+                        if let Err(e) = sql::set_script_run_output(pool, id, &output_path).await {
+                            error!(error = ?e, "Script run UPDATE error");
+                        }
+                    }
+                    Err(e) => error!(error = ?e, "Script run output write error"),
+                }
+            }
+            Err(e) => error!(error = ?e, "Script run output serialize error"),
+        }
+    }
+
+    let started_before = util::unix_ms() - config.scripts.runs_max_age_days * 24 * 60 * 60 * 1000;
+    match sql::prune_script_runs(pool, run.owner, config.scripts.runs_keep, started_before).await {
+        Ok(pruned_outputs) => {
+            for output_path in pruned_outputs {
+                if let Err(e) = storage::remove(&config.storage, run.owner, &output_path).await {
+                    error!(error = ?e, "Script run output remove error");
+                }
+            }
+        }
+        Err(e) => error!(error = ?e, "Script run prune error"),
+    }
+}
+
+async fn fetch_script(pool: &ManagedPool, owner: &str, name: &str) -> Result<ApiScript, Error> {
+    let script = match sql::get_script(pool, owner, name).await {
+        Ok(Some(x)) => x,
+        Ok(None) => return Err(Error::NotFound(ErrorCode::ScriptNotFound)),
+        Err(e) => {
+            error!(error = ?e, "/scripts/<name> SELECT error");
+            return Err(Error::InternalError);
+        }
+    };
+
+    match ApiScript::try_from(script) {
+        Ok(x) => Ok(x),
+        Err(e) => {
+            error!(error = ?e, "/scripts/<name> stored JSON error");
+            Err(Error::InternalError)
+        }
+    }
+}
+
+#[rocket::get("/scripts/list")]
+pub async fn list_scripts(
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    _ratelimit: Ratelimit,
+) -> Result<FlexibleFormat<ApiScriptSummary>, Error> {
+    match sql::list_scripts(pool, &user.username).await {
+        Ok(scripts) => Ok(FlexibleFormat::from_vec(
+            scripts.into_iter().map(ApiScriptSummary::from).collect(),
+        )),
+        Err(e) => {
+            error!(error = ?e, "/scripts/list SELECT error");
+            Err(Error::InternalError)
+        }
+    }
+}
+
+/// `slow=true` lists only runs that took at least `scripts.slow_run_ms`.
+#[rocket::get("/scripts/runs/list?<limit>&<slow>")]
+pub async fn list_script_runs(
+    limit: Option<i64>,
+    slow: Option<bool>,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    config: &State<ManagedConfig>,
+    _ratelimit: Ratelimit,
+) -> Result<FlexibleFormat<ApiScriptRun>, Error> {
+    let min_duration_ms = match slow {
+        Some(true) => match config.load().scripts.slow_run_ms {
+            Some(x) => x,
+            None => return Err(Error::invalid_input(ErrorCode::InvalidParameter, "slow")),
+        },
+        _ => 0,
+    };
+
+    let runs =
+        match sql::list_script_runs(pool, &user.username, min_duration_ms, limit.unwrap_or(50))
+            .await
+        {
+            Ok(x) => x,
+            Err(e) => {
+                error!(error = ?e, "/scripts/runs/list SELECT error");
+                return Err(Error::InternalError);
+            }
+        };
+
+    match runs
+        .into_iter()
+        .map(ApiScriptRun::try_from)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(runs) => Ok(FlexibleFormat::from_vec(runs)),
+        Err(e) => {
+            error!(error = ?e, "/scripts/runs/list stored JSON error");
+            Err(Error::InternalError)
+        }
+    }
+}
+
+/// The output a run stored, when `scripts.store_output` was on or a trigger fired it.
+#[rocket::get("/scripts/runs/<id>/output")]
+pub async fn get_script_run_output(
+    id: i64,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    config: &State<ManagedConfig>,
+    _ratelimit: Ratelimit,
+) -> Result<ApiJson<Value>, Error> {
+    let output_path = match sql::get_script_run(pool, &user.username, id).await {
+        Ok(Some(ScriptRun {
+            output_path: Some(x),
+            ..
+        })) => x,
+        Ok(_) => return Err(Error::NotFound(ErrorCode::RunOutputNotFound)),
+        Err(e) => {
+            error!(error = ?e, "/scripts/runs/<id>/output SELECT error");
+            return Err(Error::InternalError);
+        }
+    };
+
+    let bytes = match storage::read(&config.load().storage, &user.username, &output_path).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!(error = ?e, "/scripts/runs/<id>/output read error");
+            return Err(Error::InternalError);
+        }
+    };
+    match serde_json::from_slice(&bytes) {
+        Ok(x) => Ok(ApiJson(x)),
+        Err(e) => {
+            error!(error = ?e, "/scripts/runs/<id>/output stored JSON error");
+            Err(Error::InternalError)
+        }
+    }
+}
+
+#[rocket::get("/scripts/<name>")]
+pub async fn get_script(
+    name: &str,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    _ratelimit: Ratelimit,
+) -> Result<ApiJson<ApiScript>, Error> {
+    fetch_script(pool, &user.username, name).await.map(ApiJson)
+}
+
+#[rocket::put("/scripts/<name>", format = "json", data = "<script>")]
+pub async fn put_script(
+    name: &str,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    script: Json<ScriptInput>,
+    _ratelimit: Ratelimit,
+) -> Result<ApiJson<ApiScript>, Error> {
+    if name.is_empty() {
+        return Err(Error::invalid_input(ErrorCode::InvalidScriptName, name));
+    }
+
+    if let Err(e) = sql::upsert_script(
+        pool,
+        &user.username,
+        name,
+        &script.actions,
+        &script.description,
+    )
+    .await
+    {
+        error!(error = ?e, "/scripts/<name> upsert error");
+        return Err(Error::InternalError);
+    }
+
+    fetch_script(pool, &user.username, name).await.map(ApiJson)
+}
+
+/// Bundles newer than this are rejected rather than half understood.
+const BUNDLE_VERSION: u32 = 1;
+
+/// A script with the macros it needs, portable between instances.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScriptBundle {
+    version: u32,
+    name: String,
+    #[serde(default)]
+    description: String,
+    actions: Vec<Action>,
+    #[serde(default)]
+    macros: Vec<Macro>,
+}
+
+/// Adds every macro `actions` names, including inside other macros, to `macros` once each.
+fn referenced_macros(actions: &[Action], config: &Config, macros: &mut Vec<Macro>) {
+    for action in actions {
+        match action {
+            Action::Macro(name) if !macros.iter().any(|mac| &mac.name == name) => {
+                if let Some(mac) = config.macros.iter().find(|mac| &mac.name == name) {
+                    macros.push(mac.clone());
+                    referenced_macros(&mac.actions, config, macros);
+                }
+            }
+            Action::Or(actions1, actions2) | Action::Pair(actions1, actions2) => {
+                referenced_macros(actions1, config, macros);
+                referenced_macros(actions2, config, macros);
+            }
+            Action::Filter(actions) => referenced_macros(actions, config, macros),
+            _ => {}
+        }
+    }
+}
+
+/// Replaces references to `inlined` macros with their actions. Macros named inside a macro never
+/// run, so they are left out rather than becoming live once inlined.
+fn inline_macros(actions: Vec<Action>, inlined: &[&Macro]) -> Vec<Action> {
+    let mut result = vec![];
+    for action in actions {
+        match action {
+            Action::Macro(name) => match inlined.iter().find(|mac| mac.name == name) {
+                Some(mac) => result.extend(without_macros(mac.actions.clone())),
+                None => result.push(Action::Macro(name)),
+            },
+            Action::Or(actions1, actions2) => result.push(Action::Or(
+                inline_macros(actions1, inlined),
+                inline_macros(actions2, inlined),
+            )),
+            Action::Pair(actions1, actions2) => result.push(Action::Pair(
+                inline_macros(actions1, inlined),
+                inline_macros(actions2, inlined),
+            )),
+            Action::Filter(actions) => result.push(Action::Filter(inline_macros(actions, inlined))),
+            action => result.push(action),
+        }
+    }
+    result
+}
+
+/// Drops every macro reference in `actions`, including inside `Or`, `Pair` and `Filter`.
+fn without_macros(actions: Vec<Action>) -> Vec<Action> {
+    actions
+        .into_iter()
+        .filter_map(|action| match action {
+            Action::Macro(_) => None,
+            Action::Or(actions1, actions2) => Some(Action::Or(
+                without_macros(actions1),
+                without_macros(actions2),
+            )),
+            Action::Pair(actions1, actions2) => Some(Action::Pair(
+                without_macros(actions1),
+                without_macros(actions2),
+            )),
+            Action::Filter(actions) => Some(Action::Filter(without_macros(actions))),
+            action => Some(action),
+        })
+        .collect()
+}
+
+fn same_actions(actions1: &[Action], actions2: &[Action]) -> bool {
+    matches!(
+        (serde_json::to_value(actions1), serde_json::to_value(actions2)),
+        (Ok(value1), Ok(value2)) if value1 == value2
+    )
+}
+
+#[rocket::get("/scripts/<name>/export")]
+pub async fn export_script(
+    name: &str,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    config: &State<ManagedConfig>,
+    _ratelimit: Ratelimit,
+) -> Result<ApiJson<ScriptBundle>, Error> {
+    let script = fetch_script(pool, &user.username, name).await?;
+
+    let mut macros = vec![];
+    referenced_macros(&script.actions, &config.load(), &mut macros);
+
+    Ok(ApiJson(ScriptBundle {
+        version: BUNDLE_VERSION,
+        name: script.name,
+        description: script.description,
+        actions: script.actions,
+        macros,
+    }))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum OnConflict {
+    #[default]
+    Fail,
+    Replace,
+    /// Import as `<name> (2)`, `<name> (3)`, ... whichever is free first.
+    Rename,
+}
+impl FromStr for OnConflict {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(OnConflict::Fail),
+            "replace" => Ok(OnConflict::Replace),
+            "rename" => Ok(OnConflict::Rename),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiImportedScript {
+    #[serde(flatten)]
+    script: ApiScript,
+    /// Bundled macros this instance lacks or defines differently, whose actions were copied
+    /// into the script in place of the macro.
+    inlined_macros: Vec<String>,
+}
+
+/// Saves a bundle from `export_script`. `on_conflict` is `fail` (the default), `replace` or
+/// `rename` for when a script of the same name exists.
+#[rocket::post("/scripts/import?<on_conflict>", format = "json", data = "<bundle>")]
+pub async fn import_script(
+    on_conflict: Option<&str>,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    config: &State<ManagedConfig>,
+    bundle: Json<ScriptBundle>,
+    _ratelimit: Ratelimit,
+) -> Result<ApiJson<ApiImportedScript>, Error> {
+    let on_conflict = match on_conflict.map(str::parse::<OnConflict>) {
+        Some(Ok(x)) => x,
+        Some(Err(())) => {
+            return Err(Error::invalid_input(
+                ErrorCode::InvalidParameter,
+                "on_conflict",
+            ))
+        }
+        None => OnConflict::default(),
+    };
+    let bundle = bundle.into_inner();
+    if bundle.version > BUNDLE_VERSION {
+        return Err(Error::invalid_input(
+            ErrorCode::UnsupportedBundle,
+            bundle.version.to_string(),
+        ));
+    }
+    if bundle.name.is_empty() {
+        return Err(Error::invalid_input(
+            ErrorCode::InvalidScriptName,
+            bundle.name,
+        ));
+    }
+
+    let config = config.load();
+    let inlined: Vec<&Macro> = bundle
+        .macros
+        .iter()
+        .filter(|bundled| {
+            !config
+                .macros
+                .iter()
+                .any(|mac| mac.name == bundled.name && same_actions(&mac.actions, &bundled.actions))
+        })
+        .collect();
+    let actions = inline_macros(bundle.actions, &inlined);
+
+    let mut name = bundle.name.clone();
+    let mut copy = 1;
+    loop {
+        let exists = match sql::get_script(pool, &user.username, &name).await {
+            Ok(x) => x.is_some(),
+            Err(e) => {
+                error!(error = ?e, "/scripts/import SELECT error");
+                return Err(Error::InternalError);
+            }
+        };
+        match (exists, on_conflict) {
+            (false, _) | (true, OnConflict::Replace) => break,
+            (true, OnConflict::Fail) => {
+                return Err(Error::invalid_input(ErrorCode::ScriptExists, name))
+            }
+            (true, OnConflict::Rename) => {
+                copy += 1;
+                name = format!("{} ({})", bundle.name, copy);
+            }
+        }
+    }
+
+    if let Err(e) =
+        sql::upsert_script(pool, &user.username, &name, &actions, &bundle.description).await
+    {
+        error!(error = ?e, "/scripts/import upsert error");
+        return Err(Error::InternalError);
+    }
+
+    Ok(ApiJson(ApiImportedScript {
+        script: fetch_script(pool, &user.username, &name).await?,
+        inlined_macros: inlined.iter().map(|mac| mac.name.clone()).collect(),
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiScriptTrigger {
+    script_name: String,
+    from_regex: Option<String>,
+    subject_regex: Option<String>,
+    webhook: Option<String>,
+    created: i64,
+}
+impl From<ScriptTrigger> for ApiScriptTrigger {
+    fn from(trigger: ScriptTrigger) -> Self {
+        ApiScriptTrigger {
+            script_name: trigger.script_name,
+            from_regex: trigger.from_regex,
+            subject_regex: trigger.subject_regex,
+            webhook: trigger.webhook,
+            created: trigger.created,
+        }
+    }
+}
+
+/// Both regexes must match for the trigger to fire; a missing one matches every email.
+#[derive(Debug, Deserialize)]
+pub struct TriggerInput {
+    #[serde(default)]
+    from_regex: Option<String>,
+    #[serde(default)]
+    subject_regex: Option<String>,
+    /// Receives a POST with the output of each run that produced any.
+    #[serde(default)]
+    webhook: Option<String>,
+}
+
+#[rocket::get("/scripts/triggers/list")]
+pub async fn list_script_triggers(
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    _ratelimit: Ratelimit,
+) -> Result<FlexibleFormat<ApiScriptTrigger>, Error> {
+    match sql::list_script_triggers(pool, &user.username).await {
+        Ok(triggers) => Ok(FlexibleFormat::from_vec(
+            triggers.into_iter().map(ApiScriptTrigger::from).collect(),
+        )),
+        Err(e) => {
+            error!(error = ?e, "/scripts/triggers/list SELECT error");
+            Err(Error::InternalError)
+        }
+    }
+}
+
+#[rocket::get("/scripts/<name>/trigger")]
+pub async fn get_script_trigger(
+    name: &str,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    _ratelimit: Ratelimit,
+) -> Result<ApiJson<ApiScriptTrigger>, Error> {
+    match sql::get_script_trigger(pool, &user.username, name).await {
+        Ok(Some(x)) => Ok(ApiJson(x.into())),
+        Ok(None) => Err(Error::NotFound(ErrorCode::TriggerNotFound)),
+        Err(e) => {
+            error!(error = ?e, "/scripts/<name>/trigger SELECT error");
+            Err(Error::InternalError)
+        }
+    }
+}
+
+/// Makes the script run on each newly stored email of the user that matches `trigger`.
+#[rocket::put("/scripts/<name>/trigger", format = "json", data = "<trigger>")]
+pub async fn put_script_trigger(
+    name: &str,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    trigger: Json<TriggerInput>,
+    _ratelimit: Ratelimit,
+) -> Result<ApiJson<ApiScriptTrigger>, Error> {
+    let trigger = trigger.into_inner();
+    for regex in [&trigger.from_regex, &trigger.subject_regex]
+        .into_iter()
+        .flatten()
+    {
+        if let Err(e) = Regex::new(regex) {
+            return Err(Error::invalid_input(ErrorCode::InvalidRegex, regex).with_detail(e));
+        }
+    }
+    if let Some(webhook) = &trigger.webhook {
+        match Url::parse(webhook) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            Ok(_) => return Err(Error::invalid_input(ErrorCode::InvalidUrl, webhook)),
+            Err(e) => {
+                return Err(Error::invalid_input(ErrorCode::InvalidUrl, webhook).with_detail(e))
+            }
+        }
+    }
+    fetch_script(pool, &user.username, name).await?;
+
+    let trigger = ScriptTrigger {
+        owner: user.username.clone(),
+        script_name: name.to_owned(),
+        from_regex: trigger.from_regex,
+        subject_regex: trigger.subject_regex,
+        webhook: trigger.webhook,
+        created: util::unix_ms(),
+    };
+    if let Err(e) = sql::upsert_script_trigger(pool, &trigger).await {
+        error!(error = ?e, "/scripts/<name>/trigger upsert error");
+        return Err(Error::InternalError);
+    }
+
+    match sql::get_script_trigger(pool, &user.username, name).await {
+        Ok(Some(x)) => Ok(ApiJson(x.into())),
+        Ok(None) => Err(Error::NotFound(ErrorCode::TriggerNotFound)),
+        Err(e) => {
+            error!(error = ?e, "/scripts/<name>/trigger SELECT error");
+            Err(Error::InternalError)
+        }
+    }
+}
+
+#[rocket::delete("/scripts/<name>/trigger")]
+pub async fn delete_script_trigger(
+    name: &str,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    _ratelimit: Ratelimit,
+) -> Result<ApiJson<Deleted>, Error> {
+    match sql::delete_script_trigger(pool, &user.username, name).await {
+        Ok(true) => Ok(ApiJson(Deleted { deleted: true })),
+        Ok(false) => Err(Error::NotFound(ErrorCode::TriggerNotFound)),
+        Err(e) => {
+            error!(error = ?e, "/scripts/<name>/trigger DELETE error");
+            Err(Error::InternalError)
+        }
+    }
+}
+
+#[rocket::delete("/scripts/<name>")]
+pub async fn delete_script(
+    name: &str,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    _ratelimit: Ratelimit,
+) -> Result<ApiJson<Deleted>, Error> {
+    match sql::delete_script(pool, &user.username, name).await {
+        Ok(true) => Ok(ApiJson(Deleted { deleted: true })),
+        Ok(false) => Err(Error::NotFound(ErrorCode::ScriptNotFound)),
+        Err(e) => {
+            error!(error = ?e, "/scripts/<name> DELETE error");
+            Err(Error::InternalError)
+        }
+    }
+}
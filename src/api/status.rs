@@ -0,0 +1,62 @@
+use crate::{
+    rocket_types::{ApiJson, AuthorizedUser, Ratelimit},
+    status::ImapStatus,
+    ManagedPool, ManagedRatelimits, ManagedStatus, ManagedUrlCache,
+};
+use rocket::State;
+use serde::Serialize;
+use std::time::Instant;
+use tracing::error;
+
+#[derive(Debug, Serialize)]
+pub struct DatabaseStatus {
+    healthy: bool,
+    latency_ms: u128,
+    connections: u32,
+    idle_connections: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiStatus {
+    version: &'static str,
+    uptime_secs: u64,
+    database: DatabaseStatus,
+    imap: Vec<ImapStatus>,
+    running_scripts: usize,
+    url_cache_entries: usize,
+    ratelimit_entries: usize,
+}
+
+#[rocket::get("/status")]
+pub async fn get_status(
+    _user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    status: &State<ManagedStatus>,
+    url_cache: &State<ManagedUrlCache>,
+    ratelimits: &State<ManagedRatelimits>,
+    _ratelimit: Ratelimit,
+) -> ApiJson<ApiStatus> {
+    let timer = Instant::now();
+    let healthy = match sqlx::query("SELECT 1").execute(&**pool).await {
+        Ok(_) => true,
+        Err(e) => {
+            error!(error = ?e, "/status SELECT error");
+            false
+        }
+    };
+
+    ApiJson(ApiStatus {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: status.uptime_secs(),
+        database: DatabaseStatus {
+            healthy,
+            latency_ms: timer.elapsed().as_millis(),
+            connections: pool.size(),
+            idle_connections: pool.num_idle(),
+        },
+        imap: status.imap(),
+        running_scripts: status.running_scripts(),
+        url_cache_entries: url_cache.entry_count(),
+        ratelimit_entries: ratelimits.len(),
+    })
+}
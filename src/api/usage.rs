@@ -0,0 +1,81 @@
+use crate::{
+    rocket_types::{ApiJson, AuthorizedUser, Error, ErrorCode, Ratelimit},
+    sql::{self, UsageMetric},
+    ManagedPool,
+};
+use chrono::DateTime;
+use rocket::State;
+use serde::Serialize;
+use tracing::error;
+
+const MAX_DAYS: i64 = 366;
+
+#[derive(Debug, Serialize)]
+pub struct DayUsage {
+    /// UTC, as `YYYY-MM-DD`.
+    day: String,
+    requests: i64,
+    bytes_served: i64,
+    script_runs: i64,
+    http_fetches: i64,
+}
+impl DayUsage {
+    fn new(day: String) -> Self {
+        DayUsage {
+            day,
+            requests: 0,
+            bytes_served: 0,
+            script_runs: 0,
+            http_fetches: 0,
+        }
+    }
+}
+
+/// The caller's usage per UTC day over the last `days` days (default 30, at most 366), oldest
+/// first. Days without any usage are left out.
+#[rocket::get("/usage?<days>")]
+pub async fn get_usage(
+    days: Option<i64>,
+    user: AuthorizedUser,
+    pool: &State<ManagedPool>,
+    _ratelimit: Ratelimit,
+) -> Result<ApiJson<Vec<DayUsage>>, Error> {
+    let days = days.unwrap_or(30);
+    if !(1..=MAX_DAYS).contains(&days) {
+        return Err(Error::invalid_input(ErrorCode::InvalidParameter, "days"));
+    }
+
+    let rows = match sql::daily_usage(pool, &user.username, days).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!(error = ?e, "/usage SELECT error");
+            return Err(Error::InternalError);
+        }
+    };
+
+    let mut usage: Vec<DayUsage> = vec![];
+    for row in rows {
+        let Some(day) = DateTime::from_timestamp_millis(row.day) else {
+            continue;
+        };
+        let day = day.date_naive().to_string();
+        if usage.last().map(|last| &last.day) != Some(&day) {
+            usage.push(DayUsage::new(day));
+        }
+        let Some(entry) = usage.last_mut() else {
+            continue;
+        };
+
+        let metric = row.metric.as_str();
+        if metric == UsageMetric::Request.as_str() {
+            entry.requests = row.count;
+            entry.bytes_served = row.bytes;
+        } else if metric == UsageMetric::ScriptRun.as_str() {
+            entry.script_runs = row.count;
+        } else if metric == UsageMetric::HttpFetch.as_str() {
+            entry.http_fetches = row.count;
+        }
+    }
+
+    Ok(ApiJson(usage))
+}
@@ -0,0 +1,261 @@
+use mail_auth::{AuthenticatedMessage, DkimOutput, DkimResult, Resolver, SpfResult};
+use mailparse::{MailAddr, MailHeader, MailHeaderMap};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::time;
+use tracing::{error, warn};
+
+/// For all of one email's DNS lookups together.
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How DKIM or SPF came out, as stored in the `emails` table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthResult {
+    Pass,
+    Fail,
+    /// Nothing to check, or it could not be checked.
+    None,
+}
+impl AuthResult {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuthResult::Pass => "pass",
+            AuthResult::Fail => "fail",
+            AuthResult::None => "none",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Authentication {
+    pub dkim: AuthResult,
+    pub spf: AuthResult,
+}
+
+fn resolver() -> Option<&'static Resolver> {
+    static RESOLVER: OnceLock<Option<Resolver>> = OnceLock::new();
+    RESOLVER
+        .get_or_init(|| match Resolver::new_system_conf() {
+            Ok(x) => Some(x),
+            Err(e) => {
+                error!(error = ?e, "Authentication resolver error");
+                None
+            }
+        })
+        .as_ref()
+}
+
+/// The domain of the `From` address, which the reader sees and which DKIM and SPF must be
+/// aligned with to say anything about it.
+fn author_domain(headers: &[MailHeader]) -> Option<String> {
+    let addr = mailparse::addrparse_header(headers.get_first_header("From")?)
+        .ok()?
+        .iter()
+        .find_map(|addr| match addr {
+            MailAddr::Single(info) => Some(info.addr.clone()),
+            MailAddr::Group(group) => group.addrs.first().map(|info| info.addr.clone()),
+        })?;
+    let (_, domain) = addr.rsplit_once('@')?;
+    Some(domain.trim_end_matches('.').to_ascii_lowercase())
+}
+
+/// Relaxed alignment as in DMARC: the domains are the same or one is a subdomain of the other.
+/// Without the public suffix list, `example.com` and `mail.example.com` align but two
+/// subdomains of `example.com` do not.
+fn aligned(domain: &str, author_domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    domain == author_domain
+        || domain.ends_with(&format!(".{}", author_domain))
+        || author_domain.ends_with(&format!(".{}", domain))
+}
+
+/// Passes if any signature aligned with `author_domain` does and fails if there are signatures
+/// but none of those pass, so a valid signature of some other domain fails. Temporary errors,
+/// such as DNS timeouts, count as not checked.
+fn dkim_result(outputs: &[DkimOutput], author_domain: Option<&str>) -> AuthResult {
+    let passes_aligned = |output: &DkimOutput| {
+        matches!(output.result(), DkimResult::Pass)
+            && matches!(
+                (output.signature(), author_domain),
+                (Some(signature), Some(author_domain)) if aligned(&signature.d, author_domain)
+            )
+    };
+    if outputs.iter().any(passes_aligned) {
+        AuthResult::Pass
+    } else if outputs.iter().any(|output| {
+        matches!(
+            output.result(),
+            DkimResult::Pass
+                | DkimResult::Fail(_)
+                | DkimResult::PermError(_)
+                | DkimResult::Neutral(_)
+        )
+    }) {
+        AuthResult::Fail
+    } else {
+        AuthResult::None
+    }
+}
+
+/// A pass only counts for a `domain` aligned with `author_domain`, as the envelope sender is
+/// whatever the client chose.
+fn spf_result(result: SpfResult, domain: &str, author_domain: Option<&str>) -> AuthResult {
+    match result {
+        SpfResult::Pass => match author_domain {
+            Some(author_domain) if aligned(domain, author_domain) => AuthResult::Pass,
+            _ => AuthResult::Fail,
+        },
+        SpfResult::Fail | SpfResult::SoftFail | SpfResult::PermError => AuthResult::Fail,
+        SpfResult::Neutral | SpfResult::TempError | SpfResult::None => AuthResult::None,
+    }
+}
+
+/// A block of addresses in CIDR notation, as in `203.0.113.0/24` or `2001:db8::/32`.
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
+#[serde(try_from = "String")]
+pub struct Network {
+    #[schemars(with = "String")]
+    address: IpAddr,
+    prefix_len: u32,
+}
+impl TryFrom<String> for Network {
+    type Error = String;
+
+    fn try_from(network: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("{:?} is not an address block such as 10.0.0.0/8", network);
+        let (address, prefix_len) = network.split_once('/').ok_or_else(invalid)?;
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let prefix_len: u32 = prefix_len.parse().map_err(|_| invalid())?;
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(Network {
+            address,
+            prefix_len,
+        })
+    }
+}
+impl Network {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether `ip` can only be a host of the receiving side: loopback, private or link-local.
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            // Unique local fc00::/7 and link-local fe80::/10.
+            ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+/// What a receiving server recorded about its client in a `Received` header, as in
+/// `from mail.example.com (mail.example.com [203.0.113.5]) by mx.example.net`.
+struct Received {
+    helo: String,
+    ip: IpAddr,
+    /// The receiving server's own name.
+    by: String,
+}
+
+fn parse_received(received: &str) -> Option<Received> {
+    let (from, by) = received
+        .trim_start()
+        .strip_prefix("from ")?
+        .split_once(" by ")?;
+    let ip = from.split('[').skip(1).find_map(|bracketed| {
+        let address = bracketed.split(']').next()?;
+        address.trim_start_matches("IPv6:").parse().ok()
+    })?;
+    Some(Received {
+        helo: from.split_ascii_whitespace().next()?.to_owned(),
+        ip,
+        by: by.split_ascii_whitespace().next()?.to_owned(),
+    })
+}
+
+/// The first hop from outside, going down from the topmost `Received` header: the first client
+/// that is neither internal nor in `trusted`. Hops between the receiving side's own hosts,
+/// such as from the MTA in front of the SMTP listener, are skipped that way.
+fn received(headers: &[MailHeader], trusted: &[Network]) -> Option<Received> {
+    headers
+        .get_all_values("Received")
+        .iter()
+        .filter_map(|received| parse_received(received))
+        .find(|received| {
+            !is_internal(received.ip)
+                && !trusted.iter().any(|network| network.contains(received.ip))
+        })
+}
+
+/// Verifies `raw`'s DKIM signatures and, from the first client outside the receiving side its
+/// `Received` headers record, SPF for the envelope sender `sender`. Hosts of the receiving side
+/// with public addresses, such as a provider's relays, must be in `trusted` for SPF to skip them.
+/// Either only passes when its domain is aligned with the `From` domain, so a spoofer signing
+/// for their own domain or sending from it fails.
+pub async fn verify(
+    raw: &[u8],
+    headers: &[MailHeader],
+    sender: &str,
+    trusted: &[Network],
+) -> Authentication {
+    let not_checked = Authentication {
+        dkim: AuthResult::None,
+        spf: AuthResult::None,
+    };
+    let Some(resolver) = resolver() else {
+        return not_checked;
+    };
+    let Some(message) = AuthenticatedMessage::parse(raw) else {
+        return not_checked;
+    };
+
+    let verification = async {
+        let author_domain = author_domain(headers);
+        let dkim_outputs = resolver.verify_dkim(&message).await;
+        let dkim = dkim_result(&dkim_outputs, author_domain.as_deref());
+
+        let spf = match received(headers, trusted) {
+            Some(received) => {
+                let output = resolver
+                    .verify_spf_sender(received.ip, &received.helo, &received.by, sender)
+                    .await;
+                // A null sender, as bounces have, is checked for the HELO name instead.
+                let domain = match sender.rsplit_once('@') {
+                    Some((_, domain)) => domain,
+                    None => &received.helo,
+                };
+                spf_result(output.result(), domain, author_domain.as_deref())
+            }
+            None => AuthResult::None,
+        };
+
+        Authentication { dkim, spf }
+    };
+
+    match time::timeout(VERIFY_TIMEOUT, verification).await {
+        Ok(x) => x,
+        Err(_) => {
+            warn!("Authentication verification timed out");
+            not_checked
+        }
+    }
+}
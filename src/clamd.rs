@@ -0,0 +1,71 @@
+use crate::config::Clamd;
+use std::io::{Error as IoError, ErrorKind};
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::time;
+
+/// Bytes sent per `INSTREAM` chunk.
+const CHUNK_BYTES: usize = 64 * 1024;
+/// Replies are a single short line; anything longer is not clamd.
+const MAX_REPLY_BYTES: u64 = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Clean,
+    /// The signature clamd matched.
+    Infected(String),
+}
+
+fn parse_reply(reply: &[u8]) -> io::Result<Verdict> {
+    let reply = String::from_utf8_lossy(reply);
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+
+    if result == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(signature.to_owned()))
+    } else {
+        Err(IoError::new(
+            ErrorKind::InvalidData,
+            format!("unexpected clamd reply: {}", reply),
+        ))
+    }
+}
+
+async fn instream<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    body: &[u8],
+) -> io::Result<Verdict> {
+    stream.write_all(b"zINSTREAM\0").await?;
+    for chunk in body.chunks(CHUNK_BYTES) {
+        stream
+            .write_all(&(chunk.len() as u32).to_be_bytes())
+            .await?;
+        stream.write_all(chunk).await?;
+    }
+    stream.write_all(&0u32.to_be_bytes()).await?;
+    stream.flush().await?;
+
+    let mut reply = vec![];
+    stream.take(MAX_REPLY_BYTES).read_to_end(&mut reply).await?;
+    parse_reply(&reply)
+}
+
+/// Streams `body` to clamd with `INSTREAM`. Bodies over clamd's `StreamMaxLength` come back as
+/// an error rather than a verdict.
+pub async fn scan(clamd: &Clamd, body: &[u8]) -> io::Result<Verdict> {
+    let scan = async {
+        if clamd.address.starts_with('/') {
+            instream(UnixStream::connect(&clamd.address).await?, body).await
+        } else {
+            instream(TcpStream::connect(clamd.address.as_str()).await?, body).await
+        }
+    };
+
+    match time::timeout(Duration::from_secs(clamd.timeout_secs), scan).await {
+        Ok(result) => result,
+        Err(_) => Err(IoError::new(ErrorKind::TimedOut, "clamd timed out")),
+    }
+}
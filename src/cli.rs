@@ -0,0 +1,85 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(name = "epv", version, about = "Email Ponzi Ventures")]
+pub struct Cli {
+    /// Config file to use instead of searching ./config.json, $XDG_CONFIG_HOME/epv/config.json
+    /// and /etc/epv/config.json in that order.
+    #[arg(long, env = "EPV_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+    /// Defaults to `serve`.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the web server together with IMAP ingestion and maintenance.
+    Serve,
+    /// Apply pending database migrations and exit.
+    Migrate,
+    /// Manage the users listed in the config file.
+    User {
+        #[command(subcommand)]
+        command: UserCommand,
+    },
+    /// Store archived messages as if they had arrived over IMAP: .eml files, with directories
+    /// searched recursively for *.eml files, and the messages in mbox files and Maildirs.
+    Import {
+        /// Store everything for this user, rather than for whichever user each message's
+        /// recipients route to.
+        #[arg(long)]
+        user: Option<String>,
+        /// An mbox file, with `>From ` lines unescaped as in mboxrd. May be repeated.
+        #[arg(long)]
+        mbox: Vec<PathBuf>,
+        /// A Maildir, along with its Maildir++ folders such as `.Sent`. May be repeated.
+        #[arg(long)]
+        maildir: Vec<PathBuf>,
+        #[arg(required_unless_present_any = ["mbox", "maildir"])]
+        paths: Vec<PathBuf>,
+    },
+    /// Store the messages in an IMAP mailbox that are missing from the database, e.g. after
+    /// losing it, without moving or flagging them. Each is stored for whichever users its
+    /// recipients route to.
+    Backfill {
+        /// The username of the `imap` account to read, or `<username>@<server>` if it is on several
+        /// servers. May be left out when there is only one account.
+        #[arg(long)]
+        account: Option<String>,
+        /// Defaults to the account's `read_mailbox`, where processed messages are moved.
+        #[arg(long)]
+        mailbox: Option<String>,
+    },
+    /// Parse stored raw messages again, e.g. after a parser fix, and update what was derived
+    /// from them. Emails ingested before raw messages were kept are skipped.
+    Replay {
+        /// Only this user's emails.
+        #[arg(long)]
+        user: Option<String>,
+        /// Only the email with this id.
+        #[arg(long)]
+        email: Option<String>,
+    },
+    /// Copy the database and stored files into a new directory.
+    Backup { destination: PathBuf },
+    /// Validate the config and the storage it points at, then exit.
+    CheckConfig,
+    /// Print a JSON Schema describing the config file, including the macro action language.
+    ConfigSchema,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UserCommand {
+    /// Add a user to the main config file. A random password is generated and printed unless
+    /// one is given.
+    Add {
+        username: String,
+        #[arg(long, env = "EPV_PASSWORD")]
+        password: Option<String>,
+        /// Allow the user to access the admin API.
+        #[arg(long)]
+        admin: bool,
+    },
+}
@@ -0,0 +1,538 @@
+use crate::{
+    config::{self, Config, IngestProtocol},
+    imap,
+    ingest::{self, Ingested},
+    sql, startup, storage, util,
+};
+use mailparse::MailHeaderMap;
+use serde_json::{json, Value};
+use sqlx::{Pool, Sqlite};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::{self, AsyncBufReadExt, BufReader};
+
+/// Connects to the database and applies any pending migrations.
+async fn open_pool(config: &Config) -> Result<Pool<Sqlite>, String> {
+    let pool = sql::connect(&config.storage)
+        .await
+        .map_err(|e| format!("Unable to connect to DB: {}", e))?;
+    if let Err(e) = sql::MIGRATOR.run(&pool).await {
+        pool.close().await;
+        return Err(format!("Unable to run migrations: {}", e));
+    }
+    Ok(pool)
+}
+
+pub async fn migrate(config: &Config) -> Result<(), String> {
+    open_pool(config).await?.close().await;
+    println!("Migrations applied");
+    Ok(())
+}
+
+/// Appends to `users` in `path` itself; users defined only in an included file must be added
+/// there by hand, since merging an array into a single user object would replace it.
+pub async fn add_user(
+    path: &Path,
+    username: String,
+    password: Option<String>,
+    admin: bool,
+) -> Result<(), String> {
+    let config = config::read_config(path).await?;
+    if username.is_empty() {
+        return Err("Username must not be empty".to_owned());
+    }
+    if !storage::is_path_component(&username) {
+        return Err(format!(
+            "Username {:?} cannot be used as a directory name",
+            username
+        ));
+    }
+    if config
+        .users
+        .as_slice()
+        .iter()
+        .any(|user| user.username == username)
+    {
+        return Err(format!("User {:?} already exists", username));
+    }
+
+    let generated = password.is_none();
+    let password = password.unwrap_or_else(|| util::random_hex(16));
+    let mut entry = json!({ "username": username, "password": password });
+    if admin {
+        entry["admin"] = Value::Bool(true);
+    }
+
+    let mut raw = config::read_json(path).await?;
+    let Some(object) = raw.as_object_mut() else {
+        return Err(format!("{}: not a JSON object", path.display()));
+    };
+    let users = match object.remove("users") {
+        Some(Value::Array(mut users)) => {
+            users.push(entry);
+            users
+        }
+        Some(single @ Value::Object(_)) => vec![single, entry],
+        _ => {
+            return Err(format!(
+                "{}: users is not defined here, add {:?} to the file that defines it",
+                path.display(),
+                username
+            ))
+        }
+    };
+    object.insert("users".to_owned(), Value::Array(users));
+
+    let mut contents = serde_json::to_string_pretty(&raw)
+        .map_err(|e| format!("Could not serialize {}: {}", path.display(), e))?;
+    contents.push('\n');
+    fs::write(path, contents)
+        .await
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+
+    if generated {
+        println!("Added user {:?} with password {}", username, password);
+    } else {
+        println!("Added user {:?}", username);
+    }
+    println!("Send SIGHUP to a running server to pick up the new user");
+    Ok(())
+}
+
+fn expand_eml_paths(paths: Vec<PathBuf>) -> Result<Vec<PathBuf>, String> {
+    let mut files = vec![];
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path);
+            continue;
+        }
+
+        let pattern = path.join("**/*.eml");
+        let matches = glob::glob(&pattern.to_string_lossy())
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        for entry in matches {
+            files.push(entry.map_err(|e| format!("{}: {}", path.display(), e))?);
+        }
+    }
+
+    Ok(files)
+}
+
+/// The messages in the Maildir at `path` and in its Maildir++ folders, in name order.
+fn expand_maildir(path: &Path) -> Result<Vec<PathBuf>, String> {
+    if !path.join("cur").is_dir() && !path.join("new").is_dir() {
+        return Err(format!("{}: not a Maildir", path.display()));
+    }
+
+    let mut folders = vec![path.to_path_buf()];
+    let entries = std::fs::read_dir(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("{}: {}", path.display(), e))?;
+        if entry.file_name().to_string_lossy().starts_with('.') && entry.path().is_dir() {
+            folders.push(entry.path());
+        }
+    }
+
+    let mut files = vec![];
+    for folder in folders {
+        for subdirectory in ["cur", "new"] {
+            let directory = folder.join(subdirectory);
+            if !directory.is_dir() {
+                continue;
+            }
+            let entries = std::fs::read_dir(&directory)
+                .map_err(|e| format!("{}: {}", directory.display(), e))?;
+            for entry in entries {
+                let entry = entry.map_err(|e| format!("{}: {}", directory.display(), e))?;
+                if entry.path().is_file() {
+                    files.push(entry.path());
+                }
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Reads the messages of an mbox file one at a time. A message starts at a `From ` line at the
+/// start of the file or after a blank line, and ends with the blank line before the next.
+struct Mbox {
+    reader: BufReader<fs::File>,
+    /// Whether a `From ` line has been read, so the lines that follow belong to a message.
+    in_message: bool,
+}
+impl Mbox {
+    fn new(file: fs::File) -> Self {
+        Mbox {
+            reader: BufReader::new(file),
+            in_message: false,
+        }
+    }
+
+    async fn next_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut message = vec![];
+        loop {
+            let mut line = vec![];
+            if self.reader.read_until(b'\n', &mut line).await? == 0 {
+                let in_message = std::mem::replace(&mut self.in_message, false);
+                return Ok(in_message.then(|| without_separator(message)));
+            }
+
+            let follows_blank =
+                message.is_empty() || message.ends_with(b"\n\n") || message.ends_with(b"\n\r\n");
+            if line.starts_with(b"From ") && follows_blank {
+                if self.in_message {
+                    return Ok(Some(without_separator(message)));
+                }
+                self.in_message = true;
+                continue;
+            }
+            if !self.in_message {
+                continue;
+            }
+
+            let quoted = line.iter().take_while(|&&byte| byte == b'>').count();
+            if quoted > 0 && line[quoted..].starts_with(b"From ") {
+                line.remove(0);
+            }
+            message.extend_from_slice(&line);
+        }
+    }
+}
+
+/// `message` without the blank line that separates it from the next.
+fn without_separator(mut message: Vec<u8>) -> Vec<u8> {
+    if message.ends_with(b"\r\n\r\n") {
+        message.truncate(message.len() - 2);
+    } else if message.ends_with(b"\n\n") {
+        message.truncate(message.len() - 1);
+    }
+    message
+}
+
+/// What to import messages from.
+pub struct ImportSources {
+    /// .eml files, or directories to search for them.
+    pub eml: Vec<PathBuf>,
+    pub mbox: Vec<PathBuf>,
+    pub maildir: Vec<PathBuf>,
+}
+
+#[derive(Default)]
+struct ImportCounts {
+    stored: usize,
+    duplicates: usize,
+    failed: usize,
+}
+
+/// Stores `raw`, called `label` in what is printed, for `user`, or without one for each user its
+/// recipients route to.
+async fn import_message(
+    config: &Config,
+    pool: &Pool<Sqlite>,
+    user: Option<&str>,
+    label: &str,
+    raw: &[u8],
+    counts: &mut ImportCounts,
+) {
+    let (headers, _) = match mailparse::parse_headers(raw) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}: {}", label, e);
+            counts.failed += 1;
+            return;
+        }
+    };
+    let Some(from_addr) = ingest::first_address(&headers, "From") else {
+        eprintln!("{}: no From address", label);
+        counts.failed += 1;
+        return;
+    };
+    let routed = match user {
+        Some(user) => vec![(
+            user,
+            ingest::first_address(&headers, "To").unwrap_or_default(),
+        )],
+        None => ingest::route_all(
+            config,
+            &from_addr,
+            &ingest::recipients(&headers),
+            &headers.get_first_value("Subject").unwrap_or_default(),
+        )
+        .into_iter()
+        .map(|(user, to_addr)| (user.username.as_str(), to_addr))
+        .collect(),
+    };
+    if routed.is_empty() {
+        eprintln!("{}: no recipient routes to a user", label);
+        counts.failed += 1;
+        return;
+    }
+
+    for (user, to_addr) in routed {
+        match ingest::store(config, pool, user, from_addr.clone(), to_addr, raw).await {
+            Ok(Ingested::Stored(id)) => {
+                println!("{}: stored as {} for {}", label, id, user);
+                counts.stored += 1;
+            }
+            Ok(Ingested::Duplicate(id)) => {
+                println!("{}: already stored as {}", label, id);
+                counts.duplicates += 1;
+            }
+            Err(e) => {
+                eprintln!("{}: {:?}", label, e);
+                counts.failed += 1;
+            }
+        }
+    }
+}
+
+pub async fn import(
+    config: &Config,
+    user: Option<&str>,
+    sources: ImportSources,
+) -> Result<(), String> {
+    if let Some(user) = user {
+        if !config
+            .users
+            .as_slice()
+            .iter()
+            .any(|known| known.username == user)
+        {
+            return Err(format!("Unknown user {:?}", user));
+        }
+    }
+
+    let mut files = expand_eml_paths(sources.eml)?;
+    for maildir in &sources.maildir {
+        files.extend(expand_maildir(maildir)?);
+    }
+
+    let pool = open_pool(config).await?;
+
+    let mut counts = ImportCounts::default();
+    for file in files {
+        let label = file.display().to_string();
+        match fs::read(&file).await {
+            Ok(raw) => import_message(config, &pool, user, &label, &raw, &mut counts).await,
+            Err(e) => {
+                eprintln!("{}: {}", label, e);
+                counts.failed += 1;
+            }
+        }
+    }
+
+    for path in &sources.mbox {
+        let mut mbox = match fs::File::open(path).await {
+            Ok(file) => Mbox::new(file),
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                counts.failed += 1;
+                continue;
+            }
+        };
+        let mut index = 0;
+        loop {
+            match mbox.next_message().await {
+                Ok(Some(raw)) => {
+                    index += 1;
+                    let label = format!("{}#{}", path.display(), index);
+                    import_message(config, &pool, user, &label, &raw, &mut counts).await;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("{}: {}", path.display(), e);
+                    counts.failed += 1;
+                    break;
+                }
+            }
+        }
+    }
+    pool.close().await;
+
+    println!(
+        "Imported {} emails, skipped {} duplicates, {} failed",
+        counts.stored, counts.duplicates, counts.failed
+    );
+    if counts.failed > 0 {
+        return Err(format!("{} messages could not be imported", counts.failed));
+    }
+    Ok(())
+}
+
+pub async fn backfill(
+    config: &Config,
+    account: Option<&str>,
+    mailbox: Option<&str>,
+) -> Result<(), String> {
+    if config.ingest.protocol != IngestProtocol::Imap {
+        return Err("Backfill reads an IMAP mailbox, so needs ingest.protocol imap".to_owned());
+    }
+    let accounts = config.imap.as_slice();
+    let account = match account {
+        Some(name) => {
+            let mut matching = accounts
+                .iter()
+                .filter(|account| account.username == name || account.key() == name);
+            match (matching.next(), matching.next()) {
+                (Some(account), None) => account,
+                (Some(_), Some(_)) => {
+                    return Err(format!(
+                        "{:?} is on several servers, so --account needs <username>@<server>",
+                        name
+                    ))
+                }
+                (None, _) => return Err(format!("No imap account {:?}", name)),
+            }
+        }
+        None => match accounts {
+            [account] => account,
+            _ => return Err("There are several imap accounts, so --account is needed".to_owned()),
+        },
+    };
+    let mailbox = mailbox.unwrap_or(&account.read_mailbox);
+
+    let pool = open_pool(config).await?;
+
+    let result = imap::backfill(config, account, &pool, mailbox).await;
+    pool.close().await;
+    let counts = result?;
+
+    println!(
+        "Backfilled {} emails from {}, skipped {} already stored, {} failed",
+        counts.stored, mailbox, counts.duplicates, counts.failed
+    );
+    if counts.failed > 0 {
+        return Err(format!(
+            "{} messages could not be backfilled",
+            counts.failed
+        ));
+    }
+    Ok(())
+}
+
+pub async fn replay(
+    config: &Config,
+    user: Option<String>,
+    email: Option<String>,
+) -> Result<(), String> {
+    let pool = open_pool(config).await?;
+
+    let ids = match sql::email_ids(&pool, user.as_deref(), email.as_deref()).await {
+        Ok(x) => x,
+        Err(e) => {
+            pool.close().await;
+            return Err(format!("Unable to list emails: {}", e));
+        }
+    };
+
+    let (mut replayed, mut skipped, mut failed) = (0, 0, 0);
+    for id in ids {
+        let email = match sql::get_email(&pool, &id).await {
+            Ok(Some(x)) => x,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("{}: {}", id, e);
+                failed += 1;
+                continue;
+            }
+        };
+
+        match ingest::replay(config, &pool, &email).await {
+            Ok(true) => replayed += 1,
+            Ok(false) => skipped += 1,
+            Err(e) => {
+                eprintln!("{}: {:?}", id, e);
+                failed += 1;
+            }
+        }
+    }
+    pool.close().await;
+
+    println!(
+        "Replayed {} emails, skipped {} without a raw message, {} failed",
+        replayed, skipped, failed
+    );
+    if failed > 0 {
+        return Err(format!("{} emails could not be replayed", failed));
+    }
+    Ok(())
+}
+
+/// Staged files still carry their `.tmp` suffix and are left out, so a backup taken while the
+/// server runs only holds complete files.
+async fn copy_dir(from: &Path, to: &Path) -> io::Result<u64> {
+    let mut copied = 0;
+    let mut pending = vec![(from.to_path_buf(), to.to_path_buf())];
+    while let Some((from, to)) = pending.pop() {
+        fs::create_dir_all(&to).await?;
+        let mut entries = fs::read_dir(&from).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let target = to.join(entry.file_name());
+            if entry.file_type().await?.is_dir() {
+                pending.push((entry.path(), target));
+            } else if !entry.file_name().to_string_lossy().ends_with(".tmp") {
+                fs::copy(entry.path(), target).await?;
+                copied += 1;
+            }
+        }
+    }
+
+    Ok(copied)
+}
+
+/// Writes `destination/epv.sqlite` with `VACUUM INTO`, which is consistent even while the server
+/// is writing, and copies `storage.file_root` to `destination/files` as stored (still encrypted
+/// when an encryption key is configured).
+pub async fn backup(config: &Config, destination: &Path) -> Result<(), String> {
+    fs::create_dir(destination)
+        .await
+        .map_err(|e| format!("Could not create {}: {}", destination.display(), e))?;
+
+    let pool = sql::connect(&config.storage)
+        .await
+        .map_err(|e| format!("Unable to connect to DB: {}", e))?;
+    let database = destination.join("epv.sqlite");
+    let result = sqlx::query("VACUUM INTO $1")
+        .bind(database.to_string_lossy().into_owned())
+        .execute(&pool)
+        .await;
+    pool.close().await;
+    result.map_err(|e| format!("Could not back up database: {}", e))?;
+
+    let copied = copy_dir(
+        Path::new(&config.storage.file_root),
+        &destination.join("files"),
+    )
+    .await
+    .map_err(|e| format!("Could not copy {}: {}", config.storage.file_root, e))?;
+
+    println!(
+        "Backed up database and {} files to {}",
+        copied,
+        destination.display()
+    );
+    Ok(())
+}
+
+pub async fn check_config(path: &Path) -> Result<(), String> {
+    let config = config::read_config(path).await?;
+
+    let pool = sql::connect(&config.storage)
+        .await
+        .map_err(|e| format!("storage.sqlite: unable to connect: {}", e))?;
+    let problems = startup::diagnose(&config, &pool).await;
+    pool.close().await;
+
+    if !problems.is_empty() {
+        return Err(format!(
+            "Invalid {}:\n  {}",
+            path.display(),
+            problems.join("\n  ")
+        ));
+    }
+
+    println!("{} OK", path.display());
+    Ok(())
+}
@@ -1,60 +1,1373 @@
+use crate::{
+    api::execute_script::Action, authentication::Network, imap, proxy::Proxy,
+    rocket_types::RATELIMIT_CLASSES, storage, util, ManagedConfig,
+};
+use regex::Regex;
+use reqwest::header::{HeaderName, HeaderValue};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
+use url::Url;
 
 use tokio::fs;
 
-#[derive(Deserialize, Clone, Debug)]
+/// Only `users`, `imap` and `storage` are required; every other section falls back to its
+/// `Default` implementation when omitted.
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
 pub struct Config {
     pub users: Users,
-    pub imap: Imap,
+    /// The mailboxes ingested from, all at once. With `ingest.protocol` `smtp` or `lmtp`, which
+    /// need no mailbox, only their `routing` and `postfix` apply, along with the first one's
+    /// `parallelism`.
+    #[serde(default)]
+    pub imap: ImapAccounts,
+    /// Checked in order before the `routing` of `imap` accounts and aliases. Defaults to no rules.
+    #[serde(default)]
+    pub routing_rules: Vec<RoutingRule>,
     pub storage: Storage,
+    /// Defaults to no macros.
+    #[serde(default)]
     pub macros: Vec<Macro>,
+    /// Defaults to no plugins.
+    #[serde(default)]
+    pub plugins: Vec<Plugin>,
+    /// Defaults to 5 requests per second per IP.
+    #[serde(default)]
     pub ratelimit: Ratelimit,
+    #[serde(default)]
+    pub maintenance: Maintenance,
+    #[serde(default)]
+    pub scripts: Scripts,
+    #[serde(default)]
+    pub http: Http,
+    #[serde(default)]
+    pub logging: Logging,
+    #[serde(default)]
+    pub url_cache: UrlCache,
+    #[serde(default)]
+    pub csv: Csv,
+    /// Defaults to storing email HTML as received.
+    #[serde(default)]
+    pub snapshot: Snapshot,
+    /// Defaults to wrapping the text of emails without HTML in a minimal HTML document.
+    #[serde(default)]
+    pub ingest: Ingest,
+    /// Defaults to not reporting errors anywhere but the log.
+    pub error_reporting: Option<ErrorReporting>,
+    /// Defaults to not alerting on ingestion lag.
+    pub lag_alert: Option<LagAlert>,
+    /// Defaults to storing attachments unscanned.
+    pub clamd: Option<Clamd>,
+    /// Defaults to listening on `127.0.0.1:2525` when `ingest.protocol` is `smtp` or `lmtp`.
+    #[serde(default)]
+    pub smtp: Smtp,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
 #[serde(untagged)]
 pub enum Users {
     Single(User),
     Many(Vec<User>),
 }
 
-#[derive(Deserialize, Clone, Debug)]
+impl Users {
+    pub fn as_slice(&self) -> &[User] {
+        match self {
+            Users::Single(user) => std::slice::from_ref(user),
+            Users::Many(users) => users.as_slice(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
 pub struct User {
     pub username: String,
     pub password: String,
+    /// Grants access to `/api/admin/*`.
+    #[serde(default)]
+    pub admin: bool,
+    /// Addresses routed to this user besides those the `routing` of an `imap` account gives
+    /// them, for example an old address being moved away from. `*` matches any run of
+    /// characters, as in `*@old.example.com`, and case is ignored.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+}
+impl User {
+    pub fn has_alias(&self, address: &str) -> bool {
+        self.aliases
+            .iter()
+            .any(|alias| util::wildcard_match(alias, address))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
+#[serde(untagged)]
+pub enum ImapAccounts {
+    Single(Imap),
+    Many(Vec<Imap>),
+}
+impl Default for ImapAccounts {
+    fn default() -> Self {
+        ImapAccounts::Single(Imap::default())
+    }
+}
+impl ImapAccounts {
+    pub fn as_slice(&self) -> &[Imap] {
+        match self {
+            ImapAccounts::Single(account) => std::slice::from_ref(account),
+            ImapAccounts::Many(accounts) => accounts,
+        }
+    }
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
 pub struct Imap {
+    /// Required unless `ingest.protocol` is `smtp` or `lmtp`, as are `username` and one of
+    /// `password` and `oauth2`.
+    #[serde(default)]
     pub server: String,
+    /// 993 suits the default `tls`; `starttls` and `plaintext` servers usually listen on 143.
+    #[serde(default = "default_imap_port")]
     pub port: u16,
+    #[serde(default)]
+    pub connection: ImapConnection,
+    /// PEM files of CA certificates trusted besides the system's, for a server with a
+    /// certificate from a private CA.
+    #[serde(default)]
+    pub ca_certificates: Vec<PathBuf>,
+    /// The SHA-256 fingerprint of the server's certificate, as 64 hex digits, optionally
+    /// colon-separated. Only that certificate is then trusted, whoever issued it, so this also
+    /// suits a self-signed one; it must be updated when the certificate is renewed.
+    #[serde(default)]
+    pub pinned_sha256: Option<String>,
+    /// `socks5://` or `http://` proxy URL, with `user:password@` if it needs them, to connect to
+    /// the server through. The proxy resolves the server's name; HTTP proxies are sent `CONNECT`
+    /// and any credentials in the clear.
+    #[serde(default)]
+    pub proxy: Option<String>,
+    #[serde(default)]
     pub username: String,
-    pub password: String,
+    /// Set either this or `oauth2`.
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Log in with XOAUTH2 rather than a password, as Gmail and Office 365 require.
+    #[serde(default)]
+    pub oauth2: Option<ImapOAuth2>,
+    /// Recipients are routed to users by every account's `routing`, whichever account the mail
+    /// arrives at.
+    #[serde(default)]
+    pub routing: ImapRouting,
+    /// What follows the username in recipient hosts with `host` routing, such as
+    /// `.epv.example.com`.
+    #[serde(default)]
     pub postfix: String,
+    /// Where new mail is picked up.
+    #[serde(default = "default_mailbox")]
+    pub mailbox: String,
+    /// Where handled mail is moved.
+    #[serde(default = "default_read_mailbox")]
+    pub read_mailbox: String,
+    /// How many fetched emails are stored at the same time.
+    #[serde(default = "default_imap_parallelism")]
+    pub parallelism: usize,
+    /// How many messages are fetched per command, which bounds how many are held in memory.
+    #[serde(default = "default_imap_batch_size")]
+    pub batch_size: usize,
+    #[serde(default)]
+    pub after_processing: AfterProcessing,
+    /// In bytes. Larger messages are skipped without being downloaded, and logged with their
+    /// sender and subject. Defaults to no limit.
+    #[serde(default)]
+    pub max_message_size: Option<usize>,
+    /// Where skipped oversized messages are moved, which must exist. Without it, or with POP3,
+    /// they stay where they are and are not fetched again.
+    #[serde(default)]
+    pub rejected_mailbox: Option<String>,
+}
+impl Default for Imap {
+    fn default() -> Self {
+        Imap {
+            server: String::new(),
+            port: default_imap_port(),
+            connection: ImapConnection::default(),
+            ca_certificates: vec![],
+            pinned_sha256: None,
+            proxy: None,
+            username: String::new(),
+            password: None,
+            oauth2: None,
+            routing: ImapRouting::default(),
+            postfix: String::new(),
+            mailbox: default_mailbox(),
+            read_mailbox: default_read_mailbox(),
+            parallelism: default_imap_parallelism(),
+            batch_size: default_imap_batch_size(),
+            after_processing: AfterProcessing::default(),
+            max_message_size: None,
+            rejected_mailbox: None,
+        }
+    }
+}
+impl Imap {
+    /// The username on the server, which tells accounts apart in the status and the state kept
+    /// between sessions, as the same username can be on several servers.
+    pub fn key(&self) -> String {
+        format!("{}@{}", self.username, self.server)
+    }
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ImapConnection {
+    /// TLS from the first byte.
+    #[default]
+    Tls,
+    /// Plaintext upgraded with `STARTTLS` before logging in.
+    Starttls,
+    /// Unencrypted throughout, credentials included; only for a server on the same host.
+    Plaintext,
+}
+
+/// How a recipient address names the user it is for.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ImapRouting {
+    /// `<anything>@<username><postfix>`, which needs a wildcard DNS record.
+    #[default]
+    Host,
+    /// `<anything>+<username>@<anything>`, for running on an ordinary mailbox whose provider
+    /// delivers subaddresses to it.
+    Subaddress,
+}
+
+/// What happens to messages in `mailbox` once stored, or found to be already stored.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AfterProcessing {
+    /// Into `read_mailbox`, which must exist.
+    #[default]
+    Move,
+    /// Flagged `\Deleted` and expunged, for providers without folders or that charge for
+    /// storage. Messages already flagged `\Deleted` in `mailbox` are expunged along with them.
+    /// With POP3, deleted with `DELE`.
+    Delete,
+    /// Flagged `\Seen` and left in `mailbox`; only unseen messages are fetched. With POP3, which
+    /// has no flags, left on the server and remembered by their UIDL instead.
+    Flag,
+}
+
+/// A refresh token, exchanged for an access token at `token_url` on every connect.
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
+pub struct ImapOAuth2 {
+    /// For example `https://oauth2.googleapis.com/token` or
+    /// `https://login.microsoftonline.com/<tenant>/oauth2/v2.0/token`.
+    pub token_url: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    pub refresh_token: String,
+    /// Sent with each refresh, as Office 365 requires, for example
+    /// `https://outlook.office.com/IMAP.AccessAsUser.All offline_access`.
+    #[serde(default)]
+    pub scope: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
 pub struct Storage {
     pub file_root: String,
     pub sqlite: String,
+    #[serde(default = "default_frontend")]
     pub frontend: String,
+    pub encryption_key: Option<String>,
+    #[serde(default = "default_journal_mode")]
+    pub journal_mode: JournalMode,
+    #[serde(default = "default_busy_timeout_ms")]
+    pub busy_timeout_ms: u64,
+    #[serde(default = "default_synchronous")]
+    pub synchronous: Synchronous,
+    pub cache_size: Option<i64>,
+    #[serde(default = "default_max_connections")]
+    pub max_connections: u32,
+    #[serde(default = "default_min_connections")]
+    pub min_connections: u32,
+    #[serde(default = "default_acquire_timeout_ms")]
+    pub acquire_timeout_ms: u64,
+    pub idle_timeout_ms: Option<u64>,
+}
+
+fn default_imap_port() -> u16 {
+    993
+}
+
+fn default_mailbox() -> String {
+    "EPV".to_owned()
+}
+
+fn default_read_mailbox() -> String {
+    "EPV-READ".to_owned()
+}
+
+fn default_imap_parallelism() -> usize {
+    4
+}
+
+fn default_imap_batch_size() -> usize {
+    50
+}
+
+fn default_frontend() -> String {
+    "frontend".to_owned()
+}
+
+fn default_journal_mode() -> JournalMode {
+    JournalMode::Wal
+}
+
+fn default_busy_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_synchronous() -> Synchronous {
+    Synchronous::Normal
+}
+
+fn default_max_connections() -> u32 {
+    32
+}
+
+fn default_min_connections() -> u32 {
+    1
+}
+
+fn default_acquire_timeout_ms() -> u64 {
+    30000
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Copy, Debug, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, JsonSchema)]
+pub struct RatelimitRule {
+    pub num: usize,
+    pub in_ms: u128,
+}
+
+/// `num`/`in_ms` apply to every route class without an entry in `classes`.
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
 pub struct Ratelimit {
     pub num: usize,
     pub in_ms: u128,
+    #[serde(default)]
+    pub classes: HashMap<String, RatelimitRule>,
+}
+impl Ratelimit {
+    pub fn rule(&self, class: &str) -> RatelimitRule {
+        self.classes.get(class).copied().unwrap_or(RatelimitRule {
+            num: self.num,
+            in_ms: self.in_ms,
+        })
+    }
+}
+impl Default for Ratelimit {
+    fn default() -> Self {
+        Ratelimit {
+            num: 5,
+            in_ms: 1000,
+            classes: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
+#[serde(default)]
+pub struct Maintenance {
+    pub interval_secs: u64,
+    pub analyze: bool,
+    pub vacuum: bool,
+    pub reconcile: bool,
+    pub reconcile_dry_run: bool,
+}
+impl Default for Maintenance {
+    fn default() -> Self {
+        Maintenance {
+            interval_secs: 6 * 60 * 60,
+            analyze: true,
+            vacuum: true,
+            reconcile: true,
+            reconcile_dry_run: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
+#[serde(default)]
+pub struct Scripts {
+    pub runs_keep: i64,
+    pub runs_max_age_days: i64,
+    pub store_output: bool,
+    /// Runs taking at least this long are logged with their per-stage timings; `None` disables
+    /// the warning.
+    pub slow_run_ms: Option<i64>,
+    /// How many elements each stage of a script processes at the same time.
+    pub parallelism: usize,
+    /// Limits for `Eval` actions; `None`, the default, rejects scripts that use them.
+    pub eval: Option<Eval>,
+    /// Outbound requests, such as those of `UrlFollowRedirect`, one user's scripts may make per
+    /// hour before their runs fail; `None`, the default, allows any number.
+    pub http_fetches_per_hour: Option<u64>,
+}
+impl Default for Scripts {
+    fn default() -> Self {
+        Scripts {
+            runs_keep: 100,
+            runs_max_age_days: 30,
+            store_output: false,
+            slow_run_ms: Some(10_000),
+            parallelism: 32,
+            eval: None,
+            http_fetches_per_hour: None,
+        }
+    }
+}
+
+/// Per run of an `Eval` snippet, i.e. per element.
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
+#[serde(default)]
+pub struct Eval {
+    pub max_operations: u64,
+    pub max_string_bytes: usize,
+    /// Also applies to object maps.
+    pub max_array_len: usize,
+}
+impl Default for Eval {
+    fn default() -> Self {
+        Eval {
+            max_operations: 1_000_000,
+            max_string_bytes: 1024 * 1024,
+            max_array_len: 10_000,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
+#[serde(default)]
+pub struct Logging {
+    pub level: String,
+    pub format: LogFormat,
+    /// Per-target overrides, e.g. `{"email_ponzi_ventures::imap": "debug"}`.
+    pub targets: HashMap<String, String>,
+}
+impl Logging {
+    pub fn directives(&self) -> String {
+        let mut directives = self.level.clone();
+        for (target, level) in &self.targets {
+            directives.push_str(&format!(",{}={}", target, level));
+        }
+        directives
+    }
+}
+impl Default for Logging {
+    fn default() -> Self {
+        Logging {
+            level: "info".to_owned(),
+            format: LogFormat::Pretty,
+            targets: HashMap::new(),
+        }
+    }
+}
+
+/// Sends panics and every `error`-level log event, with its request id, route and user, to a
+/// Sentry-compatible endpoint. Read once at startup; changes need a restart.
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
+pub struct ErrorReporting {
+    pub dsn: String,
+    pub environment: Option<String>,
+    /// Fraction of errors to send, between 0 and 1.
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f32,
+}
+
+fn default_sample_rate() -> f32 {
+    1.0
+}
+
+/// Fires when IMAP stores an email more than `threshold_secs` after its `Date` header, usually
+/// because the ingestion loop has stalled.
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
+pub struct LagAlert {
+    pub threshold_secs: u64,
+    /// Receives a JSON POST per alert; without it alerts are only logged.
+    pub webhook: Option<String>,
+    /// Minimum time between two alerts, so draining a backlog does not send one per email.
+    #[serde(default = "default_lag_alert_cooldown_secs")]
+    pub cooldown_secs: u64,
+}
+
+fn default_lag_alert_cooldown_secs() -> u64 {
+    60 * 60
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum InfectedAttachments {
+    /// Refuse to serve them.
+    #[default]
+    Block,
+    /// Serve them with an `X-Scan-Result: infected` header.
+    Flag,
 }
 
-#[derive(Deserialize, Clone, Debug, Serialize)]
+/// Where emails come from. Changing it takes a restart.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum IngestProtocol {
+    #[default]
+    Imap,
+    /// The whole mailbox of the POP3 server that each `imap` account then describes; its `port`
+    /// is usually 995 with `tls` and 110 otherwise. Only password logins are supported, and
+    /// `after_processing` must be `delete` or `flag`. New mail is polled for every minute.
+    Pop3,
+    /// Delivered directly over SMTP to the listener `smtp` configures, by an MTA relaying to it.
+    Smtp,
+    /// Delivered directly over LMTP (RFC 2033), as by Postfix's `lmtp` transport, to the
+    /// listener `smtp` configures.
+    Lmtp,
+}
+impl IngestProtocol {
+    /// Whether emails are fetched from a mailbox described by the `imap` section, rather than
+    /// delivered.
+    pub fn polls(self) -> bool {
+        matches!(self, IngestProtocol::Imap | IngestProtocol::Pop3)
+    }
+}
+
+/// The SMTP or LMTP listener. It has neither TLS nor authentication, and accepts mail only for
+/// recipients that route to a user, so it belongs on loopback or a private network behind the
+/// MTA that faces the internet.
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
+#[serde(default)]
+pub struct Smtp {
+    pub listen: SocketAddr,
+    /// The name the listener greets with and records in the `Received` header it adds.
+    pub hostname: String,
+    /// In bytes; larger messages are refused.
+    pub max_size: usize,
+}
+impl Default for Smtp {
+    fn default() -> Self {
+        Smtp {
+            listen: SocketAddr::from(([127, 0, 0, 1], 2525)),
+            hostname: "epv".to_owned(),
+            max_size: 25 * 1024 * 1024,
+        }
+    }
+}
+
+/// Every email's HTML is stored twice: as received, after any snapshot, and sanitized.
+#[derive(
+    Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema, rocket::FromFormField,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum HtmlVersion {
+    /// Without scripts, event handlers or remote resources, so viewing an email cannot run
+    /// anything or tell the sender it was opened. Emails stored before sanitized copies were kept
+    /// are sanitized as they are served, until replayed.
+    #[default]
+    Sanitized,
+    Original,
+}
+
+/// What is stored as the HTML of an email that has no `text/html` part but a `text/plain` one.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PlainTextFallback {
+    /// The text, escaped, in a `<pre>` of a minimal HTML document so it displays as sent.
+    #[default]
+    Wrap,
+    /// The text as is.
+    Raw,
+    /// Nothing: the email is rejected, as ones with neither part are.
+    Off,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, JsonSchema)]
+#[serde(default)]
+pub struct Ingest {
+    pub protocol: IngestProtocol,
+    pub plain_text: PlainTextFallback,
+    /// Check DKIM and SPF with DNS lookups as emails are stored, recording `pass`, `fail` or
+    /// `none` for each. Either only passes when aligned with the `From` domain. Replaying keeps
+    /// the results from ingestion.
+    pub authentication: bool,
+    /// Which HTML `/emails/<id>/html` serves when not asked for one with `?version=`.
+    pub view_html: HtmlVersion,
+    /// Headers, such as `List-Unsubscribe` or `X-Mailer`, kept with every occurrence for
+    /// `EmailGetHeader` and `/emails/<id>`. Case is ignored. Replaying captures them for emails
+    /// stored before they were listed.
+    pub capture_headers: Vec<String>,
+    /// Address blocks, such as `203.0.113.0/24`, of the receiving side's own hosts with public
+    /// addresses, which SPF looks past for the client that handed the email over. Loopback,
+    /// private and link-local addresses are always looked past. Defaults to none.
+    pub trusted_networks: Vec<Network>,
+}
+
+/// Scans attachments with clamd at ingestion and records its verdict. Attachments that could
+/// not be scanned are stored anyway, with the verdict `error`.
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
+pub struct Clamd {
+    /// `host:port`, or the path of clamd's Unix socket.
+    pub address: String,
+    /// Per attachment.
+    #[serde(default = "default_clamd_timeout_secs")]
+    pub timeout_secs: u64,
+    /// What the attachments API does with infected attachments.
+    #[serde(default)]
+    pub infected: InfectedAttachments,
+}
+
+fn default_clamd_timeout_secs() -> u64 {
+    30
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotMode {
+    /// Store the HTML as received.
+    #[default]
+    Off,
+    /// Fetch remote images and stylesheets and embed them, stripping any that cannot be fetched.
+    /// Fetching images tells senders using tracking pixels that the email was received.
+    Inline,
+    /// Remove remote images and stylesheets without fetching anything.
+    Strip,
+}
+
+/// Rewrites email HTML at ingestion so that it renders without the remote servers it refers to,
+/// which eventually disappear.
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
+#[serde(default)]
+pub struct Snapshot {
+    pub mode: SnapshotMode,
+    /// Larger resources are stripped instead of inlined.
+    pub max_resource_bytes: usize,
+    /// Resources beyond this many per email are stripped instead of inlined.
+    pub max_resources: usize,
+    /// Per resource.
+    pub timeout_secs: u64,
+}
+impl Default for Snapshot {
+    fn default() -> Self {
+        Snapshot {
+            mode: SnapshotMode::Off,
+            max_resource_bytes: 2 * 1024 * 1024,
+            max_resources: 50,
+            timeout_secs: 10,
+        }
+    }
+}
+
+/// Outbound requests made by script actions such as `UrlFollowRedirect`, and by HTML snapshots.
+#[derive(Deserialize, Clone, Debug, Default, JsonSchema)]
+#[serde(default)]
+pub struct Http {
+    /// `http://`, `https://` or `socks5://` proxy URL applied to every outbound request.
+    pub proxy: Option<String>,
+    pub user_agent: Option<String>,
+    /// Added to, or replacing, the browser-like default headers.
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CsvQuoteStyle {
+    #[default]
+    Always,
+    Necessary,
+    NonNumeric,
+    Never,
+}
+
+/// Defaults for CSV and TSV output; the `bom`, `line_ending` and `quote` query parameters
+/// override them per request.
+#[derive(Deserialize, Clone, Copy, Debug, Default, JsonSchema)]
+#[serde(default)]
+pub struct Csv {
+    /// Prefix output with a UTF-8 byte order mark, which Excel needs to detect the encoding.
+    pub bom: bool,
+    pub crlf: bool,
+    pub quote_style: CsvQuoteStyle,
+}
+
+/// Resolved `UrlFollowRedirect` targets. Read once at startup; changes need a restart.
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
+#[serde(default)]
+pub struct UrlCache {
+    pub capacity: usize,
+    /// Entries older than this are fetched again; `None` keeps them until evicted.
+    pub ttl_secs: Option<u64>,
+    /// How long a URL whose fetch failed is skipped without retrying; `None` retries every time.
+    /// Failures are only remembered in memory.
+    pub failure_ttl_secs: Option<u64>,
+    /// Also store resolved redirects in the database, where they outlive restarts and the
+    /// in-memory capacity until `ttl_secs` passes.
+    pub persist: bool,
+}
+impl Default for UrlCache {
+    fn default() -> Self {
+        UrlCache {
+            capacity: 1000,
+            ttl_secs: None,
+            failure_ttl_secs: Some(300),
+            persist: true,
+        }
+    }
+}
+impl UrlCache {
+    /// Unix ms before which a redirect resolved has expired, as of `now`.
+    pub fn expired_before(&self, now: i64) -> i64 {
+        match self.ttl_secs {
+            Some(ttl_secs) => now.saturating_sub((ttl_secs as i64).saturating_mul(1000)),
+            None => i64::MIN,
+        }
+    }
+}
+
+/// Sends the emails it matches to `user`. Each regex that is set must match: `from_regex` the
+/// sender's address, `to_regex` one of the recipients', which becomes the email's `to_addr`, and
+/// `subject_regex` the decoded subject. Prefix a regex with `(?i)` to ignore case.
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
+pub struct RoutingRule {
+    pub from_regex: Option<RuleRegex>,
+    pub to_regex: Option<RuleRegex>,
+    pub subject_regex: Option<RuleRegex>,
+    pub user: String,
+}
+
+/// A regex of a [`RoutingRule`], compiled once as the config is read rather than for every
+/// message, so an invalid one fails the read.
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
+#[serde(try_from = "String")]
+pub struct RuleRegex(#[schemars(with = "String")] Regex);
+impl TryFrom<String> for RuleRegex {
+    type Error = regex::Error;
+
+    fn try_from(regex: String) -> Result<Self, Self::Error> {
+        Regex::new(&regex).map(RuleRegex)
+    }
+}
+impl RuleRegex {
+    pub fn is_match(&self, text: &str) -> bool {
+        self.0.is_match(text)
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize, JsonSchema)]
 pub struct Macro {
     pub name: String,
-    pub actions: Vec<crate::api::execute_script::Action>,
+    pub actions: Vec<Action>,
+}
+
+/// A WebAssembly module scripts can call with the `Plugin` action. See `plugins::Plugins` for
+/// what it has to export.
+#[derive(Deserialize, Clone, Debug, JsonSchema)]
+pub struct Plugin {
+    pub name: String,
+    /// Path to the `.wasm` module, which is recompiled when it changes.
+    pub path: String,
+    /// Roughly the number of instructions one call may execute.
+    #[serde(default = "default_plugin_fuel")]
+    pub fuel: u64,
+    /// Linear memory one call may grow to.
+    #[serde(default = "default_plugin_max_memory_bytes")]
+    pub max_memory_bytes: usize,
+}
+
+/// The unit WebAssembly memory grows by, and so the smallest memory a module can have.
+const WASM_PAGE_BYTES: usize = 64 * 1024;
+
+fn default_plugin_fuel() -> u64 {
+    100_000_000
+}
+
+fn default_plugin_max_memory_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+/// Names referenced from action lists that must be defined elsewhere in the config.
+struct KnownNames<'a> {
+    macros: HashSet<&'a str>,
+    plugins: HashSet<&'a str>,
+}
+
+fn validate_actions(
+    actions: &[Action],
+    path: &str,
+    known: &KnownNames,
+    problems: &mut Vec<String>,
+) {
+    for (index, action) in actions.iter().enumerate() {
+        let path = format!("{}[{}]", path, index);
+        match action {
+            Action::Macro(name) if !known.macros.contains(name.as_str()) => {
+                problems.push(format!("{}: unknown macro {:?}", path, name));
+            }
+            Action::Plugin(name, _) if !known.plugins.contains(name.as_str()) => {
+                problems.push(format!("{}: unknown plugin {:?}", path, name));
+            }
+            Action::Or(left, right) | Action::Pair(left, right) => {
+                validate_actions(left, &format!("{}.arguments[0]", path), known, problems);
+                validate_actions(right, &format!("{}.arguments[1]", path), known, problems);
+            }
+            Action::Filter(inner) => {
+                validate_actions(inner, &format!("{}.arguments", path), known, problems);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Config {
+    /// Checks everything serde cannot, returning one message per problem prefixed with its field path.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        let mut usernames = HashSet::new();
+        let mut aliases = HashSet::new();
+        for (index, user) in self.users.as_slice().iter().enumerate() {
+            if user.username.is_empty() {
+                problems.push(format!("users[{}].username: must not be empty", index));
+            } else if !storage::is_path_component(&user.username) {
+                problems.push(format!(
+                    "users[{}].username: must not be \".\", \"..\" or contain path separators",
+                    index
+                ));
+            } else if !usernames.insert(user.username.as_str()) {
+                problems.push(format!(
+                    "users[{}].username: duplicate username {:?}",
+                    index, user.username
+                ));
+            }
+
+            for (alias_index, alias) in user.aliases.iter().enumerate() {
+                if !alias.contains('@') {
+                    problems.push(format!(
+                        "users[{}].aliases[{}]: must contain \"@\"",
+                        index, alias_index
+                    ));
+                } else if !aliases.insert(alias.to_ascii_lowercase()) {
+                    problems.push(format!(
+                        "users[{}].aliases[{}]: duplicate alias {:?}",
+                        index, alias_index, alias
+                    ));
+                }
+            }
+        }
+
+        for (index, rule) in self.routing_rules.iter().enumerate() {
+            if !usernames.contains(rule.user.as_str()) {
+                problems.push(format!(
+                    "routing_rules[{}].user: unknown user {:?}",
+                    index, rule.user
+                ));
+            }
+        }
+
+        let accounts = self.imap.as_slice();
+        if accounts.is_empty() {
+            problems.push("imap: must list at least one account".to_owned());
+        }
+        let mut account_names = HashSet::new();
+        for (index, account) in accounts.iter().enumerate() {
+            if account.routing == ImapRouting::Host && account.postfix.is_empty() {
+                problems.push(format!("imap[{}].postfix: must not be empty", index));
+            }
+            if !self.ingest.protocol.polls() {
+                continue;
+            }
+            if account.server.is_empty() {
+                problems.push(format!("imap[{}].server: must not be empty", index));
+            }
+            if account.username.is_empty() {
+                problems.push(format!("imap[{}].username: must not be empty", index));
+            }
+            if !account_names.insert((account.server.as_str(), account.username.as_str())) {
+                problems.push(format!(
+                    "imap[{}]: duplicate account {:?} on {:?}",
+                    index, account.username, account.server
+                ));
+            }
+            if account.mailbox.is_empty() {
+                problems.push(format!("imap[{}].mailbox: must not be empty", index));
+            }
+            if account.read_mailbox.is_empty() || account.read_mailbox == account.mailbox {
+                problems.push(format!(
+                    "imap[{}].read_mailbox: must be set and differ from mailbox",
+                    index
+                ));
+            }
+            for (cert_index, path) in account.ca_certificates.iter().enumerate() {
+                if let Err(e) = imap::load_certificates(path) {
+                    problems.push(format!(
+                        "imap[{}].ca_certificates[{}]: {}: {}",
+                        index,
+                        cert_index,
+                        path.display(),
+                        e
+                    ));
+                }
+            }
+            if let Some(pinned) = &account.pinned_sha256 {
+                if imap::parse_fingerprint(pinned).is_none() {
+                    problems.push(format!(
+                        "imap[{}].pinned_sha256: must be 64 hex digits",
+                        index
+                    ));
+                }
+            }
+            if let Some(proxy) = &account.proxy {
+                if let Err(e) = Proxy::parse(proxy) {
+                    problems.push(format!("imap[{}].proxy: {}", index, e));
+                }
+            }
+            match (&account.password, &account.oauth2) {
+                (Some(_), Some(_)) | (None, None) => problems.push(format!(
+                    "imap[{}]: must set exactly one of password and oauth2",
+                    index
+                )),
+                (None, Some(oauth2)) => match Url::parse(&oauth2.token_url) {
+                    Ok(url) if url.scheme() == "https" => {}
+                    Ok(_) => problems.push(format!(
+                        "imap[{}].oauth2.token_url: must be an https URL",
+                        index
+                    )),
+                    Err(e) => problems.push(format!("imap[{}].oauth2.token_url: {}", index, e)),
+                },
+                (Some(_), None) => {}
+            }
+            if self.ingest.protocol == IngestProtocol::Pop3 {
+                if account.oauth2.is_some() {
+                    problems.push(format!(
+                        "imap[{}].oauth2: must not be set with ingest.protocol pop3",
+                        index
+                    ));
+                }
+                if account.after_processing == AfterProcessing::Move {
+                    problems.push(format!(
+                        "imap[{}].after_processing: must be delete or flag with ingest.protocol pop3",
+                        index
+                    ));
+                }
+            }
+        }
+        if !self.ingest.protocol.polls() {
+            if self.smtp.hostname.is_empty() || self.smtp.hostname.contains(char::is_whitespace) {
+                problems.push("smtp.hostname: must be a non-empty name without spaces".to_owned());
+            }
+            if self.smtp.max_size == 0 {
+                problems.push("smtp.max_size: must be at least 1".to_owned());
+            }
+        }
+
+        let file_root = Path::new(&self.storage.file_root);
+        if self.storage.file_root.is_empty() {
+            problems.push("storage.file_root: must not be empty".to_owned());
+        } else if !file_root.is_dir()
+            && !file_root
+                .parent()
+                .is_some_and(|parent| parent.as_os_str().is_empty() || parent.is_dir())
+        {
+            problems.push(format!(
+                "storage.file_root: neither {} nor its parent directory exists",
+                self.storage.file_root
+            ));
+        }
+
+        if self.ratelimit.num == 0 {
+            problems.push("ratelimit.num: must be at least 1".to_owned());
+        }
+        if self.ratelimit.in_ms == 0 {
+            problems.push("ratelimit.in_ms: must be at least 1".to_owned());
+        }
+        for (class, rule) in &self.ratelimit.classes {
+            if !RATELIMIT_CLASSES.contains(&class.as_str()) {
+                problems.push(format!(
+                    "ratelimit.classes.{}: unknown class, expected one of {:?}",
+                    class, RATELIMIT_CLASSES
+                ));
+            }
+            if rule.num == 0 {
+                problems.push(format!(
+                    "ratelimit.classes.{}.num: must be at least 1",
+                    class
+                ));
+            }
+            if rule.in_ms == 0 {
+                problems.push(format!(
+                    "ratelimit.classes.{}.in_ms: must be at least 1",
+                    class
+                ));
+            }
+        }
+
+        if let Some(proxy) = &self.http.proxy {
+            if let Err(e) = reqwest::Proxy::all(proxy) {
+                problems.push(format!("http.proxy: {}", e));
+            }
+        }
+        if let Some(user_agent) = &self.http.user_agent {
+            if HeaderValue::from_str(user_agent).is_err() {
+                problems.push("http.user_agent: not a valid header value".to_owned());
+            }
+        }
+        for (name, value) in &self.http.headers {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                problems.push(format!("http.headers.{}: not a valid header name", name));
+            }
+            if HeaderValue::from_str(value).is_err() {
+                problems.push(format!("http.headers.{}: not a valid header value", name));
+            }
+        }
+
+        if self.scripts.slow_run_ms.is_some_and(|ms| ms <= 0) {
+            problems.push("scripts.slow_run_ms: must be at least 1".to_owned());
+        }
+        if self.scripts.parallelism == 0 {
+            problems.push("scripts.parallelism: must be at least 1".to_owned());
+        }
+        if let Some(eval) = &self.scripts.eval {
+            if eval.max_operations == 0 {
+                problems.push("scripts.eval.max_operations: must be at least 1".to_owned());
+            }
+            if eval.max_string_bytes == 0 {
+                problems.push("scripts.eval.max_string_bytes: must be at least 1".to_owned());
+            }
+            if eval.max_array_len == 0 {
+                problems.push("scripts.eval.max_array_len: must be at least 1".to_owned());
+            }
+        }
+        for (index, account) in self.imap.as_slice().iter().enumerate() {
+            if account.parallelism == 0 {
+                problems.push(format!("imap[{}].parallelism: must be at least 1", index));
+            }
+            if account.batch_size == 0 {
+                problems.push(format!("imap[{}].batch_size: must be at least 1", index));
+            }
+            if account.max_message_size == Some(0) {
+                problems.push(format!(
+                    "imap[{}].max_message_size: must be at least 1",
+                    index
+                ));
+            }
+            if account
+                .rejected_mailbox
+                .as_ref()
+                .is_some_and(|mailbox| mailbox.is_empty())
+            {
+                problems.push(format!(
+                    "imap[{}].rejected_mailbox: must not be empty",
+                    index
+                ));
+            }
+        }
+
+        for (index, name) in self.ingest.capture_headers.iter().enumerate() {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                problems.push(format!(
+                    "ingest.capture_headers[{}]: not a valid header name",
+                    index
+                ));
+            }
+        }
+
+        if self.snapshot.timeout_secs == 0 {
+            problems.push("snapshot.timeout_secs: must be at least 1".to_owned());
+        }
+
+        if self.url_cache.capacity == 0 {
+            problems.push("url_cache.capacity: must be at least 1".to_owned());
+        }
+        if self.url_cache.ttl_secs == Some(0) {
+            problems.push("url_cache.ttl_secs: must be at least 1".to_owned());
+        }
+        if self.url_cache.failure_ttl_secs == Some(0) {
+            problems.push("url_cache.failure_ttl_secs: must be at least 1".to_owned());
+        }
+
+        if let Err(e) = EnvFilter::try_new(self.logging.directives()) {
+            problems.push(format!("logging: invalid level or target filter: {}", e));
+        }
+
+        if let Some(lag_alert) = &self.lag_alert {
+            if lag_alert.threshold_secs == 0 {
+                problems.push("lag_alert.threshold_secs: must be at least 1".to_owned());
+            }
+            if let Some(webhook) = &lag_alert.webhook {
+                if let Err(e) = Url::parse(webhook) {
+                    problems.push(format!("lag_alert.webhook: {}", e));
+                }
+            }
+        }
+
+        if let Some(clamd) = &self.clamd {
+            if clamd.address.is_empty() {
+                problems.push("clamd.address: must not be empty".to_owned());
+            }
+            if clamd.timeout_secs == 0 {
+                problems.push("clamd.timeout_secs: must be at least 1".to_owned());
+            }
+        }
+
+        if let Some(error_reporting) = &self.error_reporting {
+            if let Err(e) = error_reporting.dsn.parse::<sentry::types::Dsn>() {
+                problems.push(format!("error_reporting.dsn: {}", e));
+            }
+            if !(0.0..=1.0).contains(&error_reporting.sample_rate) {
+                problems.push("error_reporting.sample_rate: must be between 0 and 1".to_owned());
+            }
+        }
+
+        let mut known = KnownNames {
+            macros: HashSet::new(),
+            plugins: HashSet::new(),
+        };
+        for (index, mac) in self.macros.iter().enumerate() {
+            if !known.macros.insert(mac.name.as_str()) {
+                problems.push(format!(
+                    "macros[{}].name: duplicate macro {:?}",
+                    index, mac.name
+                ));
+            }
+        }
+        for (index, plugin) in self.plugins.iter().enumerate() {
+            if plugin.name.is_empty() {
+                problems.push(format!("plugins[{}].name: must not be empty", index));
+            } else if !known.plugins.insert(plugin.name.as_str()) {
+                problems.push(format!(
+                    "plugins[{}].name: duplicate plugin {:?}",
+                    index, plugin.name
+                ));
+            }
+            if !Path::new(&plugin.path).is_file() {
+                problems.push(format!(
+                    "plugins[{}].path: {} is not a file",
+                    index, plugin.path
+                ));
+            }
+            if plugin.fuel == 0 {
+                problems.push(format!("plugins[{}].fuel: must be at least 1", index));
+            }
+            if plugin.max_memory_bytes < WASM_PAGE_BYTES {
+                problems.push(format!(
+                    "plugins[{}].max_memory_bytes: must be at least {}",
+                    index, WASM_PAGE_BYTES
+                ));
+            }
+        }
+        for (index, mac) in self.macros.iter().enumerate() {
+            validate_actions(
+                &mac.actions,
+                &format!("macros[{}].actions", index),
+                &known,
+                &mut problems,
+            );
+        }
+
+        problems
+    }
+}
+
+fn config_search_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from("config.json")];
+
+    if let Some(config_home) = env::var_os("XDG_CONFIG_HOME") {
+        paths.push(PathBuf::from(config_home).join("epv/config.json"));
+    } else if let Some(home) = env::var_os("HOME") {
+        paths.push(PathBuf::from(home).join(".config/epv/config.json"));
+    }
+
+    paths.push(PathBuf::from("/etc/epv/config.json"));
+    paths
 }
 
-pub async fn load_config() -> Config {
-    let bytes = fs::read("config.json")
+/// An explicit path always wins; otherwise the first existing search path is used.
+pub fn resolve_config_path(explicit: Option<PathBuf>) -> PathBuf {
+    if let Some(path) = explicit {
+        return path;
+    }
+
+    config_search_paths()
+        .into_iter()
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| PathBuf::from("config.json"))
+}
+
+const MAX_INCLUDED_FILES: usize = 256;
+
+pub async fn read_json(path: &Path) -> Result<Value, String> {
+    let bytes = fs::read(path)
         .await
-        .expect("Could not read config.json");
-    serde_json::from_slice(&bytes).expect("Could not parse config.json")
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    serde_json::from_slice(&bytes).map_err(|e| format!("Could not parse {}: {}", path.display(), e))
+}
+
+/// Objects merge key by key, arrays concatenate, and anything else is replaced by `overlay`.
+fn merge_json(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_json(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(base), Value::Array(overlay)) => base.extend(overlay),
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Removes `include` from `value` and expands its glob patterns relative to `path`'s directory.
+fn take_includes(value: &mut Value, path: &Path) -> Result<Vec<PathBuf>, String> {
+    let Some(include) = value
+        .as_object_mut()
+        .and_then(|object| object.remove("include"))
+    else {
+        return Ok(vec![]);
+    };
+
+    let patterns: Vec<String> = serde_json::from_value(include)
+        .map_err(|e| format!("{}: include must be a list of paths: {}", path.display(), e))?;
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut included = vec![];
+    for pattern in patterns {
+        let full_pattern = dir.join(&pattern);
+        let matches = glob::glob(&full_pattern.to_string_lossy())
+            .map_err(|e| format!("{}: include {:?}: {}", path.display(), pattern, e))?;
+        for entry in matches {
+            included.push(
+                entry.map_err(|e| format!("{}: include {:?}: {}", path.display(), pattern, e))?,
+            );
+        }
+    }
+
+    Ok(included)
+}
+
+/// Reads `path` and every file it (transitively) includes, merged in the order they are listed.
+async fn read_config_json(path: &Path) -> Result<Value, String> {
+    let mut merged = read_json(path).await?;
+    let mut pending = take_includes(&mut merged, path)?;
+    pending.reverse();
+
+    let mut seen = HashSet::new();
+    while let Some(included_path) = pending.pop() {
+        if !seen.insert(included_path.clone()) {
+            continue;
+        }
+        if seen.len() > MAX_INCLUDED_FILES {
+            return Err(format!(
+                "{}: more than {} included files",
+                path.display(),
+                MAX_INCLUDED_FILES
+            ));
+        }
+
+        let mut included = read_json(&included_path).await?;
+        let mut nested = take_includes(&mut included, &included_path)?;
+        nested.reverse();
+        pending.extend(nested);
+
+        merge_json(&mut merged, included);
+    }
+
+    Ok(merged)
+}
+
+pub async fn read_config(path: &Path) -> Result<Config, String> {
+    let mut config: Config = serde_json::from_value(read_config_json(path).await?)
+        .map_err(|e| format!("Could not parse {}: {}", path.display(), e))?;
+
+    if let Ok(key) = env::var("EPV_ENCRYPTION_KEY") {
+        config.storage.encryption_key = Some(key);
+    }
+
+    let problems = config.validate();
+    if !problems.is_empty() {
+        return Err(format!(
+            "Invalid {}:\n  {}",
+            path.display(),
+            problems.join("\n  ")
+        ));
+    }
+
+    Ok(config)
+}
+
+pub async fn load_config(path: &Path) -> Config {
+    match read_config(path).await {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Swaps in a freshly read config on every SIGHUP. Storage and `imap` account settings are only
+/// read at startup, so changing them still requires a restart.
+pub async fn reload_on_sighup(config: ManagedConfig, path: PathBuf) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(x) => x,
+        Err(e) => {
+            error!(error = ?e, "Config reload SIGHUP handler error");
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        match read_config(&path).await {
+            Ok(new_config) => {
+                config.store(Arc::new(new_config));
+                info!("Config reloaded");
+            }
+            Err(e) => error!(error = %e, "Config reload error, keeping previous config"),
+        }
+    }
 }
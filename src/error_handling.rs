@@ -1,4 +1,4 @@
-use crate::rocket_types::Error;
+use crate::rocket_types::{Error, ErrorCode};
 use rocket::Request;
 
 #[rocket::catch(401)]
@@ -6,6 +6,11 @@ pub async fn unauthorized(_req: &Request<'_>) -> Error {
     Error::Unauthorized
 }
 
+#[rocket::catch(403)]
+pub async fn forbidden(_req: &Request<'_>) -> Error {
+    Error::Forbidden(ErrorCode::Forbidden)
+}
+
 #[rocket::catch(500)]
 pub async fn internal_server_error(_req: &Request<'_>) -> Error {
     Error::InternalError
@@ -13,10 +18,10 @@ pub async fn internal_server_error(_req: &Request<'_>) -> Error {
 
 #[rocket::catch(404)]
 pub async fn not_found(_req: &Request<'_>) -> Error {
-    Error::NotFound
+    Error::NotFound(ErrorCode::NotFound)
 }
 
 #[rocket::catch(429)]
 pub async fn too_many_requests(_req: &Request<'_>) -> Error {
-    Error::Ratelimited
+    Error::Ratelimited(ErrorCode::Ratelimited)
 }
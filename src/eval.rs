@@ -0,0 +1,56 @@
+use crate::config::Eval as EvalLimits;
+use rhai::{Dynamic, Engine, EvalAltResult, ParseError, Scope, AST};
+
+/// Nested function calls one snippet may make.
+const MAX_CALL_LEVELS: usize = 32;
+
+/// A compiled `Eval` snippet together with the engine that runs it. Rhai has no access to files,
+/// processes or the network, `print` and `debug` are discarded, and `scripts.eval` bounds the
+/// work and memory each run may use.
+#[derive(Debug)]
+pub struct EvalScript {
+    code: String,
+    engine: Engine,
+    ast: AST,
+}
+impl EvalScript {
+    pub fn compile(code: &str, limits: &EvalLimits) -> Result<Self, ParseError> {
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(limits.max_operations)
+            .set_max_string_size(limits.max_string_bytes)
+            .set_max_array_size(limits.max_array_len)
+            .set_max_map_size(limits.max_array_len)
+            .set_max_call_levels(MAX_CALL_LEVELS)
+            .on_print(|_| {})
+            .on_debug(|_, _, _| {});
+        let ast = engine.compile(code)?;
+
+        Ok(EvalScript {
+            code: code.to_owned(),
+            engine,
+            ast,
+        })
+    }
+
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    /// Runs the snippet with `input` bound to the element's string. A result of `()` yields
+    /// nothing, an array one string per item, and anything else itself as a string.
+    pub fn run(&self, input: &str) -> Result<Vec<String>, Box<EvalAltResult>> {
+        let mut scope = Scope::new();
+        scope.push_constant("input", input.to_owned());
+        let result: Dynamic = self.engine.eval_ast_with_scope(&mut scope, &self.ast)?;
+
+        if result.is_unit() {
+            return Ok(vec![]);
+        }
+        if result.is_array() {
+            let items = result.into_array().unwrap_or_default();
+            return Ok(items.iter().map(Dynamic::to_string).collect());
+        }
+        Ok(vec![result.to_string()])
+    }
+}
@@ -0,0 +1,44 @@
+use crate::sql::{self, UsageMetric};
+use crate::util;
+use dashmap::DashMap;
+use sqlx::{Pool, Sqlite};
+
+/// Outbound fetches each user's scripts have made in the current usage hour. Runs only record
+/// their fetches in `usage` once they finish, so concurrent runs are counted here as they go;
+/// a user's first fetch of the hour starts from what `usage` already holds, which carries the
+/// count over restarts.
+#[derive(Default)]
+pub struct FetchBudget {
+    hours: DashMap<String, (i64, u64)>,
+}
+impl FetchBudget {
+    /// Counts one fetch for `user`, unless they already made `limit` this hour.
+    pub async fn take(
+        &self,
+        pool: &Pool<Sqlite>,
+        user: &str,
+        limit: u64,
+    ) -> Result<bool, sqlx::Error> {
+        let hour = sql::usage_hour(util::unix_ms());
+
+        let current = self.hours.get(user).is_some_and(|entry| entry.0 == hour);
+        let recorded = if current {
+            0
+        } else {
+            sql::hourly_usage(pool, user, UsageMetric::HttpFetch, hour).await? as u64
+        };
+
+        let mut entry = self
+            .hours
+            .entry(user.to_owned())
+            .or_insert((hour, recorded));
+        if entry.0 != hour {
+            *entry = (hour, recorded);
+        }
+        if entry.1 >= limit {
+            return Ok(false);
+        }
+        entry.1 += 1;
+        Ok(true)
+    }
+}
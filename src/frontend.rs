@@ -0,0 +1,104 @@
+use rocket::fs::NamedFile;
+use rocket::http::{
+    uri::{fmt::Path as UriPath, Segments},
+    Method, Status,
+};
+use rocket::response::{self, Responder};
+use rocket::route::{Handler, Outcome, Route};
+use rocket::{Data, Request};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Tried after every `/api` route, the same rank Rocket gives its `FileServer`.
+const FRONTEND_RANK: isize = 10;
+/// Content-addressed files never change, so caches may keep them for a year.
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+const REVALIDATE: &str = "no-cache";
+
+/// Whether `path`'s name carries a content hash, as in `main-3f9a1c2b.js` or `app.B7x9kQ2p.css`:
+/// its last `-` or `.` separated part before the extension is at least 8 letters, digits or
+/// underscores including a digit.
+fn is_hashed(path: &Path) -> bool {
+    let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+        return false;
+    };
+    stem.rsplit(['-', '.']).next().is_some_and(|hash| {
+        hash.len() >= 8
+            && hash.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && hash.chars().any(|c| c.is_ascii_digit())
+    })
+}
+
+struct CacheControl<R>(&'static str, R);
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for CacheControl<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        let mut response = self.1.respond_to(request)?;
+        response.set_raw_header("Cache-Control", self.0);
+        Ok(response)
+    }
+}
+
+/// Serves the frontend directory. Paths without an extension that match no file get the
+/// `index.html` of their nearest existing directory, so client-side routes survive a reload.
+/// Hashed assets are cached for good, everything else is revalidated on every use.
+#[derive(Clone)]
+pub struct Frontend {
+    root: PathBuf,
+}
+impl Frontend {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Frontend { root: root.into() }
+    }
+
+    /// The deepest `index.html` on the way from `relative` up to the root.
+    async fn fallback_index(&self, relative: &Path) -> Option<NamedFile> {
+        for dir in relative.ancestors() {
+            if let Ok(file) = NamedFile::open(self.root.join(dir).join("index.html")).await {
+                return Some(file);
+            }
+        }
+        None
+    }
+}
+impl From<Frontend> for Vec<Route> {
+    fn from(frontend: Frontend) -> Self {
+        let mut route = Route::ranked(FRONTEND_RANK, Method::Get, "/<path..>", frontend);
+        route.name = Some("Frontend".into());
+        vec![route]
+    }
+}
+
+#[rocket::async_trait]
+impl Handler for Frontend {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let Some(relative) = request
+            .segments::<Segments<'_, UriPath>>(0..)
+            .ok()
+            .and_then(|segments| segments.to_path_buf(false).ok())
+        else {
+            return Outcome::forward(data, Status::NotFound);
+        };
+        if relative.starts_with("api") {
+            return Outcome::forward(data, Status::NotFound);
+        }
+
+        let path = self.root.join(&relative);
+        if fs::metadata(&path).await.is_ok_and(|meta| meta.is_file()) {
+            return match NamedFile::open(&path).await {
+                Ok(file) if is_hashed(&path) => {
+                    Outcome::from(request, CacheControl(IMMUTABLE, file))
+                }
+                Ok(file) => Outcome::from(request, CacheControl(REVALIDATE, file)),
+                Err(_) => Outcome::forward(data, Status::NotFound),
+            };
+        }
+        if relative.extension().is_some() {
+            return Outcome::forward(data, Status::NotFound);
+        }
+
+        match self.fallback_index(&relative).await {
+            Some(index) => Outcome::from(request, CacheControl(REVALIDATE, index)),
+            None => Outcome::forward(data, Status::NotFound),
+        }
+    }
+}
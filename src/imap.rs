@@ -1,23 +1,59 @@
 use crate::{
-    config::{Config, Users},
-    util,
+    alerts,
+    config::{AfterProcessing, Config, Http, Imap, ImapConnection, User},
+    ingest::{self, IngestError, Ingested},
+    oauth2::{self, RefreshToken, TokenError, XOAuth2},
+    proxy::Proxy,
+    sql,
+    status::Status,
+    systemd::{self, Readiness},
+    triggers::Triggers,
+    util::{self, WorkerPool},
+    ManagedConfig, ManagedStatus,
 };
-use async_imap::{imap_proto::Address, Client as ImapClient};
-use futures::StreamExt;
-use futures_rustls::pki_types::ServerName;
-use futures_rustls::rustls::{ClientConfig, RootCertStore};
-use futures_rustls::TlsConnector;
+use async_imap::{
+    error::Error as ImapError,
+    extensions::idle::IdleResponse,
+    imap_proto::Address,
+    types::{Fetch, UnsolicitedResponse},
+    Client as ImapClient, Session,
+};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::{StreamExt, TryStreamExt};
+use futures_rustls::pki_types::{CertificateDer, InvalidDnsNameError, ServerName, UnixTime};
+use futures_rustls::rustls::{
+    self,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::WebPkiSupportedAlgorithms,
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use futures_rustls::{client::TlsStream, TlsConnector};
 use itertools::Itertools;
+use rocket::Shutdown;
+use sha2::{Digest, Sha256};
 use sqlx::{Pool, Sqlite};
 use std::borrow::Cow;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind};
+use std::path::Path;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
-use tiny_keccak::{Hasher, Sha3};
-use tokio::fs::OpenOptions;
-use tokio::io::AsyncWriteExt;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
 use tokio::time;
-use tokio_util::compat::TokioAsyncReadCompatExt;
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+
+/// Between cycles when the server lacks IDLE.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Servers may drop clients idle for 30 minutes (RFC 2177), so IDLE is renewed before that.
+const IDLE_RENEWAL: Duration = Duration::from_secs(25 * 60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// The first reconnect delay, doubled per failed attempt up to `RECONNECT_MAX`.
+pub(crate) const RECONNECT_BASE: Duration = Duration::from_secs(1);
+pub(crate) const RECONNECT_MAX: Duration = Duration::from_secs(5 * 60);
 
 fn address_to_string(address: &Address) -> String {
     format!(
@@ -35,241 +71,1161 @@ fn address_to_string(address: &Address) -> String {
     )
 }
 
-pub async fn perform(config: Arc<Config>, pool: Pool<Sqlite>) {
-    let tcp = TcpStream::connect((config.imap.server.as_str(), config.imap.port))
-        .await
-        .expect("Could not establish TCP connection");
+#[derive(Debug)]
+enum ConnectError {
+    Io(io::Error),
+    InvalidServer(InvalidDnsNameError),
+    OAuth2(TokenError),
+    Imap(ImapError),
+}
 
-    let mut root_store = RootCertStore::empty();
-    for cert in rustls_native_certs::load_native_certs().expect("Unable to load native certs") {
-        root_store.add(cert).expect("Unable to add root cert");
+/// Why [`run_session`] returned.
+enum SessionEnd {
+    Shutdown,
+    /// The connection failed; the error has been logged and put in the status.
+    Lost,
+}
+
+/// A TCP connection to the account's server, through its `proxy` if set.
+pub(crate) async fn connect_tcp(account: &Imap) -> io::Result<TcpStream> {
+    match &account.proxy {
+        Some(proxy) => {
+            Proxy::parse(proxy)?
+                .connect(&account.server, account.port)
+                .await
+        }
+        None => TcpStream::connect((account.server.as_str(), account.port)).await,
     }
+}
+
+/// The certificates in the PEM file at `path`, failing if there are none.
+pub(crate) fn load_certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "no PEM certificates found",
+        ));
+    }
+    Ok(certs)
+}
 
-    let tls_config = ClientConfig::builder()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
-    let tls_connector = TlsConnector::from(Arc::new(tls_config));
-    let tls_stream = tls_connector
-        .connect(
-            ServerName::try_from(config.imap.server.clone()).expect("Invalid domain"),
-            tcp.compat(),
+/// A SHA-256 fingerprint written as 64 hex digits, optionally separated by colons as OpenSSL
+/// prints them.
+pub(crate) fn parse_fingerprint(fingerprint: &str) -> Option<[u8; 32]> {
+    let digits = fingerprint.replace(':', "");
+    hex::decode(digits).ok()?.try_into().ok()
+}
+
+/// Trusts the server certificate whose fingerprint is pinned, and no other, whoever issued it.
+/// Handshake signatures are still checked against it.
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: [u8; 32],
+    algorithms: WebPkiSupportedAlgorithms,
+}
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity.as_ref()).as_slice() == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "server certificate does not match pinned_sha256".to_owned(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Trusts the system's root certificates and those in the account's `ca_certificates`, or with
+/// its `pinned_sha256`, only the pinned certificate. Built on every connect, so a CA bundle
+/// replaced on disk applies from the next one.
+pub(crate) fn tls_connector(account: &Imap) -> io::Result<TlsConnector> {
+    let builder = ClientConfig::builder();
+    let tls_config = match account.pinned_sha256.as_deref() {
+        Some(pinned) => {
+            let fingerprint = parse_fingerprint(pinned)
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "invalid pinned_sha256"))?;
+            let verifier = PinnedCertificate {
+                fingerprint,
+                algorithms: rustls::crypto::ring::default_provider()
+                    .signature_verification_algorithms,
+            };
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth()
+        }
+        None => {
+            let mut root_store = RootCertStore::empty();
+            for cert in rustls_native_certs::load_native_certs()? {
+                root_store
+                    .add(cert)
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            }
+            for path in &account.ca_certificates {
+                for cert in load_certificates(path)? {
+                    root_store
+                        .add(cert)
+                        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                }
+            }
+            builder
+                .with_root_certificates(root_store)
+                .with_no_client_auth()
+        }
+    };
+    Ok(TlsConnector::from(Arc::new(tls_config)))
+}
+
+/// Ingests from every account in `imap` at once. The accounts and their settings are read at
+/// startup, so changing them takes a restart.
+pub async fn perform(
+    managed_config: ManagedConfig,
+    pool: Pool<Sqlite>,
+    status: ManagedStatus,
+    triggers: Triggers,
+    readiness: Arc<Readiness>,
+    shutdown: Shutdown,
+) {
+    let accounts = managed_config.load().imap.as_slice().to_vec();
+    let tasks = accounts.into_iter().map(|account| {
+        // Listed in the status before connecting, in config order.
+        status.update_imap(&account.key(), |_| {});
+        let span = info_span!("account", username = %account.username, server = %account.server);
+        perform_account(
+            Arc::clone(&managed_config),
+            pool.clone(),
+            Arc::clone(&status),
+            triggers.clone(),
+            Arc::clone(&readiness),
+            account,
+            shutdown.clone(),
         )
-        .await
-        .expect("Unable to establish TLS connection");
+        .instrument(span)
+    });
+    futures::future::join_all(tasks).await;
+}
+
+/// Runs ingestion for `account` until shutdown, reconnecting with backoff whenever the connection
+/// cannot be made or is lost. Readiness is reported once the first session has selected its
+/// mailbox.
+async fn perform_account(
+    managed_config: ManagedConfig,
+    pool: Pool<Sqlite>,
+    status: ManagedStatus,
+    triggers: Triggers,
+    readiness: Arc<Readiness>,
+    account: Imap,
+    shutdown: Shutdown,
+) {
+    let mut refresh_token = RefreshToken::default();
+    let mut readiness = Some(readiness);
+    let mut attempt = 0;
+    let mut cycle = 0;
+    loop {
+        let config = managed_config.load_full();
+        let connected = tokio::select! {
+            result = time::timeout(
+                CONNECT_TIMEOUT,
+                connect(&account, &config.http, &mut refresh_token),
+            ) => {
+                result.unwrap_or_else(|_| {
+                    Err(ConnectError::Io(io::Error::new(
+                        ErrorKind::TimedOut,
+                        "IMAP connect timed out",
+                    )))
+                })
+            }
+            _ = shutdown.clone() => return,
+        };
+
+        match connected {
+            Ok(connection) => {
+                if let Some(readiness) = readiness.take() {
+                    readiness.component_ready();
+                }
+                let connected_at = Instant::now();
+                let end = run_session(
+                    connection,
+                    &account,
+                    &managed_config,
+                    &pool,
+                    &status,
+                    &triggers,
+                    &shutdown,
+                    &mut cycle,
+                )
+                .await;
+                if let SessionEnd::Shutdown = end {
+                    return;
+                }
+                // Only a connection that held for a while counts as recovered.
+                if connected_at.elapsed() >= RECONNECT_MAX {
+                    attempt = 0;
+                }
+            }
+            Err(e) => {
+                error!(error = ?e, "IMAP connect error");
+                status.ingest_failed(&account.key(), format!("connect: {:?}", e));
+            }
+        }
 
-    let mut imap = ImapClient::new(tls_stream);
+        let delay = util::backoff(attempt, RECONNECT_BASE, RECONNECT_MAX);
+        attempt = attempt.saturating_add(1);
+        warn!(
+            attempt,
+            delay_ms = delay.as_millis() as u64,
+            "IMAP reconnecting"
+        );
+        tokio::select! {
+            _ = time::sleep(delay) => {}
+            _ = shutdown.clone() => return,
+        }
+    }
+}
+
+async fn read_greeting<T: AsyncRead + AsyncWrite + Unpin + fmt::Debug + Send>(
+    imap: &mut ImapClient<T>,
+) -> Result<(), ConnectError> {
+    match imap.read_response().await {
+        Some(Ok(_)) => Ok(()),
+        Some(Err(e)) => Err(ConnectError::Io(e)),
+        None => Err(ConnectError::Imap(ImapError::ConnectionLost)),
+    }
+}
 
-    let _ = imap.read_response().await.expect("Could not read greeting");
+/// A logged-in session with the account's mailbox selected.
+struct Connection {
+    session: ImapSession,
+    /// `None` if the server did not report one, in which case nothing is remembered between
+    /// sessions and every message in the mailbox is fetched again.
+    uid_validity: Option<u32>,
+}
 
-    let mut session = imap
-        .login(config.imap.username.as_str(), config.imap.password.as_str())
+/// Connects, logs in and selects the account's mailbox.
+async fn connect(
+    account: &Imap,
+    http: &Http,
+    refresh_token: &mut RefreshToken,
+) -> Result<Connection, ConnectError> {
+    let mut session = log_in(account, http, refresh_token).await?;
+    let mailbox = session
+        .select(&account.mailbox)
         .await
-        .expect("Could not log in");
-    let _ = session
-        .select("EPV")
+        .map_err(ConnectError::Imap)?;
+
+    Ok(Connection {
+        session,
+        uid_validity: mailbox.uid_validity,
+    })
+}
+
+/// Connects and logs in with the password or an access token from the account's `oauth2`.
+async fn log_in(
+    account: &Imap,
+    http: &Http,
+    refresh_token: &mut RefreshToken,
+) -> Result<ImapSession, ConnectError> {
+    let access_token = match &account.oauth2 {
+        Some(oauth2) => {
+            let tokens =
+                oauth2::refresh(http, oauth2, refresh_token.current(&oauth2.refresh_token))
+                    .await
+                    .map_err(ConnectError::OAuth2)?;
+            if let Some(replacement) = tokens.refresh_token {
+                warn!(
+                    "IMAP OAuth2 refresh token rotated, the configured refresh_token is now stale"
+                );
+                refresh_token.rotate(&oauth2.refresh_token, replacement);
+            }
+            Some(tokens.access_token)
+        }
+        None => None,
+    };
+
+    let server_name =
+        ServerName::try_from(account.server.clone()).map_err(ConnectError::InvalidServer)?;
+    let tcp = connect_tcp(account)
         .await
-        .expect("Could not select mailbox");
+        .map_err(ConnectError::Io)?
+        .compat();
+
+    let mut imap = match account.connection {
+        ImapConnection::Tls => {
+            let tls_stream = tls_connector(account)
+                .map_err(ConnectError::Io)?
+                .connect(server_name, tcp)
+                .await
+                .map_err(ConnectError::Io)?;
+            let mut imap = ImapClient::new(MailStream::Tls(Box::new(tls_stream)));
+            read_greeting(&mut imap).await?;
+            imap
+        }
+        ImapConnection::Starttls => {
+            let mut plain = ImapClient::new(tcp);
+            read_greeting(&mut plain).await?;
+            plain
+                .run_command_and_check_ok("STARTTLS", None)
+                .await
+                .map_err(ConnectError::Imap)?;
+            // The server greets only once, before the upgrade.
+            let tls_stream = tls_connector(account)
+                .map_err(ConnectError::Io)?
+                .connect(server_name, plain.into_inner())
+                .await
+                .map_err(ConnectError::Io)?;
+            ImapClient::new(MailStream::Tls(Box::new(tls_stream)))
+        }
+        ImapConnection::Plaintext => {
+            let mut imap = ImapClient::new(MailStream::Plain(tcp));
+            read_greeting(&mut imap).await?;
+            imap
+        }
+    };
+
+    let session = match access_token {
+        Some(access_token) => {
+            imap.authenticate("XOAUTH2", XOAuth2::new(&account.username, access_token))
+                .await
+        }
+        None => {
+            let password = account.password.as_deref().unwrap_or_default();
+            imap.login(account.username.as_str(), password).await
+        }
+    };
+    session.map_err(|(e, _)| ConnectError::Imap(e))
+}
+
+/// Ingests over `session`, waiting for new mail in between, until shutdown or a connection
+/// failure.
+#[allow(clippy::too_many_arguments)]
+async fn run_session(
+    connection: Connection,
+    account: &Imap,
+    managed_config: &ManagedConfig,
+    pool: &Pool<Sqlite>,
+    status: &Status,
+    triggers: &Triggers,
+    shutdown: &Shutdown,
+    cycle: &mut u64,
+) -> SessionEnd {
+    let Connection {
+        mut session,
+        uid_validity,
+    } = connection;
+    let capabilities = match session.capabilities().await {
+        Ok(capabilities) => Capabilities {
+            idle: capabilities.has_str("IDLE"),
+            moves: capabilities.has_str("MOVE"),
+            uid_expunge: capabilities.has_str("UIDPLUS"),
+        },
+        Err(e) => {
+            warn!(
+                error = ?e,
+                "IMAP capability error, polling instead of idling and copying instead of moving"
+            );
+            Capabilities::default()
+        }
+    };
+    if !capabilities.moves {
+        debug!("IMAP server lacks MOVE, copying and expunging instead");
+    }
+    status.update_imap(&account.key(), |imap| {
+        imap.connected = true;
+        imap.idle = capabilities.idle;
+    });
 
     loop {
-        time::sleep(Duration::from_secs(5)).await;
+        systemd::watchdog();
+        *cycle += 1;
+        let config = managed_config.load_full();
+        let result = ingest_cycle(
+            &mut session,
+            account,
+            capabilities,
+            uid_validity,
+            &config,
+            pool,
+            status,
+            triggers,
+            shutdown,
+        )
+        .instrument(info_span!("ingest", cycle = *cycle))
+        .await;
+        if result.is_err() {
+            status.update_imap(&account.key(), |imap| imap.connected = false);
+            return SessionEnd::Lost;
+        }
 
-        let seq_list = match session.search("ALL").await {
-            Ok(x) => x,
+        // Mail the server announced while the cycle ran would not wake the next IDLE.
+        let mut announced = false;
+        while let Ok(response) = session.unsolicited_responses.try_recv() {
+            announced |= matches!(response, UnsolicitedResponse::Exists(_));
+        }
+        if announced {
+            continue;
+        }
+
+        if !capabilities.idle {
+            tokio::select! {
+                _ = time::sleep(POLL_INTERVAL) => continue,
+                _ = shutdown.clone() => break,
+            }
+        }
+        match idle(session, shutdown).await {
+            Ok((idled, false)) => session = idled,
+            Ok((idled, true)) => {
+                session = idled;
+                break;
+            }
             Err(e) => {
-                eprintln!("IMAP search error: {:#?}", e);
-                continue;
+                error!(error = ?e, "IMAP IDLE error");
+                status.update_imap(&account.key(), |imap| imap.connected = false);
+                status.ingest_failed(&account.key(), format!("idle: {}", e));
+                return SessionEnd::Lost;
             }
-        };
+        }
+    }
 
-        let seq_list_str = match seq_list.len() {
-            0 => continue,
-            1 => seq_list
-                .into_iter()
-                .next()
-                .expect("Just checked len, but no first element")
-                .to_string(),
-            _ => seq_list.into_iter().join(","),
-        };
+    if let Err(e) = session.logout().await {
+        error!(error = ?e, "IMAP logout error");
+    }
+    status.update_imap(&account.key(), |imap| imap.connected = false);
+    SessionEnd::Shutdown
+}
+
+/// The connection under a session, encrypted unless `imap.connection` is `plaintext`. POP3
+/// sessions use it too.
+#[derive(Debug)]
+pub(crate) enum MailStream {
+    Tls(Box<TlsStream<Compat<TcpStream>>>),
+    Plain(Compat<TcpStream>),
+}
+impl AsyncRead for MailStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MailStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            MailStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+impl AsyncWrite for MailStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MailStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            MailStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MailStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            MailStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MailStream::Tls(stream) => Pin::new(stream).poll_close(cx),
+            MailStream::Plain(stream) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+type ImapSession = Session<MailStream>;
+
+/// The extensions ingestion uses, if the server has them.
+#[derive(Clone, Copy, Default)]
+struct Capabilities {
+    idle: bool,
+    /// `MOVE` (RFC 6851).
+    moves: bool,
+    /// `UID EXPUNGE`, from `UIDPLUS` (RFC 4315).
+    uid_expunge: bool,
+}
+
+/// Waits in IDLE until the server reports a change, the IDLE is due to be renewed or shutdown
+/// starts, feeding the watchdog meanwhile. Returns the session and whether shutdown started.
+async fn idle(session: ImapSession, shutdown: &Shutdown) -> Result<(ImapSession, bool), ImapError> {
+    let mut handle = session.idle();
+    handle.init().await?;
+
+    let shutting_down = {
+        let (wait, _interrupt) = handle.wait_with_timeout(IDLE_RENEWAL);
+        tokio::pin!(wait);
+        let mut watchdog = time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                response = &mut wait => {
+                    if let IdleResponse::NewData(_) = response? {
+                        debug!("IMAP IDLE woke up");
+                    }
+                    break false;
+                }
+                _ = watchdog.tick() => systemd::watchdog(),
+                _ = shutdown.clone() => break true,
+            }
+        }
+    };
+
+    Ok((handle.done().await?, shutting_down))
+}
+
+/// Everything needed to store a fetched message once the fetch stream has been dropped.
+pub(crate) struct FetchedEmail {
+    pub user: String,
+    pub from_addr: String,
+    pub to_addr: String,
+    pub body: Vec<u8>,
+}
+
+impl FetchedEmail {
+    /// One for each user in `routed`, as `ingest::route_all` returns them.
+    pub(crate) fn copies(
+        routed: Vec<(&User, String)>,
+        from_addr: String,
+        body: Vec<u8>,
+    ) -> Vec<FetchedEmail> {
+        routed
+            .into_iter()
+            .map(|(user, to_addr)| FetchedEmail {
+                user: user.username.clone(),
+                from_addr: from_addr.clone(),
+                to_addr,
+                body: body.clone(),
+            })
+            .collect()
+    }
+}
+
+/// How storing a [`FetchedEmail`] went.
+#[derive(Clone, Copy)]
+pub(crate) enum StoreOutcome {
+    /// Stored now or before, so the message can be moved, deleted or flagged.
+    Handled,
+    /// Failed in a way that may not happen again, so the message should be fetched again.
+    Retry,
+    /// Can never be stored.
+    Rejected,
+}
+
+/// How a message went, with `keys` identifying the message of each of `outcomes`: the copies
+/// of a message, which are next to each other, are retried if any of them has to be, and
+/// otherwise rejected if any of them was.
+pub(crate) fn by_message<K: PartialEq>(
+    keys: Vec<K>,
+    outcomes: Vec<StoreOutcome>,
+) -> Vec<(K, StoreOutcome)> {
+    let mut messages: Vec<(K, StoreOutcome)> = vec![];
+    for (key, outcome) in keys.into_iter().zip(outcomes) {
+        match messages.last_mut() {
+            Some((last, combined)) if *last == key => {
+                *combined = match (*combined, outcome) {
+                    (StoreOutcome::Retry, _) | (_, StoreOutcome::Retry) => StoreOutcome::Retry,
+                    (StoreOutcome::Rejected, _) | (_, StoreOutcome::Rejected) => {
+                        StoreOutcome::Rejected
+                    }
+                    (StoreOutcome::Handled, StoreOutcome::Handled) => StoreOutcome::Handled,
+                };
+            }
+            _ => messages.push((key, outcome)),
+        }
+    }
+    messages
+}
+
+/// Stores `fetched` `parallelism` at a time, firing the triggers of newly stored emails and
+/// counting them in the status of `source`, the account or listener they came from. Returns how
+/// each went, in order.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn store_fetched(
+    fetched: Vec<FetchedEmail>,
+    source: &str,
+    parallelism: usize,
+    config: &Arc<Config>,
+    pool: &Pool<Sqlite>,
+    status: &Status,
+    triggers: &Triggers,
+    shutdown: &Shutdown,
+) -> Vec<StoreOutcome> {
+    let workers = WorkerPool::new(parallelism);
+    let stored = workers
+        .run_ordered(fetched.into_iter().map(|email| {
+            let config = Arc::clone(config);
+            let pool = pool.clone();
+            let span = Span::current();
+            async move {
+                let result = ingest::store(
+                    &config,
+                    &pool,
+                    &email.user,
+                    email.from_addr.clone(),
+                    email.to_addr.clone(),
+                    &email.body,
+                )
+                .instrument(span)
+                .await;
+                (email, result)
+            }
+        }))
+        .await;
 
-        let mut emails = match session.fetch(seq_list_str, "(ENVELOPE RFC822)").await {
+    let mut outcomes = vec![];
+    for stored in stored {
+        let (email, result) = match stored {
             Ok(x) => x,
             Err(e) => {
-                eprintln!("IMAP fetch error: {:#?}", e);
+                error!(error = ?e, "Store task error");
+                status.update_imap(source, |imap| imap.failed += 1);
+                outcomes.push(StoreOutcome::Retry);
                 continue;
             }
         };
 
-        let mut moveable_seqs = vec![];
+        let outcome = match result {
+            Ok(Ingested::Stored(id)) => {
+                debug!(id = %id, user = %email.user, "Stored email");
 
-        while let Some(email_res) = emails.next().await {
-            let email = match email_res {
-                Ok(x) => x,
-                Err(e) => {
-                    eprintln!("IMAP individual fetch error: {:#?}", e);
-                    continue;
+                let now = util::unix_ms();
+                let lag_ms = mailparse::parse_headers(&email.body)
+                    .ok()
+                    .and_then(|(headers, _)| ingest::date_header(&headers))
+                    .map(|sent_at| now - sent_at);
+                status.update_imap(source, |imap| {
+                    imap.stored += 1;
+                    imap.last_ingested = Some(now);
+                    imap.last_lag_ms = lag_ms;
+                });
+                if let Some(lag_ms) = lag_ms {
+                    alerts::ingest_lag(config, status, &id, &email.user, lag_ms);
                 }
-            };
+                triggers
+                    .fire(Arc::clone(config), id, shutdown.clone())
+                    .await;
+                StoreOutcome::Handled
+            }
+            Ok(Ingested::Duplicate(_)) => {
+                status.update_imap(source, |imap| imap.duplicates += 1);
+                StoreOutcome::Handled
+            }
+            Err(e @ (IngestError::Io(_) | IngestError::Sql(_))) => {
+                error!(error = ?e, "Store error");
+                status.update_imap(source, |imap| imap.failed += 1);
+                StoreOutcome::Retry
+            }
+            Err(e) => {
+                error!(error = ?e, "Store error");
+                status.update_imap(source, |imap| imap.failed += 1);
+                StoreOutcome::Rejected
+            }
+        };
+        outcomes.push(outcome);
+    }
+    outcomes
+}
 
-            let Some(envelope) = email.envelope() else {
-                eprintln!("IMAP no envelope");
-                continue;
-            };
+/// Where the last cycle on the account's mailbox left off, or 0 if the mailbox is new to us or
+/// its UIDs have been reassigned since.
+async fn load_last_uid(pool: &Pool<Sqlite>, account: &Imap, uid_validity: Option<u32>) -> u32 {
+    let Some(uid_validity) = uid_validity else {
+        return 0;
+    };
+    match sql::get_imap_state(pool, &account.key(), &account.mailbox).await {
+        Ok(Some(state)) if state.uid_validity == i64::from(uid_validity) => {
+            u32::try_from(state.last_uid).unwrap_or(0)
+        }
+        Ok(Some(_)) => {
+            warn!(
+                uid_validity,
+                "IMAP UIDVALIDITY changed, fetching the whole mailbox"
+            );
+            0
+        }
+        Ok(None) => 0,
+        Err(e) => {
+            error!(error = ?e, "IMAP state SELECT error");
+            0
+        }
+    }
+}
 
-            let Some(to) = &envelope.to else {
-                eprintln!("IMAP no to address");
-                continue;
-            };
+/// What ingestion needs from a fetched message, one for each user it is for, or `None` if it
+/// cannot be stored, which is logged.
+fn fetched_emails(config: &Config, uid: u32, email: &Fetch) -> Option<Vec<FetchedEmail>> {
+    let Some(envelope) = email.envelope() else {
+        warn!(uid, "IMAP no envelope");
+        return None;
+    };
 
-            let Some((matching_user, to_address_string)) = (match &config.users {
-                Users::Many(users) => to.iter().find_map(|to_address| {
-                    if let Some(host) = &to_address.host {
-                        if host.len() >= config.imap.postfix.len() {
-                            let (user, postfix) =
-                                host.split_at(host.len() - config.imap.postfix.len());
-                            if postfix == config.imap.postfix.as_bytes() {
-                                return users
-                                    .iter()
-                                    .find(|user_full| user_full.username.as_bytes() == user)
-                                    .map(|val| (val, address_to_string(to_address)));
-                            }
-                        }
-                    }
+    let Some(to) = &envelope.to else {
+        warn!(uid, "IMAP no to address");
+        return None;
+    };
 
-                    None
-                }),
-                Users::Single(user) => to
-                    .iter()
-                    .next()
-                    .map(|to_address| (user, address_to_string(to_address))),
-            }) else {
-                eprintln!("IMAP no matching user");
-                continue;
-            };
+    let Some(from_address_string) = envelope
+        .from
+        .as_ref()
+        .and_then(|froms| froms.get(0))
+        .map(address_to_string)
+    else {
+        warn!(uid, "IMAP no from address");
+        return None;
+    };
 
-            let Some(from_address_string) = envelope
-                .from
-                .as_ref()
-                .and_then(|froms| froms.get(0))
-                .map(address_to_string)
-            else {
-                eprintln!("IMAP no from address");
-                continue;
-            };
+    let to = to.iter().map(address_to_string).collect::<Vec<_>>();
+    let subject = envelope
+        .subject
+        .as_ref()
+        .map(|subject| decode_header_value("Subject", subject))
+        .unwrap_or_default();
+    let routed = ingest::route_all(config, &from_address_string, &to, &subject);
+    if routed.is_empty() {
+        warn!(uid, "IMAP no matching user");
+        return None;
+    }
 
-            let Some(body_bytes) = email.body() else {
-                eprintln!("IMAP no email body");
-                continue;
-            };
+    let Some(body_bytes) = email.body() else {
+        warn!(uid, "IMAP no email body");
+        return None;
+    };
 
-            let parsed = match mailparse::parse_mail(body_bytes) {
-                Ok(x) => x,
-                Err(e) => {
-                    eprintln!("IMAP mail parse error: {:#?}", e);
-                    continue;
-                }
-            };
+    Some(FetchedEmail::copies(
+        routed,
+        from_address_string,
+        body_bytes.to_vec(),
+    ))
+}
 
-            let Some(subject) = parsed.headers.iter().find_map(|header| {
-                if header.get_key_ref() == "Subject" {
-                    Some(header.get_value())
-                } else {
-                    None
-                }
-            }) else {
-                eprintln!("IMAP subject None");
-                continue;
-            };
+/// A header value as it would read once decoded, such as an envelope's subject.
+fn decode_header_value(name: &str, raw: &[u8]) -> String {
+    let line = [name.as_bytes(), b": ", raw].concat();
+    mailparse::parse_header(&line)
+        .map(|(header, _)| header.get_value())
+        .unwrap_or_else(|_| String::from_utf8_lossy(raw).into_owned())
+}
 
-            let Some(html) =
-                util::traverse_mail(&parsed, &mut |mail| &mail.ctype.mimetype == "text/html")
-            else {
-                eprintln!("IMAP mail no body");
-                continue;
-            };
+/// The messages in `uid_set` larger than `max_size` bytes, each logged with its sender and
+/// subject.
+async fn oversized(
+    session: &mut ImapSession,
+    uid_set: String,
+    max_size: usize,
+) -> Result<Vec<u32>, ImapError> {
+    let mut sizes = session
+        .uid_fetch(uid_set, "(UID RFC822.SIZE ENVELOPE)")
+        .await?;
+    let mut oversized = vec![];
+    while let Some(fetch) = sizes.next().await {
+        let fetch = fetch?;
+        let (Some(uid), Some(size)) = (fetch.uid, fetch.size) else {
+            continue;
+        };
+        if size as usize <= max_size {
+            continue;
+        }
 
-            let html_body = match html.get_body() {
+        let envelope = fetch.envelope();
+        let from = envelope
+            .and_then(|envelope| envelope.from.as_ref())
+            .and_then(|froms| froms.first())
+            .map(address_to_string)
+            .unwrap_or_default();
+        let subject = envelope
+            .and_then(|envelope| envelope.subject.as_deref())
+            .map(|subject| decode_header_value("Subject", subject))
+            .unwrap_or_default();
+        warn!(uid, size, max_size, %from, %subject, "IMAP message too large, skipped");
+        oversized.push(uid);
+    }
+    Ok(oversized)
+}
+
+/// Moves the oversized messages `uids` into the account's `rejected_mailbox`, if set, and counts
+/// them as failed. Either way they are not fetched again, as they count as handled for the last
+/// UID.
+async fn reject_oversized(
+    session: &mut ImapSession,
+    account: &Imap,
+    capabilities: Capabilities,
+    status: &Status,
+    uids: &[u32],
+) {
+    status.update_imap(&account.key(), |imap| imap.failed += uids.len() as u64);
+    let Some(mailbox) = &account.rejected_mailbox else {
+        return;
+    };
+    if let Err(e) = move_messages(session, capabilities, uids.iter().join(","), mailbox).await {
+        error!(error = ?e, mailbox, "IMAP move of oversized messages error");
+    }
+}
+
+/// Lowers `retry_from` to `uid` if it is not already at or below it.
+fn retry_from_uid(retry_from: &mut Option<u32>, uid: u32) {
+    *retry_from = Some(retry_from.map_or(uid, |from| from.min(uid)));
+}
+
+/// Fetches the messages in the account's mailbox newer than the last UID handled, its
+/// `batch_size` at a time. Each batch's new emails are stored and their triggers fired, and the
+/// handled ones moved, deleted or flagged, before the next is fetched. The last UID is then saved
+/// up to the first message that failed in a way worth retrying, so a crash or restart neither
+/// skips nor refetches more than that. Messages that can never be stored are left in the mailbox
+/// and not fetched again, as are those over its `max_message_size` unless moved to its
+/// `rejected_mailbox`. Fails when searching or fetching does, as the connection is then likely
+/// gone.
+#[allow(clippy::too_many_arguments)]
+async fn ingest_cycle(
+    session: &mut ImapSession,
+    account: &Imap,
+    capabilities: Capabilities,
+    uid_validity: Option<u32>,
+    config: &Arc<Config>,
+    pool: &Pool<Sqlite>,
+    status: &Status,
+    triggers: &Triggers,
+    shutdown: &Shutdown,
+) -> Result<(), ImapError> {
+    let last_uid = load_last_uid(pool, account, uid_validity).await;
+
+    // `n:*` always includes the highest UID in the mailbox, even when it is below `n`.
+    let mut query = format!("UID {}:*", last_uid + 1);
+    if account.after_processing == AfterProcessing::Flag {
+        query.push_str(" UNSEEN");
+    }
+    let uids = match session.uid_search(query).await {
+        Ok(x) => x
+            .into_iter()
+            .filter(|&uid| uid > last_uid)
+            .sorted()
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            error!(error = ?e, "IMAP search error");
+            status.ingest_failed(&account.key(), format!("search: {}", e));
+            return Err(e);
+        }
+    };
+
+    status.ingest_succeeded(&account.key(), uids.len());
+
+    // Messages at or above this UID are fetched again next cycle.
+    let mut retry_from = None;
+    let mut saved_uid = last_uid;
+    for batch in uids.chunks(account.batch_size) {
+        let mut to_fetch = batch.to_vec();
+        if let Some(max_size) = account.max_message_size {
+            let oversized = match oversized(session, batch.iter().join(","), max_size).await {
                 Ok(x) => x,
                 Err(e) => {
-                    eprintln!("IMAP mail parse body error: {:#?}", e);
-                    continue;
+                    error!(error = ?e, "IMAP size fetch error");
+                    status.ingest_failed(&account.key(), format!("fetch: {}", e));
+                    return Err(e);
                 }
             };
+            if !oversized.is_empty() {
+                reject_oversized(session, account, capabilities, status, &oversized).await;
+                to_fetch.retain(|uid| !oversized.contains(uid));
+            }
+        }
 
-            let mut sha3 = Sha3::v256();
-            let mut output = [0; 32];
-            sha3.update(body_bytes);
-            sha3.finalize(&mut output);
-            let id = hex::encode(&output[0..16]);
-
-            match sqlx::query!(r#"SELECT 1 as existence FROM emails WHERE id = $1"#, id)
-                .fetch_optional(&pool)
+        let mut fetched = vec![];
+        // Every message in the batch may have been too large.
+        if !to_fetch.is_empty() {
+            // Peeking leaves \Seen to `after_processing`, so a message that fails stays unread.
+            let mut emails = match session
+                .uid_fetch(to_fetch.iter().join(","), "(UID ENVELOPE BODY.PEEK[])")
                 .await
             {
-                Ok(Some(_)) => {
-                    moveable_seqs.push(email.message);
-                    continue;
-                }
+                Ok(x) => x,
                 Err(e) => {
-                    eprintln!("IMAP check existence error: {:#?}", e);
-                    continue;
+                    error!(error = ?e, "IMAP fetch error");
+                    status.ingest_failed(&account.key(), format!("fetch: {}", e));
+                    return Err(e);
                 }
-                _ => {}
-            }
+            };
 
-            let file_name = format!("{}/{}.html", matching_user.username, id);
+            while let Some(email_res) = emails.next().await {
+                let email = match email_res {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!(error = ?e, "IMAP individual fetch error");
+                        // Which message failed is unknown, so none of this batch can be skipped.
+                        retry_from_uid(&mut retry_from, batch[0]);
+                        continue;
+                    }
+                };
 
-            let mut html_file = match util::open_parents(
-                OpenOptions::new().write(true).truncate(true).create(true),
-                format!("{}/{}", config.storage.file_root, file_name),
-            )
-            .await
-            {
-                Ok(file) => file,
-                Err(e) => {
-                    eprintln!("IMAP could not open file: {:#?}", e);
+                let Some(uid) = email.uid else {
+                    warn!("IMAP no UID");
+                    retry_from_uid(&mut retry_from, batch[0]);
                     continue;
+                };
+
+                match fetched_emails(config, uid, &email) {
+                    Some(copies) => fetched.extend(copies.into_iter().map(|copy| (uid, copy))),
+                    None => status.update_imap(&account.key(), |imap| imap.failed += 1),
                 }
-            };
+            }
+        }
 
-            if let Err(e) = html_file.write(html_body.as_bytes()).await {
-                eprintln!("IMAP file write error: {:#?}", e);
-                continue;
+        let (fetched_uids, fetched): (Vec<_>, Vec<_>) = fetched.into_iter().unzip();
+        let outcomes = store_fetched(
+            fetched,
+            &account.key(),
+            account.parallelism,
+            config,
+            pool,
+            status,
+            triggers,
+            shutdown,
+        )
+        .await;
+
+        let mut moveable_uids = vec![];
+        for (uid, outcome) in by_message(fetched_uids, outcomes) {
+            match outcome {
+                StoreOutcome::Handled => moveable_uids.push(uid),
+                StoreOutcome::Retry => retry_from_uid(&mut retry_from, uid),
+                StoreOutcome::Rejected => {}
             }
+        }
 
-            let now = util::unix_ms();
-
-            if let Err(e) = sqlx::query!(
-                r#"INSERT INTO emails (id, html, user, registered, subject, from_addr, to_addr)
-                           VALUES ($1, $2, $3, $4, $5, $6, $7)"#,
-                id,
-                file_name,
-                matching_user.username,
-                now,
-                subject,
-                from_address_string,
-                to_address_string
-            )
-            .execute(&pool)
-            .await
-            {
-                eprintln!("IMAP insert error: {:#?}", e);
+        debug!(
+            fetched = batch.len(),
+            handled = moveable_uids.len(),
+            "IMAP batch finished"
+        );
+
+        if !moveable_uids.is_empty() {
+            let uid_set = moveable_uids.into_iter().join(",");
+            if let Err(e) = after_processing(session, account, capabilities, uid_set).await {
+                // They are stored, so fetching them again next session only finds duplicates.
+                let mode = account.after_processing;
+                error!(error = ?e, ?mode, "IMAP after processing error");
             }
+        }
 
-            moveable_seqs.push(email.message);
+        let batch_end = batch[batch.len() - 1];
+        let handled_up_to = retry_from.map_or(batch_end, |uid| uid - 1);
+        if let Some(uid_validity) = uid_validity {
+            if handled_up_to > saved_uid {
+                let state = sql::ImapState {
+                    uid_validity: i64::from(uid_validity),
+                    last_uid: i64::from(handled_up_to),
+                };
+                match sql::set_imap_state(pool, &account.key(), &account.mailbox, &state).await {
+                    Ok(()) => saved_uid = handled_up_to,
+                    Err(e) => error!(error = ?e, "IMAP state UPSERT error"),
+                }
+            }
         }
+    }
 
-        drop(emails);
+    Ok(())
+}
 
-        if !moveable_seqs.is_empty() {
-            if let Err(e) = session
-                .mv(
-                    moveable_seqs.into_iter().map(|n| n.to_string()).join(","),
-                    "EPV-READ",
-                )
+/// Flags the messages in `uid_set` deleted and expunges them. Without `UID EXPUNGE`, any other
+/// messages flagged deleted in the mailbox are expunged too.
+async fn delete_messages(
+    session: &mut ImapSession,
+    capabilities: Capabilities,
+    uid_set: String,
+) -> Result<(), ImapError> {
+    session
+        .uid_store(&uid_set, "+FLAGS.SILENT (\\Deleted)")
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    if capabilities.uid_expunge {
+        session
+            .uid_expunge(uid_set)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+    } else {
+        session.expunge().await?.try_collect::<Vec<_>>().await?;
+    }
+    Ok(())
+}
+
+/// Moves the messages in `uid_set` to `mailbox`, by copying and deleting them on servers without
+/// `MOVE`.
+async fn move_messages(
+    session: &mut ImapSession,
+    capabilities: Capabilities,
+    uid_set: String,
+    mailbox: &str,
+) -> Result<(), ImapError> {
+    if capabilities.moves {
+        return session.uid_mv(uid_set, mailbox).await;
+    }
+    session.uid_copy(&uid_set, mailbox).await?;
+    delete_messages(session, capabilities, uid_set).await
+}
+
+/// Moves, deletes or flags the handled messages in `uid_set` as the account's `after_processing`
+/// says.
+async fn after_processing(
+    session: &mut ImapSession,
+    account: &Imap,
+    capabilities: Capabilities,
+    uid_set: String,
+) -> Result<(), ImapError> {
+    match account.after_processing {
+        AfterProcessing::Move => {
+            move_messages(session, capabilities, uid_set, &account.read_mailbox).await
+        }
+        AfterProcessing::Delete => delete_messages(session, capabilities, uid_set).await,
+        AfterProcessing::Flag => {
+            session
+                .uid_store(uid_set, "+FLAGS.SILENT (\\Seen)")
+                .await?
+                .try_collect::<Vec<_>>()
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+/// How a [`backfill`] went.
+#[derive(Default)]
+pub(crate) struct BackfillCounts {
+    pub stored: usize,
+    pub duplicates: usize,
+    pub failed: usize,
+}
+
+/// Stores every message in `account`'s `mailbox` for the users its recipients route to, its
+/// `batch_size` at a time. The mailbox is examined rather than selected, so nothing in it is
+/// moved or flagged. Emails stored before are recognised by their content hash and skipped, and
+/// no triggers fire, as none of them are new. Messages over its `max_message_size` are not
+/// downloaded and count as failed.
+pub(crate) async fn backfill(
+    config: &Config,
+    account: &Imap,
+    pool: &Pool<Sqlite>,
+    mailbox: &str,
+) -> Result<BackfillCounts, String> {
+    let mut session = time::timeout(
+        CONNECT_TIMEOUT,
+        log_in(account, &config.http, &mut RefreshToken::default()),
+    )
+    .await
+    .map_err(|_| "IMAP connect timed out".to_owned())?
+    .map_err(|e| format!("IMAP connect error: {:?}", e))?;
+    session
+        .examine(mailbox)
+        .await
+        .map_err(|e| format!("Unable to open {}: {}", mailbox, e))?;
+    let uids = session
+        .uid_search("ALL")
+        .await
+        .map_err(|e| format!("IMAP search error: {}", e))?
+        .into_iter()
+        .sorted()
+        .collect::<Vec<_>>();
+
+    let mut counts = BackfillCounts::default();
+    for batch in uids.chunks(account.batch_size) {
+        let mut to_fetch = batch.to_vec();
+        if let Some(max_size) = account.max_message_size {
+            let oversized = oversized(&mut session, batch.iter().join(","), max_size)
                 .await
-            {
-                eprintln!("IMAP move error: {:#?}", e);
+                .map_err(|e| format!("IMAP size fetch error: {}", e))?;
+            counts.failed += oversized.len();
+            to_fetch.retain(|uid| !oversized.contains(uid));
+        }
+        // Every message in the batch may have been too large.
+        if to_fetch.is_empty() {
+            continue;
+        }
+
+        let mut emails = session
+            .uid_fetch(to_fetch.iter().join(","), "(UID ENVELOPE BODY.PEEK[])")
+            .await
+            .map_err(|e| format!("IMAP fetch error: {}", e))?;
+        let mut fetched = vec![];
+        while let Some(email_res) = emails.next().await {
+            let email = email_res.map_err(|e| format!("IMAP fetch error: {}", e))?;
+            let Some(uid) = email.uid else {
+                warn!("IMAP no UID");
+                counts.failed += 1;
+                continue;
+            };
+            match fetched_emails(config, uid, &email) {
+                Some(copies) => fetched.extend(copies),
+                None => counts.failed += 1,
+            }
+        }
+
+        for email in fetched {
+            let result = ingest::store(
+                config,
+                pool,
+                &email.user,
+                email.from_addr,
+                email.to_addr,
+                &email.body,
+            )
+            .await;
+            match result {
+                Ok(Ingested::Stored(id)) => {
+                    debug!(id = %id, user = %email.user, "Backfilled email");
+                    counts.stored += 1;
+                }
+                Ok(Ingested::Duplicate(_)) => counts.duplicates += 1,
+                Err(e) => {
+                    error!(error = ?e, user = %email.user, "Backfill store error");
+                    counts.failed += 1;
+                }
             }
         }
+        info!(
+            stored = counts.stored,
+            duplicates = counts.duplicates,
+            failed = counts.failed,
+            "IMAP backfill batch finished"
+        );
+    }
+
+    if let Err(e) = session.logout().await {
+        error!(error = ?e, "IMAP logout error");
     }
+    Ok(counts)
 }
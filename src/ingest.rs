@@ -0,0 +1,983 @@
+use crate::{
+    authentication,
+    clamd::{self, Verdict},
+    config::{
+        Clamd, Config, Imap, ImapRouting, PlainTextFallback, RoutingRule, RuleRegex, SnapshotMode,
+        User, Users,
+    },
+    sanitize, snapshot,
+    sql::{self, Email, UsageMetric},
+    storage::{self, PendingWrite},
+    util,
+};
+use chrono::DateTime;
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+use lol_html::{element, html_content::Element, rewrite_str, HandlerResult, RewriteStrSettings};
+use mailparse::{DispositionType, MailAddr, MailHeader, MailHeaderMap, MailParseError, ParsedMail};
+use sqlx::{Pool, Sqlite, SqliteConnection};
+use std::io;
+use tracing::{error, warn};
+
+struct ExtractedAttachment {
+    filename: Option<String>,
+    mime: String,
+    path: String,
+    hash: String,
+    body: Vec<u8>,
+    /// `clean`, `infected` or `error`, if scanned.
+    scan: Option<&'static str>,
+    scan_signature: Option<String>,
+}
+
+struct ExtractedInlineImage {
+    content_id: String,
+    mime: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+struct NewEmail {
+    id: String,
+    html: String,
+    sanitized_html: String,
+    raw: String,
+    user: String,
+    subject: String,
+    from_addr: String,
+    to_addr: String,
+    from_name: Option<String>,
+    to_name: Option<String>,
+    message_id: Option<String>,
+    in_reply_to: Option<String>,
+    /// `pass`, `fail` or `none`, if checked.
+    dkim: Option<&'static str>,
+    spf: Option<&'static str>,
+    headers: String,
+    /// Lowercased names and values of the headers in `ingest.capture_headers`.
+    captured_headers: Vec<(String, String)>,
+    sent: Option<i64>,
+    sent_offset: Option<i64>,
+    attachments: Vec<ExtractedAttachment>,
+    inline_images: Vec<ExtractedInlineImage>,
+}
+
+#[derive(Debug)]
+pub enum IngestError {
+    Parse(MailParseError),
+    NoSubject,
+    /// Neither an HTML part nor, if the fallback is on, a plain-text one.
+    NoBody,
+    Io(io::Error),
+    Sql(sqlx::Error),
+}
+
+pub enum Ingested {
+    Stored(String),
+    Duplicate(String),
+}
+
+/// Lowercased header names mapped to the value of their first occurrence.
+fn headers_json(parsed: &ParsedMail) -> String {
+    let mut headers = serde_json::Map::new();
+    for header in &parsed.headers {
+        headers
+            .entry(header.get_key().to_ascii_lowercase())
+            .or_insert_with(|| serde_json::Value::String(header.get_value()));
+    }
+
+    serde_json::Value::Object(headers).to_string()
+}
+
+fn captured_headers(parsed: &ParsedMail, capture: &[String]) -> Vec<(String, String)> {
+    parsed
+        .headers
+        .iter()
+        .filter(|header| {
+            capture
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(&header.get_key()))
+        })
+        .map(|header| (header.get_key().to_ascii_lowercase(), header.get_value()))
+        .collect()
+}
+
+/// The username `address` names under the account's `routing`, whether or not there is such a
+/// user.
+fn routed_username<'a>(account: &Imap, address: &'a str) -> Option<&'a str> {
+    let (mailbox, host) = address.rsplit_once('@')?;
+    match account.routing {
+        ImapRouting::Host => host.strip_suffix(account.postfix.as_str()),
+        ImapRouting::Subaddress => mailbox.split_once('+').map(|(_, tag)| tag),
+    }
+}
+
+/// The user the first of the recipients `to` that routes anywhere, by the `routing` of an `imap`
+/// account or an alias, is for, along with that recipient.
+fn default_route<'a>(
+    config: &Config,
+    users: &'a [User],
+    to: &[String],
+) -> Option<(&'a User, String)> {
+    to.iter().find_map(|to_address| {
+        let accounts = config.imap.as_slice().iter();
+        for username in accounts.filter_map(|account| routed_username(account, to_address)) {
+            if let Some(user) = users.iter().find(|user| user.username == username) {
+                return Some((user, to_address.clone()));
+            }
+        }
+
+        users
+            .iter()
+            .find(|user| user.has_alias(to_address))
+            .map(|user| (user, to_address.clone()))
+    })
+}
+
+/// Whether `regex` is absent or matches `text`.
+fn rule_regex_matches(regex: Option<&RuleRegex>, text: &str) -> bool {
+    match regex {
+        Some(regex) => regex.is_match(text),
+        None => true,
+    }
+}
+
+/// The first of the recipients `to` that `rule` matches, if it matches the rest too.
+fn rule_matches(rule: &RoutingRule, from: &str, to: &[String], subject: &str) -> Option<String> {
+    if !rule_regex_matches(rule.from_regex.as_ref(), from)
+        || !rule_regex_matches(rule.subject_regex.as_ref(), subject)
+    {
+        return None;
+    }
+    to.iter()
+        .find(|to_address| rule_regex_matches(rule.to_regex.as_ref(), to_address))
+        .cloned()
+}
+
+/// The user an email from `from` to the recipients `to` about `subject` is for, along with the
+/// recipient that decided it: by the first of `routing_rules` that matches, or else by the
+/// `routing` of `imap` accounts and aliases. With a single user, everything is for them.
+pub fn route<'a>(
+    config: &'a Config,
+    from: &str,
+    to: &[String],
+    subject: &str,
+) -> Option<(&'a User, String)> {
+    let users = match &config.users {
+        Users::Many(users) => users,
+        Users::Single(user) => return to.first().map(|to_address| (user, to_address.clone())),
+    };
+
+    for rule in &config.routing_rules {
+        let Some(to_address) = rule_matches(rule, from, to, subject) else {
+            continue;
+        };
+        match users.iter().find(|user| user.username == rule.user) {
+            Some(user) => return Some((user, to_address)),
+            None => warn!(user = %rule.user, "Routing rule for an unknown user"),
+        }
+    }
+    default_route(config, users, to)
+}
+
+/// Every user an email from `from` to the recipients `to` about `subject` is for, each with the
+/// first recipient routed to them, in the order of the recipients. Each recipient is routed as
+/// by [`route`].
+pub fn route_all<'a>(
+    config: &'a Config,
+    from: &str,
+    to: &[String],
+    subject: &str,
+) -> Vec<(&'a User, String)> {
+    let mut routed: Vec<(&User, String)> = vec![];
+    for to_address in to {
+        let Some((user, to_address)) =
+            route(config, from, std::slice::from_ref(to_address), subject)
+        else {
+            continue;
+        };
+        if !routed
+            .iter()
+            .any(|(other, _)| other.username == user.username)
+        {
+            routed.push((user, to_address));
+        }
+    }
+    routed
+}
+
+/// Whether mail for `address` could be for a user, for refusing recipients before the sender
+/// and subject are known: it routes by the `routing` of an `imap` account or an alias, or a
+/// routing rule could match it.
+pub fn may_route(config: &Config, address: &str) -> bool {
+    let users = match &config.users {
+        Users::Many(users) => users,
+        Users::Single(_) => return true,
+    };
+    let to = [address.to_owned()];
+    config
+        .routing_rules
+        .iter()
+        .any(|rule| rule_regex_matches(rule.to_regex.as_ref(), address))
+        || default_route(config, users, &to).is_some()
+}
+
+/// Every address in header `name`, group members included.
+fn addresses(headers: &[MailHeader], name: &str) -> Vec<String> {
+    let Some(addrs) = headers
+        .get_first_header(name)
+        .and_then(|header| mailparse::addrparse_header(header).ok())
+    else {
+        return vec![];
+    };
+
+    addrs
+        .iter()
+        .flat_map(|mail_addr| match mail_addr {
+            MailAddr::Single(info) => std::slice::from_ref(info),
+            MailAddr::Group(group) => group.addrs.as_slice(),
+        })
+        .map(|info| info.addr.clone())
+        .collect()
+}
+
+/// The recipients of an email that did not come with an envelope, for routing: those in `To`,
+/// `Cc` and `Delivered-To`, in that order.
+pub fn recipients(headers: &[MailHeader]) -> Vec<String> {
+    ["To", "Cc", "Delivered-To"]
+        .into_iter()
+        .flat_map(|name| addresses(headers, name))
+        .collect()
+}
+
+/// The first address in header `name`, for emails that did not come with an IMAP envelope.
+pub fn first_address(headers: &[MailHeader], name: &str) -> Option<String> {
+    mailparse::addrparse_header(headers.get_first_header(name)?)
+        .ok()?
+        .iter()
+        .find_map(|addr| match addr {
+            MailAddr::Single(info) => Some(info.addr.clone()),
+            MailAddr::Group(group) => group.addrs.first().map(|info| info.addr.clone()),
+        })
+}
+
+/// The display name given for `addr` in the first of headers `names` that lists it.
+fn display_name(headers: &[MailHeader], names: &[&str], addr: &str) -> Option<String> {
+    let info = names
+        .iter()
+        .filter_map(|name| mailparse::addrparse_header(headers.get_first_header(name)?).ok())
+        .find_map(|addrs| {
+            addrs
+                .iter()
+                .flat_map(|mail_addr| match mail_addr {
+                    MailAddr::Single(info) => std::slice::from_ref(info),
+                    MailAddr::Group(group) => group.addrs.as_slice(),
+                })
+                .find(|info| info.addr.eq_ignore_ascii_case(addr))
+                .cloned()
+        })?;
+
+    info.display_name.filter(|name| !name.trim().is_empty())
+}
+
+/// The first message ID in header `name`, without its angle brackets. Some senders leave those
+/// out, so then the first word is taken.
+fn message_id_header(headers: &[MailHeader], name: &str) -> Option<String> {
+    let value = headers.get_first_value(name)?;
+    let value = value.trim();
+    let id = match value.find('<') {
+        Some(start) => {
+            let rest = &value[start + 1..];
+            &rest[..rest.find('>')?]
+        }
+        None => value.split_ascii_whitespace().next()?,
+    };
+    let id = id.trim();
+    (!id.is_empty()).then(|| id.to_owned())
+}
+
+/// The `Date` header as Unix ms.
+pub fn date_header(headers: &[MailHeader]) -> Option<i64> {
+    let date = headers.get_first_value("Date")?;
+    mailparse::dateparse(&date).ok().map(|secs| secs * 1000)
+}
+
+/// The sender's UTC offset in minutes, from the `Date` header.
+fn date_offset(headers: &[MailHeader]) -> Option<i64> {
+    let date = headers.get_first_value("Date")?;
+    DateTime::parse_from_rfc2822(date.trim())
+        .ok()
+        .map(|date| i64::from(date.offset().local_minus_utc() / 60))
+}
+
+/// The charset named by a `<meta>` tag near the start of `html`, found the way browsers prescan
+/// for it.
+fn meta_charset(html: &[u8]) -> Option<&'static Encoding> {
+    let head = String::from_utf8_lossy(&html[..html.len().min(1024)]).to_ascii_lowercase();
+    let start = head.find("charset=")? + "charset=".len();
+    let label: String = head[start..]
+        .trim_start_matches(['"', '\''])
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'))
+        .collect();
+    Encoding::for_label_no_replacement(label.as_bytes()).map(Encoding::output_encoding)
+}
+
+/// `part`'s body decoded with the charset its Content-Type declares. Without one, mailparse would
+/// assume US-ASCII; a `<meta>` charset is used instead, then UTF-8 if the bytes are valid UTF-8,
+/// then Windows-1252.
+fn decoded_body(part: &ParsedMail) -> Result<String, MailParseError> {
+    let raw = part.get_body_raw()?;
+    let encoding = part
+        .ctype
+        .params
+        .get("charset")
+        .and_then(|label| Encoding::for_label_no_replacement(label.as_bytes()))
+        .or_else(|| meta_charset(&raw))
+        .unwrap_or(if std::str::from_utf8(&raw).is_ok() {
+            UTF_8
+        } else {
+            WINDOWS_1252
+        });
+
+    let (decoded, _, had_errors) = encoding.decode(&raw);
+    if had_errors {
+        warn!(charset = encoding.name(), "Ingest body charset error");
+    }
+    Ok(decoded.into_owned())
+}
+
+fn extract_attachments(parsed: &ParsedMail, path_prefix: &str) -> Vec<ExtractedAttachment> {
+    let mut parts = vec![];
+    util::collect_mail(
+        parsed,
+        &mut |part| {
+            part.subparts.is_empty()
+                && part.get_content_disposition().disposition == DispositionType::Attachment
+        },
+        &mut parts,
+    );
+
+    parts
+        .into_iter()
+        .filter_map(|part| match part.get_body_raw() {
+            Ok(body) => Some((part, body)),
+            Err(e) => {
+                error!(error = ?e, "Ingest attachment body error");
+                None
+            }
+        })
+        .enumerate()
+        .map(|(idx, (part, body))| ExtractedAttachment {
+            filename: part
+                .get_content_disposition()
+                .params
+                .get("filename")
+                .or_else(|| part.ctype.params.get("name"))
+                .cloned(),
+            mime: part.ctype.mimetype.clone(),
+            path: format!("{}/attachments/{}", path_prefix, idx),
+            hash: util::sha3_hex(&body, 32),
+            body,
+            scan: None,
+            scan_signature: None,
+        })
+        .collect()
+}
+
+/// Images directly under a `multipart/related` part that have a `Content-ID`, which is how HTML
+/// bodies embed them as `cid:` URLs (RFC 2392).
+fn extract_inline_images(parsed: &ParsedMail, path_prefix: &str) -> Vec<ExtractedInlineImage> {
+    let mut related = vec![];
+    util::collect_mail(
+        parsed,
+        &mut |part| &part.ctype.mimetype == "multipart/related",
+        &mut related,
+    );
+
+    related
+        .into_iter()
+        .flat_map(|part| &part.subparts)
+        .filter(|part| part.ctype.mimetype.starts_with("image/"))
+        .filter_map(|part| {
+            let content_id = part.headers.get_first_value("Content-ID")?;
+            let content_id = content_id
+                .trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>');
+            match part.get_body_raw() {
+                Ok(body) => Some((content_id.to_owned(), part, body)),
+                Err(e) => {
+                    error!(error = ?e, "Ingest inline image body error");
+                    None
+                }
+            }
+        })
+        .enumerate()
+        .map(|(idx, (content_id, part, body))| ExtractedInlineImage {
+            content_id,
+            mime: part.ctype.mimetype.clone(),
+            path: format!("{}/inline/{}", path_prefix, idx),
+            body,
+        })
+        .collect()
+}
+
+/// Points `attribute` at the stored copy of the inline image its `cid:` URL names, if any.
+fn rewrite_cid(
+    el: &mut Element,
+    attribute: &str,
+    inline_images: &[ExtractedInlineImage],
+) -> HandlerResult {
+    let Some(value) = el.get_attribute(attribute) else {
+        return Ok(());
+    };
+    let value = value.trim();
+    if !value
+        .get(..4)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("cid:"))
+    {
+        return Ok(());
+    }
+    let content_id = &value[4..];
+    if let Some(idx) = inline_images
+        .iter()
+        .position(|image| image.content_id == content_id)
+    {
+        el.set_attribute(attribute, &format!("inline/{}", idx))?;
+    }
+    Ok(())
+}
+
+/// Replaces `cid:` URLs in `src` and `background` attributes with `inline/<idx>`, which resolves
+/// against `/emails/<id>/html` to the route serving the stored image.
+fn rewrite_cids(html: String, inline_images: &[ExtractedInlineImage]) -> String {
+    if inline_images.is_empty() {
+        return html;
+    }
+    let rewritten = rewrite_str(
+        &html,
+        RewriteStrSettings {
+            element_content_handlers: ["src", "background"]
+                .into_iter()
+                .map(|attribute| {
+                    element!(format!("[{}]", attribute), move |el| {
+                        rewrite_cid(el, attribute, inline_images)
+                    })
+                })
+                .collect(),
+            ..RewriteStrSettings::default()
+        },
+    );
+    match rewritten {
+        Ok(x) => x,
+        Err(e) => {
+            error!(error = ?e, "Ingest cid rewrite error");
+            html
+        }
+    }
+}
+
+/// Records clamd's verdict on every attachment. Scan failures are logged and recorded rather than
+/// failing ingestion, since clamd being down should not hold up email.
+async fn scan_attachments(clamd: &Clamd, email_id: &str, attachments: &mut [ExtractedAttachment]) {
+    for (idx, attachment) in attachments.iter_mut().enumerate() {
+        match clamd::scan(clamd, &attachment.body).await {
+            Ok(Verdict::Clean) => attachment.scan = Some("clean"),
+            Ok(Verdict::Infected(signature)) => {
+                warn!(email_id = %email_id, idx, signature = %signature, "Infected attachment");
+                attachment.scan = Some("infected");
+                attachment.scan_signature = Some(signature);
+            }
+            Err(e) => {
+                error!(error = ?e, email_id = %email_id, idx, "clamd scan error");
+                attachment.scan = Some("error");
+            }
+        }
+    }
+}
+
+async fn insert_attachments(
+    connection: &mut SqliteConnection,
+    email: &NewEmail,
+) -> Result<(), sqlx::Error> {
+    for (idx, attachment) in email.attachments.iter().enumerate() {
+        let idx = idx as i64;
+        let size = attachment.body.len() as i64;
+        sqlx::query!(
+            r#"INSERT INTO attachments (email_id, idx, filename, mime, size, path, hash, scan,
+                                        scan_signature)
+                       VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)"#,
+            email.id,
+            idx,
+            attachment.filename,
+            attachment.mime,
+            size,
+            attachment.path,
+            attachment.hash,
+            attachment.scan,
+            attachment.scan_signature
+        )
+        .execute(&mut *connection)
+        .await?;
+    }
+
+    for (idx, inline_image) in email.inline_images.iter().enumerate() {
+        let idx = idx as i64;
+        let size = inline_image.body.len() as i64;
+        sqlx::query!(
+            r#"INSERT INTO inline_images (email_id, idx, content_id, mime, size, path)
+                       VALUES ($1, $2, $3, $4, $5, $6)"#,
+            email.id,
+            idx,
+            inline_image.content_id,
+            inline_image.mime,
+            size,
+            inline_image.path
+        )
+        .execute(&mut *connection)
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn insert_captured_headers(
+    connection: &mut SqliteConnection,
+    email: &NewEmail,
+) -> Result<(), sqlx::Error> {
+    for (idx, (name, value)) in email.captured_headers.iter().enumerate() {
+        let idx = idx as i64;
+        sqlx::query!(
+            r#"INSERT INTO captured_headers (email_id, idx, name, value)
+                       VALUES ($1, $2, $3, $4)"#,
+            email.id,
+            idx,
+            name,
+            value
+        )
+        .execute(&mut *connection)
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn insert_email(
+    connection: &mut SqliteConnection,
+    email: &NewEmail,
+) -> Result<(), sqlx::Error> {
+    let now = util::unix_ms();
+
+    sqlx::query!(
+        r#"INSERT INTO emails (id, html, user, registered, subject, from_addr, to_addr, headers,
+                               sent, sent_offset, from_name, to_name, raw, message_id,
+                               in_reply_to, dkim, spf, sanitized_html)
+                   VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15,
+                           $16, $17, $18)"#,
+        email.id,
+        email.html,
+        email.user,
+        now,
+        email.subject,
+        email.from_addr,
+        email.to_addr,
+        email.headers,
+        email.sent,
+        email.sent_offset,
+        email.from_name,
+        email.to_name,
+        email.raw,
+        email.message_id,
+        email.in_reply_to,
+        email.dkim,
+        email.spf,
+        email.sanitized_html
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    insert_captured_headers(&mut *connection, email).await?;
+    insert_attachments(connection, email).await
+}
+
+/// Overwrites the rows derived from the raw message; the email keeps its id, addresses and
+/// registration time.
+async fn update_email(
+    connection: &mut SqliteConnection,
+    email: &NewEmail,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE emails SET subject = $2, headers = $3, sent = $4, sent_offset = $5,
+                             from_name = $6, to_name = $7, message_id = $8, in_reply_to = $9,
+                             sanitized_html = $10
+           WHERE id = $1"#,
+        email.id,
+        email.subject,
+        email.headers,
+        email.sent,
+        email.sent_offset,
+        email.from_name,
+        email.to_name,
+        email.message_id,
+        email.in_reply_to,
+        email.sanitized_html
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    sqlx::query!(
+        r#"DELETE FROM captured_headers WHERE email_id = $1"#,
+        email.id
+    )
+    .execute(&mut *connection)
+    .await?;
+    insert_captured_headers(&mut *connection, email).await?;
+
+    sqlx::query!(r#"DELETE FROM attachments WHERE email_id = $1"#, email.id)
+        .execute(&mut *connection)
+        .await?;
+    sqlx::query!(r#"DELETE FROM inline_images WHERE email_id = $1"#, email.id)
+        .execute(&mut *connection)
+        .await?;
+    insert_attachments(connection, email).await
+}
+
+/// Everything about an email that is derived from its raw message, except the HTML body.
+fn derive_email(
+    parsed: &ParsedMail,
+    id: String,
+    user: &str,
+    from_addr: String,
+    to_addr: String,
+    capture_headers: &[String],
+) -> Result<NewEmail, IngestError> {
+    let subject = parsed
+        .headers
+        .get_first_value("Subject")
+        .ok_or(IngestError::NoSubject)?;
+
+    Ok(NewEmail {
+        html: format!("{}/{}.html", user, id),
+        sanitized_html: format!("{}/{}.sanitized.html", user, id),
+        raw: format!("{}/{}.eml", user, id),
+        attachments: extract_attachments(parsed, &format!("{}/{}", user, id)),
+        inline_images: extract_inline_images(parsed, &format!("{}/{}", user, id)),
+        id,
+        user: user.to_owned(),
+        subject,
+        from_name: display_name(&parsed.headers, &["From"], &from_addr),
+        to_name: display_name(&parsed.headers, &["To", "Cc"], &to_addr),
+        message_id: message_id_header(&parsed.headers, "Message-ID"),
+        in_reply_to: message_id_header(&parsed.headers, "In-Reply-To"),
+        dkim: None,
+        spf: None,
+        from_addr,
+        to_addr,
+        headers: headers_json(parsed),
+        captured_headers: captured_headers(parsed, capture_headers),
+        sent: date_header(&parsed.headers),
+        sent_offset: date_offset(&parsed.headers),
+    })
+}
+
+/// The `text/html` part, or else the first `text/plain` part that is not an attachment as
+/// `fallback` says.
+fn extract_html(parsed: &ParsedMail, fallback: PlainTextFallback) -> Result<String, IngestError> {
+    if let Some(html) = util::traverse_mail(parsed, &mut |mail| &mail.ctype.mimetype == "text/html")
+    {
+        return decoded_body(html).map_err(IngestError::Parse);
+    }
+    if fallback == PlainTextFallback::Off {
+        return Err(IngestError::NoBody);
+    }
+
+    let text = util::traverse_mail(parsed, &mut |mail| {
+        &mail.ctype.mimetype == "text/plain"
+            && mail.get_content_disposition().disposition != DispositionType::Attachment
+    })
+    .ok_or(IngestError::NoBody)?;
+    let text = decoded_body(text).map_err(IngestError::Parse)?;
+
+    Ok(match fallback {
+        PlainTextFallback::Wrap => format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"></head><body>\
+             <pre style=\"white-space: pre-wrap\">{}</pre></body></html>\n",
+            util::html_escape(&text)
+        ),
+        PlainTextFallback::Raw | PlainTextFallback::Off => text,
+    })
+}
+
+/// Stages every file in `files`, discarding those already staged if one fails.
+async fn stage_files<'a>(
+    config: &Config,
+    user: &str,
+    files: impl Iterator<Item = (&'a str, &'a [u8])>,
+) -> Result<Vec<PendingWrite>, IngestError> {
+    let mut pending_files = vec![];
+    for (name, contents) in files {
+        match storage::stage(&config.storage, user, name, contents).await {
+            Ok(x) => pending_files.push(x),
+            Err(e) => {
+                storage::discard_all(pending_files).await;
+                return Err(IngestError::Io(e));
+            }
+        }
+    }
+    Ok(pending_files)
+}
+
+/// Commits every file in `pending_files`. If one fails, those already committed are removed
+/// again and the rest, the failed one included, discarded.
+async fn commit_files(pending_files: Vec<PendingWrite>) -> Result<(), IngestError> {
+    let mut committed = vec![];
+    let mut pending_files = pending_files.into_iter();
+    while let Some(pending_file) = pending_files.next() {
+        if let Err(e) = pending_file.commit().await {
+            for committed_file in committed {
+                committed_file.revert().await;
+            }
+            pending_file.discard().await;
+            storage::discard_all(pending_files.collect()).await;
+            return Err(IngestError::Io(e));
+        }
+        committed.push(pending_file);
+    }
+    Ok(())
+}
+
+/// Attachments and inline images.
+fn attachment_files(email: &NewEmail) -> impl Iterator<Item = (&str, &[u8])> {
+    let attachments = email
+        .attachments
+        .iter()
+        .map(|attachment| (attachment.path.as_str(), attachment.body.as_slice()));
+    let inline_images = email
+        .inline_images
+        .iter()
+        .map(|image| (image.path.as_str(), image.body.as_slice()));
+    attachments.chain(inline_images)
+}
+
+/// The id of `raw` for `user`: the hash of the message and username, so each user it is routed to
+/// stores it once, whatever order its recipients come in.
+fn email_id(raw: &[u8], user: &str) -> String {
+    util::sha3_hex(&[raw, b"\0", user.as_bytes()].concat(), 16)
+}
+
+/// Stores the raw RFC822 message `raw` for `user`, unless they already have it. Files are staged
+/// before the row is inserted and removed again if the transaction fails, so an error never
+/// leaves half an email behind.
+pub async fn store(
+    config: &Config,
+    pool: &Pool<Sqlite>,
+    user: &str,
+    from_addr: String,
+    to_addr: String,
+    raw: &[u8],
+) -> Result<Ingested, IngestError> {
+    let parsed = mailparse::parse_mail(raw).map_err(IngestError::Parse)?;
+
+    let id = email_id(raw, user);
+    // Emails stored before ids included the username have the hash of the message alone.
+    let legacy_id = util::sha3_hex(raw, 16);
+
+    if let Some(existing) = sqlx::query!(
+        r#"SELECT id FROM emails WHERE user = $1 AND id IN ($2, $3)"#,
+        user,
+        id,
+        legacy_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(IngestError::Sql)?
+    {
+        return Ok(Ingested::Duplicate(existing.id));
+    }
+
+    let mut new_email = derive_email(
+        &parsed,
+        id,
+        user,
+        from_addr,
+        to_addr,
+        &config.ingest.capture_headers,
+    )?;
+    let html_body = extract_html(&parsed, config.ingest.plain_text)?;
+    if config.ingest.authentication {
+        // SPF is checked against the envelope sender, which the receiving server records here.
+        let sender = first_address(&parsed.headers, "Return-Path")
+            .unwrap_or_else(|| new_email.from_addr.clone());
+        let verified = authentication::verify(
+            raw,
+            &parsed.headers,
+            &sender,
+            &config.ingest.trusted_networks,
+        )
+        .await;
+        new_email.dkim = Some(verified.dkim.as_str());
+        new_email.spf = Some(verified.spf.as_str());
+    }
+    let html_body = rewrite_cids(html_body, &new_email.inline_images);
+    let html_body = snapshot::apply(config, html_body).await;
+    let sanitized_body = sanitize::sanitize(&html_body);
+    if let Some(clamd) = &config.clamd {
+        scan_attachments(clamd, &new_email.id, &mut new_email.attachments).await;
+    }
+
+    let files = [
+        (new_email.html.as_str(), html_body.as_bytes()),
+        (new_email.sanitized_html.as_str(), sanitized_body.as_bytes()),
+        (new_email.raw.as_str(), raw),
+    ];
+    let pending_files = stage_files(
+        config,
+        user,
+        files.into_iter().chain(attachment_files(&new_email)),
+    )
+    .await?;
+
+    let mut transaction = match pool.begin().await {
+        Ok(x) => x,
+        Err(e) => {
+            storage::discard_all(pending_files).await;
+            return Err(IngestError::Sql(e));
+        }
+    };
+
+    if let Err(e) = insert_email(&mut transaction, &new_email).await {
+        storage::discard_all(pending_files).await;
+        return Err(IngestError::Sql(e));
+    }
+
+    commit_files(pending_files).await?;
+
+    if let Err(e) = transaction.commit().await {
+        let stored_files = [
+            new_email.html.as_str(),
+            new_email.sanitized_html.as_str(),
+            new_email.raw.as_str(),
+        ]
+        .into_iter()
+        .chain(attachment_files(&new_email).map(|(name, _)| name));
+        for name in stored_files {
+            if let Err(e) = storage::remove(&config.storage, user, name).await {
+                error!(error = ?e, "Ingest file rollback error");
+            }
+        }
+        return Err(IngestError::Sql(e));
+    }
+
+    let bytes = html_body.len()
+        + sanitized_body.len()
+        + raw.len()
+        + attachment_files(&new_email)
+            .map(|(_, body)| body.len())
+            .sum::<usize>();
+    if let Err(e) = sql::record_usage(pool, user, UsageMetric::EmailIngested, 1, bytes as i64).await
+    {
+        error!(error = ?e, "Ingest usage accounting error");
+    }
+
+    Ok(Ingested::Stored(new_email.id))
+}
+
+/// Derives `email`'s rows, HTML and attachments from its stored raw message again, as `store`
+/// would today. Running it twice leaves the same result as running it once. HTML snapshotted
+/// with `inline` is kept as stored, since the resources it embedded may be gone by now, and
+/// sanitized again from there.
+///
+/// Returns `false` for emails stored before raw messages were kept.
+pub async fn replay(
+    config: &Config,
+    pool: &Pool<Sqlite>,
+    email: &Email,
+) -> Result<bool, IngestError> {
+    let Some(raw_path) = &email.raw else {
+        return Ok(false);
+    };
+    let raw = storage::read(&config.storage, &email.user, raw_path)
+        .await
+        .map_err(IngestError::Io)?;
+    let parsed = mailparse::parse_mail(&raw).map_err(IngestError::Parse)?;
+
+    let mut new_email = derive_email(
+        &parsed,
+        email.id.clone(),
+        &email.user,
+        email.from_addr.clone(),
+        email.to_addr.clone(),
+        &config.ingest.capture_headers,
+    )?;
+    let html_body = match config.snapshot.mode {
+        SnapshotMode::Inline => None,
+        SnapshotMode::Off | SnapshotMode::Strip => {
+            let html_body = extract_html(&parsed, config.ingest.plain_text)?;
+            let html_body = rewrite_cids(html_body, &new_email.inline_images);
+            Some(snapshot::apply(config, html_body).await)
+        }
+    };
+    let sanitized_body = match &html_body {
+        Some(html_body) => sanitize::sanitize(html_body),
+        None => {
+            let stored = storage::read_to_string(&config.storage, &email.user, &email.html)
+                .await
+                .map_err(IngestError::Io)?;
+            sanitize::sanitize(&stored)
+        }
+    };
+    if let Some(clamd) = &config.clamd {
+        scan_attachments(clamd, &new_email.id, &mut new_email.attachments).await;
+    }
+
+    let old_attachments = sql::email_attachments(pool, &email.id)
+        .await
+        .map_err(IngestError::Sql)?;
+    let old_inline_images = sql::email_inline_images(pool, &email.id)
+        .await
+        .map_err(IngestError::Sql)?;
+
+    let html_file = html_body
+        .as_deref()
+        .map(|html_body| (email.html.as_str(), html_body.as_bytes()));
+    let sanitized_file = (new_email.sanitized_html.as_str(), sanitized_body.as_bytes());
+    let pending_files = stage_files(
+        config,
+        &email.user,
+        html_file
+            .into_iter()
+            .chain([sanitized_file])
+            .chain(attachment_files(&new_email)),
+    )
+    .await?;
+
+    let mut transaction = match pool.begin().await {
+        Ok(x) => x,
+        Err(e) => {
+            storage::discard_all(pending_files).await;
+            return Err(IngestError::Sql(e));
+        }
+    };
+
+    if let Err(e) = update_email(&mut transaction, &new_email).await {
+        storage::discard_all(pending_files).await;
+        return Err(IngestError::Sql(e));
+    }
+
+    commit_files(pending_files).await?;
+    transaction.commit().await.map_err(IngestError::Sql)?;
+
+    let old_paths = old_attachments
+        .into_iter()
+        .map(|attachment| attachment.path)
+        .chain(old_inline_images.into_iter().map(|image| image.path));
+    for old_path in old_paths {
+        if attachment_files(&new_email).all(|(path, _)| path != old_path) {
+            if let Err(e) = storage::remove(&config.storage, &email.user, &old_path).await {
+                error!(error = ?e, email_id = %email.id, "Replay stale attachment remove error");
+            }
+        }
+    }
+
+    Ok(true)
+}
@@ -0,0 +1,52 @@
+use crate::config::{ErrorReporting, LogFormat, Logging};
+use sentry::ClientInitGuard;
+use std::borrow::Cow;
+use std::env;
+use std::io;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+/// `RUST_LOG`, when set, takes precedence over the configured level and targets.
+fn env_filter(logging: &Logging) -> EnvFilter {
+    match env::var("RUST_LOG") {
+        Ok(directives) => EnvFilter::new(directives),
+        Err(_) => EnvFilter::new(logging.directives()),
+    }
+}
+
+/// Error events are only reported for as long as the returned guard is alive. Filters apply per
+/// layer so that the tokio-console layer, when enabled, still sees the runtime's trace events.
+pub fn init(
+    logging: &Logging,
+    error_reporting: Option<&ErrorReporting>,
+) -> Option<ClientInitGuard> {
+    let fmt_layer = tracing_subscriber::fmt::layer().with_writer(io::stderr);
+    let fmt_layer = match logging.format {
+        LogFormat::Pretty => fmt_layer.boxed(),
+        LogFormat::Json => fmt_layer.json().boxed(),
+    };
+
+    let guard = error_reporting.map(|error_reporting| {
+        sentry::init((
+            error_reporting.dsn.as_str(),
+            sentry::ClientOptions {
+                release: sentry::release_name!(),
+                environment: error_reporting.environment.clone().map(Cow::Owned),
+                sample_rate: error_reporting.sample_rate,
+                ..Default::default()
+            },
+        ))
+    });
+
+    let registry = tracing_subscriber::registry()
+        .with(fmt_layer.with_filter(env_filter(logging)))
+        .with(
+            guard
+                .as_ref()
+                .map(|_| sentry_tracing::layer().with_filter(env_filter(logging))),
+        );
+    #[cfg(feature = "tokio-console")]
+    let registry = registry.with(console_subscriber::spawn());
+    registry.init();
+
+    guard
+}
@@ -1,92 +1,325 @@
+mod alerts;
 mod api;
+mod authentication;
+mod clamd;
+mod cli;
+mod commands;
 mod config;
 mod error_handling;
+mod eval;
+mod fetch_budget;
+mod frontend;
 mod imap;
+mod ingest;
+mod logging;
+mod maintenance;
+mod oauth2;
+mod plugins;
+mod pop3;
+mod proxy;
 mod rocket_types;
+mod sanitize;
+mod smtp;
+mod snapshot;
 mod sql;
+mod startup;
+mod status;
+mod storage;
+mod systemd;
+mod triggers;
+mod unsubscribe;
 mod util;
 
 use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::time::Instant;
 
-use rocket::{
-    fs::{FileServer, Options as FsOptions},
-    Config as RocketConfig,
-};
+use rocket::{fairing::AdHoc, Config as RocketConfig};
 use sqlx::{Pool, Sqlite};
 
-use sqlx::sqlite::SqlitePoolOptions;
-
+use arc_swap::ArcSwap;
 use dashmap::DashMap;
 
 use url::Url;
 
-use config::Config;
-use util::Cache;
+use clap::Parser;
+
+use tracing::{error, info};
 
-pub type ManagedConfig = Arc<Config>;
+use cli::{Cli, Command, UserCommand};
+use config::{Config, IngestProtocol};
+use rocket_types::Traced;
+use status::Status;
+use systemd::Readiness;
+use util::{Cache, WorkerPool};
+
+pub type ManagedConfig = Arc<ArcSwap<Config>>;
 pub type ManagedPool = Pool<Sqlite>;
-pub type ManagedRatelimits = Arc<DashMap<IpAddr, Vec<Instant>>>;
-pub type ManagedUrlCache = Cache<Url, Url, 1000>;
+pub type ManagedRatelimits = Arc<DashMap<(IpAddr, &'static str), Vec<Instant>>>;
+/// `None` marks a URL whose fetch failed recently.
+pub type ManagedUrlCache = Cache<Url, Option<Url>>;
+pub type ManagedStatus = Arc<Status>;
+pub type ManagedPlugins = Arc<plugins::Plugins>;
+pub type ManagedFetchBudget = Arc<fetch_budget::FetchBudget>;
 
 #[tokio::main]
 async fn main() {
-    let config = Arc::new(config::load_config().await);
+    let cli = Cli::parse();
+    let config_path = config::resolve_config_path(cli.config);
+
+    let result = match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            serve(config_path).await;
+            Ok(())
+        }
+        Command::Migrate => commands::migrate(&command_config(&config_path).await).await,
+        Command::User {
+            command:
+                UserCommand::Add {
+                    username,
+                    password,
+                    admin,
+                },
+        } => commands::add_user(&config_path, username, password, admin).await,
+        Command::Import {
+            user,
+            mbox,
+            maildir,
+            paths,
+        } => {
+            let sources = commands::ImportSources {
+                eml: paths,
+                mbox,
+                maildir,
+            };
+            commands::import(
+                &command_config(&config_path).await,
+                user.as_deref(),
+                sources,
+            )
+            .await
+        }
+        Command::Backfill { account, mailbox } => {
+            commands::backfill(
+                &command_config(&config_path).await,
+                account.as_deref(),
+                mailbox.as_deref(),
+            )
+            .await
+        }
+        Command::Replay { user, email } => {
+            commands::replay(&command_config(&config_path).await, user, email).await
+        }
+        Command::Backup { destination } => {
+            commands::backup(&command_config(&config_path).await, &destination).await
+        }
+        Command::CheckConfig => commands::check_config(&config_path).await,
+        Command::ConfigSchema => {
+            let schema = schemars::schema_for!(Config);
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&schema).expect("Unable to serialize config schema")
+            );
+            Ok(())
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+/// Loads the config for a one-off command, exiting if it is invalid.
+async fn command_config(config_path: &Path) -> Config {
+    let config = config::load_config(config_path).await;
+    logging::init(&config.logging, None);
+    config
+}
+
+async fn serve(config_path: PathBuf) {
+    let managed_config: ManagedConfig = Arc::new(ArcSwap::from_pointee(
+        config::load_config(&config_path).await,
+    ));
+    let config = managed_config.load_full();
+    let _error_reporting = logging::init(&config.logging, config.error_reporting.as_ref());
+    storage::cipher(&config.storage).expect("Invalid storage.encryption_key");
+
     let ratelimits: ManagedRatelimits = Arc::new(DashMap::new());
-    let url_cache = ManagedUrlCache::new();
+    let status = ManagedStatus::default();
+    // Rocket's liftoff and each IMAP or POP3 account's first session, or the SMTP listener.
+    let ingest_components = if config.ingest.protocol.polls() {
+        config.imap.as_slice().len()
+    } else {
+        1
+    };
+    let readiness = Arc::new(Readiness::new(1 + ingest_components));
+    let url_cache = ManagedUrlCache::new(
+        config.url_cache.capacity,
+        config.url_cache.ttl_secs.map(Duration::from_secs),
+    );
 
-    let pool = SqlitePoolOptions::new()
-        .max_connections(32)
-        .min_connections(1)
-        .connect(&config.storage.sqlite)
+    let plugins: ManagedPlugins =
+        Arc::new(plugins::Plugins::new().expect("Unable to initialize plugin engine"));
+
+    let fetch_budget = ManagedFetchBudget::default();
+
+    let pool = sql::connect(&config.storage)
         .await
         .expect("Unable to connect to DB");
 
-    let config_imap = Arc::clone(&config);
-    let pool_imap = pool.clone();
-    tokio::spawn(imap::perform(config_imap, pool_imap));
+    let problems = startup::diagnose(&config, &pool).await;
+    if !problems.is_empty() {
+        for problem in problems {
+            error!("Startup check failed: {}", problem);
+        }
+        process::exit(1);
+    }
 
-    rocket::custom(
+    sql::MIGRATOR
+        .run(&pool)
+        .await
+        .expect("Unable to run migrations");
+    startup::adopt_legacy_account_state(&config, &pool).await;
+    api::execute_script::restore_url_cache(&url_cache, &pool, &config).await;
+
+    tokio::spawn(config::reload_on_sighup(
+        Arc::clone(&managed_config),
+        config_path,
+    ));
+
+    let rocket = rocket::custom(
         RocketConfig::figment()
             .merge(("port", 57331))
             .merge(("ident", false))
             .merge(("cli_colors", false)),
     )
-    .manage(Arc::clone(&config))
-    .manage(pool)
+    .manage(Arc::clone(&managed_config))
+    .manage(pool.clone())
     .manage(ratelimits)
-    .manage(url_cache)
+    .manage(url_cache.clone())
+    .manage(Arc::clone(&plugins))
+    .manage(Arc::clone(&fetch_budget))
+    .manage(Arc::clone(&status))
+    .attach(AdHoc::on_liftoff("systemd readiness", {
+        let readiness = Arc::clone(&readiness);
+        move |_| {
+            readiness.component_ready();
+            Box::pin(async {})
+        }
+    }))
     .mount(
         "/api",
-        rocket::routes![
+        Traced::wrap(rocket::routes![
             api::list_emails,
             api::view_email,
             api::execute_script::execute_script,
             api::list_macros,
             api::get_macro,
             api::verify_auth,
-            api::get_email
-        ],
-    )
-    .mount(
-        "/",
-        FileServer::new(
-            config.storage.frontend.to_string(),
-            FsOptions::Index | FsOptions::NormalizeDirs,
-        ),
+            api::get_email,
+            api::get_email_flags,
+            api::put_email_flags,
+            api::list_attachments,
+            api::get_attachment,
+            api::get_inline_image,
+            api::unsubscribe_email,
+            api::scripts::list_scripts,
+            api::scripts::list_script_runs,
+            api::scripts::get_script_run_output,
+            api::scripts::get_script,
+            api::scripts::put_script,
+            api::scripts::delete_script,
+            api::scripts::list_script_triggers,
+            api::scripts::get_script_trigger,
+            api::scripts::put_script_trigger,
+            api::scripts::delete_script_trigger,
+            api::scripts::export_script,
+            api::scripts::import_script,
+            api::status::get_status,
+            api::usage::get_usage,
+            api::admin::get_stats,
+            api::admin::get_diagnostics,
+            api::admin::get_caches,
+            api::admin::invalidate_cache
+        ]),
     )
+    .mount("/", frontend::Frontend::new(&config.storage.frontend))
     .register(
         "/",
         rocket::catchers![
             error_handling::unauthorized,
+            error_handling::forbidden,
             error_handling::internal_server_error,
             error_handling::not_found,
             error_handling::too_many_requests
         ],
     )
-    .launch()
+    .ignite()
     .await
-    .expect("Failed to launch Rocket");
+    .expect("Failed to ignite Rocket");
+
+    // Background tasks only stop between units of work, so waiting for them after Rocket has
+    // drained its requests leaves no half-written emails or files behind.
+    let shutdown = rocket.shutdown();
+
+    let config_imap = Arc::clone(&managed_config);
+    let pool_imap = pool.clone();
+    let triggers = triggers::Triggers {
+        pool: pool.clone(),
+        url_cache,
+        plugins,
+        fetch_budget,
+        status: Arc::clone(&status),
+        workers: WorkerPool::new(triggers::CONCURRENT_FIRES),
+    };
+    let imap_task = match config.ingest.protocol {
+        IngestProtocol::Imap => tokio::spawn(imap::perform(
+            config_imap,
+            pool_imap,
+            status,
+            triggers,
+            readiness,
+            shutdown.clone(),
+        )),
+        IngestProtocol::Pop3 => tokio::spawn(pop3::perform(
+            config_imap,
+            pool_imap,
+            status,
+            triggers,
+            readiness,
+            shutdown.clone(),
+        )),
+        IngestProtocol::Smtp | IngestProtocol::Lmtp => tokio::spawn(smtp::perform(
+            config_imap,
+            pool_imap,
+            status,
+            triggers,
+            readiness,
+            shutdown.clone(),
+        )),
+    };
+
+    let config_maintenance = Arc::clone(&managed_config);
+    let pool_maintenance = pool.clone();
+    let maintenance_task = tokio::spawn(maintenance::perform(
+        config_maintenance,
+        pool_maintenance,
+        shutdown,
+    ));
+
+    rocket.launch().await.expect("Failed to launch Rocket");
+    systemd::stopping();
+
+    for (name, task) in [("IMAP", imap_task), ("Maintenance", maintenance_task)] {
+        if let Err(e) = task.await {
+            error!(error = ?e, "{} task error", name);
+        }
+    }
+    pool.close().await;
+    info!("Shut down");
 }
@@ -0,0 +1,221 @@
+use crate::{config::Config, sql, storage, util, ManagedConfig};
+use rocket::Shutdown;
+use sqlx::{Pool, Sqlite};
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::time::{self, Instant};
+use tracing::{error, info, warn};
+
+/// Files younger than this may belong to an ingestion that has not inserted its row yet.
+const ORPHAN_GRACE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Default)]
+pub struct ReconcileReport {
+    pub orphan_files: Vec<String>,
+    pub orphan_rows: Vec<String>,
+}
+
+async fn run_statement(pool: &Pool<Sqlite>, statement: &str) {
+    let started = Instant::now();
+    match sqlx::query(statement).execute(pool).await {
+        Ok(_) => info!(
+            statement,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "Maintenance statement finished"
+        ),
+        Err(e) => error!(error = ?e, statement, "Maintenance statement error"),
+    }
+}
+
+/// Switches the database to incremental auto-vacuum, without which `PRAGMA incremental_vacuum`
+/// frees nothing. The switch only takes effect with a full `VACUUM`, which is run once, the
+/// first time the database is found in another mode.
+async fn enable_incremental_vacuum(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    // The mode and the `VACUUM` applying it must be on the same connection.
+    let mut connection = pool.acquire().await?;
+    let mode = sqlx::query_scalar::<_, i64>("PRAGMA auto_vacuum")
+        .fetch_one(&mut *connection)
+        .await?;
+    // 2 is INCREMENTAL.
+    if mode == 2 {
+        return Ok(());
+    }
+
+    info!(
+        mode,
+        "Switching to incremental auto-vacuum with a full VACUUM"
+    );
+    sqlx::query("PRAGMA auto_vacuum = INCREMENTAL")
+        .execute(&mut *connection)
+        .await?;
+    sqlx::query("VACUUM").execute(&mut *connection).await?;
+    Ok(())
+}
+
+async fn list_stored_files(file_root: &str) -> Vec<String> {
+    let mut files = vec![];
+
+    let mut user_dirs = match fs::read_dir(file_root).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!(error = ?e, file_root, "Reconcile read_dir error");
+            return files;
+        }
+    };
+
+    while let Ok(Some(user_dir)) = user_dirs.next_entry().await {
+        let user_name = user_dir.file_name().to_string_lossy().into_owned();
+
+        // Attachments and inline images are in `<user>/<id>/` and script run outputs in
+        // `<user>/runs/`, so every level below is listed.
+        let mut dirs = vec![(user_dir.path(), user_name)];
+        while let Some((dir, name)) = dirs.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let Ok(metadata) = entry.metadata().await else {
+                    continue;
+                };
+                let entry_name = format!("{}/{}", name, entry.file_name().to_string_lossy());
+                if metadata.is_dir() {
+                    dirs.push((entry.path(), entry_name));
+                    continue;
+                }
+                if !metadata.is_file() {
+                    continue;
+                }
+
+                let old_enough = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                    .is_some_and(|age| age >= ORPHAN_GRACE);
+                if old_enough {
+                    files.push(entry_name);
+                }
+            }
+        }
+    }
+
+    files
+}
+
+pub async fn reconcile(
+    config: &Config,
+    pool: &Pool<Sqlite>,
+    dry_run: bool,
+) -> Result<ReconcileReport, sqlx::Error> {
+    let rows = sqlx::query!(r#"SELECT id, user, html, sanitized_html, raw FROM emails"#)
+        .fetch_all(pool)
+        .await?;
+    let part_paths = sqlx::query_scalar!(
+        r#"SELECT path FROM attachments UNION ALL SELECT path FROM inline_images"#
+    )
+    .fetch_all(pool)
+    .await?;
+    let run_outputs =
+        sqlx::query_scalar!(r#"SELECT output_path FROM script_runs WHERE output_path IS NOT NULL"#)
+            .fetch_all(pool)
+            .await?;
+
+    let mut report = ReconcileReport::default();
+
+    let known_files: HashSet<&str> = rows
+        .iter()
+        .flat_map(|row| {
+            std::iter::once(row.html.as_str())
+                .chain(row.sanitized_html.as_deref())
+                .chain(row.raw.as_deref())
+        })
+        .chain(part_paths.iter().map(String::as_str))
+        .chain(run_outputs.iter().flatten().map(String::as_str))
+        .collect();
+    for file in list_stored_files(&config.storage.file_root).await {
+        if !known_files.contains(file.as_str()) {
+            report.orphan_files.push(file);
+        }
+    }
+
+    for row in &rows {
+        let path = match storage::user_path(&config.storage, &row.user, &row.html) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(error = ?e, id = %row.id, "Reconcile invalid path");
+                continue;
+            }
+        };
+        if let Ok(false) = fs::try_exists(&path).await {
+            report.orphan_rows.push(row.id.clone());
+        }
+    }
+
+    for file in &report.orphan_files {
+        warn!(file = %file, "Reconcile orphan file");
+        if !dry_run {
+            let user = file.split('/').next().unwrap_or_default();
+            if let Err(e) = storage::remove(&config.storage, user, file).await {
+                error!(error = ?e, "Reconcile remove_file error");
+            }
+        }
+    }
+
+    for id in &report.orphan_rows {
+        warn!(id = %id, "Reconcile orphan row");
+        if !dry_run {
+            sqlx::query!(r#"DELETE FROM emails WHERE id = $1"#, id)
+                .execute(pool)
+                .await?;
+        }
+    }
+
+    Ok(report)
+}
+
+pub async fn perform(managed_config: ManagedConfig, pool: Pool<Sqlite>, shutdown: Shutdown) {
+    loop {
+        let interval = managed_config.load().maintenance.interval_secs;
+        tokio::select! {
+            _ = time::sleep(Duration::from_secs(interval)) => {}
+            _ = shutdown.clone() => return,
+        }
+
+        let config = managed_config.load_full();
+
+        if config.maintenance.vacuum {
+            match enable_incremental_vacuum(&pool).await {
+                Ok(()) => run_statement(&pool, "PRAGMA incremental_vacuum").await,
+                Err(e) => error!(error = ?e, "Auto-vacuum switch error"),
+            }
+        }
+
+        if config.maintenance.analyze {
+            run_statement(&pool, "ANALYZE").await;
+        }
+
+        run_statement(&pool, "PRAGMA optimize").await;
+
+        if config.url_cache.persist && config.url_cache.ttl_secs.is_some() {
+            let before = config.url_cache.expired_before(util::unix_ms());
+            match sql::prune_url_redirects(&pool, before).await {
+                Ok(pruned) => info!(pruned, "URL redirect prune finished"),
+                Err(e) => error!(error = ?e, "URL redirect prune error"),
+            }
+        }
+
+        if config.maintenance.reconcile {
+            match reconcile(&config, &pool, config.maintenance.reconcile_dry_run).await {
+                Ok(report) => info!(
+                    orphan_files = report.orphan_files.len(),
+                    orphan_rows = report.orphan_rows.len(),
+                    dry_run = config.maintenance.reconcile_dry_run,
+                    "Reconcile finished"
+                ),
+                Err(e) => error!(error = ?e, "Reconcile error"),
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{
+    api::execute_script::http_client,
+    config::{Http, ImapOAuth2},
+};
+use async_imap::Authenticator;
+use serde::Deserialize;
+use std::time::Duration;
+
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum TokenError {
+    Http(reqwest::Error),
+    Json(serde_json::Error),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Tokens {
+    pub access_token: String,
+    /// A replacement for the refresh token used, when the provider rotates them.
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+}
+
+/// The refresh token to use next: the configured one, unless the provider has replaced it since.
+/// Replacements only live in memory, so they are lost on restart.
+#[derive(Debug, Default)]
+pub struct RefreshToken {
+    /// The configured token and what replaced it.
+    rotated: Option<(String, String)>,
+}
+impl RefreshToken {
+    pub fn current<'a>(&'a self, configured: &'a str) -> &'a str {
+        match &self.rotated {
+            Some((replaced, replacement)) if replaced == configured => replacement,
+            _ => configured,
+        }
+    }
+
+    pub fn rotate(&mut self, configured: &str, replacement: String) {
+        self.rotated = Some((configured.to_owned(), replacement));
+    }
+}
+
+/// Exchanges `refresh_token` for an access token with the refresh-token grant (RFC 6749 §6).
+pub async fn refresh(
+    http: &Http,
+    oauth2: &ImapOAuth2,
+    refresh_token: &str,
+) -> Result<Tokens, TokenError> {
+    let mut form = vec![
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+        ("client_id", oauth2.client_id.as_str()),
+    ];
+    if let Some(client_secret) = &oauth2.client_secret {
+        form.push(("client_secret", client_secret.as_str()));
+    }
+    if let Some(scope) = &oauth2.scope {
+        form.push(("scope", scope.as_str()));
+    }
+
+    let body = http_client(http)
+        .map_err(TokenError::Http)?
+        .post(&oauth2.token_url)
+        .form(&form)
+        .timeout(TOKEN_TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(TokenError::Http)?
+        .bytes()
+        .await
+        .map_err(TokenError::Http)?;
+
+    serde_json::from_slice(&body).map_err(TokenError::Json)
+}
+
+/// The SASL `XOAUTH2` mechanism as Gmail and Office 365 implement it.
+pub struct XOAuth2 {
+    user: String,
+    access_token: String,
+    sent: bool,
+}
+impl XOAuth2 {
+    pub fn new(user: impl Into<String>, access_token: impl Into<String>) -> Self {
+        XOAuth2 {
+            user: user.into(),
+            access_token: access_token.into(),
+            sent: false,
+        }
+    }
+}
+impl Authenticator for XOAuth2 {
+    type Response = String;
+
+    /// A rejected token is answered with a challenge holding the error, which the client must
+    /// acknowledge with an empty response for the server to fail the login.
+    fn process(&mut self, _challenge: &[u8]) -> Self::Response {
+        if self.sent {
+            return String::new();
+        }
+        self.sent = true;
+        format!(
+            "user={}\x01auth=Bearer {}\x01\x01",
+            self.user, self.access_token
+        )
+    }
+}
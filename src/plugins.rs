@@ -0,0 +1,124 @@
+use crate::{api::execute_script::SerdeElement, config::Plugin};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::SystemTime;
+use wasmtime::{
+    Config as EngineConfig, Engine, Instance, Module, Store, StoreLimits, StoreLimitsBuilder,
+};
+
+/// Larger outputs are rejected rather than copied out of the module.
+const MAX_OUTPUT_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Serialize)]
+struct PluginInput<'a> {
+    element: &'a SerdeElement,
+    arguments: &'a Value,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "value")]
+pub enum PluginOutput {
+    Html(String),
+    Text(String),
+    Url(String),
+    /// ISO-8601 local date and time, `YYYY-MM-DDTHH:MM:SS`.
+    Date(String),
+}
+
+/// Compiled plugin modules, shared by every script execution and recompiled when their file
+/// changes.
+///
+/// A module imports nothing and exports:
+///
+/// - `memory`, its linear memory;
+/// - `epv_alloc(len: i32) -> i32`, returning where the host may write `len` bytes of input;
+/// - `epv_run(ptr: i32, len: i32) -> i64`, which reads its input from there and returns where
+///   its output lies, with the pointer in the upper 32 bits and the length in the lower 32.
+///
+/// The input is the JSON object `{"element": <element>, "arguments": <arguments>}`, where an
+/// element looks like it does in script output. The output is a JSON array of elements, each
+/// `Html`, `Text`, `Url` or `Date`. Every call gets a fresh instance, so nothing carries over
+/// from one element to the next.
+pub struct Plugins {
+    engine: Engine,
+    modules: Mutex<HashMap<PathBuf, (SystemTime, Module)>>,
+}
+impl Plugins {
+    pub fn new() -> wasmtime::Result<Self> {
+        let mut config = EngineConfig::new();
+        config.consume_fuel(true);
+        Ok(Plugins {
+            engine: Engine::new(&config)?,
+            modules: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn module(&self, path: &Path) -> wasmtime::Result<Module> {
+        let modified = fs::metadata(path)?.modified()?;
+        if let Some((compiled_at, module)) = self
+            .modules
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(path)
+        {
+            if *compiled_at == modified {
+                return Ok(module.clone());
+            }
+        }
+
+        let module = Module::from_file(&self.engine, path)?;
+        self.modules
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(path.to_path_buf(), (modified, module.clone()));
+        Ok(module)
+    }
+
+    /// Runs `plugin` on one element within its fuel and memory limits. Compiles the module on
+    /// first use, so call this off the async runtime.
+    pub fn call(
+        &self,
+        plugin: &Plugin,
+        element: &SerdeElement,
+        arguments: &Value,
+    ) -> wasmtime::Result<Vec<PluginOutput>> {
+        let module = self.module(Path::new(&plugin.path))?;
+        let input = serde_json::to_vec(&PluginInput { element, arguments })?;
+
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(plugin.max_memory_bytes)
+            .instances(1)
+            .build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits: &mut StoreLimits| limits);
+        store.set_fuel(plugin.fuel)?;
+
+        let instance = Instance::new(&mut store, &module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("module does not export its memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "epv_alloc")?;
+        let run = instance.get_typed_func::<(i32, i32), i64>(&mut store, "epv_run")?;
+
+        let input_len = i32::try_from(input.len())?;
+        let input_ptr = alloc.call(&mut store, input_len)?;
+        memory.write(&mut store, usize::try_from(input_ptr)?, &input)?;
+
+        let packed = run.call(&mut store, (input_ptr, input_len))? as u64;
+        let output_ptr = (packed >> 32) as usize;
+        let output_len = (packed & 0xffff_ffff) as usize;
+        if output_len > MAX_OUTPUT_BYTES {
+            return Err(wasmtime::Error::msg("output too large"));
+        }
+        let output = memory
+            .data(&store)
+            .get(output_ptr..output_ptr + output_len)
+            .ok_or_else(|| wasmtime::Error::msg("output out of bounds"))?;
+
+        Ok(serde_json::from_slice(output)?)
+    }
+}
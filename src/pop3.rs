@@ -0,0 +1,479 @@
+use crate::{
+    config::{AfterProcessing, Config, Imap, ImapConnection},
+    imap::{self, FetchedEmail, MailStream, StoreOutcome, RECONNECT_BASE, RECONNECT_MAX},
+    ingest, sql,
+    status::Status,
+    systemd::{self, Readiness},
+    triggers::Triggers,
+    util, ManagedConfig, ManagedStatus,
+};
+use futures::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use futures_rustls::pki_types::{InvalidDnsNameError, ServerName};
+use mailparse::MailHeaderMap;
+use rocket::Shutdown;
+use sqlx::{Pool, Sqlite};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+use tracing::{debug, error, info_span, warn, Instrument};
+
+/// Between cycles, as POP3 has no way to wait for new mail.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the watchdog is fed while waiting.
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+enum Pop3Error {
+    Io(io::Error),
+    InvalidServer(InvalidDnsNameError),
+    /// A `-ERR` or otherwise unexpected response.
+    Server(String),
+}
+impl From<io::Error> for Pop3Error {
+    fn from(e: io::Error) -> Self {
+        Pop3Error::Io(e)
+    }
+}
+impl fmt::Display for Pop3Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pop3Error::Io(e) => write!(f, "{}", e),
+            Pop3Error::InvalidServer(e) => write!(f, "invalid server: {}", e),
+            Pop3Error::Server(response) => write!(f, "server said: {}", response),
+        }
+    }
+}
+
+/// A POP3 connection (RFC 1939), past the greeting.
+struct Pop3Client<S> {
+    stream: BufReader<S>,
+}
+impl<S: AsyncRead + AsyncWrite + Unpin> Pop3Client<S> {
+    /// Reads the greeting from a fresh connection.
+    async fn new(stream: S) -> Result<Self, Pop3Error> {
+        let mut client = Pop3Client {
+            stream: BufReader::new(stream),
+        };
+        client.read_status().await?;
+        Ok(client)
+    }
+
+    /// The next line without its line ending.
+    async fn read_line(&mut self) -> Result<Vec<u8>, Pop3Error> {
+        let mut line = vec![];
+        if self.stream.read_until(b'\n', &mut line).await? == 0 {
+            return Err(Pop3Error::Io(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "POP3 connection closed",
+            )));
+        }
+        if line.ends_with(b"\n") {
+            line.pop();
+        }
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+        Ok(line)
+    }
+
+    /// Reads a status line, returning what follows `+OK`.
+    async fn read_status(&mut self) -> Result<String, Pop3Error> {
+        let line = self.read_line().await?;
+        let line = String::from_utf8_lossy(&line);
+        match line.strip_prefix("+OK") {
+            Some(rest) => Ok(rest.trim().to_owned()),
+            None => Err(Pop3Error::Server(line.into_owned())),
+        }
+    }
+
+    async fn command(&mut self, command: &str) -> Result<String, Pop3Error> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await?;
+        self.read_status().await
+    }
+
+    /// The lines of a multi-line response, up to the terminating `.` and with byte-stuffing
+    /// undone.
+    async fn read_multiline(&mut self) -> Result<Vec<Vec<u8>>, Pop3Error> {
+        let mut lines = vec![];
+        loop {
+            let mut line = self.read_line().await?;
+            if line == b"." {
+                return Ok(lines);
+            }
+            if line.starts_with(b"..") {
+                line.remove(0);
+            }
+            lines.push(line);
+        }
+    }
+
+    /// The message numbers and unique ids of the messages on the server.
+    async fn uidl(&mut self) -> Result<Vec<(u32, String)>, Pop3Error> {
+        self.command("UIDL").await?;
+        self.read_multiline()
+            .await?
+            .into_iter()
+            .map(|line| {
+                let line = String::from_utf8_lossy(&line);
+                line.split_once(' ')
+                    .and_then(|(number, uidl)| Some((number.parse().ok()?, uidl.trim().to_owned())))
+                    .ok_or_else(|| Pop3Error::Server(format!("bad UIDL line: {}", line)))
+            })
+            .collect()
+    }
+
+    /// The size in bytes of each message on the server, by message number.
+    async fn list(&mut self) -> Result<HashMap<u32, usize>, Pop3Error> {
+        self.command("LIST").await?;
+        self.read_multiline()
+            .await?
+            .into_iter()
+            .map(|line| {
+                let line = String::from_utf8_lossy(&line);
+                line.split_once(' ')
+                    .and_then(|(number, size)| {
+                        Some((number.parse().ok()?, size.trim().parse().ok()?))
+                    })
+                    .ok_or_else(|| Pop3Error::Server(format!("bad LIST line: {}", line)))
+            })
+            .collect()
+    }
+
+    /// The headers of message `number`, without downloading its body.
+    async fn top(&mut self, number: u32) -> Result<Vec<u8>, Pop3Error> {
+        self.command(&format!("TOP {} 0", number)).await?;
+        let lines = self.read_multiline().await?;
+        Ok(lines.join(&b"\r\n"[..]))
+    }
+
+    async fn retr(&mut self, number: u32) -> Result<Vec<u8>, Pop3Error> {
+        self.command(&format!("RETR {}", number)).await?;
+        let lines = self.read_multiline().await?;
+        Ok(lines.join(&b"\r\n"[..]))
+    }
+
+    fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+}
+
+type Pop3Session = Pop3Client<MailStream>;
+
+/// Ingests from the POP3 server of every account in `imap` at once. The accounts and their
+/// settings are read at startup, so changing them takes a restart.
+pub async fn perform(
+    managed_config: ManagedConfig,
+    pool: Pool<Sqlite>,
+    status: ManagedStatus,
+    triggers: Triggers,
+    readiness: Arc<Readiness>,
+    shutdown: Shutdown,
+) {
+    let accounts = managed_config.load().imap.as_slice().to_vec();
+    let tasks = accounts.into_iter().map(|account| {
+        // Listed in the status before connecting, in config order.
+        status.update_imap(&account.key(), |_| {});
+        let span = info_span!("account", username = %account.username, server = %account.server);
+        perform_account(
+            Arc::clone(&managed_config),
+            pool.clone(),
+            Arc::clone(&status),
+            triggers.clone(),
+            Arc::clone(&readiness),
+            account,
+            shutdown.clone(),
+        )
+        .instrument(span)
+    });
+    futures::future::join_all(tasks).await;
+}
+
+/// Runs ingestion for `account` until shutdown, connecting once per cycle and backing off when a
+/// cycle fails. Readiness is reported once the first login succeeds.
+async fn perform_account(
+    managed_config: ManagedConfig,
+    pool: Pool<Sqlite>,
+    status: ManagedStatus,
+    triggers: Triggers,
+    readiness: Arc<Readiness>,
+    account: Imap,
+    shutdown: Shutdown,
+) {
+    let mut readiness = Some(readiness);
+    let mut attempt = 0;
+    let mut cycle = 0;
+    loop {
+        systemd::watchdog();
+        let config = managed_config.load_full();
+
+        let connected = tokio::select! {
+            result = time::timeout(CONNECT_TIMEOUT, connect(&account)) => {
+                result.unwrap_or_else(|_| {
+                    Err(Pop3Error::Io(io::Error::new(
+                        ErrorKind::TimedOut,
+                        "POP3 connect timed out",
+                    )))
+                })
+            }
+            _ = shutdown.clone() => return,
+        };
+
+        let result = match connected {
+            Ok(mut session) => {
+                if let Some(readiness) = readiness.take() {
+                    readiness.component_ready();
+                }
+                status.update_imap(&account.key(), |imap| imap.connected = true);
+                cycle += 1;
+                let result = ingest_cycle(
+                    &mut session,
+                    &account,
+                    &config,
+                    &pool,
+                    &status,
+                    &triggers,
+                    &shutdown,
+                )
+                .instrument(info_span!("ingest", cycle))
+                .await;
+                // Deletions only take effect once the session ends with QUIT.
+                let result = match result {
+                    Ok(()) => session.command("QUIT").await.map(|_| ()),
+                    Err(e) => Err(e),
+                };
+                status.update_imap(&account.key(), |imap| imap.connected = false);
+                result
+            }
+            Err(e) => Err(e),
+        };
+
+        let delay = match result {
+            Ok(()) => {
+                attempt = 0;
+                POLL_INTERVAL
+            }
+            Err(e) => {
+                error!(error = ?e, "POP3 error");
+                status.ingest_failed(&account.key(), format!("pop3: {}", e));
+                let delay = util::backoff(attempt, RECONNECT_BASE, RECONNECT_MAX);
+                attempt = attempt.saturating_add(1);
+                warn!(
+                    attempt,
+                    delay_ms = delay.as_millis() as u64,
+                    "POP3 reconnecting"
+                );
+                delay
+            }
+        };
+
+        if wait(delay, &shutdown).await {
+            return;
+        }
+    }
+}
+
+/// Sleeps for `delay`, feeding the watchdog meanwhile. Returns whether shutdown started.
+async fn wait(delay: Duration, shutdown: &Shutdown) -> bool {
+    let sleep = time::sleep(delay);
+    tokio::pin!(sleep);
+    let mut watchdog = time::interval(WATCHDOG_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = &mut sleep => return false,
+            _ = watchdog.tick() => systemd::watchdog(),
+            _ = shutdown.clone() => return true,
+        }
+    }
+}
+
+/// Connects as the account's `connection` says, upgrading with STLS for `starttls`, and logs in
+/// with the password.
+async fn connect(account: &Imap) -> Result<Pop3Session, Pop3Error> {
+    let server_name =
+        ServerName::try_from(account.server.clone()).map_err(Pop3Error::InvalidServer)?;
+    let tcp = imap::connect_tcp(account).await?.compat();
+
+    let mut session = match account.connection {
+        ImapConnection::Tls => {
+            let tls_stream = imap::tls_connector(account)?
+                .connect(server_name, tcp)
+                .await?;
+            Pop3Client::new(MailStream::Tls(Box::new(tls_stream))).await?
+        }
+        ImapConnection::Starttls => {
+            let mut plain = Pop3Client::new(tcp).await?;
+            plain.command("STLS").await?;
+            let tls_stream = imap::tls_connector(account)?
+                .connect(server_name, plain.into_inner())
+                .await?;
+            // The server greets only once, before the upgrade.
+            Pop3Client {
+                stream: BufReader::new(MailStream::Tls(Box::new(tls_stream))),
+            }
+        }
+        ImapConnection::Plaintext => Pop3Client::new(MailStream::Plain(tcp)).await?,
+    };
+
+    session
+        .command(&format!("USER {}", account.username))
+        .await?;
+    let password = account.password.as_deref().unwrap_or_default();
+    session.command(&format!("PASS {}", password)).await?;
+
+    Ok(session)
+}
+
+/// What ingestion needs from a retrieved message, one for each user it is for, or `None` if it
+/// cannot be stored, which is logged. POP3 has no envelope, so the recipients come from the
+/// headers.
+fn fetched_emails(config: &Config, uidl: &str, raw: Vec<u8>) -> Option<Vec<FetchedEmail>> {
+    let headers = match mailparse::parse_headers(&raw) {
+        Ok((headers, _)) => headers,
+        Err(e) => {
+            warn!(uidl, error = ?e, "POP3 unparseable headers");
+            return None;
+        }
+    };
+
+    let Some(from_address_string) = ingest::first_address(&headers, "From") else {
+        warn!(uidl, "POP3 no from address");
+        return None;
+    };
+
+    let to = ingest::recipients(&headers);
+    let subject = headers.get_first_value("Subject").unwrap_or_default();
+    let routed = ingest::route_all(config, &from_address_string, &to, &subject);
+    if routed.is_empty() {
+        warn!(uidl, "POP3 no matching user");
+        return None;
+    }
+
+    Some(FetchedEmail::copies(routed, from_address_string, raw))
+}
+
+/// Retrieves the messages on the server not yet handled, the account's `batch_size` at a time,
+/// storing each batch's new emails and firing their triggers before the next is retrieved.
+/// Handled messages are deleted, or remembered by UIDL with `after_processing` `flag`; those that
+/// can never be stored, or are over `max_message_size`, are remembered and left on the server.
+/// Fails when a command does, as the connection is then likely gone.
+async fn ingest_cycle(
+    session: &mut Pop3Session,
+    account: &Imap,
+    config: &Arc<Config>,
+    pool: &Pool<Sqlite>,
+    status: &Status,
+    triggers: &Triggers,
+    shutdown: &Shutdown,
+) -> Result<(), Pop3Error> {
+    let listing = session.uidl().await?;
+
+    let current = listing
+        .iter()
+        .map(|(_, uidl)| uidl.as_str())
+        .collect::<Vec<_>>();
+    if let Err(e) = sql::prune_pop3_seen(pool, &account.key(), &current).await {
+        error!(error = ?e, "POP3 seen DELETE error");
+    }
+    let seen = match sql::pop3_seen(pool, &account.key()).await {
+        Ok(x) => x,
+        Err(e) => {
+            // Without it everything would be retrieved again, only to find duplicates.
+            error!(error = ?e, "POP3 seen SELECT error");
+            status.ingest_failed(&account.key(), format!("seen: {}", e));
+            return Ok(());
+        }
+    };
+    let messages = listing
+        .into_iter()
+        .filter(|(_, uidl)| !seen.contains(uidl))
+        .collect::<Vec<_>>();
+
+    status.ingest_succeeded(&account.key(), messages.len());
+
+    let sizes = match account.max_message_size {
+        Some(_) => session.list().await?,
+        None => HashMap::new(),
+    };
+
+    for batch in messages.chunks(account.batch_size) {
+        let mut fetched = vec![];
+        let mut remembered = vec![];
+        for (number, uidl) in batch {
+            if let (Some(max_size), Some(&size)) = (account.max_message_size, sizes.get(number)) {
+                if size > max_size {
+                    let headers = session.top(*number).await?;
+                    let (from, subject) = match mailparse::parse_headers(&headers) {
+                        Ok((headers, _)) => (
+                            ingest::first_address(&headers, "From").unwrap_or_default(),
+                            headers.get_first_value("Subject").unwrap_or_default(),
+                        ),
+                        Err(_) => Default::default(),
+                    };
+                    warn!(uidl, size, max_size, %from, %subject, "POP3 message too large, skipped");
+                    status.update_imap(&account.key(), |imap| imap.failed += 1);
+                    remembered.push(uidl.as_str());
+                    continue;
+                }
+            }
+
+            let raw = session.retr(*number).await?;
+            match fetched_emails(config, uidl, raw) {
+                Some(copies) => fetched.extend(
+                    copies
+                        .into_iter()
+                        .map(|copy| ((*number, uidl.as_str()), copy)),
+                ),
+                None => {
+                    status.update_imap(&account.key(), |imap| imap.failed += 1);
+                    remembered.push(uidl.as_str());
+                }
+            }
+        }
+
+        let (fetched_messages, fetched): (Vec<_>, Vec<_>) = fetched.into_iter().unzip();
+        let outcomes = imap::store_fetched(
+            fetched,
+            &account.key(),
+            account.parallelism,
+            config,
+            pool,
+            status,
+            triggers,
+            shutdown,
+        )
+        .await;
+
+        let mut handled = 0;
+        for ((number, uidl), outcome) in imap::by_message(fetched_messages, outcomes) {
+            match outcome {
+                StoreOutcome::Handled => {
+                    handled += 1;
+                    if account.after_processing == AfterProcessing::Delete {
+                        session.command(&format!("DELE {}", number)).await?;
+                    } else {
+                        remembered.push(uidl);
+                    }
+                }
+                StoreOutcome::Retry => {}
+                StoreOutcome::Rejected => remembered.push(uidl),
+            }
+        }
+
+        debug!(fetched = batch.len(), handled, "POP3 batch finished");
+
+        if let Err(e) = sql::mark_pop3_seen(pool, &account.key(), &remembered).await {
+            // Retrieving them again next cycle only finds duplicates.
+            error!(error = ?e, "POP3 seen INSERT error");
+        }
+    }
+
+    Ok(())
+}
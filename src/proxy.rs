@@ -0,0 +1,194 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use percent_encoding::percent_decode_str;
+use std::io::{self, ErrorKind};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use url::Url;
+
+/// HTTP `CONNECT` responses longer than this are refused.
+const CONNECT_RESPONSE_MAX: usize = 8192;
+
+/// How `imap.proxy` reaches the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// `socks5://` or `socks5h://`. Either way the proxy resolves the server's name.
+    Socks5,
+    /// `http://`, with `CONNECT`.
+    Http,
+}
+
+/// A parsed `imap.proxy` URL.
+#[derive(Debug)]
+pub struct Proxy {
+    kind: Kind,
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, message.into())
+}
+
+fn decode(component: &str) -> String {
+    percent_decode_str(component)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+impl Proxy {
+    /// Parses `socks5://`, `socks5h://` or `http://` URLs, with optional `user:password@`.
+    pub fn parse(proxy: &str) -> io::Result<Proxy> {
+        let url = Url::parse(proxy).map_err(|e| invalid(e.to_string()))?;
+        let (kind, default_port) = match url.scheme() {
+            "socks5" | "socks5h" => (Kind::Socks5, 1080),
+            "http" => (Kind::Http, 80),
+            scheme => {
+                return Err(invalid(format!(
+                    "unsupported scheme {:?}, use socks5 or http",
+                    scheme
+                )))
+            }
+        };
+        let host = url
+            .host_str()
+            .ok_or_else(|| invalid("missing host"))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        let credentials = (!url.username().is_empty()).then(|| {
+            (
+                decode(url.username()),
+                decode(url.password().unwrap_or_default()),
+            )
+        });
+        if kind == Kind::Socks5 {
+            if let Some((username, password)) = &credentials {
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(invalid("SOCKS5 credentials must be at most 255 bytes each"));
+                }
+            }
+        }
+
+        Ok(Proxy {
+            kind,
+            host,
+            port: url.port().unwrap_or(default_port),
+            credentials,
+        })
+    }
+
+    /// A connection to `host:port` through the proxy, ready for TLS or the mail protocol.
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        match self.kind {
+            Kind::Socks5 => self.socks5_handshake(&mut stream, host, port).await?,
+            Kind::Http => self.http_connect(&mut stream, host, port).await?,
+        }
+        Ok(stream)
+    }
+
+    /// RFC 1928, with username and password authentication from RFC 1929.
+    async fn socks5_handshake(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> io::Result<()> {
+        let method = if self.credentials.is_some() { 2 } else { 0 };
+        stream.write_all(&[5, 1, method]).await?;
+        let mut choice = [0; 2];
+        stream.read_exact(&mut choice).await?;
+        if choice != [5, method] {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "SOCKS5 proxy refused the authentication method",
+            ));
+        }
+
+        if let Some((username, password)) = &self.credentials {
+            let mut request = vec![1, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request).await?;
+            let mut reply = [0; 2];
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "SOCKS5 proxy rejected the credentials",
+                ));
+            }
+        }
+
+        let host_len =
+            u8::try_from(host.len()).map_err(|_| invalid("server name too long for SOCKS5"))?;
+        let mut request = vec![5, 1, 0, 3, host_len];
+        request.extend_from_slice(host.as_bytes());
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("SOCKS5 proxy could not connect, reply {}", reply[1]),
+            ));
+        }
+        // The address the proxy bound, which is of no use here.
+        let bound_len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => usize::from(stream.read_u8().await?),
+            other => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("SOCKS5 proxy replied with address type {}", other),
+                ))
+            }
+        };
+        let mut bound = vec![0; bound_len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+
+    /// Sends `CONNECT` and reads the response head a byte at a time, so nothing the server sends
+    /// after it is consumed.
+    async fn http_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        let authority = if host.contains(':') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some((username, password)) = &self.credentials {
+            let token = STANDARD.encode(format!("{}:{}", username, password));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = vec![];
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= CONNECT_RESPONSE_MAX {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "HTTP proxy response too long",
+                ));
+            }
+            response.push(stream.read_u8().await?);
+        }
+
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        match status_line.split_ascii_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("HTTP proxy refused CONNECT: {}", status_line),
+            )),
+        }
+    }
+}
@@ -1,46 +1,294 @@
 use crate::{
-    config::{User, Users},
-    ManagedConfig, ManagedRatelimits,
+    config::{Csv, CsvQuoteStyle, User, Users},
+    sql::{self, UsageMetric},
+    util, ManagedConfig, ManagedPool, ManagedRatelimits,
 };
-use csv::{QuoteStyle, WriterBuilder};
+use chrono::{NaiveDateTime, NaiveTime, Utc};
+use csv::{QuoteStyle, Terminator, WriterBuilder};
+use futures::{Stream, StreamExt};
 use rocket::http::ContentType;
 use rocket::{
+    data::Data,
     http::Status,
     request::{FromRequest, Outcome, Request},
-    response::Responder,
+    response::{stream::ByteStream, Responder, Response},
+    route::{self, Handler, Route},
     serde::json::Json,
     State,
 };
+use rust_xlsxwriter::{ExcelDateTime, Format, Workbook, XlsxError};
+use sentry::{Hub, SentryFutureExt};
 use serde::Serialize;
+use serde_json::Value;
+use std::io::{self, Write};
+use std::marker::PhantomData;
 use std::ops::Deref;
+use std::sync::Arc;
 use tokio::time::Instant;
+use tracing::{error, info, info_span, Instrument};
+use zip::{result::ZipResult, write::FileOptions, CompressionMethod, ZipWriter};
 
-#[derive(Debug, Serialize)]
-#[serde(tag = "error", content = "data")]
-pub enum Error {
-    InternalError,
+/// Stable identifiers for why a request failed, returned as `code` so that frontends can branch
+/// on them. Codes are part of the API: add new ones rather than renaming existing ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Internal,
     Unauthorized,
-    InvalidInput(String),
+    Forbidden,
     NotFound,
     Ratelimited,
+    Unavailable,
+    InvalidCursor,
+    InvalidParameter,
+    InvalidHeaderName,
+    EmailNotFound,
+    MacroNotFound,
+    ScriptNotFound,
+    InvalidScriptName,
+    UnknownMacro,
+    RecursiveMacro,
+    UnknownPlugin,
+    PluginFailed,
+    EvalDisabled,
+    InvalidEval,
+    EvalFailed,
+    InvalidSelector,
+    InvalidRegex,
+    InvalidUrl,
+    InvalidDate,
+    CacheNotFound,
+    InvalidCacheKey,
+    AttachmentNotFound,
+    AttachmentInfected,
+    UnsubscribeNotOffered,
+    FetchBudgetExceeded,
+    ScriptExists,
+    UnsupportedBundle,
+    TriggerNotFound,
+    RunOutputNotFound,
+    InlineImageNotFound,
+}
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::Internal => "internal",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::Ratelimited => "ratelimited",
+            ErrorCode::Unavailable => "unavailable",
+            ErrorCode::InvalidCursor => "request.invalid_cursor",
+            ErrorCode::InvalidParameter => "request.invalid_parameter",
+            ErrorCode::InvalidHeaderName => "email.invalid_header_name",
+            ErrorCode::EmailNotFound => "email.not_found",
+            ErrorCode::MacroNotFound => "macro.not_found",
+            ErrorCode::ScriptNotFound => "script.not_found",
+            ErrorCode::InvalidScriptName => "script.invalid_name",
+            ErrorCode::UnknownMacro => "script.unknown_macro",
+            ErrorCode::RecursiveMacro => "script.recursive_macro",
+            ErrorCode::UnknownPlugin => "script.unknown_plugin",
+            ErrorCode::PluginFailed => "script.plugin_failed",
+            ErrorCode::EvalDisabled => "script.eval_disabled",
+            ErrorCode::InvalidEval => "script.invalid_eval",
+            ErrorCode::EvalFailed => "script.eval_failed",
+            ErrorCode::InvalidSelector => "script.invalid_selector",
+            ErrorCode::InvalidRegex => "script.invalid_regex",
+            ErrorCode::InvalidUrl => "script.invalid_url",
+            ErrorCode::InvalidDate => "script.invalid_date",
+            ErrorCode::CacheNotFound => "cache.not_found",
+            ErrorCode::InvalidCacheKey => "cache.invalid_key",
+            ErrorCode::AttachmentNotFound => "attachment.not_found",
+            ErrorCode::AttachmentInfected => "attachment.infected",
+            ErrorCode::UnsubscribeNotOffered => "email.unsubscribe_not_offered",
+            ErrorCode::FetchBudgetExceeded => "script.fetch_budget_exceeded",
+            ErrorCode::ScriptExists => "script.exists",
+            ErrorCode::UnsupportedBundle => "script.unsupported_bundle",
+            ErrorCode::TriggerNotFound => "script.trigger_not_found",
+            ErrorCode::RunOutputNotFound => "script.run_output_not_found",
+            ErrorCode::InlineImageNotFound => "email.inline_image_not_found",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    InternalError,
+    Unauthorized,
+    Forbidden(ErrorCode),
+    /// `input` is the rejected value (or the parameter name when the value is not worth echoing)
+    /// and `detail` says what was wrong with it, when there is more to say than `code`.
+    InvalidInput {
+        code: ErrorCode,
+        input: String,
+        detail: Option<String>,
+    },
+    NotFound(ErrorCode),
+    Ratelimited(ErrorCode),
+    /// The server is shutting down.
+    Unavailable,
+}
+impl Error {
+    pub fn invalid_input(code: ErrorCode, input: impl Into<String>) -> Self {
+        Error::InvalidInput {
+            code,
+            input: input.into(),
+            detail: None,
+        }
+    }
+
+    /// Only meaningful for [`Error::InvalidInput`]; other errors are returned unchanged.
+    pub fn with_detail(mut self, detail: impl ToString) -> Self {
+        if let Error::InvalidInput { detail: d, .. } = &mut self {
+            *d = Some(detail.to_string());
+        }
+        self
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Error::InternalError => "InternalError",
+            Error::Unauthorized => "Unauthorized",
+            Error::Forbidden(_) => "Forbidden",
+            Error::InvalidInput { .. } => "InvalidInput",
+            Error::NotFound(_) => "NotFound",
+            Error::Ratelimited(_) => "Ratelimited",
+            Error::Unavailable => "Unavailable",
+        }
+    }
+
+    fn code(&self) -> ErrorCode {
+        match self {
+            Error::InternalError => ErrorCode::Internal,
+            Error::Unauthorized => ErrorCode::Unauthorized,
+            Error::InvalidInput { code, .. }
+            | Error::Forbidden(code)
+            | Error::NotFound(code)
+            | Error::Ratelimited(code) => *code,
+            Error::Unavailable => ErrorCode::Unavailable,
+        }
+    }
+}
+
+/// `error` and `data` predate `code` and are kept for existing clients.
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'static str,
+    code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+    request_id: &'a str,
 }
 
 impl<'r, 'o: 'r> Responder<'r, 'o> for Error {
     fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'o> {
-        match self {
-            Error::InternalError => (Status::InternalServerError, Json(self)).respond_to(request),
-            Error::Unauthorized => (Status::Unauthorized, Json(self)).respond_to(request),
-            Error::InvalidInput(_) => (Status::BadRequest, Json(self)).respond_to(request),
-            Error::NotFound => (Status::NotFound, Json(self)).respond_to(request),
-            Error::Ratelimited => (Status::TooManyRequests, Json(self)).respond_to(request),
+        let status = match self {
+            Error::InternalError => Status::InternalServerError,
+            Error::Unauthorized => Status::Unauthorized,
+            Error::Forbidden(_) => Status::Forbidden,
+            Error::InvalidInput { .. } => Status::BadRequest,
+            Error::NotFound(_) => Status::NotFound,
+            Error::Ratelimited(_) => Status::TooManyRequests,
+            Error::Unavailable => Status::ServiceUnavailable,
+        };
+
+        let (data, detail) = match &self {
+            Error::InvalidInput { input, detail, .. } => (Some(input.as_str()), detail.as_deref()),
+            _ => (None, None),
+        };
+        let body = ErrorBody {
+            error: self.name(),
+            code: self.code().as_str(),
+            data,
+            detail,
+            request_id: RequestId::of(request),
+        };
+        (status, ApiJson(body)).respond_to(request)
+    }
+}
+
+/// Random per-request id, logged with everything the request does and returned in error
+/// bodies so that a reported error can be found in the logs.
+#[derive(Debug)]
+pub struct RequestId(String);
+impl RequestId {
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r str {
+        &request.local_cache(|| RequestId(util::random_hex(8))).0
+    }
+}
+
+/// JSON response that is indented when the request asks for `pretty=true`.
+#[derive(Debug)]
+pub struct ApiJson<T>(pub T);
+impl<'r, 'o: 'r, T: Serialize> Responder<'r, 'o> for ApiJson<T> {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'o> {
+        if !matches!(query_param(request, "pretty"), Some("true" | "1")) {
+            return Json(self.0).respond_to(request);
+        }
+
+        match serde_json::to_string_pretty(&self.0) {
+            Ok(x) => (ContentType::JSON, x).respond_to(request),
+            Err(e) => {
+                error!(error = ?e, "Pretty JSON serialize error");
+                Err(Status::InternalServerError)
+            }
         }
     }
 }
 
+/// A JSON array written element by element as the stream yields them. The status has already
+/// been sent by the time an error arrives, so the array is left unterminated instead.
+pub struct JsonStream<S>(pub S);
+impl<'r, S, T> Responder<'r, 'r> for JsonStream<S>
+where
+    S: Stream<Item = Result<T, Error>> + Send + 'r,
+    T: Serialize + Send + 'r,
+{
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'r> {
+        let mut items = Box::pin(self.0);
+        let bytes = ByteStream! {
+            yield b"[".to_vec();
+            let mut first = true;
+            while let Some(item) = items.next().await {
+                let item = match item {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!(error = ?e, "JSON stream error");
+                        return;
+                    }
+                };
+                let json = match serde_json::to_vec(&item) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!(error = ?e, "JSON stream serialize error");
+                        return;
+                    }
+                };
+
+                if !first {
+                    yield b",".to_vec();
+                }
+                first = false;
+                yield json;
+            }
+            yield b"]".to_vec();
+        };
+
+        (ContentType::JSON, bytes).respond_to(request)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum ExpectedFormat {
     Json,
-    Csv,
+    /// Delimiter-separated values; `format=tsv` is `Csv(b'\t')`.
+    Csv(u8),
+    Xlsx,
+    Markdown,
+    Html,
+    Ics,
+    Zip,
 }
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for ExpectedFormat {
@@ -52,20 +300,65 @@ impl<'r> FromRequest<'r> for ExpectedFormat {
 }
 impl ExpectedFormat {
     pub fn from_request_sync(request: &Request) -> Self {
-        if Some("csv")
-            == request.uri().query().and_then(|query| {
-                query
-                    .segments()
-                    .find_map(|(key, value)| if key == "format" { Some(value) } else { None })
-            })
-        {
-            ExpectedFormat::Csv
-        } else {
-            ExpectedFormat::Json
+        match query_param(request, "format") {
+            Some("csv") => {
+                // Anything other than a single ASCII character keeps the default comma.
+                let delimiter = match query_param(request, "delimiter").map(str::as_bytes) {
+                    Some(&[delimiter]) if delimiter.is_ascii() => delimiter,
+                    _ => b',',
+                };
+                ExpectedFormat::Csv(delimiter)
+            }
+            Some("tsv") => ExpectedFormat::Csv(b'\t'),
+            Some("xlsx") => ExpectedFormat::Xlsx,
+            Some("md") => ExpectedFormat::Markdown,
+            Some("html") => ExpectedFormat::Html,
+            Some("ics") => ExpectedFormat::Ics,
+            Some("zip") => ExpectedFormat::Zip,
+            _ => ExpectedFormat::Json,
         }
     }
 }
 
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// The configured CSV defaults with any per-request overrides applied.
+fn csv_options(request: &Request) -> Csv {
+    let mut options = request
+        .rocket()
+        .state::<ManagedConfig>()
+        .map(|config| config.load().csv)
+        .unwrap_or_default();
+
+    match query_param(request, "bom") {
+        Some("true") => options.bom = true,
+        Some("false") => options.bom = false,
+        _ => {}
+    }
+    match query_param(request, "line_ending") {
+        Some("crlf") => options.crlf = true,
+        Some("lf") => options.crlf = false,
+        _ => {}
+    }
+    match query_param(request, "quote") {
+        Some("always") => options.quote_style = CsvQuoteStyle::Always,
+        Some("necessary") => options.quote_style = CsvQuoteStyle::Necessary,
+        Some("nonnumeric") => options.quote_style = CsvQuoteStyle::NonNumeric,
+        Some("never") => options.quote_style = CsvQuoteStyle::Never,
+        _ => {}
+    }
+
+    options
+}
+
+fn query_param<'r>(request: &'r Request, name: &str) -> Option<&'r str> {
+    request.uri().query().and_then(|query| {
+        query
+            .segments()
+            .find_map(|(key, value)| if key == name { Some(value) } else { None })
+    })
+}
+
 pub struct FlexibleFormatComplex<T, F> {
     data: T,
     processor: F,
@@ -75,29 +368,206 @@ enum FlexibleFormatInner<T, V, F> {
     Complex(FlexibleFormatComplex<T, F>),
     Vec(Vec<V>),
 }
+/// Navigation details for paginated responses, sent as `X-` headers and, when the request asks
+/// for `envelope=true`, next to the data in JSON responses.
+#[derive(Debug, Default, Serialize)]
+pub struct PageMeta {
+    pub total: Option<i64>,
+    pub next_cursor: Option<String>,
+    pub elapsed_ms: Option<u128>,
+}
+impl PageMeta {
+    fn set_headers(&self, response: &mut Response) {
+        if let Some(total) = self.total {
+            response.set_raw_header("X-Total-Count", total.to_string());
+        }
+        if let Some(next_cursor) = &self.next_cursor {
+            response.set_raw_header("X-Next-Cursor", next_cursor.clone());
+        }
+        if let Some(elapsed_ms) = self.elapsed_ms {
+            response.set_raw_header("X-Elapsed-Ms", elapsed_ms.to_string());
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, D> {
+    data: D,
+    #[serde(flatten)]
+    meta: &'a PageMeta,
+}
+
+fn respond_json<'r, 'o: 'r, D: Serialize>(
+    data: D,
+    meta: Option<&PageMeta>,
+    request: &'r Request<'_>,
+) -> rocket::response::Result<'o> {
+    match meta {
+        Some(meta) if query_param(request, "envelope") == Some("true") => {
+            ApiJson(Envelope { data, meta }).respond_to(request)
+        }
+        _ => ApiJson(data).respond_to(request),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CalendarEvent {
+    /// A start at midnight is written as an all-day event.
+    pub start: NaiveDateTime,
+    pub summary: Option<String>,
+}
+
+fn ics_escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+/// Folds content lines longer than the 75 octets RFC 5545 allows.
+fn push_ics_line(ics: &mut String, line: &str) {
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > 75 {
+            ics.push_str("\r\n ");
+            octets = 1;
+        }
+        ics.push(c);
+        octets += c.len_utf8();
+    }
+    ics.push_str("\r\n");
+}
+
+fn ics_calendar(events: &[CalendarEvent]) -> String {
+    let stamp = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+
+    let mut ics = String::new();
+    push_ics_line(&mut ics, "BEGIN:VCALENDAR");
+    push_ics_line(&mut ics, "VERSION:2.0");
+    push_ics_line(&mut ics, "PRODID:-//Email Ponzi Ventures//EN");
+    for event in events {
+        // Derived from the content so that subscribed calendars update events in place.
+        let uid = util::sha3_hex(
+            format!(
+                "{}\0{}",
+                event.start,
+                event.summary.as_deref().unwrap_or("")
+            )
+            .as_bytes(),
+            16,
+        );
+
+        push_ics_line(&mut ics, "BEGIN:VEVENT");
+        push_ics_line(&mut ics, &format!("UID:{}@epv", uid));
+        push_ics_line(&mut ics, &format!("DTSTAMP:{}", stamp));
+        if event.start.time() == NaiveTime::MIN {
+            push_ics_line(
+                &mut ics,
+                &format!("DTSTART;VALUE=DATE:{}", event.start.format("%Y%m%d")),
+            );
+        } else {
+            push_ics_line(
+                &mut ics,
+                &format!("DTSTART:{}", event.start.format("%Y%m%dT%H%M%S")),
+            );
+        }
+        if let Some(summary) = &event.summary {
+            push_ics_line(&mut ics, &format!("SUMMARY:{}", ics_escape(summary)));
+        }
+        push_ics_line(&mut ics, "END:VEVENT");
+    }
+    push_ics_line(&mut ics, "END:VCALENDAR");
+
+    ics
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchiveFile {
+    pub name: String,
+    pub contents: Vec<u8>,
+}
+
+fn zip_archive(files: Vec<ArchiveFile>) -> ZipResult<Vec<u8>> {
+    let mut zip = ZipWriter::new(io::Cursor::new(vec![]));
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for file in files {
+        zip.start_file(file.name, options)?;
+        zip.write_all(&file.contents)?;
+    }
+
+    Ok(zip.finish()?.into_inner())
+}
+
 pub struct FlexibleFormat<T, V = T, F = fn(T) -> Vec<V>> {
     inner: FlexibleFormatInner<T, V, F>,
     include_header: bool,
+    header: Option<Vec<String>>,
+    meta: Option<PageMeta>,
+    events: Option<Vec<CalendarEvent>>,
+    files: Option<Vec<ArchiveFile>>,
 }
 impl<'r, 'o: 'r, T: Serialize, V: Serialize, F: FnOnce(T) -> Vec<V>> Responder<'r, 'o>
     for FlexibleFormat<T, V, F>
 {
     fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'o> {
         let expected_format = ExpectedFormat::from_request_sync(request);
-        match (self.inner, expected_format) {
+        let meta = self.meta;
+        let mut response = match (self.inner, expected_format) {
+            // Only endpoints that know which of their values are dates can offer a calendar.
+            (_, ExpectedFormat::Ics) => match self.events {
+                Some(events) => (ContentType::new("text", "calendar"), ics_calendar(&events))
+                    .respond_to(request),
+                None => Err(Status::NotAcceptable),
+            },
+            (_, ExpectedFormat::Zip) => match self.files {
+                Some(files) => match zip_archive(files) {
+                    Ok(bytes) => (ContentType::ZIP, bytes).respond_to(request),
+                    Err(e) => {
+                        error!(error = ?e, "ZIP writer error");
+                        Err(Status::InternalServerError)
+                    }
+                },
+                None => Err(Status::NotAcceptable),
+            },
             (FlexibleFormatInner::Complex(inner), ExpectedFormat::Json) => {
-                Json(inner.data).respond_to(request)
+                respond_json(inner.data, meta.as_ref(), request)
+            }
+            (FlexibleFormatInner::Vec(v), ExpectedFormat::Json) => {
+                respond_json(v, meta.as_ref(), request)
             }
-            (FlexibleFormatInner::Vec(v), ExpectedFormat::Json) => Json(v).respond_to(request),
-            (FlexibleFormatInner::Vec(v), ExpectedFormat::Csv) => {
+            (FlexibleFormatInner::Vec(v), ExpectedFormat::Csv(delimiter)) => {
+                let options = csv_options(request);
                 let mut writer = WriterBuilder::new()
-                    .has_headers(self.include_header)
-                    .quote_style(QuoteStyle::Always)
-                    .from_writer(vec![]);
+                    .delimiter(delimiter)
+                    .has_headers(self.include_header && self.header.is_none())
+                    .quote_style(match options.quote_style {
+                        CsvQuoteStyle::Always => QuoteStyle::Always,
+                        CsvQuoteStyle::Necessary => QuoteStyle::Necessary,
+                        CsvQuoteStyle::NonNumeric => QuoteStyle::NonNumeric,
+                        CsvQuoteStyle::Never => QuoteStyle::Never,
+                    })
+                    .terminator(if options.crlf {
+                        Terminator::CRLF
+                    } else {
+                        Terminator::Any(b'\n')
+                    })
+                    .from_writer(if options.bom {
+                        UTF8_BOM.to_vec()
+                    } else {
+                        vec![]
+                    });
+
+                if let Some(header) = &self.header {
+                    if let Err(e) = writer.write_record(header) {
+                        error!(error = ?e, "CSV header writer error");
+                        return Err(Status::InternalServerError);
+                    }
+                }
 
                 for item in v {
                     if let Err(e) = writer.serialize(item) {
-                        eprintln!("CSV writer error: {:#?}", e);
+                        error!(error = ?e, "CSV writer error");
                         return Err(Status::InternalServerError);
                     }
                 }
@@ -105,21 +575,88 @@ impl<'r, 'o: 'r, T: Serialize, V: Serialize, F: FnOnce(T) -> Vec<V>> Responder<'
                 let bytes = match writer.into_inner() {
                     Ok(x) => x,
                     Err(e) => {
-                        eprintln!("CSV inner error: {:#?}", e);
+                        error!(error = ?e, "CSV inner error");
                         return Err(Status::InternalServerError);
                     }
                 };
 
-                (ContentType::CSV, bytes).respond_to(request)
+                let content_type = if delimiter == b'\t' {
+                    ContentType::new("text", "tab-separated-values")
+                } else {
+                    ContentType::CSV
+                };
+                (content_type, bytes).respond_to(request)
             }
-            (FlexibleFormatInner::Complex(inner), ExpectedFormat::Csv) => {
-                (FlexibleFormat::<u8, V> {
-                    inner: FlexibleFormatInner::Vec((inner.processor)(inner.data)),
-                    include_header: self.include_header,
-                })
-                .respond_to(request)
+            (FlexibleFormatInner::Vec(v), ExpectedFormat::Xlsx) => {
+                let table = match Table::new(v, self.include_header, self.header) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!(error = ?e, "XLSX serialize error");
+                        return Err(Status::InternalServerError);
+                    }
+                };
+
+                let bytes = match xlsx_workbook(table) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!(error = ?e, "XLSX writer error");
+                        return Err(Status::InternalServerError);
+                    }
+                };
+
+                (
+                    ContentType::new(
+                        "application",
+                        "vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+                    ),
+                    bytes,
+                )
+                    .respond_to(request)
             }
+            (FlexibleFormatInner::Vec(v), ExpectedFormat::Markdown) => {
+                let table = match Table::new(v, self.include_header, self.header) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!(error = ?e, "Markdown serialize error");
+                        return Err(Status::InternalServerError);
+                    }
+                };
+
+                (ContentType::new("text", "markdown"), markdown_table(table)).respond_to(request)
+            }
+            (FlexibleFormatInner::Vec(v), ExpectedFormat::Html) => {
+                let table = match Table::new(v, self.include_header, self.header) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!(error = ?e, "HTML serialize error");
+                        return Err(Status::InternalServerError);
+                    }
+                };
+
+                (ContentType::HTML, html_table(table)).respond_to(request)
+            }
+            (
+                FlexibleFormatInner::Complex(inner),
+                ExpectedFormat::Csv(_)
+                | ExpectedFormat::Xlsx
+                | ExpectedFormat::Markdown
+                | ExpectedFormat::Html,
+            ) => (FlexibleFormat::<u8, V> {
+                inner: FlexibleFormatInner::Vec((inner.processor)(inner.data)),
+                include_header: self.include_header,
+                header: self.header,
+                meta: None,
+                events: None,
+                files: None,
+            })
+            .respond_to(request),
+        }?;
+
+        if let Some(meta) = &meta {
+            meta.set_headers(&mut response);
         }
+
+        Ok(response)
     }
 }
 impl<T, V, F: FnOnce(T) -> Vec<V>> FlexibleFormat<T, V, F> {
@@ -127,6 +664,10 @@ impl<T, V, F: FnOnce(T) -> Vec<V>> FlexibleFormat<T, V, F> {
         FlexibleFormat {
             inner,
             include_header: true,
+            header: None,
+            meta: None,
+            events: None,
+            files: None,
         }
     }
 
@@ -145,33 +686,342 @@ impl<T, V, F: FnOnce(T) -> Vec<V>> FlexibleFormat<T, V, F> {
         self.include_header = new_value;
         self
     }
+
+    /// Column names written as the first row regardless of `include_header`, for rows whose
+    /// serialized form has no meaningful field names.
+    pub fn header(&mut self, names: Vec<String>) -> &mut Self {
+        self.header = Some(names);
+        self
+    }
+
+    pub fn meta(&mut self, meta: PageMeta) -> &mut Self {
+        self.meta = Some(meta);
+        self
+    }
+
+    /// Enables `format=ics`.
+    pub fn events(&mut self, events: Vec<CalendarEvent>) -> &mut Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Enables `format=zip`.
+    pub fn files(&mut self, files: Vec<ArchiveFile>) -> &mut Self {
+        self.files = Some(files);
+        self
+    }
+}
+
+/// Rows flattened into cells for the table-shaped formats.
+struct Table {
+    /// The explicit header if one was set, otherwise the first row's field names when
+    /// `include_header` is on.
+    header: Option<Vec<String>>,
+    rows: Vec<Vec<Value>>,
+}
+impl Table {
+    fn new<V: Serialize>(
+        v: Vec<V>,
+        include_header: bool,
+        header: Option<Vec<String>>,
+    ) -> serde_json::Result<Self> {
+        let mut derived_header = None;
+        let mut rows = Vec::with_capacity(v.len());
+        for item in v {
+            let mut names = vec![];
+            let mut cells = vec![];
+            flatten_cells(serde_json::to_value(item)?, "", &mut names, &mut cells);
+            derived_header.get_or_insert(names);
+            rows.push(cells);
+        }
+
+        Ok(Table {
+            header: header.or(derived_header.filter(|_| include_header)),
+            rows,
+        })
+    }
+}
+
+/// Flattens a row the way the CSV writer does: nested objects and arrays become consecutive
+/// columns, named after the innermost key.
+fn flatten_cells(value: Value, key: &str, header: &mut Vec<String>, cells: &mut Vec<Value>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                flatten_cells(value, &key, header, cells);
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                flatten_cells(value, key, header, cells);
+            }
+        }
+        other => {
+            header.push(key.to_owned());
+            cells.push(other);
+        }
+    }
+}
+
+/// Only strings starting with an ISO-8601 `YYYY-MM-DD` date are typed as dates, so that
+/// tracking numbers and other digit strings stay text.
+fn iso_date(text: &str) -> Option<ExcelDateTime> {
+    let bytes = text.as_bytes();
+    if bytes.len() < 10
+        || bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || !bytes[..4].iter().all(u8::is_ascii_digit)
+    {
+        return None;
+    }
+
+    ExcelDateTime::parse_from_str(text).ok()
+}
+
+fn xlsx_workbook(table: Table) -> Result<Vec<u8>, XlsxError> {
+    let mut workbook = Workbook::new();
+    let worksheet = workbook.add_worksheet();
+    let date_format = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss");
+
+    let mut row_idx = 0;
+    if let Some(header) = &table.header {
+        for (col, name) in header.iter().enumerate() {
+            worksheet.write_string(row_idx, col as u16, name)?;
+        }
+        row_idx += 1;
+    }
+
+    for cells in table.rows {
+        for (col, cell) in cells.into_iter().enumerate() {
+            let col = col as u16;
+            match cell {
+                Value::Number(number) => {
+                    if let Some(number) = number.as_f64() {
+                        worksheet.write_number(row_idx, col, number)?;
+                    }
+                }
+                Value::Bool(boolean) => {
+                    worksheet.write_boolean(row_idx, col, boolean)?;
+                }
+                Value::String(text) => match iso_date(&text) {
+                    Some(date) => {
+                        worksheet.write_datetime_with_format(row_idx, col, &date, &date_format)?;
+                    }
+                    None => {
+                        worksheet.write_string(row_idx, col, text)?;
+                    }
+                },
+                _ => {}
+            }
+        }
+        row_idx += 1;
+    }
+
+    workbook.save_to_buffer()
+}
+
+fn markdown_cell(value: &Value) -> String {
+    match value {
+        Value::String(text) => markdown_escape(text),
+        Value::Null => String::new(),
+        other => markdown_escape(&other.to_string()),
+    }
+}
+
+fn markdown_escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('|', "\\|")
+        .replace("\r\n", "<br>")
+        .replace('\n', "<br>")
+}
+
+/// GitHub-flavored Markdown requires a header row, so it is left blank when the table has none.
+fn markdown_table(table: Table) -> String {
+    let header = table.header.unwrap_or_default();
+    let body = table
+        .rows
+        .iter()
+        .map(|cells| cells.iter().map(markdown_cell).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    let columns = body
+        .iter()
+        .map(Vec::len)
+        .chain(std::iter::once(header.len()))
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    let mut table = String::new();
+    let mut push_row = |cells: &[String]| {
+        table.push('|');
+        for col in 0..columns {
+            table.push(' ');
+            table.push_str(cells.get(col).map(String::as_str).unwrap_or(""));
+            table.push_str(" |");
+        }
+        table.push('\n');
+    };
+
+    push_row(
+        &header
+            .iter()
+            .map(|name| markdown_escape(name))
+            .collect::<Vec<_>>(),
+    );
+    push_row(&vec!["---".to_owned(); columns]);
+    for cells in &body {
+        push_row(cells);
+    }
+
+    table
+}
+
+fn html_cell(value: &Value) -> String {
+    match value {
+        Value::String(text) => util::html_escape(text),
+        Value::Null => String::new(),
+        other => util::html_escape(&other.to_string()),
+    }
+}
+
+const HTML_TABLE_STYLE: &str = "body{font-family:sans-serif;margin:1rem}\
+table{border-collapse:collapse}\
+th,td{border:1px solid #ccc;padding:.25rem .5rem;text-align:left;vertical-align:top}\
+th{background:#f0f0f0}\
+tr:nth-child(even) td{background:#fafafa}";
+
+/// A standalone page, so that a bookmarked request URL renders as a small report.
+fn html_table(table: Table) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><style>{}</style></head><body><table>",
+        HTML_TABLE_STYLE
+    );
+
+    if let Some(header) = &table.header {
+        html.push_str("<thead><tr>");
+        for name in header {
+            html.push_str(&format!("<th>{}</th>", util::html_escape(name)));
+        }
+        html.push_str("</tr></thead>");
+    }
+
+    html.push_str("<tbody>");
+    for cells in &table.rows {
+        html.push_str("<tr>");
+        for cell in cells {
+            html.push_str(&format!("<td>{}</td>", html_cell(cell)));
+        }
+        html.push_str("</tr>");
+    }
+    html.push_str("</tbody></table></body></html>");
+
+    html
+}
+
+/// Wraps a route's handler so that everything logged while handling a request, guards included,
+/// is inside a `request` span, and logs how the request ended.
+#[derive(Clone)]
+pub struct Traced(Box<dyn Handler>);
+impl Traced {
+    pub fn wrap(routes: Vec<Route>) -> Vec<Route> {
+        routes
+            .into_iter()
+            .map(|mut route| {
+                route.handler = Box::new(Traced(route.handler));
+                route
+            })
+            .collect()
+    }
+}
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        let request_id = RequestId::of(request);
+        let span = info_span!(
+            "request",
+            request_id,
+            method = %request.method(),
+            uri = %request.uri()
+        );
+        // A hub per request keeps one request's error-reporting scope out of another's.
+        let hub = Arc::new(Hub::new_from_top(Hub::current()));
+        hub.configure_scope(|scope| {
+            scope.set_tag("request_id", request_id);
+            if let Some(route) = request.route() {
+                scope.set_tag("route", &route.uri);
+            }
+        });
+
+        let started = Instant::now();
+        let mut outcome = self
+            .0
+            .handle(request, data)
+            .instrument(span.clone())
+            .bind_hub(hub)
+            .await;
+
+        let (status, bytes) = match &mut outcome {
+            route::Outcome::Success(response) => {
+                response.set_raw_header("X-Request-Id", request_id);
+                (response.status(), response.body().preset_size())
+            }
+            route::Outcome::Error(status) => (*status, None),
+            route::Outcome::Forward((_, status)) => (*status, None),
+        };
+        if let Some(username) = &request.local_cache(|| RequestUser(None)).0 {
+            record_request(request, username.clone(), bytes.unwrap_or(0) as i64);
+        }
+        span.in_scope(|| {
+            info!(
+                status = status.code,
+                elapsed_ms = started.elapsed().as_millis() as u64,
+                "Request finished"
+            )
+        });
+
+        outcome
+    }
+}
+
+/// Streamed bodies have no size up front and are counted as zero bytes served.
+fn record_request(request: &Request<'_>, username: String, bytes: i64) {
+    let Some(pool) = request.rocket().state::<ManagedPool>() else {
+        error!("Request usage accounting has no ManagedPool");
+        return;
+    };
+
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        if let Err(e) = sql::record_usage(&pool, &username, UsageMetric::Request, 1, bytes).await {
+            error!(error = ?e, "Request usage accounting error");
+        }
+    });
 }
 
 #[derive(Debug)]
-pub struct AuthorizedUser<'a> {
-    pub user: &'a User,
+pub struct AuthorizedUser {
+    pub user: User,
 }
 
-impl<'a> Deref for AuthorizedUser<'a> {
+impl Deref for AuthorizedUser {
     type Target = User;
 
     fn deref(&self) -> &Self::Target {
-        self.user
+        &self.user
     }
 }
 
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for AuthorizedUser<'r> {
+impl<'r> FromRequest<'r> for AuthorizedUser {
     type Error = Error;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let Some(auth) = request.headers().get_one("Authorization").or_else(|| {
-            request.uri().query().and_then(|query| {
-                query
-                    .segments()
-                    .find_map(|(key, value)| if key == "auth" { Some(value) } else { None })
-            })
-        }) else {
+        let Some(auth) = request
+            .headers()
+            .get_one("Authorization")
+            .or_else(|| query_param(request, "auth"))
+        else {
             return Outcome::Error((Status::Unauthorized, Error::Unauthorized));
         };
 
@@ -184,6 +1034,8 @@ impl<'r> FromRequest<'r> for AuthorizedUser<'r> {
             _ => return Outcome::Error((Status::Unauthorized, Error::Unauthorized)),
         };
 
+        let config = config.load();
+
         if let Some(user) = match &config.users {
             Users::Many(users) => users
                 .iter()
@@ -196,28 +1048,106 @@ impl<'r> FromRequest<'r> for AuthorizedUser<'r> {
                 }
             }
         } {
-            Outcome::Success(AuthorizedUser { user })
+            request.local_cache(|| RequestUser(Some(user.username.clone())));
+            sentry::configure_scope(|scope| {
+                scope.set_user(Some(sentry::User {
+                    username: Some(user.username.clone()),
+                    ..Default::default()
+                }))
+            });
+            Outcome::Success(AuthorizedUser { user: user.clone() })
         } else {
             Outcome::Error((Status::Unauthorized, Error::Unauthorized))
         }
     }
 }
 
+/// The username [`AuthorizedUser`] accepted, for guards that run after it.
+struct RequestUser(Option<String>);
+
+/// An [`AuthorizedUser`] with `admin` set in the config.
 #[derive(Debug)]
-pub struct Ratelimit;
+pub struct AdminUser {
+    pub user: User,
+}
+impl Deref for AdminUser {
+    type Target = User;
 
+    fn deref(&self) -> &Self::Target {
+        &self.user
+    }
+}
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for Ratelimit {
+impl<'r> FromRequest<'r> for AdminUser {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.guard::<AuthorizedUser>().await {
+            Outcome::Success(AuthorizedUser { user }) if user.admin => {
+                Outcome::Success(AdminUser { user })
+            }
+            Outcome::Success(_) => {
+                Outcome::Error((Status::Forbidden, Error::Forbidden(ErrorCode::Forbidden)))
+            }
+            Outcome::Error(e) => Outcome::Error(e),
+            Outcome::Forward(status) => Outcome::Forward(status),
+        }
+    }
+}
+
+pub trait RatelimitClass: Send + Sync + 'static {
+    const NAME: &'static str;
+}
+
+/// Cheap reads and writes; the class used when a route does not pick one.
+#[derive(Debug)]
+pub struct StandardClass;
+impl RatelimitClass for StandardClass {
+    const NAME: &'static str = "standard";
+}
+
+/// Script execution, which fans out over a whole mailbox and may make outbound requests.
+#[derive(Debug)]
+pub struct ScriptClass;
+impl RatelimitClass for ScriptClass {
+    const NAME: &'static str = "script";
+}
+
+pub const RATELIMIT_CLASSES: &[&str] = &[StandardClass::NAME, ScriptClass::NAME];
+
+#[derive(Debug)]
+pub struct Ratelimit<C: RatelimitClass = StandardClass> {
+    class: PhantomData<C>,
+}
+
+/// Accounted in the background so a client hammering the API is not slowed down by its own
+/// bookkeeping.
+async fn record_ratelimit_hit(request: &Request<'_>, username: String) {
+    let pool: &State<ManagedPool> = match request.guard().await {
+        Outcome::Success(x) => x,
+        other => {
+            error!(outcome = ?other, "Ratelimit from_request ManagedPool error");
+            return;
+        }
+    };
+
+    let pool = (*pool).clone();
+    tokio::spawn(async move {
+        if let Err(e) = sql::record_usage(&pool, &username, UsageMetric::RatelimitHit, 1, 0).await {
+            error!(error = ?e, "Ratelimit usage accounting error");
+        }
+    });
+}
+
+#[rocket::async_trait]
+impl<'r, C: RatelimitClass> FromRequest<'r> for Ratelimit<C> {
     type Error = Error;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
         let ratelimits: &State<ManagedRatelimits> = match request.guard().await {
             Outcome::Success(x) => x,
             other => {
-                eprintln!(
-                    "Ratelimit from_request ManagedRatelimits error: {:#?}",
-                    other
-                );
+                error!(outcome = ?other, "Ratelimit from_request ManagedRatelimits error");
                 return Outcome::Error((Status::InternalServerError, Error::InternalError));
             }
         };
@@ -225,30 +1155,45 @@ impl<'r> FromRequest<'r> for Ratelimit {
         let config: &State<ManagedConfig> = match request.guard().await {
             Outcome::Success(x) => x,
             other => {
-                eprintln!("Ratelimit from_request ManagedConfig error: {:#?}", other);
+                error!(outcome = ?other, "Ratelimit from_request ManagedConfig error");
                 return Outcome::Error((Status::InternalServerError, Error::InternalError));
             }
         };
 
         let Some(ip) = request.client_ip() else {
-            eprintln!("Ratelimit from_request .client_ip() None");
+            error!("Ratelimit from_request .client_ip() None");
             return Outcome::Error((Status::InternalServerError, Error::InternalError));
         };
 
-        let mut previous_requests = ratelimits
-            .entry(ip)
-            .or_insert_with(|| Vec::with_capacity(config.ratelimit.num));
-        *previous_requests = previous_requests
-            .iter()
-            .filter(|instant| instant.elapsed().as_millis() < config.ratelimit.in_ms)
-            .copied()
-            .collect();
-        if previous_requests.len() >= config.ratelimit.num {
-            Outcome::Error((Status::TooManyRequests, Error::Ratelimited))
-        } else {
-            previous_requests.push(Instant::now());
+        let rule = config.load().ratelimit.rule(C::NAME);
+
+        let limited = {
+            let mut previous_requests = ratelimits
+                .entry((ip, C::NAME))
+                .or_insert_with(|| Vec::with_capacity(rule.num));
+            *previous_requests = previous_requests
+                .iter()
+                .filter(|instant| instant.elapsed().as_millis() < rule.in_ms)
+                .copied()
+                .collect();
+            if previous_requests.len() >= rule.num {
+                true
+            } else {
+                previous_requests.push(Instant::now());
+                false
+            }
+        };
 
-            Outcome::Success(Ratelimit)
+        if limited {
+            if let Some(username) = &request.local_cache(|| RequestUser(None)).0 {
+                record_ratelimit_hit(request, username.clone()).await;
+            }
+            Outcome::Error((
+                Status::TooManyRequests,
+                Error::Ratelimited(ErrorCode::Ratelimited),
+            ))
+        } else {
+            Outcome::Success(Ratelimit { class: PhantomData })
         }
     }
 }
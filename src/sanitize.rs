@@ -0,0 +1,132 @@
+use lol_html::html_content::{ContentType, Element};
+use lol_html::{element, rewrite_str, text, HandlerResult, RewriteStrSettings};
+use tracing::error;
+
+/// Elements removed with their content: those that run code, embed other documents or change
+/// where relative URLs point.
+const REMOVED_ELEMENTS: &str = "script, noscript, iframe, frame, frameset, object, embed, applet, \
+                                base, portal, meta[http-equiv]";
+
+/// Attributes holding a URL that is followed or loaded.
+const URL_ATTRIBUTES: &[&str] = &[
+    "href",
+    "src",
+    "action",
+    "formaction",
+    "background",
+    "poster",
+    "lowsrc",
+    "dynsrc",
+    "xlink:href",
+];
+
+/// Attributes holding a URL that is loaded as the page renders, which a sender can use to learn
+/// that the email was opened.
+const LOADED_URL_ATTRIBUTES: &[&str] = &["src", "background", "poster", "lowsrc", "dynsrc"];
+
+/// The scheme of `url`, lowercased, ignoring the whitespace and control characters browsers do.
+fn scheme(url: &str) -> Option<String> {
+    let url = url
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>();
+    let (scheme, _) = url.split_once(':')?;
+    scheme
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+        .then(|| scheme.to_ascii_lowercase())
+}
+
+fn is_remote(url: &str) -> bool {
+    let url = url.trim_start();
+    url.starts_with("//") || matches!(scheme(url).as_deref(), Some("http" | "https"))
+}
+
+/// Whether the CSS `css` can load anything, counting escapes as they could spell `url(` or
+/// `@import`.
+fn css_loads(css: &str) -> bool {
+    let css = css.to_ascii_lowercase();
+    ["url(", "image-set(", "expression(", "@import", "\\"]
+        .iter()
+        .any(|needle| css.contains(needle))
+}
+
+fn sanitize_attributes(el: &mut Element) -> HandlerResult {
+    let names = el
+        .attributes()
+        .iter()
+        .map(|attribute| attribute.name())
+        .collect::<Vec<_>>();
+    for name in names {
+        let Some(value) = el.get_attribute(&name) else {
+            continue;
+        };
+        let remove = if name.starts_with("on") {
+            true
+        } else if URL_ATTRIBUTES.contains(&name.as_str()) {
+            match scheme(&value).as_deref() {
+                Some("javascript" | "vbscript") => true,
+                // Only images may be data, as an `<img>` cannot run what it loads.
+                Some("data") => !(el.tag_name() == "img" && name == "src"),
+                _ => LOADED_URL_ATTRIBUTES.contains(&name.as_str()) && is_remote(&value),
+            }
+        } else if name == "srcset" {
+            value.contains("://") || value.trim_start().starts_with("//")
+        } else if name == "style" {
+            css_loads(&value)
+        } else {
+            false
+        };
+        if remove {
+            el.remove_attribute(&name);
+        }
+    }
+    Ok(())
+}
+
+/// A copy of `html` that cannot run scripts or load remote resources: script-like elements are
+/// removed, as are `<link>`s, event handler attributes, `javascript:` URLs, remote images, and
+/// `style` attributes and `<style>` element contents that load anything, such as with `url()` or
+/// `@import`. If `html` cannot be rewritten, the result is empty rather than unsafe.
+pub fn sanitize(html: &str) -> String {
+    let mut stylesheet = String::new();
+    let rewritten = rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![
+                element!(REMOVED_ELEMENTS, |el| {
+                    el.remove();
+                    Ok(())
+                }),
+                element!("link", |el| {
+                    el.remove();
+                    Ok(())
+                }),
+                element!("*", sanitize_attributes),
+                text!("style", move |chunk| {
+                    // A stylesheet can arrive in several chunks, so it is held back until its end.
+                    stylesheet.push_str(chunk.as_str());
+                    if chunk.last_in_text_node() {
+                        if css_loads(&stylesheet) {
+                            chunk.remove();
+                        } else {
+                            chunk.replace(&stylesheet, ContentType::Html);
+                        }
+                        stylesheet.clear();
+                    } else {
+                        chunk.remove();
+                    }
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::default()
+        },
+    );
+    match rewritten {
+        Ok(x) => x,
+        Err(e) => {
+            error!(error = ?e, "Sanitize rewrite error");
+            String::new()
+        }
+    }
+}
@@ -0,0 +1,525 @@
+use crate::{
+    config::{Config, IngestProtocol},
+    imap::{self, FetchedEmail, StoreOutcome, RECONNECT_BASE, RECONNECT_MAX},
+    ingest,
+    status::Status,
+    systemd::{self, Readiness},
+    triggers::Triggers,
+    util, ManagedConfig, ManagedStatus,
+};
+use chrono::Utc;
+use mailparse::MailHeaderMap;
+use rocket::Shutdown;
+use sqlx::{Pool, Sqlite};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio::time;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+/// How often the watchdog is fed while listening.
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a client may take to send a command or a line of a message (RFC 5321 4.5.3.2).
+const READ_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// After a failed accept, such as when out of file descriptors, so it is not retried in a loop.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+/// Longer command lines are refused; RFC 5321 allows 512 bytes.
+const COMMAND_LINE_MAX: u64 = 4096;
+/// RFC 5321 requires accepting at least 100.
+const RECIPIENTS_MAX: usize = 100;
+
+/// Listens for deliveries until shutdown, binding again with backoff if `smtp.listen` cannot be
+/// bound. Readiness is reported once it is. Sessions in progress at shutdown are told so between
+/// commands and waited for, so no message is left half stored.
+pub async fn perform(
+    managed_config: ManagedConfig,
+    pool: Pool<Sqlite>,
+    status: ManagedStatus,
+    triggers: Triggers,
+    readiness: Arc<Readiness>,
+    shutdown: Shutdown,
+) {
+    // Read at startup like the protocol, as the status lists the listener by it.
+    let listen = managed_config.load().smtp.listen;
+    let account = listen.to_string();
+    status.update_imap(&account, |_| {});
+
+    let mut attempt = 0;
+    let listener = loop {
+        let bound = tokio::select! {
+            bound = TcpListener::bind(listen) => bound,
+            _ = shutdown.clone() => return,
+        };
+        match bound {
+            Ok(x) => {
+                info!(%listen, "SMTP listening");
+                break x;
+            }
+            Err(e) => {
+                error!(error = ?e, %listen, "SMTP bind error");
+                status.ingest_failed(&account, format!("bind: {}", e));
+            }
+        }
+
+        let delay = util::backoff(attempt, RECONNECT_BASE, RECONNECT_MAX);
+        attempt = attempt.saturating_add(1);
+        warn!(
+            attempt,
+            delay_ms = delay.as_millis() as u64,
+            "SMTP rebinding"
+        );
+        tokio::select! {
+            _ = time::sleep(delay) => {}
+            _ = shutdown.clone() => return,
+        }
+    };
+
+    readiness.component_ready();
+    status.update_imap(&account, |imap| imap.connected = true);
+    status.ingest_succeeded(&account, 0);
+
+    let mut sessions = JoinSet::new();
+    let mut watchdog = time::interval(WATCHDOG_INTERVAL);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let session = Session {
+                        account: account.clone(),
+                        peer,
+                        config: managed_config.load_full(),
+                        pool: pool.clone(),
+                        status: Arc::clone(&status),
+                        triggers: triggers.clone(),
+                        shutdown: shutdown.clone(),
+                    };
+                    sessions.spawn(session.run(stream).instrument(info_span!("smtp", %peer)));
+                }
+                Err(e) => {
+                    error!(error = ?e, "SMTP accept error");
+                    time::sleep(ACCEPT_ERROR_DELAY).await;
+                }
+            },
+            Some(_) = sessions.join_next(), if !sessions.is_empty() => {}
+            _ = watchdog.tick() => systemd::watchdog(),
+            _ = shutdown.clone() => break,
+        }
+    }
+
+    drop(listener);
+    while sessions.join_next().await.is_some() {}
+    status.update_imap(&account, |imap| imap.connected = false);
+}
+
+/// The transaction a `MAIL` command starts.
+struct Envelope {
+    /// The name the client greeted with.
+    client: String,
+    /// Empty for bounces, whose reverse path is `<>`.
+    sender: String,
+    /// Those that may route to a user. Routing rules can depend on the message, so which user
+    /// each is for is decided once it has been sent.
+    recipients: Vec<String>,
+}
+
+/// What the client sent after `DATA`.
+enum Data {
+    Message(Vec<u8>),
+    TooLarge,
+}
+
+/// Why reading from the client stopped.
+enum ReadEnd {
+    Closed,
+    TimedOut,
+    TooLong,
+    Io(io::Error),
+}
+
+struct Session {
+    /// The listener's entry in the status.
+    account: String,
+    peer: SocketAddr,
+    config: Arc<Config>,
+    pool: Pool<Sqlite>,
+    status: Arc<Status>,
+    triggers: Triggers,
+    shutdown: Shutdown,
+}
+impl Session {
+    fn lmtp(&self) -> bool {
+        self.config.ingest.protocol == IngestProtocol::Lmtp
+    }
+
+    async fn run(self, stream: TcpStream) {
+        let mut stream = BufReader::new(stream);
+        if let Err(e) = self.converse(&mut stream).await {
+            debug!(error = ?e, "SMTP session error");
+        }
+        if let Err(e) = stream.get_mut().shutdown().await {
+            debug!(error = ?e, "SMTP close error");
+        }
+    }
+
+    /// Handles commands until the client quits or disconnects or shutdown starts.
+    async fn converse(&self, stream: &mut BufReader<TcpStream>) -> io::Result<()> {
+        let hostname = self.config.smtp.hostname.as_str();
+        let service = if self.lmtp() { "LMTP" } else { "ESMTP" };
+        reply(stream, &format!("220 {} {} EPV", hostname, service)).await?;
+
+        let mut greeted = None;
+        let mut envelope: Option<Envelope> = None;
+        loop {
+            let line = tokio::select! {
+                line = read_line(stream, COMMAND_LINE_MAX) => line,
+                _ = self.shutdown.clone() => {
+                    return reply(stream, &format!("421 4.3.0 {} shutting down", hostname)).await;
+                }
+            };
+            let line = match line {
+                Ok(x) => x,
+                Err(ReadEnd::Closed) => return Ok(()),
+                Err(ReadEnd::TimedOut) => {
+                    return reply(stream, &format!("421 4.4.2 {} timeout", hostname)).await;
+                }
+                Err(ReadEnd::TooLong) => {
+                    return reply(stream, "500 5.5.6 Line too long").await;
+                }
+                Err(ReadEnd::Io(e)) => return Err(e),
+            };
+            let line = String::from_utf8_lossy(&line);
+            let (verb, argument) = line.split_once(' ').unwrap_or((&line, ""));
+            let verb = verb.to_ascii_uppercase();
+
+            match verb.as_str() {
+                "LHLO" if self.lmtp() => {
+                    greeted = Some(client_name(argument));
+                    envelope = None;
+                    reply(stream, &self.extensions()).await?;
+                }
+                "EHLO" if !self.lmtp() => {
+                    greeted = Some(client_name(argument));
+                    envelope = None;
+                    reply(stream, &self.extensions()).await?;
+                }
+                "HELO" if !self.lmtp() => {
+                    greeted = Some(client_name(argument));
+                    envelope = None;
+                    reply(stream, &format!("250 {}", hostname)).await?;
+                }
+                "MAIL" => {
+                    let (Some(client), None) = (&greeted, &envelope) else {
+                        reply(stream, "503 5.5.1 Bad sequence of commands").await?;
+                        continue;
+                    };
+                    let Some((sender, parameters)) = path_argument(argument, "FROM:") else {
+                        reply(stream, "501 5.5.4 Syntax: MAIL FROM:<address>").await?;
+                        continue;
+                    };
+                    let declared_size = parameters.split_ascii_whitespace().find_map(|parameter| {
+                        let (name, value) = parameter.split_once('=')?;
+                        if name.eq_ignore_ascii_case("SIZE") {
+                            value.parse::<usize>().ok()
+                        } else {
+                            None
+                        }
+                    });
+                    if declared_size.is_some_and(|size| size > self.config.smtp.max_size) {
+                        reply(stream, "552 5.3.4 Message too large").await?;
+                        continue;
+                    }
+                    envelope = Some(Envelope {
+                        client: client.clone(),
+                        sender: sender.to_owned(),
+                        recipients: vec![],
+                    });
+                    reply(stream, "250 2.1.0 OK").await?;
+                }
+                "RCPT" => {
+                    let Some(envelope) = &mut envelope else {
+                        reply(stream, "503 5.5.1 Bad sequence of commands").await?;
+                        continue;
+                    };
+                    let Some((address, _)) = path_argument(argument, "TO:") else {
+                        reply(stream, "501 5.5.4 Syntax: RCPT TO:<address>").await?;
+                        continue;
+                    };
+                    if envelope.recipients.len() >= RECIPIENTS_MAX {
+                        reply(stream, "452 4.5.3 Too many recipients").await?;
+                        continue;
+                    }
+                    if ingest::may_route(&self.config, address) {
+                        envelope.recipients.push(address.to_owned());
+                        reply(stream, "250 2.1.5 OK").await?;
+                    } else {
+                        debug!(address, "SMTP no matching user");
+                        reply(stream, "550 5.1.1 No such user").await?;
+                    }
+                }
+                "DATA" => {
+                    let Some(current) = envelope.take() else {
+                        reply(stream, "503 5.5.1 Bad sequence of commands").await?;
+                        continue;
+                    };
+                    if current.recipients.is_empty() {
+                        reply(stream, "554 5.5.1 No valid recipients").await?;
+                        continue;
+                    }
+                    reply(stream, "354 End data with <CR><LF>.<CR><LF>").await?;
+                    let data = match read_data(stream, self.config.smtp.max_size).await {
+                        Ok(x) => x,
+                        Err(ReadEnd::TimedOut) => {
+                            return reply(stream, &format!("421 4.4.2 {} timeout", hostname)).await;
+                        }
+                        Err(ReadEnd::Io(e)) => return Err(e),
+                        // `read_data` reads past overlong lines.
+                        Err(ReadEnd::Closed | ReadEnd::TooLong) => return Ok(()),
+                    };
+                    let replies = match data {
+                        Data::Message(message) => self.deliver(&current, message).await,
+                        Data::TooLarge => {
+                            vec!["552 5.3.4 Message too large"; current.recipients.len()]
+                        }
+                    };
+                    if self.lmtp() {
+                        for text in replies {
+                            reply(stream, text).await?;
+                        }
+                    } else {
+                        reply(stream, overall_reply(&replies)).await?;
+                    }
+                }
+                "RSET" => {
+                    envelope = None;
+                    reply(stream, "250 2.0.0 OK").await?;
+                }
+                "NOOP" => reply(stream, "250 2.0.0 OK").await?,
+                "VRFY" => reply(stream, "252 2.5.0 Cannot verify, send some mail").await?,
+                "QUIT" => {
+                    return reply(stream, &format!("221 2.0.0 {} closing", hostname)).await;
+                }
+                _ => reply(stream, "502 5.5.2 Command not implemented").await?,
+            }
+        }
+    }
+
+    /// The reply to `EHLO` or `LHLO`. LMTP requires `PIPELINING` and `ENHANCEDSTATUSCODES`.
+    fn extensions(&self) -> String {
+        format!(
+            "250-{}\r\n250-PIPELINING\r\n250-8BITMIME\r\n250-ENHANCEDSTATUSCODES\r\n250 SIZE {}",
+            self.config.smtp.hostname, self.config.smtp.max_size
+        )
+    }
+
+    /// Routes each recipient, stores `message` once for each user among them, to the first of
+    /// their addresses, and returns the reply for each recipient in order. A `Return-Path`
+    /// header with the envelope sender and a `Received` header with the client are added, as
+    /// final delivery does, so SPF is checked against them.
+    async fn deliver(&self, envelope: &Envelope, message: Vec<u8>) -> Vec<&'static str> {
+        let headers = mailparse::parse_headers(&message)
+            .ok()
+            .map(|(headers, _)| headers);
+        let from_addr = headers
+            .as_ref()
+            .and_then(|headers| ingest::first_address(headers, "From"))
+            .unwrap_or_else(|| envelope.sender.clone());
+        let subject = headers
+            .as_ref()
+            .and_then(|headers| headers.get_first_value("Subject"))
+            .unwrap_or_default();
+        let service = if self.lmtp() { "LMTP" } else { "ESMTP" };
+        let mut body = format!(
+            "Return-Path: <{}>\r\nReceived: from {} ([{}]) by {} with {}; {}\r\n",
+            envelope.sender,
+            envelope.client,
+            self.peer.ip(),
+            self.config.smtp.hostname,
+            service,
+            Utc::now().to_rfc2822()
+        )
+        .into_bytes();
+        body.extend_from_slice(&message);
+
+        let routed = envelope
+            .recipients
+            .iter()
+            .map(|address| {
+                ingest::route(&self.config, &from_addr, &[address.clone()], &subject)
+                    .map(|(user, _)| user.username.as_str())
+            })
+            .collect::<Vec<_>>();
+
+        let mut users: Vec<&str> = vec![];
+        let mut fetched = vec![];
+        for (address, user) in envelope.recipients.iter().zip(&routed) {
+            let Some(user) = *user else {
+                debug!(address, "SMTP no matching user");
+                continue;
+            };
+            if users.contains(&user) {
+                continue;
+            }
+            fetched.push(FetchedEmail {
+                user: user.to_owned(),
+                from_addr: from_addr.clone(),
+                to_addr: address.clone(),
+                body: body.clone(),
+            });
+            users.push(user);
+        }
+
+        // Deliveries are not for any one account, so the first one's parallelism applies.
+        let accounts = self.config.imap.as_slice();
+        let parallelism = accounts.first().map_or(1, |account| account.parallelism);
+        let outcomes = imap::store_fetched(
+            fetched,
+            &self.account,
+            parallelism,
+            &self.config,
+            &self.pool,
+            &self.status,
+            &self.triggers,
+            &self.shutdown,
+        )
+        .await;
+        debug!(
+            sender = %envelope.sender,
+            users = users.len(),
+            "SMTP message handled"
+        );
+
+        routed
+            .iter()
+            .map(|user| {
+                let Some(user) = *user else {
+                    return "550 5.1.1 No such user";
+                };
+                let index = users
+                    .iter()
+                    .position(|&listed| listed == user)
+                    .expect("every routed recipient's user is listed");
+                match outcomes.get(index) {
+                    Some(StoreOutcome::Handled) => "250 2.0.0 Stored",
+                    Some(StoreOutcome::Rejected) => "554 5.6.0 Message cannot be stored",
+                    Some(StoreOutcome::Retry) | None => "451 4.3.0 Temporary failure, try again",
+                }
+            })
+            .collect()
+    }
+}
+
+/// SMTP's single reply for all recipients: a temporary failure for any of them has the whole
+/// message sent again, as duplicates of those stored are recognised. Otherwise it is only
+/// refused if no recipient got it.
+fn overall_reply<'a>(replies: &[&'a str]) -> &'a str {
+    if let Some(temporary) = replies.iter().find(|text| text.starts_with('4')) {
+        return *temporary;
+    }
+    replies
+        .iter()
+        .find(|text| text.starts_with('2'))
+        .or_else(|| replies.first())
+        .copied()
+        .unwrap_or("554 5.5.1 No valid recipients")
+}
+
+async fn reply(stream: &mut BufReader<TcpStream>, text: &str) -> io::Result<()> {
+    let stream = stream.get_mut();
+    stream.write_all(text.as_bytes()).await?;
+    stream.write_all(b"\r\n").await?;
+    stream.flush().await
+}
+
+/// The name a client greeted with in `argument`, for the `Received` header, or `unknown` if it
+/// gave none.
+fn client_name(argument: &str) -> String {
+    argument
+        .split_ascii_whitespace()
+        .next()
+        .unwrap_or("unknown")
+        .to_owned()
+}
+
+/// `argument`, as in `FROM:<a@example.com> SIZE=123` with `keyword` `FROM:`, split into the
+/// address between the angle brackets and the parameters after them.
+fn path_argument<'a>(argument: &'a str, keyword: &str) -> Option<(&'a str, &'a str)> {
+    let prefix = argument.get(..keyword.len())?;
+    if !prefix.eq_ignore_ascii_case(keyword) {
+        return None;
+    }
+    let rest = argument[keyword.len()..].trim_start().strip_prefix('<')?;
+    let (path, parameters) = rest.split_once('>')?;
+    // A source route, as in `<@relay.example:a@example.com>`, is to be ignored.
+    let address = path.rsplit_once(':').map_or(path, |(_, address)| address);
+    Some((address, parameters.trim()))
+}
+
+/// The next line without its line ending, of at most `limit` bytes.
+async fn read_line(stream: &mut BufReader<TcpStream>, limit: u64) -> Result<Vec<u8>, ReadEnd> {
+    let mut line = vec![];
+    let read = time::timeout(
+        READ_TIMEOUT,
+        (&mut *stream).take(limit).read_until(b'\n', &mut line),
+    )
+    .await
+    .map_err(|_| ReadEnd::TimedOut)?
+    .map_err(ReadEnd::Io)?;
+    if read == 0 {
+        return Err(ReadEnd::Closed);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(if (read as u64) < limit {
+            ReadEnd::Closed
+        } else {
+            ReadEnd::TooLong
+        });
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(line)
+}
+
+/// The message after `DATA`, up to the lone `.`, with dot-stuffing undone and lines ending in
+/// CRLF. A message over `max_size` is read to its end but not kept.
+async fn read_data(stream: &mut BufReader<TcpStream>, max_size: usize) -> Result<Data, ReadEnd> {
+    let mut message = vec![];
+    let mut too_large = false;
+    loop {
+        let line = match read_line(stream, max_size as u64 + 2).await {
+            Ok(x) => x,
+            Err(ReadEnd::TooLong) => {
+                // The rest of the line is read, and discarded, as further lines.
+                too_large = true;
+                message = vec![];
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if line == b"." {
+            break;
+        }
+        if too_large {
+            continue;
+        }
+        let line = line.strip_prefix(b".").unwrap_or(&line);
+        if message.len() + line.len() + 2 > max_size {
+            too_large = true;
+            message = vec![];
+            continue;
+        }
+        message.extend_from_slice(line);
+        message.extend_from_slice(b"\r\n");
+    }
+
+    if too_large {
+        Ok(Data::TooLarge)
+    } else {
+        Ok(Data::Message(message))
+    }
+}
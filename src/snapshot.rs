@@ -0,0 +1,226 @@
+use crate::{
+    api::execute_script::http_client,
+    config::{Config, SnapshotMode},
+    util::WorkerPool,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use itertools::Itertools;
+use lol_html::{
+    element,
+    errors::RewritingError,
+    html_content::{ContentType, Element},
+    rewrite_str, HandlerResult, RewriteStrSettings,
+};
+use reqwest::{header::CONTENT_TYPE, Client as HttpClient, Response};
+use scraper::{Html, Selector};
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::{error, warn};
+use url::Url;
+
+/// Resources of one email fetched at the same time.
+const SNAPSHOT_FETCHES: usize = 4;
+
+struct Resource {
+    mime: String,
+    body: Vec<u8>,
+}
+impl Resource {
+    fn data_uri(&self) -> String {
+        format!("data:{};base64,{}", self.mime, STANDARD.encode(&self.body))
+    }
+
+    /// `</` would end the `<style>` element early; `<\/` means the same to CSS.
+    fn css(&self) -> String {
+        String::from_utf8_lossy(&self.body).replace("</", "<\\/")
+    }
+}
+
+fn remote_url(value: &str) -> Option<Url> {
+    Url::parse(value)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+fn is_stylesheet(rel: Option<&str>) -> bool {
+    rel.is_some_and(|rel| {
+        rel.split_ascii_whitespace()
+            .any(|rel| rel.eq_ignore_ascii_case("stylesheet"))
+    })
+}
+
+/// Remote image sources and stylesheet links, in document order without duplicates.
+fn remote_resources(html: &str) -> Vec<Url> {
+    let document = Html::parse_document(html);
+    let images = Selector::parse("img[src]").expect("remote_resources: invalid premade selector");
+    let links = Selector::parse("link[href]").expect("remote_resources: invalid premade selector");
+
+    let image_urls = document
+        .select(&images)
+        .filter_map(|el| el.value().attr("src"));
+    let stylesheet_urls = document
+        .select(&links)
+        .filter(|el| is_stylesheet(el.value().attr("rel")))
+        .filter_map(|el| el.value().attr("href"));
+    image_urls
+        .chain(stylesheet_urls)
+        .filter_map(remote_url)
+        .unique()
+        .collect()
+}
+
+async fn fetch(
+    client: HttpClient,
+    url: Url,
+    timeout: Duration,
+    max_bytes: usize,
+) -> Option<Resource> {
+    let mut response = match client
+        .get(url.clone())
+        .timeout(timeout)
+        .send()
+        .await
+        .and_then(Response::error_for_status)
+    {
+        Ok(x) => x,
+        Err(e) => {
+            warn!(error = ?e, url = %url, "Snapshot fetch error");
+            return None;
+        }
+    };
+    if response
+        .content_length()
+        .is_some_and(|len| len > max_bytes as u64)
+    {
+        warn!(url = %url, "Snapshot resource too large");
+        return None;
+    }
+
+    let mime = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(str::trim)
+        .filter(|mime| !mime.is_empty())
+        .unwrap_or("application/octet-stream")
+        .to_owned();
+
+    let mut body = vec![];
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                if body.len() + chunk.len() > max_bytes {
+                    warn!(url = %url, "Snapshot resource too large");
+                    return None;
+                }
+                body.extend_from_slice(&chunk);
+            }
+            Ok(None) => break,
+            Err(e) => {
+                warn!(error = ?e, url = %url, "Snapshot fetch error");
+                return None;
+            }
+        }
+    }
+
+    Some(Resource { mime, body })
+}
+
+async fn fetch_all(config: &Config, html: &str) -> HashMap<Url, Resource> {
+    let client = match http_client(&config.http) {
+        Ok(x) => x,
+        Err(e) => {
+            error!(error = ?e, "Snapshot initialize HTTP client error");
+            return HashMap::new();
+        }
+    };
+
+    let urls: Vec<Url> = remote_resources(html)
+        .into_iter()
+        .take(config.snapshot.max_resources)
+        .collect();
+    let timeout = Duration::from_secs(config.snapshot.timeout_secs);
+    let max_bytes = config.snapshot.max_resource_bytes;
+    let fetched = WorkerPool::new(SNAPSHOT_FETCHES)
+        .run_ordered(
+            urls.iter()
+                .map(|url| fetch(client.clone(), url.clone(), timeout, max_bytes)),
+        )
+        .await;
+
+    urls.into_iter()
+        .zip(fetched)
+        .filter_map(|(url, resource)| Some((url, resource.ok().flatten()?)))
+        .collect()
+}
+
+/// Candidates in `srcset` would each need fetching, and `src` renders fine without them.
+fn strip_remote_srcset(el: &mut Element) -> HandlerResult {
+    if el
+        .get_attribute("srcset")
+        .is_some_and(|srcset| srcset.contains("://"))
+    {
+        el.remove_attribute("srcset");
+    }
+    Ok(())
+}
+
+/// Replaces every remote image and stylesheet with its entry in `resources`, or removes it when
+/// there is none.
+fn rewrite(html: &str, resources: &HashMap<Url, Resource>) -> Result<String, RewritingError> {
+    rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![
+                element!("img[src]", |el| {
+                    let Some(url) = el.get_attribute("src").as_deref().and_then(remote_url) else {
+                        return Ok(());
+                    };
+                    match resources.get(&url) {
+                        Some(resource) => el.set_attribute("src", &resource.data_uri())?,
+                        None => el.remove_attribute("src"),
+                    }
+                    Ok(())
+                }),
+                element!("img[srcset]", strip_remote_srcset),
+                element!("source[srcset]", strip_remote_srcset),
+                element!("link[href]", |el| {
+                    if !is_stylesheet(el.get_attribute("rel").as_deref()) {
+                        return Ok(());
+                    }
+                    let Some(url) = el.get_attribute("href").as_deref().and_then(remote_url) else {
+                        return Ok(());
+                    };
+                    match resources.get(&url) {
+                        Some(resource) => el.replace(
+                            &format!("<style>{}</style>", resource.css()),
+                            ContentType::Html,
+                        ),
+                        None => el.remove(),
+                    }
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::default()
+        },
+    )
+}
+
+/// Rewrites `html` according to `config.snapshot`. Only `<img>` and stylesheet `<link>`s are
+/// handled; `url()`s inside stylesheets and `style` attributes are left as they are.
+pub async fn apply(config: &Config, html: String) -> String {
+    let resources = match config.snapshot.mode {
+        SnapshotMode::Off => return html,
+        SnapshotMode::Strip => HashMap::new(),
+        SnapshotMode::Inline => fetch_all(config, &html).await,
+    };
+
+    match rewrite(&html, &resources) {
+        Ok(x) => x,
+        Err(e) => {
+            error!(error = ?e, "Snapshot rewrite error");
+            html
+        }
+    }
+}
@@ -1,5 +1,55 @@
-use crate::api::execute_script::EmailAttribute;
-use sqlx::FromRow;
+use crate::api::execute_script::{Action, EmailAttribute, StageTiming, DATE_FORMAT};
+use crate::config::{JournalMode, Storage, Synchronous};
+use crate::util;
+use chrono::{DateTime, FixedOffset, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use sqlx::{FromRow, Pool, QueryBuilder, Sqlite};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+pub fn connect_options(storage: &Storage) -> Result<SqliteConnectOptions, sqlx::Error> {
+    let journal_mode = match storage.journal_mode {
+        JournalMode::Delete => SqliteJournalMode::Delete,
+        JournalMode::Truncate => SqliteJournalMode::Truncate,
+        JournalMode::Persist => SqliteJournalMode::Persist,
+        JournalMode::Memory => SqliteJournalMode::Memory,
+        JournalMode::Wal => SqliteJournalMode::Wal,
+        JournalMode::Off => SqliteJournalMode::Off,
+    };
+    let synchronous = match storage.synchronous {
+        Synchronous::Off => SqliteSynchronous::Off,
+        Synchronous::Normal => SqliteSynchronous::Normal,
+        Synchronous::Full => SqliteSynchronous::Full,
+        Synchronous::Extra => SqliteSynchronous::Extra,
+    };
+
+    let mut options = SqliteConnectOptions::from_str(&storage.sqlite)?
+        .journal_mode(journal_mode)
+        .synchronous(synchronous)
+        .busy_timeout(Duration::from_millis(storage.busy_timeout_ms));
+    if let Some(cache_size) = storage.cache_size {
+        options = options.pragma("cache_size", cache_size.to_string());
+    }
+
+    Ok(options)
+}
+
+pub async fn connect(storage: &Storage) -> Result<Pool<Sqlite>, sqlx::Error> {
+    SqlitePoolOptions::new()
+        .max_connections(storage.max_connections)
+        .min_connections(storage.min_connections)
+        .acquire_timeout(Duration::from_millis(storage.acquire_timeout_ms))
+        .idle_timeout(storage.idle_timeout_ms.map(Duration::from_millis))
+        .connect_with(connect_options(storage)?)
+        .await
+}
 
 #[derive(FromRow, Debug, Clone)]
 pub struct Email {
@@ -10,14 +60,1147 @@ pub struct Email {
     pub from_addr: String,
     pub to_addr: String,
     pub subject: String,
+    pub headers: String,
+    pub from_name: Option<String>,
+    pub to_name: Option<String>,
+    /// Unix ms from the `Date` header.
+    pub sent: Option<i64>,
+    /// The sender's UTC offset in minutes, from the `Date` header.
+    pub sent_offset: Option<i64>,
+    /// Path of the stored RFC822 message, for emails ingested since it has been kept.
+    pub raw: Option<String>,
+    /// The `Message-ID` header without angle brackets.
+    pub message_id: Option<String>,
+    /// The first message ID in the `In-Reply-To` header, which is the parent's `message_id`.
+    pub in_reply_to: Option<String>,
+    /// `pass`, `fail` or `none`, if checked at ingestion.
+    pub dkim: Option<String>,
+    pub spf: Option<String>,
+    /// Path of the sanitized copy of `html`, for emails stored or replayed since it has been
+    /// kept.
+    pub sanitized_html: Option<String>,
 }
 impl Email {
-    pub(crate) fn get_attribute(&self, attribute: EmailAttribute) -> &str {
+    /// The first value of header `name` from `headers`.
+    pub fn first_header(&self, name: &str) -> Option<String> {
+        let headers: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(&self.headers).ok()?;
+        match headers.get(&name.to_ascii_lowercase())? {
+            serde_json::Value::String(value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get_attribute(&self, attribute: EmailAttribute) -> Cow<'_, str> {
         match attribute {
-            EmailAttribute::Id => &self.id,
-            EmailAttribute::FromAddress => &self.from_addr,
-            EmailAttribute::Subject => &self.subject,
-            EmailAttribute::ToAddress => &self.to_addr,
+            EmailAttribute::Id => Cow::Borrowed(&self.id),
+            EmailAttribute::FromAddress => Cow::Borrowed(&self.from_addr),
+            EmailAttribute::FromName => {
+                Cow::Borrowed(self.from_name.as_deref().unwrap_or_default())
+            }
+            EmailAttribute::Subject => Cow::Borrowed(&self.subject),
+            EmailAttribute::ToAddress => Cow::Borrowed(&self.to_addr),
+            EmailAttribute::MessageId => {
+                Cow::Borrowed(self.message_id.as_deref().unwrap_or_default())
+            }
+            EmailAttribute::InReplyTo => {
+                Cow::Borrowed(self.in_reply_to.as_deref().unwrap_or_default())
+            }
+            EmailAttribute::Dkim => Cow::Borrowed(self.dkim.as_deref().unwrap_or("none")),
+            EmailAttribute::Spf => Cow::Borrowed(self.spf.as_deref().unwrap_or("none")),
+            EmailAttribute::SentAt => self
+                .sent_local()
+                .map(|sent| Cow::Owned(sent.format(DATE_FORMAT).to_string()))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// `sent` as the sender's local date and time.
+    pub fn sent_local(&self) -> Option<NaiveDateTime> {
+        let offset =
+            FixedOffset::east_opt(i32::try_from(self.sent_offset.unwrap_or(0) * 60).ok()?)?;
+        Some(
+            DateTime::from_timestamp_millis(self.sent?)?
+                .with_timezone(&offset)
+                .naive_local(),
+        )
+    }
+}
+
+/// The timestamp email listings are ordered by, newest first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmailOrder {
+    #[default]
+    Registered,
+    /// The `Date` header, or `registered` for emails without one.
+    Sent,
+}
+impl EmailOrder {
+    /// Must match the expression indexed by the migrations.
+    fn column(self) -> &'static str {
+        match self {
+            EmailOrder::Registered => "registered",
+            EmailOrder::Sent => "COALESCE(sent, registered)",
+        }
+    }
+
+    fn key(self, email: &Email) -> i64 {
+        match self {
+            EmailOrder::Registered => email.registered,
+            EmailOrder::Sent => email.sent.unwrap_or(email.registered),
+        }
+    }
+}
+impl FromStr for EmailOrder {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "registered" => Ok(EmailOrder::Registered),
+            "sent" => Ok(EmailOrder::Sent),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A position in the newest-first `(key, id)` ordering shared by every email listing, where
+/// `key` is the timestamp of the listing's [`EmailOrder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    pub key: i64,
+    pub id: String,
+}
+impl Cursor {
+    pub fn start() -> Self {
+        Cursor {
+            key: i64::MAX,
+            id: String::new(),
+        }
+    }
+
+    pub fn after(email: &Email, order: EmailOrder) -> Self {
+        Cursor {
+            key: order.key(email),
+            id: email.id.clone(),
+        }
+    }
+
+    /// The cursor for the page following `page`, if `page` was cut short by `limit`.
+    pub fn next_page(page: &[Email], order: EmailOrder, limit: i64) -> Option<Self> {
+        match page.last() {
+            Some(last) if limit >= 0 && page.len() as i64 == limit => {
+                Some(Cursor::after(last, order))
+            }
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.key, self.id)
+    }
+}
+impl FromStr for Cursor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Ids are hex, while the key can be a negative `sent`.
+        let (key, id) = s.rsplit_once('-').ok_or(())?;
+        Ok(Cursor {
+            key: key.parse().map_err(|_| ())?,
+            id: id.to_owned(),
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct EmailFilter<'a> {
+    pub from_addr: Option<&'a str>,
+    /// Header name (case-insensitive) and, optionally, the exact value it must have.
+    pub header: Option<(&'a str, Option<&'a str>)>,
+}
+
+/// Header names are stored lowercased, so lookups must be too.
+pub fn header_path(name: &str) -> String {
+    format!("$.\"{}\"", name.to_ascii_lowercase())
+}
+
+pub fn valid_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && b != b':' && b != b'"' && b != b'\\')
+}
+
+fn push_email_filter<'a>(
+    query: &mut QueryBuilder<'a, Sqlite>,
+    user: &'a str,
+    filter: &EmailFilter<'a>,
+) {
+    query.push(" WHERE user = ").push_bind(user);
+
+    if let Some(from_addr) = filter.from_addr {
+        query.push(" AND from_addr = ").push_bind(from_addr);
+    }
+
+    if let Some((name, value)) = filter.header {
+        let path = header_path(name);
+        match value {
+            Some(value) => {
+                query
+                    .push(" AND json_extract(headers, ")
+                    .push_bind(path)
+                    .push(") = ")
+                    .push_bind(value);
+            }
+            None => {
+                query
+                    .push(" AND json_extract(headers, ")
+                    .push_bind(path)
+                    .push(") IS NOT NULL");
+            }
+        }
+    }
+}
+
+/// Fetches up to `limit` emails strictly after `cursor`; a negative limit fetches everything.
+pub async fn emails_page(
+    pool: &Pool<Sqlite>,
+    user: &str,
+    filter: &EmailFilter<'_>,
+    order: EmailOrder,
+    cursor: &Cursor,
+    limit: i64,
+) -> Result<Vec<Email>, sqlx::Error> {
+    let mut query = QueryBuilder::new("SELECT * FROM emails");
+    push_email_filter(&mut query, user, filter);
+
+    let key = order.column();
+    query
+        .push(" AND (")
+        .push(key)
+        .push(" < ")
+        .push_bind(cursor.key)
+        .push(" OR (")
+        .push(key)
+        .push(" = ")
+        .push_bind(cursor.key)
+        .push(" AND id < ")
+        .push_bind(cursor.id.as_str())
+        .push(")) ORDER BY ")
+        .push(key)
+        .push(" DESC, id DESC LIMIT ")
+        .push_bind(limit);
+
+    query.build_query_as::<Email>().fetch_all(pool).await
+}
+
+/// Number of emails matching `filter`, ignoring pagination.
+pub async fn count_emails(
+    pool: &Pool<Sqlite>,
+    user: &str,
+    filter: &EmailFilter<'_>,
+) -> Result<i64, sqlx::Error> {
+    let mut query = QueryBuilder::new("SELECT COUNT(*) FROM emails");
+    push_email_filter(&mut query, user, filter);
+
+    query.build_query_scalar::<i64>().fetch_one(pool).await
+}
+
+#[derive(FromRow, Debug, Clone)]
+pub struct SavedScript {
+    pub owner: String,
+    pub name: String,
+    pub json: String,
+    pub description: String,
+    pub created: i64,
+    pub updated: i64,
+}
+impl SavedScript {
+    pub fn actions(&self) -> Result<Vec<Action>, serde_json::Error> {
+        serde_json::from_str(&self.json)
+    }
+}
+
+pub async fn list_scripts(
+    pool: &Pool<Sqlite>,
+    owner: &str,
+) -> Result<Vec<SavedScript>, sqlx::Error> {
+    sqlx::query_as!(
+        SavedScript,
+        r#"SELECT * FROM scripts WHERE owner = $1 ORDER BY name"#,
+        owner
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_script(
+    pool: &Pool<Sqlite>,
+    owner: &str,
+    name: &str,
+) -> Result<Option<SavedScript>, sqlx::Error> {
+    sqlx::query_as!(
+        SavedScript,
+        r#"SELECT * FROM scripts WHERE owner = $1 AND name = $2"#,
+        owner,
+        name
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn upsert_script(
+    pool: &Pool<Sqlite>,
+    owner: &str,
+    name: &str,
+    actions: &[Action],
+    description: &str,
+) -> Result<(), sqlx::Error> {
+    let json = serde_json::to_string(actions).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+    let now = util::unix_ms();
+
+    sqlx::query!(
+        r#"INSERT INTO scripts (owner, name, json, description, created, updated)
+                   VALUES ($1, $2, $3, $4, $5, $5)
+                   ON CONFLICT (owner, name) DO UPDATE
+                   SET json = excluded.json, description = excluded.description, updated = excluded.updated"#,
+        owner,
+        name,
+        json,
+        description,
+        now
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete_script(
+    pool: &Pool<Sqlite>,
+    owner: &str,
+    name: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"DELETE FROM scripts WHERE owner = $1 AND name = $2"#,
+        owner,
+        name
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+#[derive(FromRow, Debug, Clone)]
+pub struct ScriptTrigger {
+    pub owner: String,
+    pub script_name: String,
+    pub from_regex: Option<String>,
+    pub subject_regex: Option<String>,
+    pub webhook: Option<String>,
+    pub created: i64,
+}
+
+pub async fn list_script_triggers(
+    pool: &Pool<Sqlite>,
+    owner: &str,
+) -> Result<Vec<ScriptTrigger>, sqlx::Error> {
+    sqlx::query_as!(
+        ScriptTrigger,
+        r#"SELECT * FROM script_triggers WHERE owner = $1 ORDER BY script_name"#,
+        owner
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_script_trigger(
+    pool: &Pool<Sqlite>,
+    owner: &str,
+    script_name: &str,
+) -> Result<Option<ScriptTrigger>, sqlx::Error> {
+    sqlx::query_as!(
+        ScriptTrigger,
+        r#"SELECT * FROM script_triggers WHERE owner = $1 AND script_name = $2"#,
+        owner,
+        script_name
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Keeps `created` of a trigger that is being replaced.
+pub async fn upsert_script_trigger(
+    pool: &Pool<Sqlite>,
+    trigger: &ScriptTrigger,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO script_triggers (owner, script_name, from_regex, subject_regex, webhook, created)
+                   VALUES ($1, $2, $3, $4, $5, $6)
+                   ON CONFLICT (owner, script_name) DO UPDATE
+                   SET from_regex = excluded.from_regex, subject_regex = excluded.subject_regex, webhook = excluded.webhook"#,
+        trigger.owner,
+        trigger.script_name,
+        trigger.from_regex,
+        trigger.subject_regex,
+        trigger.webhook,
+        trigger.created
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete_script_trigger(
+    pool: &Pool<Sqlite>,
+    owner: &str,
+    script_name: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"DELETE FROM script_triggers WHERE owner = $1 AND script_name = $2"#,
+        owner,
+        script_name
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RunTrigger {
+    Manual,
+    /// A [`ScriptTrigger`] fired by a newly stored email.
+    Email,
+}
+impl RunTrigger {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RunTrigger::Manual => "manual",
+            RunTrigger::Email => "email",
+        }
+    }
+}
+
+#[derive(FromRow, Debug, Clone)]
+pub struct ScriptRun {
+    pub id: i64,
+    pub owner: String,
+    pub script_name: Option<String>,
+    pub trigger_type: String,
+    pub started: i64,
+    pub duration_ms: i64,
+    pub input_count: i64,
+    pub output_count: i64,
+    pub error: Option<String>,
+    pub output_path: Option<String>,
+    /// JSON array of [`StageTiming`]s.
+    pub stages: String,
+    pub http_fetches: i64,
+    pub email_id: Option<String>,
+}
+impl ScriptRun {
+    pub fn stages(&self) -> Result<Vec<StageTiming>, serde_json::Error> {
+        serde_json::from_str(&self.stages)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NewScriptRun<'a> {
+    pub owner: &'a str,
+    pub script_name: Option<&'a str>,
+    pub trigger: RunTrigger,
+    pub started: i64,
+    pub duration_ms: i64,
+    pub input_count: i64,
+    pub output_count: i64,
+    pub error: Option<String>,
+    pub stages: &'a [StageTiming],
+    pub http_fetches: i64,
+    pub email_id: Option<&'a str>,
+}
+
+pub async fn insert_script_run(
+    pool: &Pool<Sqlite>,
+    run: &NewScriptRun<'_>,
+) -> Result<i64, sqlx::Error> {
+    let trigger = run.trigger.as_str();
+    let stages = serde_json::to_string(run.stages).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+    let result = sqlx::query!(
+        r#"INSERT INTO script_runs (owner, script_name, trigger_type, started, duration_ms, input_count, output_count, error, stages, http_fetches, email_id)
+                   VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)"#,
+        run.owner,
+        run.script_name,
+        trigger,
+        run.started,
+        run.duration_ms,
+        run.input_count,
+        run.output_count,
+        run.error,
+        stages,
+        run.http_fetches,
+        run.email_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.last_insert_rowid())
+}
+
+pub async fn set_script_run_output(
+    pool: &Pool<Sqlite>,
+    id: i64,
+    output_path: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE script_runs SET output_path = $1 WHERE id = $2"#,
+        output_path,
+        id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Only runs that took at least `min_duration_ms` are listed; pass 0 to list every run.
+pub async fn list_script_runs(
+    pool: &Pool<Sqlite>,
+    owner: &str,
+    min_duration_ms: i64,
+    limit: i64,
+) -> Result<Vec<ScriptRun>, sqlx::Error> {
+    sqlx::query_as!(
+        ScriptRun,
+        r#"SELECT * FROM script_runs WHERE owner = $1 AND duration_ms >= $2 ORDER BY started DESC, id DESC LIMIT $3"#,
+        owner,
+        min_duration_ms,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_script_run(
+    pool: &Pool<Sqlite>,
+    owner: &str,
+    id: i64,
+) -> Result<Option<ScriptRun>, sqlx::Error> {
+    sqlx::query_as!(
+        ScriptRun,
+        r#"SELECT * FROM script_runs WHERE owner = $1 AND id = $2"#,
+        owner,
+        id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Deletes runs beyond the newest `keep` or started before `started_before`, returning their stored outputs.
+pub async fn prune_script_runs(
+    pool: &Pool<Sqlite>,
+    owner: &str,
+    keep: i64,
+    started_before: i64,
+) -> Result<Vec<String>, sqlx::Error> {
+    let pruned = sqlx::query!(
+        r#"DELETE FROM script_runs
+           WHERE owner = $1
+             AND (started < $2
+                  OR id NOT IN (SELECT id FROM script_runs WHERE owner = $1 ORDER BY started DESC, id DESC LIMIT $3))
+           RETURNING output_path"#,
+        owner,
+        started_before,
+        keep
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(pruned
+        .into_iter()
+        .filter_map(|row| row.output_path)
+        .collect())
+}
+
+#[derive(FromRow, Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmailFlags {
+    pub seen: bool,
+    pub spam: bool,
+    pub archived: bool,
+    pub starred: bool,
+    pub snoozed_until: Option<i64>,
+}
+
+/// Ids of every email, oldest first, optionally only `user`'s or the one with `id`.
+pub async fn email_ids(
+    pool: &Pool<Sqlite>,
+    user: Option<&str>,
+    id: Option<&str>,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT id FROM emails
+           WHERE ($1 IS NULL OR user = $1) AND ($2 IS NULL OR id = $2)
+           ORDER BY registered"#,
+        user,
+        id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_email(pool: &Pool<Sqlite>, id: &str) -> Result<Option<Email>, sqlx::Error> {
+    sqlx::query_as!(Email, r#"SELECT * FROM emails WHERE id = $1"#, id)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn get_email_flags(
+    pool: &Pool<Sqlite>,
+    email_id: &str,
+) -> Result<EmailFlags, sqlx::Error> {
+    let flags = sqlx::query_as!(
+        EmailFlags,
+        r#"SELECT seen, spam, archived, starred, snoozed_until FROM email_flags WHERE email_id = $1"#,
+        email_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(flags.unwrap_or_default())
+}
+
+pub async fn set_email_flags(
+    pool: &Pool<Sqlite>,
+    email_id: &str,
+    flags: &EmailFlags,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO email_flags (email_id, seen, spam, archived, starred, snoozed_until)
+                   VALUES ($1, $2, $3, $4, $5, $6)
+                   ON CONFLICT (email_id) DO UPDATE
+                   SET seen = excluded.seen, spam = excluded.spam, archived = excluded.archived,
+                       starred = excluded.starred, snoozed_until = excluded.snoozed_until"#,
+        email_id,
+        flags.seen,
+        flags.spam,
+        flags.archived,
+        flags.starred,
+        flags.snoozed_until
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[derive(FromRow, Debug, Clone)]
+pub struct Attachment {
+    pub idx: i64,
+    pub filename: Option<String>,
+    pub mime: String,
+    pub size: i64,
+    pub path: String,
+    pub hash: String,
+    /// `clean`, `infected` or `error` as clamd saw it at ingestion; `None` if not scanned.
+    pub scan: Option<String>,
+    pub scan_signature: Option<String>,
+}
+impl Attachment {
+    pub fn infected(&self) -> bool {
+        self.scan.as_deref() == Some("infected")
+    }
+}
+
+pub async fn email_attachments(
+    pool: &Pool<Sqlite>,
+    email_id: &str,
+) -> Result<Vec<Attachment>, sqlx::Error> {
+    sqlx::query_as!(
+        Attachment,
+        r#"SELECT idx, filename, mime, size, path, hash, scan, scan_signature
+           FROM attachments WHERE email_id = $1 ORDER BY idx"#,
+        email_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn email_attachment(
+    pool: &Pool<Sqlite>,
+    email_id: &str,
+    idx: i64,
+) -> Result<Option<Attachment>, sqlx::Error> {
+    sqlx::query_as!(
+        Attachment,
+        r#"SELECT idx, filename, mime, size, path, hash, scan, scan_signature
+           FROM attachments WHERE email_id = $1 AND idx = $2"#,
+        email_id,
+        idx
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+#[derive(FromRow, Debug, Clone, Serialize)]
+pub struct CapturedHeader {
+    pub name: String,
+    pub value: String,
+}
+
+pub async fn captured_headers(
+    pool: &Pool<Sqlite>,
+    email_id: &str,
+) -> Result<Vec<CapturedHeader>, sqlx::Error> {
+    sqlx::query_as!(
+        CapturedHeader,
+        r#"SELECT name, value FROM captured_headers WHERE email_id = $1 ORDER BY idx"#,
+        email_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Every captured value of header `name`, in order.
+pub async fn captured_header_values(
+    pool: &Pool<Sqlite>,
+    email_id: &str,
+    name: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    let name = name.to_ascii_lowercase();
+    sqlx::query_scalar!(
+        r#"SELECT value FROM captured_headers WHERE email_id = $1 AND name = $2 ORDER BY idx"#,
+        email_id,
+        name
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(FromRow, Debug, Clone)]
+pub struct InlineImage {
+    pub idx: i64,
+    pub content_id: String,
+    pub mime: String,
+    pub size: i64,
+    pub path: String,
+}
+
+pub async fn email_inline_images(
+    pool: &Pool<Sqlite>,
+    email_id: &str,
+) -> Result<Vec<InlineImage>, sqlx::Error> {
+    sqlx::query_as!(
+        InlineImage,
+        r#"SELECT idx, content_id, mime, size, path
+           FROM inline_images WHERE email_id = $1 ORDER BY idx"#,
+        email_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn email_inline_image(
+    pool: &Pool<Sqlite>,
+    email_id: &str,
+    idx: i64,
+) -> Result<Option<InlineImage>, sqlx::Error> {
+    sqlx::query_as!(
+        InlineImage,
+        r#"SELECT idx, content_id, mime, size, path
+           FROM inline_images WHERE email_id = $1 AND idx = $2"#,
+        email_id,
+        idx
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum UsageMetric {
+    EmailIngested,
+    ScriptRun,
+    RatelimitHit,
+    Request,
+    HttpFetch,
+}
+impl UsageMetric {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UsageMetric::EmailIngested => "email_ingested",
+            UsageMetric::ScriptRun => "script_run",
+            UsageMetric::RatelimitHit => "ratelimit_hit",
+            UsageMetric::Request => "request",
+            UsageMetric::HttpFetch => "http_fetch",
         }
     }
 }
+
+const USAGE_BUCKET_MS: i64 = 60 * 60 * 1000;
+const USAGE_DAY_MS: i64 = 24 * USAGE_BUCKET_MS;
+
+/// Unix ms of the start of the hourly bucket `unix_ms` falls in.
+pub fn usage_hour(unix_ms: i64) -> i64 {
+    unix_ms - unix_ms.rem_euclid(USAGE_BUCKET_MS)
+}
+
+/// Adds `count` occurrences of `metric`, and the `bytes` they stored or served, to `user`'s
+/// current hourly bucket.
+pub async fn record_usage(
+    pool: &Pool<Sqlite>,
+    user: &str,
+    metric: UsageMetric,
+    count: i64,
+    bytes: i64,
+) -> Result<(), sqlx::Error> {
+    let hour = usage_hour(util::unix_ms());
+    let metric = metric.as_str();
+
+    sqlx::query!(
+        r#"INSERT INTO usage (user, hour, metric, count, bytes)
+                   VALUES ($1, $2, $3, $4, $5)
+                   ON CONFLICT (user, hour, metric) DO UPDATE
+                   SET count = count + excluded.count, bytes = bytes + excluded.bytes"#,
+        user,
+        hour,
+        metric,
+        count,
+        bytes
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// `user`'s count of `metric` in the hourly bucket starting at `hour`.
+pub async fn hourly_usage(
+    pool: &Pool<Sqlite>,
+    user: &str,
+    metric: UsageMetric,
+    hour: i64,
+) -> Result<i64, sqlx::Error> {
+    let metric = metric.as_str();
+    let count = sqlx::query_scalar!(
+        r#"SELECT count FROM usage WHERE user = $1 AND hour = $2 AND metric = $3"#,
+        user,
+        hour,
+        metric
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(count.unwrap_or(0))
+}
+
+#[derive(FromRow, Debug, Clone)]
+pub struct UsageTotal {
+    pub user: String,
+    pub metric: String,
+    pub count: i64,
+    pub bytes: i64,
+}
+
+/// Per-user totals of every metric, counting whole hourly buckets from the one containing `since`.
+pub async fn usage_totals(pool: &Pool<Sqlite>, since: i64) -> Result<Vec<UsageTotal>, sqlx::Error> {
+    let since = since - since.rem_euclid(USAGE_BUCKET_MS);
+    sqlx::query_as!(
+        UsageTotal,
+        r#"SELECT user, metric, SUM(count) as "count!: i64", SUM(bytes) as "bytes!: i64"
+           FROM usage WHERE hour >= $1 GROUP BY user, metric"#,
+        since
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(FromRow, Debug, Clone)]
+pub struct DailyUsage {
+    /// Unix ms of the start of the UTC day.
+    pub day: i64,
+    pub metric: String,
+    pub count: i64,
+    pub bytes: i64,
+}
+
+/// `user`'s totals of every metric per UTC day, oldest first, over the last `days` days including
+/// today.
+pub async fn daily_usage(
+    pool: &Pool<Sqlite>,
+    user: &str,
+    days: i64,
+) -> Result<Vec<DailyUsage>, sqlx::Error> {
+    let now = util::unix_ms();
+    let since = now - now.rem_euclid(USAGE_DAY_MS) - (days - 1) * USAGE_DAY_MS;
+    sqlx::query_as!(
+        DailyUsage,
+        r#"SELECT hour / $1 * $1 as "day!: i64", metric, SUM(count) as "count!: i64", SUM(bytes) as "bytes!: i64"
+           FROM usage WHERE user = $2 AND hour >= $3 GROUP BY day, metric ORDER BY day"#,
+        USAGE_DAY_MS,
+        user,
+        since
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(FromRow, Debug, Clone)]
+pub struct UrlRedirect {
+    pub url: String,
+    pub target: String,
+    /// Unix ms.
+    pub resolved: i64,
+}
+
+/// The newest `limit` redirects resolved at or after `since`, newest first.
+pub async fn list_url_redirects(
+    pool: &Pool<Sqlite>,
+    since: i64,
+    limit: i64,
+) -> Result<Vec<UrlRedirect>, sqlx::Error> {
+    sqlx::query_as!(
+        UrlRedirect,
+        r#"SELECT url, target, resolved FROM url_redirects
+           WHERE resolved >= $1 ORDER BY resolved DESC LIMIT $2"#,
+        since,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_url_redirect(
+    pool: &Pool<Sqlite>,
+    url: &str,
+    since: i64,
+) -> Result<Option<UrlRedirect>, sqlx::Error> {
+    sqlx::query_as!(
+        UrlRedirect,
+        r#"SELECT url, target, resolved FROM url_redirects WHERE url = $1 AND resolved >= $2"#,
+        url,
+        since
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn upsert_url_redirect(
+    pool: &Pool<Sqlite>,
+    url: &str,
+    target: &str,
+    resolved: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO url_redirects (url, target, resolved) VALUES ($1, $2, $3)
+           ON CONFLICT (url) DO UPDATE SET target = excluded.target, resolved = excluded.resolved"#,
+        url,
+        target,
+        resolved
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn prune_url_redirects(pool: &Pool<Sqlite>, before: i64) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(r#"DELETE FROM url_redirects WHERE resolved < $1"#, before)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Deletes the redirect stored for `url`, or every redirect when `url` is `None`.
+pub async fn delete_url_redirects(
+    pool: &Pool<Sqlite>,
+    url: Option<&str>,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"DELETE FROM url_redirects WHERE $1 IS NULL OR url = $1"#,
+        url
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+#[derive(FromRow, Debug, Clone, Serialize)]
+pub struct Unsubscribe {
+    pub sender: String,
+    pub email_id: String,
+    pub url: String,
+    /// Unix ms.
+    pub attempted: i64,
+    /// HTTP status of the response, `None` when none arrived.
+    pub status: Option<i64>,
+    /// Why no response arrived.
+    pub error: Option<String>,
+}
+impl Unsubscribe {
+    pub fn succeeded(&self) -> bool {
+        self.status
+            .is_some_and(|status| (200..300).contains(&status))
+    }
+}
+
+pub async fn get_unsubscribe(
+    pool: &Pool<Sqlite>,
+    user: &str,
+    sender: &str,
+) -> Result<Option<Unsubscribe>, sqlx::Error> {
+    sqlx::query_as!(
+        Unsubscribe,
+        r#"SELECT sender, email_id, url, attempted, status, error FROM unsubscribes
+           WHERE user = $1 AND sender = $2"#,
+        user,
+        sender
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn upsert_unsubscribe(
+    pool: &Pool<Sqlite>,
+    user: &str,
+    unsubscribe: &Unsubscribe,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO unsubscribes (user, sender, email_id, url, attempted, status, error)
+                   VALUES ($1, $2, $3, $4, $5, $6, $7)
+                   ON CONFLICT (user, sender) DO UPDATE
+                   SET email_id = excluded.email_id, url = excluded.url,
+                       attempted = excluded.attempted, status = excluded.status,
+                       error = excluded.error"#,
+        user,
+        unsubscribe.sender,
+        unsubscribe.email_id,
+        unsubscribe.url,
+        unsubscribe.attempted,
+        unsubscribe.status,
+        unsubscribe.error
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[derive(FromRow, Debug, Clone)]
+pub struct ImapState {
+    pub uid_validity: i64,
+    pub last_uid: i64,
+}
+
+pub async fn get_imap_state(
+    pool: &Pool<Sqlite>,
+    account: &str,
+    mailbox: &str,
+) -> Result<Option<ImapState>, sqlx::Error> {
+    sqlx::query_as!(
+        ImapState,
+        r#"SELECT uid_validity, last_uid FROM imap_state WHERE account = $1 AND mailbox = $2"#,
+        account,
+        mailbox
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn set_imap_state(
+    pool: &Pool<Sqlite>,
+    account: &str,
+    mailbox: &str,
+    state: &ImapState,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO imap_state (account, mailbox, uid_validity, last_uid)
+                   VALUES ($1, $2, $3, $4)
+                   ON CONFLICT (account, mailbox) DO UPDATE
+                   SET uid_validity = excluded.uid_validity, last_uid = excluded.last_uid"#,
+        account,
+        mailbox,
+        state.uid_validity,
+        state.last_uid
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn pop3_seen(pool: &Pool<Sqlite>, account: &str) -> Result<HashSet<String>, sqlx::Error> {
+    let uidls = sqlx::query_scalar!(r#"SELECT uidl FROM pop3_seen WHERE account = $1"#, account)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(uidls.into_iter().collect())
+}
+
+pub async fn mark_pop3_seen(
+    pool: &Pool<Sqlite>,
+    account: &str,
+    uidls: &[&str],
+) -> Result<(), sqlx::Error> {
+    let now = util::unix_ms();
+    for uidl in uidls {
+        sqlx::query!(
+            r#"INSERT OR IGNORE INTO pop3_seen (account, uidl, seen) VALUES ($1, $2, $3)"#,
+            account,
+            uidl,
+            now
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Moves the IMAP and POP3 state kept under account `from` to `to`. Rows `to` already has are
+/// kept.
+pub async fn rename_account_state(
+    pool: &Pool<Sqlite>,
+    from: &str,
+    to: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE OR IGNORE imap_state SET account = $2 WHERE account = $1"#,
+        from,
+        to
+    )
+    .execute(pool)
+    .await?;
+    sqlx::query!(
+        r#"UPDATE OR IGNORE pop3_seen SET account = $2 WHERE account = $1"#,
+        from,
+        to
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Forgets the messages that are no longer on the server, given the UIDLs of those that are.
+pub async fn prune_pop3_seen(
+    pool: &Pool<Sqlite>,
+    account: &str,
+    current: &[&str],
+) -> Result<(), sqlx::Error> {
+    let current = serde_json::json!(current).to_string();
+    sqlx::query!(
+        r#"DELETE FROM pop3_seen
+           WHERE account = $1 AND uidl NOT IN (SELECT value FROM json_each($2))"#,
+        account,
+        current
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
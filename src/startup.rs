@@ -0,0 +1,124 @@
+use crate::{
+    config::Config,
+    sql::{self, MIGRATOR},
+};
+use sqlx::{Pool, Sqlite};
+use tokio::fs;
+use tracing::error;
+
+const WRITE_PROBE: &str = ".epv-write-check";
+
+/// The versions of the migrations applied to the database, none for a fresh one.
+async fn applied_migrations(pool: &Pool<Sqlite>) -> Result<Vec<i64>, sqlx::Error> {
+    // A fresh database has no migrations table yet, which is fine: migrations will create it.
+    let tables: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'",
+    )
+    .fetch_one(pool)
+    .await?;
+    if tables == 0 {
+        return Ok(vec![]);
+    }
+
+    sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success = 1")
+        .fetch_all(pool)
+        .await
+}
+
+async fn check_schema(pool: &Pool<Sqlite>, problems: &mut Vec<String>) {
+    let applied = match applied_migrations(pool).await {
+        Ok(x) => x,
+        Err(e) => {
+            problems.push(format!(
+                "storage.sqlite: could not read the schema version: {}",
+                e
+            ));
+            return;
+        }
+    };
+
+    let latest_known = MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0);
+    for version in applied {
+        if !MIGRATOR.iter().any(|m| m.version == version) {
+            problems.push(format!(
+                "storage.sqlite: database has schema version {} but this binary only knows up to {}; upgrade epv or restore a matching database backup",
+                version, latest_known
+            ));
+        }
+    }
+}
+
+async fn check_file_root(config: &Config, problems: &mut Vec<String>) {
+    let root = &config.storage.file_root;
+    if let Err(e) = fs::create_dir_all(root).await {
+        problems.push(format!(
+            "storage.file_root: could not create {}: {}",
+            root, e
+        ));
+        return;
+    }
+
+    let probe = format!("{}/{}", root, WRITE_PROBE);
+    match fs::write(&probe, b"").await {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe).await;
+        }
+        Err(e) => problems.push(format!(
+            "storage.file_root: {} is not writable by this user: {}",
+            root, e
+        )),
+    }
+}
+
+async fn check_frontend(config: &Config, problems: &mut Vec<String>) {
+    let frontend = &config.storage.frontend;
+    match fs::metadata(frontend).await {
+        Ok(metadata) if metadata.is_dir() => {
+            if !fs::try_exists(format!("{}/index.html", frontend))
+                .await
+                .unwrap_or(false)
+            {
+                problems.push(format!(
+                    "storage.frontend: {} has no index.html; point it at the frontend directory of the epv checkout",
+                    frontend
+                ));
+            }
+        }
+        Ok(_) => problems.push(format!("storage.frontend: {} is not a directory", frontend)),
+        Err(e) => problems.push(format!(
+            "storage.frontend: cannot access {}: {}",
+            frontend, e
+        )),
+    }
+}
+
+/// Returns every problem that would make the server fail later, so they can be reported at once.
+pub async fn diagnose(config: &Config, pool: &Pool<Sqlite>) -> Vec<String> {
+    let mut problems = vec![];
+
+    check_schema(pool, &mut problems).await;
+    check_file_root(config, &mut problems).await;
+    check_frontend(config, &mut problems).await;
+
+    problems
+}
+
+/// Moves the state of each account kept under its username alone, from before accounts were told
+/// apart by server, to its key. A username on several servers is left alone, as which of them the
+/// state is for is unknown; those accounts start over.
+pub async fn adopt_legacy_account_state(config: &Config, pool: &Pool<Sqlite>) {
+    let accounts = config.imap.as_slice();
+    for account in accounts {
+        let shared = accounts
+            .iter()
+            .filter(|other| other.username == account.username)
+            .count()
+            > 1;
+        if shared {
+            continue;
+        }
+        if let Err(e) = sql::rename_account_state(pool, &account.username, &account.key()).await {
+            error!(error = ?e, username = %account.username, "Account state UPDATE error");
+        }
+    }
+}
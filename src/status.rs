@@ -0,0 +1,158 @@
+use crate::util;
+use serde::Serialize;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex, PoisonError, RwLock,
+};
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+/// Errors in a row, without a successful search in between, after which ingestion is unhealthy.
+const UNHEALTHY_AFTER_ERRORS: u32 = 3;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImapStatus {
+    /// The mailbox's username and server, as in `user@imap.example.com`, or with SMTP or LMTP
+    /// delivery, the address listened on.
+    pub account: String,
+    /// Whether there is a session with the mailbox, or the listener is up.
+    pub connected: bool,
+    /// Whether the server supports IDLE, so new mail starts a cycle right away rather than at the
+    /// next poll.
+    pub idle: bool,
+    /// Messages in the mailbox newer than the last UID handled when the last cycle started, or
+    /// with POP3, messages on the server not yet handled.
+    pub pending: usize,
+    /// Unix ms of the last successful mailbox search, or with SMTP or LMTP delivery, of binding
+    /// the listener.
+    pub last_cycle: Option<i64>,
+    /// Unix ms of the last newly stored email.
+    pub last_ingested: Option<i64>,
+    /// How long after its `Date` header the last newly stored email was ingested.
+    pub last_lag_ms: Option<i64>,
+    /// Why the last cycle ended early, cleared by the next successful search.
+    pub last_error: Option<String>,
+    /// Errors since the last successful search.
+    pub consecutive_errors: u32,
+    /// Whether a search has succeeded since startup and fewer than 3 errors have followed it.
+    pub healthy: bool,
+    /// Emails newly stored since startup.
+    pub stored: u64,
+    /// Messages found to be stored already since startup.
+    pub duplicates: u64,
+    /// Messages that could not be stored since startup, whether or not they will be retried.
+    pub failed: u64,
+}
+
+/// In-memory state the background tasks report into, for `/api/status`. Nothing here survives a
+/// restart.
+#[derive(Debug)]
+pub struct Status {
+    started: Instant,
+    /// One for each account, in the order they were added.
+    imap: RwLock<Vec<ImapStatus>>,
+    running_scripts: AtomicUsize,
+    last_lag_alert: Mutex<Option<Instant>>,
+}
+impl Default for Status {
+    fn default() -> Self {
+        Status {
+            started: Instant::now(),
+            imap: RwLock::new(vec![]),
+            running_scripts: AtomicUsize::new(0),
+            last_lag_alert: Mutex::new(None),
+        }
+    }
+}
+impl Status {
+    pub fn uptime_secs(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    pub fn imap(&self) -> Vec<ImapStatus> {
+        let mut accounts = self
+            .imap
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        for imap in &mut accounts {
+            imap.healthy =
+                imap.last_cycle.is_some() && imap.consecutive_errors < UNHEALTHY_AFTER_ERRORS;
+        }
+        accounts
+    }
+
+    /// Updates the status of `account`, adding it first if it is new.
+    pub fn update_imap<T>(&self, account: &str, update: impl FnOnce(&mut ImapStatus) -> T) -> T {
+        let mut accounts = self.imap.write().unwrap_or_else(PoisonError::into_inner);
+        let index = match accounts.iter().position(|imap| imap.account == account) {
+            Some(index) => index,
+            None => {
+                accounts.push(ImapStatus {
+                    account: account.to_owned(),
+                    ..ImapStatus::default()
+                });
+                accounts.len() - 1
+            }
+        };
+        update(&mut accounts[index])
+    }
+
+    /// Records a successful search of `account` that found `pending` messages, logging if it ends
+    /// a run of errors long enough to have made ingestion from it unhealthy.
+    pub fn ingest_succeeded(&self, account: &str, pending: usize) {
+        let errors = self.update_imap(account, |imap| {
+            imap.pending = pending;
+            imap.last_cycle = Some(util::unix_ms());
+            imap.last_error = None;
+            std::mem::take(&mut imap.consecutive_errors)
+        });
+        if errors >= UNHEALTHY_AFTER_ERRORS {
+            info!(account, errors, "Ingestion recovered");
+        }
+    }
+
+    /// Records why ingestion from `account` failed, logging when this makes it unhealthy.
+    pub fn ingest_failed(&self, account: &str, error: String) {
+        let errors = self.update_imap(account, |imap| {
+            imap.consecutive_errors = imap.consecutive_errors.saturating_add(1);
+            imap.last_error = Some(error.clone());
+            imap.consecutive_errors
+        });
+        if errors == UNHEALTHY_AFTER_ERRORS {
+            error!(account, errors, last_error = %error, "Ingestion unhealthy");
+        }
+    }
+
+    pub fn running_scripts(&self) -> usize {
+        self.running_scripts.load(Ordering::Relaxed)
+    }
+
+    /// Whether a lag alert may fire now, i.e. none has in the last `cooldown`. Returning `true`
+    /// starts a new cooldown.
+    pub fn take_lag_alert(&self, cooldown: Duration) -> bool {
+        let mut last = self
+            .last_lag_alert
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if last.is_some_and(|last| last.elapsed() < cooldown) {
+            return false;
+        }
+
+        *last = Some(Instant::now());
+        true
+    }
+
+    /// Counts a script execution as running until the returned guard is dropped.
+    pub fn script_started(self: &Arc<Self>) -> RunningScript {
+        self.running_scripts.fetch_add(1, Ordering::Relaxed);
+        RunningScript(Arc::clone(self))
+    }
+}
+
+pub struct RunningScript(Arc<Status>);
+impl Drop for RunningScript {
+    fn drop(&mut self) {
+        self.0.running_scripts.fetch_sub(1, Ordering::Relaxed);
+    }
+}
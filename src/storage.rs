@@ -0,0 +1,191 @@
+use crate::config::Storage;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::io::{Error as IoError, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{self, AsyncWriteExt};
+use tracing::error;
+
+const ENCRYPTED_MAGIC: &[u8] = b"EPVENC1\0";
+const NONCE_LEN: usize = 24;
+
+pub fn cipher(storage: &Storage) -> io::Result<Option<XChaCha20Poly1305>> {
+    let Some(key_hex) = &storage.encryption_key else {
+        return Ok(None);
+    };
+
+    let key = hex::decode(key_hex).map_err(|e| IoError::new(ErrorKind::InvalidInput, e))?;
+    XChaCha20Poly1305::new_from_slice(&key)
+        .map(Some)
+        .map_err(|_| IoError::new(ErrorKind::InvalidInput, "encryption_key must be 32 bytes"))
+}
+
+/// Whether `name` can be used as a single directory name under `file_root`.
+pub fn is_path_component(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', '\0'])
+}
+
+fn outside(user: &str, name: &str) -> IoError {
+    IoError::new(
+        ErrorKind::PermissionDenied,
+        format!("{:?} is outside the storage of {:?}", name, user),
+    )
+}
+
+/// Where `name`, as stored in the database, lives on disk. Names always start with the owning
+/// user's directory; any other prefix, `..`, or an absolute path is refused, so a bad row cannot
+/// address another user's files.
+pub fn user_path(storage: &Storage, user: &str, name: &str) -> io::Result<PathBuf> {
+    if !is_path_component(user) {
+        return Err(outside(user, name));
+    }
+
+    let mut components = Path::new(name).components();
+    if components.next() != Some(Component::Normal(OsStr::new(user))) {
+        return Err(outside(user, name));
+    }
+    let mut rest = components.peekable();
+    if rest.peek().is_none() || !rest.all(|component| matches!(component, Component::Normal(_))) {
+        return Err(outside(user, name));
+    }
+
+    Ok(Path::new(&storage.file_root).join(name))
+}
+
+/// Fails unless `path`, with symlinks resolved, is inside the user's directory. `path` must exist.
+async fn check_inside(storage: &Storage, user: &str, name: &str, path: &Path) -> io::Result<()> {
+    let root = fs::canonicalize(Path::new(&storage.file_root).join(user)).await?;
+    if !fs::canonicalize(path).await?.starts_with(root) {
+        return Err(outside(user, name));
+    }
+    Ok(())
+}
+
+async fn existing_user_path(storage: &Storage, user: &str, name: &str) -> io::Result<PathBuf> {
+    let path = user_path(storage, user, name)?;
+    check_inside(storage, user, name, &path).await?;
+    Ok(path)
+}
+
+pub async fn read(storage: &Storage, user: &str, name: &str) -> io::Result<Vec<u8>> {
+    let bytes = fs::read(existing_user_path(storage, user, name).await?).await?;
+
+    let Some(sealed) = bytes.strip_prefix(ENCRYPTED_MAGIC) else {
+        return Ok(bytes);
+    };
+
+    let Some(cipher) = cipher(storage)? else {
+        return Err(IoError::new(
+            ErrorKind::InvalidData,
+            "file is encrypted but no encryption_key is configured",
+        ));
+    };
+
+    if sealed.len() < NONCE_LEN {
+        return Err(IoError::new(
+            ErrorKind::InvalidData,
+            "encrypted file truncated",
+        ));
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| IoError::new(ErrorKind::InvalidData, "could not decrypt file"))
+}
+
+pub async fn read_to_string(storage: &Storage, user: &str, name: &str) -> io::Result<String> {
+    String::from_utf8(read(storage, user, name).await?)
+        .map_err(|e| IoError::new(ErrorKind::InvalidData, e))
+}
+
+/// A file written under a temporary name, made visible by [`PendingWrite::commit`].
+pub struct PendingWrite {
+    temp_path: PathBuf,
+    final_path: PathBuf,
+}
+impl PendingWrite {
+    pub async fn commit(&self) -> io::Result<()> {
+        fs::rename(&self.temp_path, &self.final_path).await
+    }
+
+    /// Removes the file again after a [`PendingWrite::commit`].
+    pub async fn revert(self) {
+        if let Err(e) = fs::remove_file(&self.final_path).await {
+            error!(error = ?e, path = %self.final_path.display(), "Storage revert error");
+        }
+    }
+
+    pub async fn discard(self) {
+        if let Err(e) = fs::remove_file(&self.temp_path).await {
+            error!(error = ?e, path = %self.temp_path.display(), "Storage discard error");
+        }
+    }
+}
+
+pub async fn discard_all(pending: Vec<PendingWrite>) {
+    for pending_write in pending {
+        pending_write.discard().await;
+    }
+}
+
+pub async fn stage(
+    storage: &Storage,
+    user: &str,
+    name: &str,
+    contents: &[u8],
+) -> io::Result<PendingWrite> {
+    let final_path = user_path(storage, user, name)?;
+    let mut temp_path = OsString::from(&final_path);
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let data = match cipher(storage)? {
+        Some(cipher) => {
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let ciphertext = cipher
+                .encrypt(&nonce, contents)
+                .map_err(|_| IoError::new(ErrorKind::Other, "could not encrypt file"))?;
+
+            let mut sealed =
+                Vec::with_capacity(ENCRYPTED_MAGIC.len() + NONCE_LEN + ciphertext.len());
+            sealed.extend_from_slice(ENCRYPTED_MAGIC);
+            sealed.extend_from_slice(&nonce);
+            sealed.extend_from_slice(&ciphertext);
+            Cow::Owned(sealed)
+        }
+        None => Cow::Borrowed(contents),
+    };
+
+    // `user_path` guarantees a parent below the user's directory.
+    if let Some(parent) = final_path.parent() {
+        fs::create_dir_all(parent).await?;
+        check_inside(storage, user, name, parent).await?;
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(&temp_path)
+        .await?;
+    file.write_all(&data).await?;
+    file.sync_all().await?;
+
+    Ok(PendingWrite {
+        temp_path,
+        final_path,
+    })
+}
+
+pub async fn write(storage: &Storage, user: &str, name: &str, contents: &[u8]) -> io::Result<()> {
+    stage(storage, user, name, contents).await?.commit().await
+}
+
+pub async fn remove(storage: &Storage, user: &str, name: &str) -> io::Result<()> {
+    fs::remove_file(existing_user_path(storage, user, name).await?).await
+}
@@ -0,0 +1,40 @@
+use sd_notify::NotifyState;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::{error, info};
+
+/// A no-op unless started by systemd with `NOTIFY_SOCKET` set.
+fn notify(state: NotifyState) {
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        error!(error = ?e, "systemd notify error");
+    }
+}
+
+/// Sends `READY=1` once every one of a fixed number of components has reported in, so
+/// `Type=notify` units only count as started when both HTTP and IMAP are up.
+pub struct Readiness {
+    remaining: AtomicUsize,
+}
+impl Readiness {
+    pub fn new(components: usize) -> Self {
+        Readiness {
+            remaining: AtomicUsize::new(components),
+        }
+    }
+
+    pub fn component_ready(&self) {
+        if self.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+            info!("Ready");
+            notify(NotifyState::Ready);
+        }
+    }
+}
+
+/// Sent once per ingestion cycle and every 5 seconds while idling, so `WatchdogSec` needs to
+/// comfortably exceed the 5 second polling interval plus the longest expected fetch.
+pub fn watchdog() {
+    notify(NotifyState::Watchdog);
+}
+
+pub fn stopping() {
+    notify(NotifyState::Stopping);
+}
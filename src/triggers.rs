@@ -0,0 +1,161 @@
+use crate::{
+    api::execute_script::{self, http_client, SerdeElement},
+    config::Config,
+    sql::{self, Email, ScriptTrigger},
+    util::WorkerPool,
+    ManagedFetchBudget, ManagedPlugins, ManagedPool, ManagedStatus, ManagedUrlCache,
+};
+use regex::Regex;
+use reqwest::header::CONTENT_TYPE;
+use rocket::Shutdown;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, warn};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many emails' triggers run at a time. Firing for another waits for one of them to finish,
+/// holding up ingestion rather than letting runs pile up.
+pub const CONCURRENT_FIRES: usize = 8;
+
+#[derive(Debug, Serialize)]
+struct TriggerPayload<'a> {
+    event: &'static str,
+    script_name: &'a str,
+    email_id: &'a str,
+    user: &'a str,
+    output: &'a [SerdeElement],
+}
+
+/// Whether `regex` is absent or matches `text`. Regexes are checked when triggers are saved, so
+/// one that no longer compiles matches nothing.
+fn regex_matches(regex: Option<&str>, text: &str) -> bool {
+    let Some(regex) = regex else {
+        return true;
+    };
+    match Regex::new(regex) {
+        Ok(regex) => regex.is_match(text),
+        Err(e) => {
+            warn!(error = ?e, regex, "Trigger regex error");
+            false
+        }
+    }
+}
+
+fn matches(trigger: &ScriptTrigger, email: &Email) -> bool {
+    regex_matches(trigger.from_regex.as_deref(), &email.from_addr)
+        && regex_matches(trigger.subject_regex.as_deref(), &email.subject)
+}
+
+/// What trigger runs share with the scripts users run through the API.
+#[derive(Clone)]
+pub struct Triggers {
+    pub pool: ManagedPool,
+    pub url_cache: ManagedUrlCache,
+    pub plugins: ManagedPlugins,
+    pub fetch_budget: ManagedFetchBudget,
+    pub status: ManagedStatus,
+    /// Holds `CONCURRENT_FIRES` slots.
+    pub workers: WorkerPool,
+}
+impl Triggers {
+    /// Runs the triggers of the stored email's user that match it, one after the other, in the
+    /// background, once one of the `CONCURRENT_FIRES` slots is free.
+    pub async fn fire(&self, config: Arc<Config>, email_id: String, shutdown: Shutdown) {
+        let triggers = self.clone();
+        self.workers
+            .spawn(async move { triggers.run(config, &email_id, shutdown).await })
+            .await;
+    }
+
+    async fn run(&self, config: Arc<Config>, email_id: &str, shutdown: Shutdown) {
+        let email = match sql::get_email(&self.pool, email_id).await {
+            Ok(Some(x)) => x,
+            Ok(None) => return,
+            Err(e) => {
+                error!(error = ?e, email_id, "Trigger email SELECT error");
+                return;
+            }
+        };
+        let triggers = match sql::list_script_triggers(&self.pool, &email.user).await {
+            Ok(x) => x,
+            Err(e) => {
+                error!(error = ?e, email_id, "Trigger SELECT error");
+                return;
+            }
+        };
+
+        for trigger in triggers.iter().filter(|trigger| matches(trigger, &email)) {
+            let script_name = &trigger.script_name;
+            let actions = match sql::get_script(&self.pool, &trigger.owner, script_name)
+                .await
+                .map(|script| script.map(|script| script.actions()))
+            {
+                Ok(Some(Ok(x))) => x,
+                Ok(None) => continue,
+                Ok(Some(Err(e))) => {
+                    error!(error = ?e, %script_name, "Trigger stored JSON error");
+                    continue;
+                }
+                Err(e) => {
+                    error!(error = ?e, %script_name, "Trigger script SELECT error");
+                    continue;
+                }
+            };
+
+            // Failed runs are recorded with their error like any other.
+            let Ok(output) = execute_script::run_trigger(
+                Arc::clone(&config),
+                self,
+                script_name,
+                &actions,
+                email.clone(),
+                shutdown.clone(),
+            )
+            .await
+            else {
+                continue;
+            };
+
+            let Some(webhook) = &trigger.webhook else {
+                continue;
+            };
+            if output.is_empty() {
+                continue;
+            }
+            let payload = TriggerPayload {
+                event: "script_trigger",
+                script_name,
+                email_id,
+                user: &email.user,
+                output: &output,
+            };
+            post_webhook(&config, webhook, &payload).await;
+        }
+    }
+}
+
+async fn post_webhook(config: &Config, webhook: &str, payload: &TriggerPayload<'_>) {
+    let body = match serde_json::to_vec(payload) {
+        Ok(x) => x,
+        Err(e) => {
+            error!(error = ?e, "Trigger webhook serialize error");
+            return;
+        }
+    };
+
+    let result = match http_client(&config.http) {
+        Ok(client) => client
+            .post(webhook)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .timeout(WEBHOOK_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        error!(error = ?e, script_name = payload.script_name, "Trigger webhook error");
+    }
+}
@@ -0,0 +1,111 @@
+use crate::{
+    api::execute_script::http_client,
+    config::Config,
+    sql::{self, Email, Unsubscribe},
+    util,
+};
+use reqwest::header::CONTENT_TYPE;
+use serde_json::{Map, Value};
+use sqlx::{Pool, Sqlite};
+use std::time::Duration;
+use tracing::warn;
+use url::Url;
+
+const UNSUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug)]
+pub enum UnsubscribeError {
+    Sql(sqlx::Error),
+    Client(reqwest::Error),
+}
+
+#[derive(Debug)]
+pub enum Unsubscribed {
+    /// The email has no `List-Unsubscribe-Post` header or no HTTPS `List-Unsubscribe` URL.
+    NotOffered,
+    /// The sender already accepted an earlier request, which was not repeated.
+    Already(Unsubscribe),
+    Attempted(Unsubscribe),
+}
+
+/// The URL to POST to, if `email` offers RFC 8058 one-click unsubscription: the first HTTPS
+/// entry of `List-Unsubscribe`, next to `List-Unsubscribe-Post: List-Unsubscribe=One-Click`.
+pub fn one_click_url(email: &Email) -> Option<Url> {
+    let headers: Map<String, Value> = serde_json::from_str(&email.headers).ok()?;
+    let post = headers.get("list-unsubscribe-post")?.as_str()?;
+    if !post
+        .trim()
+        .eq_ignore_ascii_case("List-Unsubscribe=One-Click")
+    {
+        return None;
+    }
+
+    headers
+        .get("list-unsubscribe")?
+        .as_str()?
+        .split(',')
+        .filter_map(|entry| entry.trim().strip_prefix('<')?.strip_suffix('>'))
+        .filter_map(|entry| Url::parse(entry.trim()).ok())
+        .find(|url| url.scheme() == "https")
+}
+
+/// The earlier request that unsubscribed the email's user from its sender, which makes another
+/// one unnecessary.
+pub async fn succeeded_before(
+    pool: &Pool<Sqlite>,
+    email: &Email,
+) -> Result<Option<Unsubscribe>, UnsubscribeError> {
+    let sender = email.from_addr.to_lowercase();
+    let previous = sql::get_unsubscribe(pool, &email.user, &sender)
+        .await
+        .map_err(UnsubscribeError::Sql)?;
+    Ok(previous.filter(Unsubscribe::succeeded))
+}
+
+/// Unsubscribes the email's user from its sender with the one-click POST, and records the
+/// attempt for that sender whatever the response.
+pub async fn unsubscribe(
+    config: &Config,
+    pool: &Pool<Sqlite>,
+    email: &Email,
+) -> Result<Unsubscribed, UnsubscribeError> {
+    let Some(url) = one_click_url(email) else {
+        return Ok(Unsubscribed::NotOffered);
+    };
+
+    if let Some(previous) = succeeded_before(pool, email).await? {
+        return Ok(Unsubscribed::Already(previous));
+    }
+
+    let sender = email.from_addr.to_lowercase();
+    let client = http_client(&config.http).map_err(UnsubscribeError::Client)?;
+    let result = client
+        .post(url.clone())
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body("List-Unsubscribe=One-Click")
+        .timeout(UNSUBSCRIBE_TIMEOUT)
+        .send()
+        .await;
+
+    let (status, error) = match result {
+        Ok(response) => (Some(i64::from(response.status().as_u16())), None),
+        Err(e) => {
+            warn!(error = ?e, email_id = %email.id, "Unsubscribe HTTP error");
+            (None, Some(e.to_string()))
+        }
+    };
+    let attempt = Unsubscribe {
+        sender,
+        email_id: email.id.clone(),
+        url: url.to_string(),
+        attempted: util::unix_ms(),
+        status,
+        error,
+    };
+
+    sql::upsert_unsubscribe(pool, &email.user, &attempt)
+        .await
+        .map_err(UnsubscribeError::Sql)?;
+
+    Ok(Unsubscribed::Attempted(attempt))
+}
@@ -1,27 +1,21 @@
+use std::future::Future;
 use std::hash::Hash;
 use std::ops::Deref;
-use std::path::Path;
 use std::sync::{
-    atomic::{AtomicUsize, Ordering},
+    atomic::{AtomicU64, AtomicUsize, Ordering},
     Arc,
 };
-use std::time::{self, SystemTime};
+use std::time::{self, Duration, Instant, SystemTime};
 
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
 use mailparse::ParsedMail;
+use tiny_keccak::{Hasher, Sha3};
 
-use tokio::fs::{self, File, OpenOptions};
-use tokio::io;
+use tokio::sync::Semaphore;
+use tokio::task::{JoinError, JoinHandle};
 
 use dashmap::DashMap;
-
-pub async fn open_parents(opts: &mut OpenOptions, path: impl AsRef<Path>) -> io::Result<File> {
-    let mut buf = path.as_ref().to_path_buf();
-    buf.pop();
-
-    fs::create_dir_all(buf).await?;
-
-    opts.open(path).await
-}
+use serde::Serialize;
 
 pub fn traverse_mail<'a>(
     mail: &'a ParsedMail<'a>,
@@ -40,6 +34,73 @@ pub fn traverse_mail<'a>(
     return None;
 }
 
+pub fn collect_mail<'a>(
+    mail: &'a ParsedMail<'a>,
+    search: &mut impl FnMut(&ParsedMail) -> bool,
+    found: &mut Vec<&'a ParsedMail<'a>>,
+) {
+    if search(mail) {
+        found.push(mail);
+    }
+
+    for subpart in &mail.subparts {
+        collect_mail(subpart, search, found);
+    }
+}
+
+pub fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0; len];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// How long to wait before retry number `attempt`, counting from 0: `base` doubled per attempt
+/// up to `max`, then cut by a random factor of up to a half so that failures do not retry in step.
+pub fn backoff(attempt: u32, base: Duration, max: Duration) -> Duration {
+    let ceiling = base.saturating_mul(2u32.saturating_pow(attempt)).min(max);
+    let jitter = 0.5 + f64::from(OsRng.next_u32()) / f64::from(u32::MAX) / 2.0;
+    ceiling.mul_f64(jitter)
+}
+
+pub fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+pub fn sha3_hex(bytes: &[u8], len: usize) -> String {
+    let mut sha3 = Sha3::v256();
+    let mut output = [0; 32];
+    sha3.update(bytes);
+    sha3.finalize(&mut output);
+    hex::encode(&output[0..len])
+}
+
+/// Whether `text` matches `pattern`, where `*` stands for any run of characters. ASCII case is
+/// ignored.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let text = text.to_ascii_lowercase();
+
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = text.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
 pub fn unix_ms() -> i64 {
     let (dur, multiplier) = match SystemTime::now().duration_since(time::UNIX_EPOCH) {
         Ok(dur) => (dur, 1),
@@ -53,10 +114,12 @@ pub fn unix_ms() -> i64 {
     (dur.as_millis() as i64) * multiplier
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct CacheEntry<V> {
     value: V,
-    id: usize,
+    /// Tick of the last insert or lookup, for least-recently-used eviction.
+    last_used: AtomicUsize,
+    expires: Option<Instant>,
 }
 impl<V> Deref for CacheEntry<V> {
     type Target = V;
@@ -66,28 +129,345 @@ impl<V> Deref for CacheEntry<V> {
     }
 }
 
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Concurrent map holding at most `capacity` entries, evicting the least recently used one to
+/// make room. Entries older than `ttl`, or than the TTL they were inserted with, are never
+/// returned.
 #[derive(Debug, Clone)]
-pub struct Cache<K: Hash + PartialEq + Eq, V, const N: usize> {
+pub struct Cache<K: Hash + PartialEq + Eq, V> {
     data: Arc<DashMap<K, CacheEntry<V>>>,
-    last_id: Arc<AtomicUsize>,
+    clock: Arc<AtomicUsize>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+    capacity: usize,
+    ttl: Option<Duration>,
 }
-impl<K: Hash + PartialEq + Eq, V, const N: usize> Cache<K, V, N> {
+impl<K: Hash + PartialEq + Eq, V> Cache<K, V> {
     pub fn insert(&self, key: K, value: V) {
-        let id = self.last_id.fetch_add(1, Ordering::Relaxed);
-        self.data.insert(key, CacheEntry { value, id });
-        if self.data.len() >= N {
-            self.data.retain(|_k, v| id.wrapping_sub(v.id) >= N);
+        self.insert_expiring(key, value, self.ttl.map(|ttl| Instant::now() + ttl));
+    }
+
+    /// Overrides the cache's TTL for this entry.
+    pub fn insert_for(&self, key: K, value: V, ttl: Duration) {
+        self.insert_expiring(key, value, Some(Instant::now() + ttl));
+    }
+
+    fn insert_expiring(&self, key: K, value: V, expires: Option<Instant>) {
+        self.data.insert(
+            key,
+            CacheEntry {
+                value,
+                last_used: AtomicUsize::new(self.tick()),
+                expires,
+            },
+        );
+        if self.data.len() > self.capacity {
+            self.data.retain(|_k, v| !self.is_expired(v));
+        }
+        while self.data.len() > self.capacity {
+            self.evict_lru();
         }
     }
 
+    /// Inserts an entry that was already `age` old, e.g. one restored from persistent storage, so
+    /// that it expires when it would have had it stayed in memory.
+    pub fn insert_aged(&self, key: K, value: V, age: Duration) {
+        let expires = self.ttl.map(|ttl| Instant::now() + ttl.saturating_sub(age));
+        self.insert_expiring(key, value, expires);
+    }
+
     pub fn get(&self, key: &K) -> Option<dashmap::mapref::one::Ref<'_, K, CacheEntry<V>>> {
-        self.data.get(key)
+        let Some(entry) = self.data.get(key) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        if self.is_expired(&entry) {
+            drop(entry);
+            self.data.remove_if(key, |_k, v| self.is_expired(v));
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        entry.last_used.store(self.tick(), Ordering::Relaxed);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(entry)
+    }
+
+    pub fn remove(&self, key: &K) -> bool {
+        self.data.remove(key).is_some()
+    }
+
+    /// Returns how many entries were dropped.
+    pub fn clear(&self) -> usize {
+        let entries = self.data.len();
+        self.data.clear();
+        entries
+    }
+
+    /// Includes expired entries that have not been looked up or evicted since.
+    pub fn entry_count(&self) -> usize {
+        self.data.len()
     }
 
-    pub fn new() -> Self {
+    /// Hits and misses are counted since startup and survive [`Cache::clear`].
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.data.len(),
+            capacity: self.capacity,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn tick(&self) -> usize {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn is_expired(&self, entry: &CacheEntry<V>) -> bool {
+        entry
+            .expires
+            .is_some_and(|expires| Instant::now() >= expires)
+    }
+
+    /// The scan is linear, which is fine for caches sized in the thousands.
+    fn evict_lru(&self) {
+        let Some(oldest) = self
+            .data
+            .iter()
+            .map(|entry| entry.last_used.load(Ordering::Relaxed))
+            .min()
+        else {
+            return;
+        };
+        self.data
+            .retain(|_k, v| v.last_used.load(Ordering::Relaxed) != oldest);
+    }
+
+    pub fn new(capacity: usize, ttl: Option<Duration>) -> Self {
         Cache {
             data: Arc::new(DashMap::new()),
-            last_id: Arc::new(AtomicUsize::new(0)),
+            clock: Arc::new(AtomicUsize::new(0)),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+            capacity,
+            ttl,
+        }
+    }
+}
+
+/// Spawns tasks with at most `limit` of them running at a time. Tasks that spawn more work into
+/// the same pool can deadlock it once every permit is held, so nested work gets its own pool.
+#[derive(Debug, Clone)]
+pub struct WorkerPool {
+    permits: Arc<Semaphore>,
+}
+impl WorkerPool {
+    pub fn new(limit: usize) -> Self {
+        WorkerPool {
+            permits: Arc::new(Semaphore::new(limit)),
+        }
+    }
+
+    /// Waits for a free slot, then spawns `task` into it.
+    pub async fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let permit = Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .expect("WorkerPool semaphore is never closed");
+        tokio::spawn(async move {
+            let output = task.await;
+            drop(permit);
+            output
+        })
+    }
+
+    /// Runs every task, returning their outputs in the order the tasks were given.
+    pub async fn run_ordered<F>(
+        &self,
+        tasks: impl IntoIterator<Item = F>,
+    ) -> Vec<Result<F::Output, JoinError>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let mut handles = vec![];
+        for task in tasks {
+            handles.push(self.spawn(task).await);
+        }
+
+        let mut outputs = Vec::with_capacity(handles.len());
+        for handle in handles {
+            outputs.push(handle.await);
         }
+        outputs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backoff, wildcard_match, Cache, WorkerPool};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let base = Duration::from_secs(1);
+        let max = Duration::from_secs(60);
+        for attempt in 0..5 {
+            let delay = backoff(attempt, base, max);
+            let ceiling = base * 2u32.pow(attempt);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{:?}", delay);
+        }
+        for attempt in [6, 31, 32, u32::MAX] {
+            let delay = backoff(attempt, base, max);
+            assert!(delay >= max / 2 && delay <= max, "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn wildcard_match_spans_stars() {
+        assert!(wildcard_match("alice@example.com", "Alice@Example.com"));
+        assert!(!wildcard_match(
+            "alice@example.com",
+            "alice@example.com.evil"
+        ));
+        assert!(wildcard_match(
+            "*@old.example.com",
+            "anything@old.example.com"
+        ));
+        assert!(wildcard_match(
+            "alice+*@example.com",
+            "alice+news@example.com"
+        ));
+        assert!(!wildcard_match(
+            "alice+*@example.com",
+            "bob+news@example.com"
+        ));
+        assert!(wildcard_match("a*b*c", "abc"));
+        assert!(!wildcard_match("a*bc*c", "abc"));
+    }
+
+    #[test]
+    fn cache_keeps_capacity() {
+        let cache = Cache::new(3, None);
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+
+        assert_eq!(cache.entry_count(), 3);
+        for i in 7..10 {
+            assert_eq!(cache.get(&i).map(|v| **v), Some(i));
+        }
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let cache = Cache::new(2, None);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert!(cache.get(&"a").is_some());
+        cache.insert("c", 3);
+
+        assert!(cache.get(&"a").is_some());
+        assert!(cache.get(&"b").is_none());
+        assert!(cache.get(&"c").is_some());
+    }
+
+    #[test]
+    fn cache_replaces_existing_key() {
+        let cache = Cache::new(2, None);
+        cache.insert("a", 1);
+        cache.insert("a", 2);
+
+        assert_eq!(cache.entry_count(), 1);
+        assert_eq!(cache.get(&"a").map(|v| **v), Some(2));
+    }
+
+    #[test]
+    fn cache_expires_entries() {
+        let cache = Cache::new(2, Some(Duration::from_millis(20)));
+        cache.insert("a", 1);
+        assert!(cache.get(&"a").is_some());
+
+        thread::sleep(Duration::from_millis(30));
+        assert!(cache.get(&"a").is_none());
+        assert_eq!(cache.entry_count(), 0);
+    }
+
+    #[test]
+    fn cache_entry_ttl_overrides_cache_ttl() {
+        let cache = Cache::new(2, None);
+        cache.insert_for("a", 1, Duration::from_millis(20));
+        cache.insert("b", 2);
+
+        thread::sleep(Duration::from_millis(30));
+        assert!(cache.get(&"a").is_none());
+        assert!(cache.get(&"b").is_some());
+    }
+
+    #[test]
+    fn cache_counts_hits_and_misses() {
+        let cache = Cache::new(2, None);
+        cache.insert("a", 1);
+        assert!(cache.get(&"a").is_some());
+        assert!(cache.get(&"b").is_none());
+        assert!(cache.remove(&"a"));
+        assert!(cache.get(&"a").is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+        assert_eq!(stats.entries, 0);
+    }
+
+    #[test]
+    fn cache_drops_all_expired_when_full() {
+        let cache = Cache::new(2, Some(Duration::from_millis(20)));
+        cache.insert("x", 1);
+        cache.insert("y", 2);
+        thread::sleep(Duration::from_millis(30));
+        cache.insert("a", 3);
+
+        assert_eq!(cache.entry_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn worker_pool_keeps_order_and_limit() {
+        let pool = WorkerPool::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let tasks = (0..8).map(|i| {
+            let running = Arc::clone(&running);
+            let peak = Arc::clone(&peak);
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(5 * (8 - i))).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                i
+            }
+        });
+        let outputs: Vec<u64> = pool
+            .run_ordered(tasks)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+
+        assert_eq!(outputs, (0..8).collect::<Vec<_>>());
+        assert!(peak.load(Ordering::SeqCst) <= 2);
     }
 }